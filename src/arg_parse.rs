@@ -6,9 +6,16 @@
 // found could cope with the odd 'mount'-style arguments this uses (i.e. -o foo, -o bar).
 //
 
-use std::borrow::Borrow;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::num::ParseIntError;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use crate::backfs::XattrMode;
+use crate::block_map::{MapFormat, MetaFormat};
+use crate::bucket_store::{BucketAlloc, StoreFormat};
+use crate::fscache::{CachePolicy, Eviction, MtimePolicy};
 use crate::osstrextras::OsStrExtras;
 
 pub const USAGE: &str = "
@@ -16,6 +23,8 @@ BackFS.
 
 Usage:
     backfs [-o <option,[option]>] <backing> <mount point>
+    backfs --control <mount point> <command> [<argument>]
+    backfs --inspect <cache directory>
 
 General Options:
     -h --help         Show this help.
@@ -24,11 +33,143 @@ General Options:
 BackFS Options:
     -o cache            Cache location (REQUIRED)
     -o backing_fs       Backing filesystem location (REQUIRED here or
-                            as the first non-option argument)
+                            as the first non-option argument). May be a
+                            colon-separated list of directories, searched in
+                            order
     -o cache_size       Maximum size for the cache (default is for the cache to
                             grow to fill the device it is on)
+    -o max_per_file     Maximum amount of any one file to keep in the cache
+                            (default is no limit besides cache_size)
+    -o max_buckets      Maximum number of buckets in the cache, for cache
+                            filesystems that run out of inodes before space
+                            (default is no limit besides cache_size)
+    -o mem_cache        Also keep up to this much of the most recently read
+                            cached data in memory (default is 0, which is none)
+    -o skip_tail_below  Don't cache files' last blocks if they're shorter than this;
+                            read them from the backing filesystem every time
+                            (default is 0, which caches everything)
+    -o max_read         Fail reads asking for more than this much at once with
+                            EINVAL. Defaults to 16M; 0 disables
     -o rw               (experimental) Be a read-write cache (default is read-only)
     -o block_size       Cache block size. Defaults to 128K
+    -o read_size        How much to read from the backing filesystem on a cache
+                            miss. Must be a multiple of the block size, which
+                            it defaults to
+    -o backing_align    Round reads from the backing filesystem out to multiples
+                            of this size, caching the extra whole blocks read
+    -o honor_odirect    Don't cache reads of files opened with O_DIRECT or O_SYNC
+    -o file_mode        Report this (octal) mode for all files instead of their
+                            real permissions
+    -o dir_mode         Report this (octal) mode for all directories
+    -o uid              Report this user ID as the owner of everything
+    -o gid              Report this group ID as the group of everything
+    -o blocks           What to report as files' disk usage: their blocks in the
+                            'backing' filesystem (the default), or how much of
+                            them is 'cached'
+    -o fixed_mtime      Report this time (in seconds since the epoch) as the
+                            modification and change time of everything, for
+                            tools whose output depends on them
+    -o attr_ttl         How many seconds the kernel can keep files' attributes
+                            and directory entries before asking again.
+                            Defaults to 1
+    -o show_real_mode   Report files' real write permissions on a read-only mount
+                            (writing to them still fails)
+    -o bucket_alloc     How to get cache buckets for new data: 'reuse' freed ones
+                            (the default), or always allocate 'new' ones
+    -o dedup            Store blocks with identical contents in the cache once
+    -o eviction         How to pick what to evict when the cache is full: the
+                            least recently used block ('lru', the default), or
+                            of those, the one quickest to read again ('cost')
+    -o map_format       How the cache's map of files to buckets is stored: a
+                            symlink per block ('links', the default), or an
+                            'index' file per file. Can't be changed once the
+                            cache has been created
+    -o meta_format      How the map stores files' metadata: a 'text' file per
+                            file (the default), a smaller 'binary' file per
+                            file, or 'packed' into one file per directory.
+                            Can't be changed once the cache has been created
+    -o store            How the cached data is stored: a directory per block
+                            ('dirs', the default), or all in one sparse
+                            'slabfile'. Can't be changed once the cache has
+                            been created
+    -o warm_from        After mounting, prefetch what's listed in this manifest
+                            (from the 'manifest' control command) into the
+                            cache, in the background
+    -o fast_init        If the last unmount was clean, use the cache usage saved
+                            then instead of scanning the cache at startup
+    -o check_cache      Check the cache for inconsistencies on startup, and
+                            repair them
+    -o backing_id       Something which identifies the backing filesystem, to make
+                            sure the cache isn't used with the wrong one.
+                            Defaults to its device ID
+    -o reset_on_backing_change
+                        Empty the cache if the backing filesystem isn't the
+                            one it was used with before, instead of warning
+    -o no_cache_upgrade Refuse to use a cache made by an older version of BackFS
+                            instead of upgrading its format
+    -o allow_same_device
+                        Mount even if the cache is on the same device as the
+                            backing filesystem
+    -o no_auto_cache    Only cache what's prefetched, not everything that's read
+    -o policy           How to cache files by extension, overriding no_auto_cache
+                            and read_size: a comma-separated list of
+                            '.ext:cache', '.ext:cache:<read size>', or
+                            '.ext:nocache'. Extensions match in any case
+    -o no_control       Hide the .backfs_control file, so the cache can't be
+                            controlled through the mount
+    -o control_uid      Only let this user ID write to .backfs_control or use the
+                            control socket. Defaults to the user BackFS runs as
+    -o control_socket   Also take control commands, and answer 'health' checks, on
+                            a Unix socket at this path
+    -o file_hash        Work out the SHA-256 of files which are read all the way
+                            through in order, and report it in the
+                            user.backfs.sha256 xattr
+    -o xattr            Which extended attributes to show: the backing files' own
+                            as well as BackFS's user.backfs.* ones
+                            ('passthrough', the default), only BackFS's
+                            ('synthetic_only'), or none at all ('off')
+    -o cache_tree       Show what's in the cache in a read-only .backfs_cache
+                            directory at the root of the mount
+    -o cache_dirs       Cache directory listings until the directory's mtime
+                            changes. Changes made within the same second as
+                            the listing might not show up
+    -o assume_immutable Never check backing files' modification times; once
+                            cached, data is used until it's evicted
+    -o revalidate       How to check that cached files haven't changed: by their
+                            size and modification time ('mtime', the default),
+                            or also, when they're opened, by a 'sample' of
+                            their first and last blocks
+    -o always_revalidate  Treat cached data for files with a modification time
+                            of 0 (i.e. unknown) as always stale
+    -o stale_while_revalidate
+                        When a cached file has changed in the backing filesystem
+                            within this many seconds, keep serving reads of
+                            it from the old cached data while it's refreshed
+                            in the background (default is 0, which is off)
+    -o track_atime      Keep track of access times in the cache instead of
+                            reporting the backing filesystem's
+    -o flush_interval   Write tracked access times and read latencies to the cache
+                            directory every this many seconds, instead of on
+                            every read. Defaults to 5; 0 writes immediately
+    -o passthrough_after
+                        After this many writes to the cache fail in a row,
+                            stop using the cache until the resume_caching
+                            command is given. Defaults to 10; 0 never stops
+    -o sweep_interval   Every this many seconds, drop the cached data of files
+                            which have changed or been deleted from the backing
+                            filesystem, even if they aren't read again. Off
+                            by default
+    -o prefetch_blocks  Read files of at most this many blocks entirely into the
+                            cache when they are opened. Defaults to 4; 0 disables
+    -o admit_after      Only cache blocks once they've been read this many times
+                            without being in the cache (default is 1)
+    -o read_retries     Retry reads from the backing filesystem which fail with a
+                            transient error (EIO, EAGAIN, EINTR) this many times
+                            (default is 0)
+    -o backing_bw       Limit reads from the backing filesystem on cache misses
+                            to this many bytes per second (default is unlimited)
+    -o max_inflight     Limit how many reads from the backing filesystem on cache
+                            misses can be going on at once (default is unlimited)
     -v --verbose        Enable all debugging messages
        -o verbose
     -f --foreground     Enable foreground operation.
@@ -46,13 +187,63 @@ pub struct BackfsSettings {
     pub cache: OsString,
     pub backing_fs: OsString,
     pub cache_size: u64,
+    pub max_per_file: u64,
+    pub max_buckets: u64,
+    pub mem_cache: u64,
     pub rw: bool,
     pub block_size: u64,
+    pub read_size: u64,
+    pub max_read: u64,
+    pub skip_tail_below: u64,
+    pub backing_align: u64,
+    pub honor_odirect: bool,
+    pub prefetch_blocks: u64,
+    pub backing_bw: u64,
+    pub max_inflight: usize,
+    pub read_retries: u32,
+    pub admit_after: u32,
+    pub track_atime: bool,
+    pub flush_interval: u64,
+    pub sweep_interval: u64,
+    pub passthrough_after: u32,
+    pub cache_dirs: bool,
+    pub no_auto_cache: bool,
+    pub policies: Vec<(String, CachePolicy)>,
+    pub no_control: bool,
+    pub cache_tree: bool,
+    pub file_hash: bool,
+    pub xattr: XattrMode,
+    pub control_uid: Option<u32>,
+    pub control_socket: Option<OsString>,
+    pub check_cache: bool,
+    pub fast_init: bool,
+    pub warm_from: Option<OsString>,
+    pub no_cache_upgrade: bool,
+    pub backing_id: Option<String>,
+    pub reset_on_backing_change: bool,
+    pub allow_same_device: bool,
+    pub bucket_alloc: BucketAlloc,
+    pub dedup: bool,
+    pub map_format: MapFormat,
+    pub meta_format: MetaFormat,
+    pub store_format: StoreFormat,
+    pub eviction: Eviction,
+    pub mtime_policy: MtimePolicy,
+    pub revalidate_sample: bool,
+    pub stale_while_revalidate: u64,
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub fixed_mtime: Option<u64>,
+    pub attr_ttl: u64,
+    pub show_real_mode: bool,
+    pub cached_blocks: bool,
     pub foreground: bool,
     pub verbose: bool,
 }
 
-fn parse_human_number(s: &str) -> Result<u64, <u64 as FromStr>::Err> {
+pub fn parse_human_number(s: &str) -> Result<u64, <u64 as FromStr>::Err> {
     let (multiplier, s) = if s.ends_with('T') {
         (1024 * 1024 * 1024 * 1024, s.trim_end_matches('T'))
     } else if s.ends_with('G') {
@@ -65,14 +256,103 @@ fn parse_human_number(s: &str) -> Result<u64, <u64 as FromStr>::Err> {
         (1, s)
     };
 
-    match s.parse::<u64>() {
-        Ok(n) => Ok(n * multiplier),
-        Err(e) => Err(e)
+    let n = s.parse::<u64>()?;
+    match n.checked_mul(multiplier) {
+        Some(n) => Ok(n),
+        // A ParseIntError can't be made directly, but parsing the whole product gives the same one
+        // as a number that was too big to begin with.
+        None => (u128::from(n) * u128::from(multiplier)).to_string().parse(),
     }
 }
 
+/// Parse a caching policy for a file extension: `.ext:cache`, `.ext:cache:<read size>`, or
+/// `.ext:nocache`. Returns the extension (without the dot) and the policy.
+fn parse_policy(s: &str) -> Result<(String, CachePolicy), String> {
+    let mut parts = s.splitn(3, ':');
+    let extension = parts.next().unwrap_or("");
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    if extension.is_empty() {
+        return Err("no file extension given".to_owned());
+    }
+    let cache = match parts.next() {
+        Some("cache") => true,
+        Some("nocache") => false,
+        _ => return Err("expected 'cache' or 'nocache' after the extension".to_owned()),
+    };
+    let read_size = match parts.next() {
+        Some(_) if !cache => return Err("only 'cache' can have a read size".to_owned()),
+        Some(size) => Some(parse_human_number(size).map_err(|e| format!("invalid read size: {}", e))?),
+        None => None,
+    };
+    Ok((extension.to_owned(), CachePolicy { cache, read_size }))
+}
+
+/// What's wrong with the command line, from `BackfsSettings::parse`. It displays as the message
+/// to print before the usage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A flag (starting with `-`) which isn't one of ours.
+    UnknownOption(OsString),
+    /// An option whose value should be a number, or a size like `10G`, and isn't.
+    InvalidNumber { what: &'static str, error: ParseIntError },
+    /// An option whose value isn't one of the ones it takes.
+    InvalidChoice { what: &'static str, value: OsString },
+    /// One of the `policy` option's comma-separated policies.
+    InvalidPolicy { policy: String, error: String },
+    /// Two options which can't be used together.
+    Conflicting(&'static str, &'static str),
+    /// Only one non-option argument (the mount point), and no `backing_fs` option either.
+    MissingBacking,
+    /// Other than one or two non-option arguments.
+    WrongArgumentCount,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownOption(arg) => write!(f, "unrecognized option \"{:?}\"", arg),
+            ParseError::InvalidNumber { what, error } => write!(f, "invalid {}: {}", what, error),
+            ParseError::InvalidChoice { what, value } => write!(f, "invalid {}: {:?}", what, value),
+            ParseError::InvalidPolicy { policy, error } => {
+                write!(f, "invalid policy {:?}: {}", policy, error)
+            },
+            ParseError::Conflicting(a, b) => write!(f, "{} and {} can't be used together", a, b),
+            ParseError::MissingBacking => {
+                write!(f, "required backing filesystem argument not specified!")
+            },
+            ParseError::WrongArgumentCount => {
+                write!(f, "invalid number of non-option arguments given.")
+            },
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn number<T: FromStr<Err = ParseIntError>>(what: &'static str, value: &OsStr)
+    -> Result<T, ParseError>
+{
+    value.to_string_lossy().parse().map_err(|error| ParseError::InvalidNumber { what, error })
+}
+
+fn size(what: &'static str, value: &OsStr) -> Result<u64, ParseError> {
+    parse_human_number(&value.to_string_lossy())
+        .map_err(|error| ParseError::InvalidNumber { what, error })
+}
+
+/// Permission bits, in octal.
+fn mode(what: &'static str, value: &OsStr) -> Result<u32, ParseError> {
+    u32::from_str_radix(&value.to_string_lossy(), 8)
+        .map(|n| n & 0o7777)
+        .map_err(|error| ParseError::InvalidNumber { what, error })
+}
+
+fn invalid_choice(what: &'static str, value: &OsStr) -> ParseError {
+    ParseError::InvalidChoice { what, value: value.to_os_string() }
+}
+
 impl BackfsSettings {
-    pub fn parse(args: &[OsString]) -> BackfsSettings {
+    pub fn parse(args: &[OsString]) -> Result<BackfsSettings, ParseError> {
 
         // These are the default settings:
         let mut settings = BackfsSettings {
@@ -83,8 +363,58 @@ impl BackfsSettings {
             cache: OsString::new(),
             backing_fs: OsString::new(),
             cache_size: 0,
+            max_per_file: 0,
+            max_buckets: 0,
+            mem_cache: 0,
             rw: false,
             block_size: 0x2_0000,   // 131072 = 128 KiB
+            read_size: 0,           // same as the block size
+            max_read: 16 * 1024 * 1024,
+            skip_tail_below: 0,
+            backing_align: 0,
+            honor_odirect: false,
+            prefetch_blocks: 4,
+            backing_bw: 0,
+            max_inflight: 0,
+            read_retries: 0,
+            admit_after: 1,
+            track_atime: false,
+            flush_interval: 5,
+            sweep_interval: 0,
+            passthrough_after: 10,
+            cache_dirs: false,
+            no_auto_cache: false,
+            policies: vec![],
+            no_control: false,
+            cache_tree: false,
+            file_hash: false,
+            xattr: XattrMode::Passthrough,
+            control_uid: None,
+            control_socket: None,
+            check_cache: false,
+            fast_init: false,
+            warm_from: None,
+            no_cache_upgrade: false,
+            backing_id: None,
+            reset_on_backing_change: false,
+            allow_same_device: false,
+            bucket_alloc: BucketAlloc::Reuse,
+            dedup: false,
+            map_format: MapFormat::Links,
+            meta_format: MetaFormat::Text,
+            store_format: StoreFormat::Dirs,
+            eviction: Eviction::Lru,
+            mtime_policy: MtimePolicy::Check,
+            revalidate_sample: false,
+            stale_while_revalidate: 0,
+            file_mode: None,
+            dir_mode: None,
+            uid: None,
+            gid: None,
+            fixed_mtime: None,
+            attr_ttl: 1,
+            show_real_mode: false,
+            cached_blocks: false,
             foreground: false,
             verbose: false
         };
@@ -109,6 +439,16 @@ impl BackfsSettings {
 
                 if is_opt {
                     for option in arg.split(b',') {
+                        // Policies are comma-separated too: "policy=.iso:cache,.tmp:nocache".
+                        if option.starts_with(".") {
+                            if let Some(last) = options.last_mut() {
+                                if last.as_os_str().starts_with("policy=") {
+                                    last.push(",");
+                                    last.push(option);
+                                    continue;
+                                }
+                            }
+                        }
                         options.push(option.to_os_string());
                     }
                     is_opt = false;
@@ -126,9 +466,7 @@ impl BackfsSettings {
                     options.push(OsString::from("foreground"));
                     options.push(OsString::from("verbose"));
                 } else if arg.starts_with("-") {
-                    println!("unrecognized option \"{:?}\"", arg);
-                    options.push(OsString::from("help"));
-                    break;
+                    return Err(ParseError::UnknownOption(arg.clone()));
                 } else {
                     parsing_options = false;
                     values.push(arg.clone());
@@ -142,30 +480,143 @@ impl BackfsSettings {
         for opt in options {
             let parts: Vec<&OsStr> = opt.splitn(2, b'=').collect();
             if parts.len() == 2 {
+                let value = parts[1];
                 match parts[0].to_str() {
-                    Some("cache") => settings.cache = parts[1].to_os_string(),
-                    Some("backing_fs") => settings.backing_fs = parts[1].to_os_string(),
-                    Some("cache_size") => match parse_human_number(parts[1].to_string_lossy().borrow()) {
-                        Ok(n) => { settings.cache_size = n; },
-                        Err(e) => {
-                            println!("invalid cache size: {}", e);
-                            settings.help = true;
-                        }
+                    Some("cache") => settings.cache = value.to_os_string(),
+                    Some("backing_fs") => settings.backing_fs = value.to_os_string(),
+                    Some("warm_from") => settings.warm_from = Some(value.to_os_string()),
+                    Some("control_socket") => settings.control_socket = Some(value.to_os_string()),
+                    Some("cache_size") => settings.cache_size = size("cache size", value)?,
+                    Some("block_size") => settings.block_size = size("block size", value)?,
+                    Some("read_size") => settings.read_size = size("read size", value)?,
+                    Some("backing_align") => {
+                        settings.backing_align = size("backing alignment", value)?;
+                    },
+                    Some("max_read") => settings.max_read = size("max read size", value)?,
+                    Some("mem_cache") => settings.mem_cache = size("mem cache size", value)?,
+                    Some("stale_while_revalidate") => {
+                        settings.stale_while_revalidate =
+                            number("stale_while_revalidate window", value)?;
+                    },
+                    Some("skip_tail_below") => {
+                        settings.skip_tail_below = size("skip_tail_below size", value)?;
+                    },
+                    Some("read_retries") => {
+                        settings.read_retries = number("read retry count", value)?;
+                    },
+                    Some("admit_after") => settings.admit_after = number("admit_after count", value)?,
+                    Some("max_per_file") => {
+                        settings.max_per_file = size("per-file cache size", value)?;
+                    },
+                    Some("max_buckets") => settings.max_buckets = number("max_buckets count", value)?,
+                    Some("backing_bw") => settings.backing_bw = size("backing bandwidth", value)?,
+                    Some("max_inflight") => {
+                        settings.max_inflight = number("max_inflight count", value)?;
+                    },
+                    Some("flush_interval") => {
+                        settings.flush_interval = number("flush_interval", value)?;
+                    },
+                    Some("passthrough_after") => {
+                        settings.passthrough_after = number("passthrough_after count", value)?;
+                    },
+                    Some("sweep_interval") => {
+                        settings.sweep_interval = number("sweep_interval", value)?;
+                    },
+                    Some("prefetch_blocks") => {
+                        settings.prefetch_blocks = number("prefetch block count", value)?;
+                    },
+                    Some("bucket_alloc") => settings.bucket_alloc = match value.to_str() {
+                        Some("reuse") => BucketAlloc::Reuse,
+                        Some("new") => BucketAlloc::New,
+                        _ => return Err(invalid_choice("bucket allocation policy", value)),
                     },
-                    Some("block_size") => match parse_human_number(parts[1].to_string_lossy().borrow()) {
-                        Ok(n) => { settings.block_size = n; },
-                        Err(e) => {
-                            println!("invalid block size: {}", e);
-                            settings.help = true;
+                    Some("blocks") => settings.cached_blocks = match value.to_str() {
+                        Some("backing") => false,
+                        Some("cached") => true,
+                        _ => return Err(invalid_choice("blocks setting", value)),
+                    },
+                    Some("eviction") => settings.eviction = match value.to_str() {
+                        Some("lru") => Eviction::Lru,
+                        Some("cost") => Eviction::Cost,
+                        _ => return Err(invalid_choice("eviction policy", value)),
+                    },
+                    Some("revalidate") => settings.revalidate_sample = match value.to_str() {
+                        Some("mtime") => false,
+                        Some("sample") => true,
+                        _ => return Err(invalid_choice("revalidation method", value)),
+                    },
+                    Some("xattr") => settings.xattr = match value.to_str() {
+                        Some("passthrough") => XattrMode::Passthrough,
+                        Some("synthetic_only") => XattrMode::SyntheticOnly,
+                        Some("off") => XattrMode::Off,
+                        _ => return Err(invalid_choice("xattr setting", value)),
+                    },
+                    Some("policy") => {
+                        for policy in value.to_string_lossy().split(',') {
+                            let parsed = parse_policy(policy).map_err(|error| {
+                                ParseError::InvalidPolicy { policy: policy.to_owned(), error }
+                            })?;
+                            settings.policies.push(parsed);
                         }
                     },
-                    _ => settings.fuse_options.push(parts[1].to_os_string())
+                    Some("map_format") => settings.map_format = match value.to_str() {
+                        Some("links") => MapFormat::Links,
+                        Some("index") => MapFormat::Index,
+                        _ => return Err(invalid_choice("map format", value)),
+                    },
+                    Some("meta_format") => settings.meta_format = match value.to_str() {
+                        Some("text") => MetaFormat::Text,
+                        Some("binary") => MetaFormat::Binary,
+                        Some("packed") => MetaFormat::Packed,
+                        _ => return Err(invalid_choice("meta format", value)),
+                    },
+                    Some("store") => settings.store_format = match value.to_str() {
+                        Some("dirs") => StoreFormat::Dirs,
+                        Some("slabfile") => StoreFormat::Slab,
+                        _ => return Err(invalid_choice("store format", value)),
+                    },
+                    Some("file_mode") => settings.file_mode = Some(mode("file mode", value)?),
+                    Some("dir_mode") => settings.dir_mode = Some(mode("directory mode", value)?),
+                    Some("uid") => settings.uid = Some(number("uid", value)?),
+                    Some("backing_id") => {
+                        settings.backing_id = Some(value.to_string_lossy().into_owned());
+                    },
+                    Some("control_uid") => settings.control_uid = Some(number("control_uid", value)?),
+                    Some("gid") => settings.gid = Some(number("gid", value)?),
+                    Some("fixed_mtime") => settings.fixed_mtime = Some(number("fixed_mtime", value)?),
+                    Some("attr_ttl") => settings.attr_ttl = number("attr_ttl", value)?,
+                    _ => settings.fuse_options.push(value.to_os_string())
                 }
             } else {
                 match opt.to_str() {
                     Some("help") => settings.help = true,
                     Some("version") => settings.version = true,
                     Some("rw") => settings.rw = true,
+                    Some("honor_odirect") => settings.honor_odirect = true,
+                    Some("track_atime") => settings.track_atime = true,
+                    Some(name @ "assume_immutable") | Some(name @ "always_revalidate") => {
+                        let policy = if name == "assume_immutable" {
+                            MtimePolicy::Ignore
+                        } else {
+                            MtimePolicy::ZeroIsStale
+                        };
+                        if settings.mtime_policy != MtimePolicy::Check && settings.mtime_policy != policy {
+                            return Err(ParseError::Conflicting("assume_immutable", "always_revalidate"));
+                        }
+                        settings.mtime_policy = policy;
+                    },
+                    Some("cache_dirs") => settings.cache_dirs = true,
+                    Some("dedup") => settings.dedup = true,
+                    Some("no_auto_cache") => settings.no_auto_cache = true,
+                    Some("no_control") => settings.no_control = true,
+                    Some("cache_tree") => settings.cache_tree = true,
+                    Some("file_hash") => settings.file_hash = true,
+                    Some("show_real_mode") => settings.show_real_mode = true,
+                    Some("check_cache") => settings.check_cache = true,
+                    Some("fast_init") => settings.fast_init = true,
+                    Some("no_cache_upgrade") => settings.no_cache_upgrade = true,
+                    Some("reset_on_backing_change") => settings.reset_on_backing_change = true,
+                    Some("allow_same_device") => settings.allow_same_device = true,
                     Some("verbose") => settings.verbose = true,
                     Some("foreground") => settings.foreground = true,
                     _ => settings.fuse_options.push(opt.to_os_string())
@@ -176,11 +627,9 @@ impl BackfsSettings {
         match values.len() {
             1 => {
                 if settings.backing_fs.is_empty() {
-                    println!("required backing filesystem argument not specified!");
-                    settings.help = true;
-                } else {
-                    settings.mount_point = values[0].clone();
+                    return Err(ParseError::MissingBacking);
                 }
+                settings.mount_point = values[0].clone();
             },
             2 => {
                 settings.backing_fs = values[0].clone();
@@ -188,12 +637,244 @@ impl BackfsSettings {
             },
             _ => {
                 if !settings.help && !settings.version {
-                    println!("invalid number of non-option arguments given.");
-                    settings.help = true;
+                    return Err(ParseError::WrongArgumentCount);
+                }
+            }
+        }
+
+        Ok(settings)
+    }
+
+    /// The backing filesystem directories, in the order they are searched. `backing_fs` can be a
+    /// colon-separated list of directories, layered so that earlier ones shadow later ones.
+    pub fn backing_layers(&self) -> Vec<PathBuf> {
+        self.backing_fs.as_os_str()
+            .split(b':')
+            .filter(|layer| !layer.is_empty())
+            .map(PathBuf::from)
+            .collect()
+    }
+
+    /// Whether the FUSE options let users other than the one mounting it use the mount.
+    pub fn allows_other_users(&self) -> bool {
+        self.fuse_options.iter().any(|option| option == "allow_other" || option == "allow_root")
+    }
+
+    /// Whether the kernel is asked to buffer writes and keep track of file sizes and mtimes itself,
+    /// with the `writeback_cache` FUSE option.
+    pub fn uses_writeback_cache(&self) -> bool {
+        self.fuse_options.iter().any(|option| option == "writeback_cache")
+    }
+
+    /// Check that `writeback_cache` is only given in `rw` mode. It only changes how writes are
+    /// handled, so for a read-only mount all it would do is have the kernel trust its own idea of
+    /// file attributes over BackFS's.
+    pub fn check_writeback_cache(&self) -> Result<(), String> {
+        if self.uses_writeback_cache() && !self.rw {
+            return Err("writeback_cache can only be used with rw".to_owned());
+        }
+        Ok(())
+    }
+
+    /// The user ID allowed to use the control file and the cache tree: `control_uid` if it was
+    /// given, or else the user BackFS runs as.
+    pub fn control_uid(&self) -> u32 {
+        self.control_uid.unwrap_or_else(|| unsafe { libc::getuid() })
+    }
+
+    /// Check that the mount point isn't a backing filesystem directory or the cache directory, or
+    /// inside one, which would have BackFS reading through itself once it's mounted. The paths
+    /// are compared canonicalized, so symlinks and `..` can't hide it.
+    pub fn check_mount_point(&self) -> Result<(), String> {
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+        let mount_point = canonical(Path::new(&self.mount_point));
+        let dirs = self.backing_layers().into_iter()
+            .map(|layer| ("backing filesystem", layer))
+            .chain(Some(("cache directory", PathBuf::from(&self.cache))))
+            .filter(|(_, path)| !path.as_os_str().is_empty());
+        for (what, path) in dirs {
+            let path = canonical(&path);
+            if mount_point == path {
+                return Err(format!("the mount point {:?} is the {}", mount_point, what));
+            } else if mount_point.starts_with(&path) {
+                return Err(format!("the mount point {:?} is inside the {} {:?}",
+                                   mount_point, what, path));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn parse_args(args: &[&str]) -> Result<BackfsSettings, ParseError> {
+    let args: Vec<OsString> = std::iter::once("backfs").chain(args.iter().copied())
+        .map(OsString::from)
+        .collect();
+    BackfsSettings::parse(&args)
+}
+
+#[test]
+fn test_parse_errors() {
+    macro_rules! test {
+        ($args:expr => $expected:pat) => {
+            let args: &[&str] = &$args;
+            match parse_args(args) {
+                Err($expected) => (),
+                other => panic!("{:?}: expected {}, got {:?}", args, stringify!($expected), other),
+            }
+        }
+    }
+    test!(["-x", "/backing", "/mnt"] => ParseError::UnknownOption(_));
+    test!(["-o", "cache_size=lots", "/backing", "/mnt"]
+          => ParseError::InvalidNumber { what: "cache size", .. });
+    test!(["-o", "block_size=12Q", "/backing", "/mnt"]
+          => ParseError::InvalidNumber { what: "block size", .. });
+    test!(["-o", "cache_size=16777216T", "/backing", "/mnt"]
+          => ParseError::InvalidNumber { what: "cache size", .. });
+    test!(["-o", "read_retries=-1", "/backing", "/mnt"]
+          => ParseError::InvalidNumber { what: "read retry count", .. });
+    test!(["-o", "file_mode=789", "/backing", "/mnt"]
+          => ParseError::InvalidNumber { what: "file mode", .. });
+    test!(["-o", "uid=abc", "/backing", "/mnt"] => ParseError::InvalidNumber { what: "uid", .. });
+    test!(["-o", "eviction=mru", "/backing", "/mnt"]
+          => ParseError::InvalidChoice { what: "eviction policy", .. });
+    test!(["-o", "store=tarball", "/backing", "/mnt"]
+          => ParseError::InvalidChoice { what: "store format", .. });
+    test!(["-o", "policy=.iso:cache,.tmp:maybe", "/backing", "/mnt"]
+          => ParseError::InvalidPolicy { .. });
+    test!(["-o", "assume_immutable,always_revalidate", "/backing", "/mnt"]
+          => ParseError::Conflicting(..));
+    test!(["/mnt"] => ParseError::MissingBacking);
+    test!([] => ParseError::WrongArgumentCount);
+    test!(["/backing", "/mnt", "/extra"] => ParseError::WrongArgumentCount);
+
+    // Messages are what they always were.
+    assert_eq!(parse_args(&["-o", "cache_size=lots", "/backing", "/mnt"]).unwrap_err().to_string(),
+               "invalid cache size: invalid digit found in string");
+    assert_eq!(parse_args(&["-o", "cache_size=16777216T", "/backing", "/mnt"]).unwrap_err()
+                   .to_string(),
+               "invalid cache size: number too large to fit in target type");
+    assert_eq!(parse_human_number("16777215T"), Ok(16777215 << 40));
+    assert_eq!(parse_args(&["-o", "eviction=mru", "/backing", "/mnt"]).unwrap_err().to_string(),
+               "invalid eviction policy: \"mru\"");
+}
+
+#[test]
+fn test_parse_ok() {
+    let settings = parse_args(&["-o", "cache=/cache,cache_size=10G,policy=.iso:cache:1M,.tmp:nocache",
+                                "-o", "allow_other", "-f", "/backing", "/mnt"]).unwrap();
+    assert_eq!(settings.cache, "/cache");
+    assert_eq!(settings.cache_size, 10 * 1024 * 1024 * 1024);
+    assert_eq!(settings.policies.len(), 2);
+    assert_eq!(settings.fuse_options, vec![OsString::from("allow_other")]);
+    assert!(settings.foreground);
+    assert_eq!((settings.backing_fs.as_os_str(), settings.mount_point.as_os_str()),
+               (OsStr::new("/backing"), OsStr::new("/mnt")));
+
+    // Asking for help or the version doesn't need anything else.
+    assert!(parse_args(&["--help"]).unwrap().help);
+    assert!(parse_args(&["-V"]).unwrap().version);
+    let settings = parse_args(&["-o", "backing_fs=/backing", "/mnt"]).unwrap();
+    assert_eq!(settings.backing_fs, "/backing");
+}
+
+/// Throw lots of random option strings made of bits of real ones at the parser. Whatever it makes
+/// of them, it shouldn't panic, and anything it rejects should say why.
+#[test]
+fn test_parse_fuzz() {
+    const NAMES: &[&str] = &["cache", "cache_size", "block_size", "read_size", "max_read",
+        "read_retries", "file_mode", "uid", "eviction", "blocks", "store", "xattr", "policy",
+        "stale_while_revalidate", "assume_immutable", "always_revalidate", "rw", "help", ""];
+    const VALUES: &[&str] = &["", "0", "10", "10G", "1T", "99999999999999999999", "-1", "0x10",
+        "16777216T", "16777215T", "18446744073709551615K", "17179869184G", "99999999999999M",
+        "777", "lru", "cost", "cached", "slabfile", "off", ".iso:cache", ".tmp:nocache:1M",
+        ":cache", ".x", "=", ",", "\u{fffd}", " "];
+
+    // xorshift, so every run tries the same things.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = |n: usize| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state % n as u64) as usize
+    };
+
+    for _ in 0 .. 2000 {
+        let mut args = vec![];
+        for _ in 0 .. next(4) {
+            let mut option = String::new();
+            for i in 0 .. 1 + next(3) {
+                if i > 0 {
+                    option.push(',');
+                }
+                option.push_str(NAMES[next(NAMES.len())]);
+                if next(4) != 0 {
+                    option.push('=');
+                    option.push_str(VALUES[next(VALUES.len())]);
                 }
             }
+            args.push("-o".to_owned());
+            args.push(option);
         }
+        for _ in 0 .. next(4) {
+            args.push(["/backing", "/mnt", "-q", "--", "-o"][next(5)].to_owned());
+        }
+
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        if let Err(e) = parse_args(&args) {
+            assert!(!e.to_string().is_empty(), "{:?}", args);
+        }
+    }
+}
 
-        settings
+#[test]
+fn test_check_mount_point() {
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-mount-point", std::process::id()));
+    let _ = fs::remove_dir_all(&base);
+    for dir in &["backing/sub", "other", "cache"] {
+        fs::create_dir_all(base.join(dir)).unwrap();
     }
+    std::os::unix::fs::symlink(base.join("backing"), base.join("link")).unwrap();
+    let check = |backing: &str, mount_point: &str| {
+        let mut cache = OsString::from("cache=");
+        cache.push(base.join("cache"));
+        let backing = backing.split(':')
+            .map(|layer| base.join(layer).into_os_string().into_string().unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        let settings = parse_args(&["-o", cache.to_str().unwrap(), &backing,
+                                    base.join(mount_point).to_str().unwrap()]).unwrap();
+        settings.check_mount_point()
+    };
+
+    assert!(check("backing", "other").is_ok());
+    assert!(check("backing", "backing").unwrap_err().contains("is the backing filesystem"));
+    assert!(check("backing", "backing/sub").unwrap_err().contains("is inside the backing"));
+    // However it's spelled.
+    assert!(check("backing", "link/sub").is_err());
+    assert!(check("backing", "other/../backing/sub").is_err());
+    assert!(check("other:link", "backing/sub").is_err());
+    assert!(check("backing", "cache").unwrap_err().contains("is the cache directory"));
+    // Only whole path components count.
+    fs::create_dir_all(base.join("backing2")).unwrap();
+    assert!(check("backing", "backing2").is_ok());
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_check_writeback_cache() {
+    let settings = parse_args(&["-o", "writeback_cache", "/backing", "/mnt"]).unwrap();
+    assert!(settings.uses_writeback_cache());
+    assert!(settings.check_writeback_cache().unwrap_err().contains("only be used with rw"));
+
+    let settings = parse_args(&["-o", "rw,writeback_cache", "/backing", "/mnt"]).unwrap();
+    assert!(settings.uses_writeback_cache());
+    assert!(settings.check_writeback_cache().is_ok());
+    // It's still passed on to FUSE.
+    assert_eq!(settings.fuse_options, vec![OsString::from("writeback_cache")]);
+
+    let settings = parse_args(&["-o", "allow_other", "/backing", "/mnt"]).unwrap();
+    assert!(!settings.uses_writeback_cache());
+    assert!(settings.check_writeback_cache().is_ok());
 }
@@ -4,38 +4,143 @@
 //
 
 use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::ffi::{CStr, CString, OsStr, OsString};
 use std::fs;
 use std::fs::File;
-use std::io;
-use std::mem;
+use std::io::{self, BufRead, Read, Write};
+use std::mem::{self, MaybeUninit};
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
-use std::os::unix::fs::MetadataExt;
-use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::fs::{DirBuilderExt, FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
 use std::time::{Duration, SystemTime};
 
-use crate::arg_parse::BackfsSettings;
-use crate::block_map::FsCacheBlockMap;
-use crate::bucket_store::FsCacheBucketStore;
-use crate::fscache::{FsCache, Cache};
+use crate::arg_parse::{self, BackfsSettings};
+use crate::block_map::{self, AnyBlockMap, CacheBlockMap, MapFormat, MetaFormat};
+use crate::bucket_store::{AnyBucketStore, FsCacheBucketStore, SlabBucketStore, StoreFormat};
+use crate::fscache::{FsCache, Cache, CacheMetrics, Eviction, ManifestEntry, MtimePolicy};
 use crate::fsll::Fsll;
 use crate::libc_wrappers;
 use crate::utils;
 
 use daemonize::Daemonize;
 use fuse_mt::*;
+use sha2::{Digest, Sha256};
 
 const TTL: Duration = Duration::from_secs(1);
 
 const BACKFS_CONTROL_FILE_NAME: &str = ".backfs_control";
 const BACKFS_CONTROL_FILE_PATH: &str = "/.backfs_control";
 
+// How far back errors count against the `health` control socket command.
+const HEALTH_WINDOW: Duration = Duration::from_secs(60);
+
+// How long a control socket connection can sit idle before it's dropped.
+const CONTROL_SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+
+// With -o stale_while_revalidate, how many files can be waiting to be refreshed. Past that, stale
+// data is still served, but isn't queued to be refreshed.
+const REFRESH_QUEUE_LIMIT: usize = 1000;
+
 const BACKFS_VERSION_FILE_NAME: &str = ".backfs_version";
 const BACKFS_VERSION_FILE_PATH: &str = "/.backfs_version";
 
-const BACKFS_CONTROL_FILE_HELP: &str = "commands: test, noop, invalidate <path>, free_orphans\n";
+const BACKFS_CONTROL_FILE_HELP: &str = "commands: test, noop, selftest, invalidate <path>, prefetch <path>, free_orphans, reset, rebuild_lru, free_bytes <size>, resume_caching, set_cache_size <size>, manifest\n";
+
+// Where in the cache directory the `manifest` control command writes the manifest.
+const MANIFEST_FILE_NAME: &str = "manifest";
+
+// How many buckets to scan at startup between progress reports.
+const INIT_PROGRESS_INTERVAL: u64 = 10_000;
+
+#[cfg(target_os = "linux")]
+const O_DIRECT: libc::c_int = libc::O_DIRECT;
+#[cfg(not(target_os = "linux"))]
+const O_DIRECT: libc::c_int = 0;
+
+// Open flags that signal the client doesn't want its reads cached (if honor_odirect is set).
+const UNCACHED_OPEN_FLAGS: libc::c_int = O_DIRECT | libc::O_SYNC;
+
+const BACKFS_CACHE_TREE_NAME: &str = ".backfs_cache";
+const BACKFS_CACHE_TREE_PATH: &str = "/.backfs_cache";
+
+// The directory handle given out for directories with nothing to open in the backing filesystem:
+// the root when the backing root can't be opened, and the cache tree. Real handles are DIR
+// pointers, which can't have this value.
+const FAKE_DIR_FH: u64 = u64::MAX;
+
+// File handles for open fake files start here. Real ones are file descriptors, which are far
+// smaller.
+const FAKE_FILE_FH_BASE: u64 = 1 << 48;
+
+// From <linux/fuse.h>: the kernel may keep its page cache of the file from earlier opens.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// Something in the cache tree, with `-o cache_tree`.
+enum CacheTreeNode {
+    /// A file with data in the cache, from when it had this mtime.
+    File { mtime: i64 },
+    /// A directory with cached files somewhere under it, and what's in it.
+    Dir(Vec<DirectoryEntry>),
+}
+
+/// The whole-file SHA-256 being worked out from one file handle's reads, with `-o file_hash`.
+struct FileHashState {
+    /// Where the next read has to start for it to keep going.
+    next_offset: u64,
+    /// None once a read has come out of order, until the file is read from the start again.
+    hasher: Option<Sha256>,
+}
+
+impl FileHashState {
+    fn new() -> Self {
+        Self { next_offset: 0, hasher: Some(Sha256::new()) }
+    }
+}
+
+const FILE_HASH_XATTR: &str = "user.backfs.sha256";
+
+/// How reads of a file handle which bypass the cache are done.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Passthrough {
+    /// Positional reads of a regular file or block device.
+    Positional,
+    /// Sequential reads of a pipe, socket, or character device, which can't seek.
+    Stream,
+}
+
+/// Which extended attributes the mount has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XattrMode {
+    /// The backing files' own, and BackFS's synthetic `user.backfs.*` ones.
+    Passthrough,
+    /// Only the `user.backfs.*` ones. The backing filesystem's xattrs are never looked at.
+    SyntheticOnly,
+    /// None at all: xattr calls fail with `ENOTSUP`.
+    Off,
+}
+
+/// What to reply when getting or listing the backing file's xattrs fails. A backing filesystem
+/// without xattr support just doesn't have the attribute; anything unexpected is logged and
+/// counted in `errors`.
+fn backing_xattr_error(op: &str, path: &Path, e: libc::c_int, errors: &AtomicU64) -> libc::c_int {
+    match e {
+        libc::ENOTSUP => libc_wrappers::ENOATTR,
+        libc::ERANGE => e,
+        _ if e == libc_wrappers::ENOATTR => e,
+        _ => {
+            warn!("{}({:?}): {}", op, path, io::Error::from_raw_os_error(e));
+            errors.fetch_add(1, Ordering::Relaxed);
+            e
+        }
+    }
+}
 
 fn epoch_time(secs: i64, nanos: u32) -> SystemTime {
     if secs > 0 {
@@ -47,9 +152,67 @@ fn epoch_time(secs: i64, nanos: u32) -> SystemTime {
 
 pub struct BackFs {
     pub settings: BackfsSettings,
-    fscache: FsCache<FsCacheBlockMap, FsCacheBlockMap,
-                     FsCacheBucketStore<Fsll>, FsCacheBucketStore<Fsll>>,
+    backing_layers: Vec<PathBuf>,
+    // Shared with the thread which flushes deferred state, with -o flush_interval, the one which
+    // sweeps out changed files, with -o sweep_interval, and the one answering the control socket,
+    // with -o control_socket.
+    fscache: Arc<BackfsCache>,
+    // Owner of the magic files, and the only user allowed to write to the control file.
     uid: u32,
+    passthrough_fhs: Mutex<HashMap<u64, Passthrough>>,
+    // Directory listings and the mtimes of the directories they're from, with -o cache_dirs.
+    dir_cache: Mutex<HashMap<OsString, (i64, Vec<DirectoryEntry>)>>,
+    // With -o file_hash: hashes in progress, by file handle, and finished ones (hex), by path,
+    // with the mtime of the file they were worked out from.
+    hashing: Mutex<HashMap<u64, FileHashState>>,
+    file_hashes: Mutex<HashMap<OsString, (i64, String)>>,
+    // The contents of each open fake file as of when it was opened, so that its size and what's
+    // read from it always match, even if what it would say now is different.
+    fake_fhs: Mutex<HashMap<u64, Arc<Vec<u8>>>>,
+    next_fake_fh: AtomicU64,
+    control_socket: Mutex<Option<ControlSocket>>,
+    // Files whose stale cached data is to be refreshed in the background, with
+    // -o stale_while_revalidate.
+    refresh_queue: Arc<Mutex<RefreshQueue>>,
+    // Failed stats of backing files, other than for ones which don't exist, and unexpected errors
+    // from getting or listing their xattrs.
+    stat_errors: AtomicU64,
+    xattr_errors: AtomicU64,
+}
+
+/// A snapshot of the cache's metrics, and of errors from operations which don't go through the
+/// cache, from `BackFs::metrics`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BackfsMetrics {
+    pub cache: CacheMetrics,
+    /// Stats of backing files which failed, not counting ones which weren't there.
+    pub stat_errors: u64,
+    /// Unexpected errors from getting or listing backing files' xattrs.
+    pub xattr_errors: u64,
+}
+
+type BackfsCache = FsCache<AnyBlockMap, AnyBlockMap, AnyBucketStore, AnyBucketStore>;
+
+/// A backing file's stat, with its birth time if its filesystem has one, as from
+/// `libc_wrappers::lstatx`.
+type StatX = (libc::stat64, Option<(i64, u32)>);
+
+/// The thread listening on the control socket, and how to stop it.
+struct ControlSocket {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// Files waiting for their stale cached data to be refreshed, and whether a thread is working
+/// through them.
+#[derive(Default)]
+struct RefreshQueue {
+    pending: VecDeque<OsString>,
+    // The pending files, and the one being refreshed now.
+    queued: HashSet<OsString>,
+    running: bool,
 }
 
 fn is_backfs_fake_file(path: &Path) -> bool {
@@ -57,12 +220,273 @@ fn is_backfs_fake_file(path: &Path) -> bool {
         || path == Path::new(BACKFS_VERSION_FILE_PATH)
 }
 
-fn backfs_version_str() -> String {
-    format!("BackFS version: {} {}\nFuseMT version: {}\n",
-            super::VERSION, super::GIT_REVISION, ::fuse_mt::VERSION)
+/// The `.` and `..` entries, for directories which aren't listed from the backing filesystem.
+fn dot_entries() -> Vec<DirectoryEntry> {
+    [".", ".."].iter()
+        .map(|name| DirectoryEntry { name: OsString::from(name), kind: FileType::Directory })
+        .collect()
+}
+
+/// The backing path for `partial` (a path from the root of the mount): the first layer it exists
+/// in, or the first layer if it doesn't exist in any.
+fn layered_path<T: AsRef<OsStr>>(layers: &[PathBuf], partial: &T) -> OsString {
+    let relative = Path::new(partial).strip_prefix("/").unwrap();
+    if layers.len() > 1 {
+        for layer in layers {
+            let candidate = layer.join(relative);
+            if fs::symlink_metadata(&candidate).is_ok() {
+                return candidate.into_os_string();
+            }
+        }
+    }
+    layers.first()
+        .map(|layer| layer.join(relative))
+        .unwrap_or_else(|| relative.to_owned())
+        .into_os_string()
+}
+
+/// Split a control command into the command and its argument. Fails with EINVAL if the argument
+/// can't be told apart from the command unambiguously, and EBADMSG if the command isn't UTF-8.
+fn parse_command(data: &[u8]) -> Result<(&str, &OsStr), libc::c_int> {
+    let (command_bytes, arg_bytes) = if data.first() == Some(&0) {
+        // Binary format, for scripts: "\0command\0argument\0". The argument can be any bytes
+        // other than NUL, including leading or trailing whitespace and newlines. Leaving off the
+        // final NUL, or having more fields than that, could mean the argument got cut short or
+        // run together with something else, so neither is allowed. A newline after the final NUL
+        // is ignored, for the control socket's sake.
+        let fields = data[1..].strip_suffix(b"\0\n").or_else(|| data[1..].strip_suffix(b"\0"));
+        let fields = match fields {
+            Some(fields) => fields,
+            None => {
+                warn!("binary control command doesn't end with a NUL");
+                return Err(libc::EINVAL);
+            }
+        };
+        let mut fields = fields.splitn(2, |x| *x == 0);
+        let command_bytes = fields.next().unwrap_or(&[]);
+        let arg_bytes = fields.next().unwrap_or(&[]);
+        if arg_bytes.contains(&0) {
+            warn!("binary control command has more than one argument");
+            return Err(libc::EINVAL);
+        }
+        (command_bytes, arg_bytes)
+    } else {
+        // remove a trailing newline if it exists
+        let data_trimmed = if data.last() == Some(&0x0A) {
+            &data[..data.len() - 1]
+        } else {
+            data
+        };
+
+        let first_space = data_trimmed.iter().position(|x| *x == 0x20)
+                .unwrap_or(data_trimmed.len());
+        let (command_bytes, arg_bytes) = data_trimmed.split_at(first_space);
+        let arg_start = if arg_bytes.is_empty() { 0 } else { 1 }; // skip over the space delimiter if there is one
+        (command_bytes, &arg_bytes[arg_start..])
+    };
+
+    if arg_bytes.contains(&0) {
+        // No path has a NUL in it; this is probably a binary command that lost its first byte.
+        warn!("control command argument has a NUL in it");
+        return Err(libc::EINVAL);
+    }
+    let command = str::from_utf8(command_bytes).map_err(|e| {
+        warn!("control command isn't valid UTF-8: {}", e);
+        libc::EBADMSG
+    })?;
+    Ok((command, OsStr::from_bytes(arg_bytes)))
+}
+
+/// Carry out a command written to the control file or sent to the control socket.
+fn run_command(fscache: &BackfsCache, backing_layers: &[PathBuf], cache_dir: &Path, command: &str,
+               arg: &OsStr)
+    -> Result<(), libc::c_int>
+{
+    debug!("command: {:?}, arg: {:?}", command, arg);
+
+    match command {
+        "test" => {
+            return Err(libc::EXDEV);
+        },
+        "noop" => (),
+        "selftest" => {
+            // The control file is never cached, so its path is free to use for test data.
+            if let Err(e) = fscache.self_test(OsStr::new(BACKFS_CONTROL_FILE_PATH)) {
+                error!("selftest failed: {}", e);
+                return Err(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        },
+        "invalidate" => {
+            let _ignore_errors = fscache.invalidate_path(arg);
+        },
+        "prefetch" => {
+            if !Path::new(arg).is_absolute() {
+                warn!("prefetch: {:?} is not an absolute path", arg);
+                return Err(libc::EINVAL);
+            }
+            let result = File::open(layered_path(backing_layers, &arg))
+                .and_then(|mut file| {
+                    let metadata = file.metadata()?;
+                    if metadata.len() == 0 {
+                        return Ok(());
+                    }
+                    fscache.prefetch(arg, 0, metadata.len(), &mut file, metadata.mtime())
+                });
+            if let Err(e) = result {
+                error!("prefetch: failed to read {:?}: {}", arg, e);
+                return Err(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        },
+        "free_block" => {
+            let path_and_block = Path::new(arg);
+            let path = path_and_block.parent()
+                .ok_or_else(|| { warn!("bad path: no parent"); libc::EINVAL })?;
+            let blocks = path_and_block.file_name()
+                .ok_or_else(|| { warn!("no filename given"); libc::EINVAL })?
+                .to_str()
+                .ok_or_else(|| { warn!("bad UTF-8"); libc::EINVAL })?;
+            // Either a single block number, or an inclusive range like "3-7".
+            let (start, end) = match blocks.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => (blocks, blocks),
+            };
+            let parse = |s: &str| s.parse::<u64>().map_err(|e| {
+                warn!("doesn't end in a valid number or range: {}", e);
+                libc::EINVAL
+            });
+            let (start, end) = (parse(start)?, parse(end)?);
+            if start > end {
+                warn!("invalid block range: {} is after {}", start, end);
+                return Err(libc::EINVAL);
+            }
+            let mut total = 0;
+            for block in start ..= end {
+                match fscache.free_block(path.as_os_str(), block) {
+                    Ok(Some(n)) => {
+                        debug!("{:?}/{}: {} bytes freed", path, block, n);
+                        total += n;
+                    },
+                    Ok(None) => debug!("{:?}/{} file or block not found", path, block),
+                    Err(e) => error!("error freeing block {} of {:?}: {}", block, path, e),
+                }
+            }
+            if start != end {
+                debug!("{:?}/{}-{}: {} bytes freed in total", path, start, end, total);
+            }
+        },
+        "free_orphans" => {
+            let _ignore_errors = fscache.free_orphaned_buckets();
+        },
+        "reset" => {
+            let _ignore_errors = fscache.reset();
+        },
+        "rebuild_lru" => {
+            if let Err(e) = fscache.rebuild_lru() {
+                return Err(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        },
+        "free_bytes" => {
+            let bytes = arg.to_str()
+                .ok_or_else(|| { warn!("bad UTF-8"); libc::EINVAL })
+                .and_then(|s| arg_parse::parse_human_number(s)
+                    .map_err(|e| { warn!("invalid number of bytes: {}", e); libc::EINVAL }))?;
+            if let Err(e) = fscache.free_bytes(bytes) {
+                return Err(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        },
+        "resume_caching" => {
+            if !fscache.resume_caching() {
+                info!("resume_caching: the cache wasn't being bypassed");
+            }
+        },
+        "set_cache_size" => {
+            let size = arg.to_str()
+                .ok_or_else(|| { warn!("bad UTF-8"); libc::EINVAL })
+                .and_then(|s| arg_parse::parse_human_number(s)
+                    .map_err(|e| { warn!("invalid cache size: {}", e); libc::EINVAL }))?;
+            let max_size = if size == 0 { None } else { Some(size) };
+            let _ignore_errors = fscache.set_max_size(max_size);
+        },
+        "manifest" => {
+            // Commands run as the daemon's user, so they don't get to pick where it writes files.
+            let path = cache_dir.join(MANIFEST_FILE_NAME);
+            if !arg.is_empty() {
+                warn!("manifest: takes no argument; it's always written to {:?}", path);
+                return Err(libc::EINVAL);
+            }
+            let partial_path = cache_dir.join(format!("{}.tmp", MANIFEST_FILE_NAME));
+            let result = fscache.manifest()
+                .and_then(|entries| {
+                    let mut out = io::BufWriter::new(File::create(&partial_path)?);
+                    write_manifest(&entries, &mut out)?;
+                    out.flush()
+                })
+                .and_then(|()| fs::rename(&partial_path, &path));
+            if let Err(e) = result {
+                error!("manifest: failed to write {:?}: {}", path, e);
+                return Err(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        },
+        _ => {
+            return Err(libc::EBADMSG);
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer commands from one control socket connection, a line at a time, until it's closed. Each
+/// gets a one-line reply: "OK", or "error: " and what went wrong. `health` isn't a control file
+/// command; it replies "OK", or "degraded: " and what has been failing lately.
+fn serve_control_connection(fscache: &BackfsCache, backing_layers: &[PathBuf], cache_dir: &Path,
+                            stream: UnixStream)
+    -> io::Result<()>
+{
+    // Don't let an idle client hold on to its thread forever.
+    stream.set_read_timeout(Some(CONTROL_SOCKET_TIMEOUT))?;
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let reply = match parse_command(&line) {
+            Ok(("health", _)) => {
+                let errors = fscache.recent_errors(HEALTH_WINDOW);
+                if errors.is_empty() {
+                    "OK".to_owned()
+                } else {
+                    format!("degraded: {}", errors.join(", "))
+                }
+            },
+            Ok((command, arg)) => {
+                match run_command(fscache, backing_layers, cache_dir, command, arg) {
+                    Ok(()) => "OK".to_owned(),
+                    Err(errno) => format!("error: {}", io::Error::from_raw_os_error(errno)),
+                }
+            },
+            Err(errno) => format!("error: {}", io::Error::from_raw_os_error(errno)),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+}
+
+/// Version information, as printed by `--version` and found in the `.backfs_version` file.
+pub fn version_string() -> String {
+    format!("BackFS version: {} {}\n\
+             FuseMT version: {}\n\
+             fuser version: {}\n\
+             Build time: {}\n\
+             Cache format version: {}\n",
+            crate::VERSION, crate::GIT_REVISION, crate::FUSEMT_VERSION, crate::FUSER_VERSION,
+            utils::format_utc_time(crate::BUILD_TIME), crate::CACHE_FORMAT_VERSION)
 }
 
-fn backfs_fake_file_attr(path: Option<&str>, uid: u32) -> Option<FileAttr> {
+/// The attributes of a fake file, given its size, which has to come from the same snapshot of its
+/// contents as reads of it get (see `BackFs::fake_file_data`).
+fn backfs_fake_file_attr(path: Option<&str>, uid: u32, size: u64) -> Option<FileAttr> {
     let fake_file_attrs = FileAttr {
         size: 0,
         blocks: 0,
@@ -84,20 +508,219 @@ fn backfs_fake_file_attr(path: Option<&str>, uid: u32) -> Option<FileAttr> {
             let mut attr = fake_file_attrs;
             attr.perm = 0o600; // -rw-------
             attr.uid = uid;
-            attr.size = BACKFS_CONTROL_FILE_HELP.as_bytes().len() as u64;
+            attr.size = size;
             Some(attr)
         },
         Some(BACKFS_VERSION_FILE_PATH) => {
             let mut attr = fake_file_attrs;
             attr.perm = 0o444; // -r--r--r--
             attr.uid = uid;
-            attr.size = backfs_version_str().as_bytes().len() as u64;
+            attr.size = size;
             Some(attr)
         },
         _ => None
     }
 }
 
+// One line per file: the mtime, the cached blocks as a list of ranges, and then the path, which
+// is last so it can contain spaces. E.g. `1617225600 0-3,7 /some/file`
+fn write_manifest<W: Write>(entries: &[ManifestEntry], out: &mut W) -> io::Result<()> {
+    for entry in entries {
+        let mut ranges: Vec<String> = block_ranges(&entry.blocks).into_iter()
+            .map(|(start, end)| if start == end {
+                format!("{}", start)
+            } else {
+                format!("{}-{}", start, end)
+            })
+            .collect();
+        if ranges.is_empty() {
+            ranges.push("-".to_owned());
+        }
+
+        write!(out, "{} {} ", entry.mtime, ranges.join(","))?;
+        out.write_all(entry.path.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Read a manifest in the format `write_manifest` writes.
+fn read_manifest<R: BufRead>(input: R) -> io::Result<Vec<ManifestEntry>> {
+    let mut entries = vec![];
+    for (i, line) in input.split(b'\n').enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let bad = |what: &str| io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("line {} of the manifest: {}", i + 1, what));
+        let mut fields = line.splitn(3, |b| *b == b' ');
+        let mtime = fields.next()
+            .and_then(|field| str::from_utf8(field).ok())
+            .and_then(|field| field.parse::<i64>().ok())
+            .ok_or_else(|| bad("bad mtime"))?;
+        let ranges = fields.next()
+            .and_then(|field| str::from_utf8(field).ok())
+            .ok_or_else(|| bad("bad block list"))?;
+        let path = fields.next()
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| bad("no path"))?;
+        let mut blocks = vec![];
+        if ranges != "-" {
+            for range in ranges.split(',') {
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let parse = |s: &str| s.parse::<u64>().map_err(|_| bad("bad block range"));
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end {
+                    return Err(bad("bad block range"));
+                }
+                blocks.extend(start ..= end);
+            }
+        }
+        entries.push(ManifestEntry { path: OsString::from_vec(path.to_vec()), mtime, blocks });
+    }
+    Ok(entries)
+}
+
+/// Runs of consecutive block numbers in `blocks` (which is sorted), as inclusive ranges.
+fn block_ranges(blocks: &[u64]) -> Vec<(u64, u64)> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    for &block in blocks {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == block => *end = block,
+            _ => ranges.push((block, block)),
+        }
+    }
+    ranges
+}
+
+/// The mtime and size of the backing file for `path`, for sweeping the cache, or None if it no
+/// longer exists (or isn't a file anymore).
+fn backing_file_state(backing_layers: &[PathBuf], path: &OsStr) -> io::Result<Option<(i64, u64)>> {
+    match fs::metadata(layered_path(backing_layers, &path)) {
+        Ok(metadata) if metadata.is_file() => Ok(Some((metadata.mtime(), metadata.len()))),
+        Ok(_) => Ok(None),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound
+            || e.raw_os_error() == Some(libc::ENOTDIR) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Prefetch what a manifest lists into the cache, for `-o warm_from`. Files that have gone from the
+/// backing filesystem are skipped, and it stops early once the cache is full, or if the
+/// filesystem is unmounted.
+fn warm_cache(fscache: &Weak<BackfsCache>, backing_layers: &[PathBuf], block_size: u64,
+              entries: &[ManifestEntry])
+{
+    let (mut warmed, mut missing, mut failed) = (0, 0, 0);
+    for (i, entry) in entries.iter().enumerate() {
+        let fscache = match fscache.upgrade() {
+            Some(fscache) => fscache,
+            None => return,
+        };
+        let mut file = match File::open(layered_path(backing_layers, &entry.path)) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("warm: {:?} no longer exists", entry.path);
+                missing += 1;
+                continue;
+            },
+            Err(e) => {
+                warn!("warm: failed to open {:?}: {}", entry.path, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let result = file.metadata().and_then(|metadata| {
+            for (start, end) in block_ranges(&entry.blocks) {
+                let offset = start * block_size;
+                if offset >= metadata.len() {
+                    break;
+                }
+                let len = cmp::min((end + 1) * block_size, metadata.len()) - offset;
+                if let Some(max) = fscache.max_size() {
+                    if fscache.used_size() + len > max {
+                        return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+                    }
+                }
+                fscache.prefetch(&entry.path, offset, len, &mut file, metadata.mtime())?;
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => warmed += 1,
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                info!("warm: the cache is full; stopping after {} of {} files",
+                      i, entries.len());
+                break;
+            },
+            Err(e) => {
+                warn!("warm: failed to prefetch {:?}: {}", entry.path, e);
+                failed += 1;
+            }
+        }
+        if (i + 1) % 100 == 0 {
+            info!("warm: {} of {} files done", i + 1, entries.len());
+        }
+    }
+    info!("warm: finished: {} files prefetched, {} missing, {} failed", warmed, missing, failed);
+}
+
+/// A report on the cache in `cache_dir`, for `--inspect`. It's only read, never changed, so this
+/// is safe to use on a cache that's mounted, though the numbers might be out of date right away.
+pub fn inspect_cache(cache_dir: &Path) -> io::Result<String> {
+    let bucket_size_path = cache_dir.join("buckets").join("bucket_size");
+    let block_size = match fs::read_to_string(&bucket_size_path) {
+        Ok(size) => size.trim().parse::<u64>().map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("bad block size in {:?}: {}", bucket_size_path, e))
+        })?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("{:?} isn't a BackFS cache directory", cache_dir)));
+        },
+        Err(e) => return Err(e),
+    };
+    // Caches from before the version file existed are version 1.
+    let version = utils::read_number_file(&cache_dir.join("version"), None::<u32>)?.unwrap_or(1);
+    let map_format = MapFormat::of_cache(cache_dir)?.unwrap_or(MapFormat::Links);
+    let meta_format = MetaFormat::of_cache(cache_dir)?.unwrap_or(MetaFormat::Text);
+    let store_format = StoreFormat::of_cache(cache_dir)?.unwrap_or(StoreFormat::Dirs);
+
+    let buckets_dir = cache_dir.join("buckets").into_os_string();
+    let (used_bytes, used_buckets) = match store_format {
+        StoreFormat::Dirs => {
+            let used_list = Fsll::new(&buckets_dir, "head", "tail");
+            let free_list = Fsll::new(&buckets_dir, "free_head", "free_tail");
+            FsCacheBucketStore::new(buckets_dir, used_list, free_list, block_size, None).inspect()?
+        },
+        StoreFormat::Slab => SlabBucketStore::new(buckets_dir, block_size, None).inspect()?,
+    };
+
+    let (mut files, mut blocks) = (0, 0);
+    let map_dir = cache_dir.join("map");
+    if map_dir.exists() {
+        AnyBlockMap::new(map_format, map_dir.into_os_string())
+            .with_meta_format(meta_format)
+            .for_each_file(|_path, _mtime, file_blocks| {
+                files += 1;
+                blocks += file_blocks.len();
+                Ok(())
+            })?;
+    }
+
+    Ok(format!("Cache directory: {}\n\
+                Cache format version: {}\n\
+                Map format: {} ({} metadata)\n\
+                Store format: {}\n\
+                Block size: {}\n\
+                Used: {} bytes ({}) in {} buckets\n\
+                Files: {} ({} blocks)\n",
+               cache_dir.display(), version, map_format.name(), meta_format.name(),
+               store_format.name(), block_size,
+               used_bytes, human_number(used_bytes), used_buckets, files, blocks))
+}
+
 fn human_number(n: u64) -> String {
     if n >= 1024 * 1024 * 1024 {
         format!("{:.2} GiB", n as f64 / (1024. * 1024. * 1024.))
@@ -110,6 +733,43 @@ fn human_number(n: u64) -> String {
     }
 }
 
+/// Parses the offset and length out of a `user.backfs.cached_range.<offset>.<length>` xattr name.
+fn parse_cached_range_xattr(name: &OsStr) -> Option<(u64, u64)> {
+    let range = name.to_str()?.strip_prefix("user.backfs.cached_range.")?;
+    let mut parts = range.splitn(2, '.');
+    let offset = parts.next()?.parse().ok()?;
+    let len = parts.next()?.parse().ok()?;
+    Some((offset, len))
+}
+
+#[test]
+fn test_parse_cached_range_xattr() {
+    macro_rules! test {
+        ($name:expr => $expected:expr) => (assert_eq!(parse_cached_range_xattr(OsStr::new($name)), $expected));
+    }
+    test!("user.backfs.cached_range.0.65536" => Some((0, 65536)));
+    test!("user.backfs.cached_range.131072.10" => Some((131072, 10)));
+    test!("user.backfs.cached_range.0" => None);
+    test!("user.backfs.cached_range.0.1.2" => None);
+    test!("user.backfs.cached_range.-1.10" => None);
+    test!("user.backfs.in_cache" => None);
+}
+
+fn read_uncached(file: &File, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0u8; size as usize];
+    let mut nread = 0;
+    while nread < buf.len() {
+        match file.read_at(&mut buf[nread..], offset + nread as u64) {
+            Ok(0) => break,
+            Ok(n) => nread += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(nread);
+    Ok(buf)
+}
+
 fn mode_to_filetype(mode: libc::mode_t) -> Result<FileType, libc::c_int> {
     Ok(match mode & libc::S_IFMT {
         libc::S_IFDIR => FileType::Directory,
@@ -162,255 +822,276 @@ impl BackFs {
             Some(settings.cache_size)
         };
 
+        // main() checks that an existing cache's map and meta formats match the options.
+        if MapFormat::of_cache(&settings.cache).unwrap().is_none() {
+            settings.map_format.mark_cache(&settings.cache).unwrap();
+        }
+        if MetaFormat::of_cache(&settings.cache).unwrap().is_none() {
+            settings.meta_format.mark_cache(&settings.cache).unwrap();
+        }
+
         let map_dir = PathBuf::from(&settings.cache).join("map").into_os_string();
-        debug!("map dir: {:?}", map_dir);
+        debug!("map dir: {:?} ({}, {} metadata)", map_dir, settings.map_format.name(),
+               settings.meta_format.name());
         utils::create_dir_and_check_access(&map_dir).unwrap();
-        let map = FsCacheBlockMap::new(map_dir);
+        let map = AnyBlockMap::new(settings.map_format, map_dir)
+            .with_meta_format(settings.meta_format);
+
+        // Likewise for the store format.
+        if StoreFormat::of_cache(&settings.cache).unwrap().is_none() {
+            settings.store_format.mark_cache(&settings.cache).unwrap();
+        }
 
         let buckets_dir = PathBuf::from(&settings.cache).join("buckets").into_os_string();
-        debug!("buckets dir: {:?}", buckets_dir);
+        debug!("buckets dir: {:?} ({})", buckets_dir, settings.store_format.name());
         utils::create_dir_and_check_access(&buckets_dir).unwrap();
-        let used_list = Fsll::new(&buckets_dir, "head", "tail");
-        let free_list = Fsll::new(&buckets_dir, "free_head", "free_tail");
-        let store = FsCacheBucketStore::new(buckets_dir, used_list, free_list,
-                                            settings.block_size, max_bytes);
+        let store = match settings.store_format {
+            StoreFormat::Dirs => {
+                let used_list = Fsll::new(&buckets_dir, "head", "tail");
+                let free_list = Fsll::new(&buckets_dir, "free_head", "free_tail");
+                let foreground = settings.foreground;
+                let mut store = FsCacheBucketStore::new(buckets_dir, used_list, free_list,
+                                                        settings.block_size, max_bytes);
+                if settings.max_buckets != 0 {
+                    store = store.with_max_buckets(settings.max_buckets);
+                }
+                if settings.mem_cache != 0 {
+                    store = store.with_mem_cache(settings.mem_cache);
+                }
+                AnyBucketStore::Dirs(store
+                    .with_alloc(settings.bucket_alloc)
+                    .with_dedup(settings.dedup)
+                    .with_fast_init(settings.fast_init)
+                    .with_init_progress(INIT_PROGRESS_INTERVAL, Box::new(move |buckets, bytes| {
+                        if foreground {
+                            println!("BackFS: ... scanned {} buckets ({})",
+                                     buckets, human_number(bytes));
+                        } else {
+                            debug!("init: scanned {} buckets ({} bytes)", buckets, bytes);
+                        }
+                    })))
+            },
+            StoreFormat::Slab => {
+                AnyBucketStore::Slab(SlabBucketStore::new(buckets_dir, settings.block_size, max_bytes))
+            },
+        };
 
-        let uid = unsafe { libc::getuid() };
+        let uid = settings.control_uid();
         debug!("uid = {}", uid);
 
+        let mut fscache = FsCache::new(map, store, settings.block_size);
+        if settings.read_size != 0 {
+            fscache = fscache.with_read_size(settings.read_size);
+        }
+        if settings.admit_after > 1 {
+            fscache = fscache.with_admit_after(settings.admit_after);
+        }
+        if settings.read_retries != 0 {
+            fscache = fscache.with_read_retries(settings.read_retries);
+        }
+        if settings.eviction != Eviction::Lru {
+            fscache = fscache.with_eviction(settings.eviction);
+        }
+        if settings.mtime_policy != MtimePolicy::Check {
+            fscache = fscache.with_mtime_policy(settings.mtime_policy);
+        }
+        if settings.revalidate_sample {
+            fscache = fscache.with_sampling(true);
+        }
+        if settings.no_auto_cache {
+            fscache = fscache.with_auto_cache(false);
+        }
+        for (extension, policy) in &settings.policies {
+            fscache = fscache.with_policy(extension, *policy);
+        }
+        if settings.max_per_file != 0 {
+            fscache = fscache.with_max_per_file(settings.max_per_file);
+        }
+        if settings.backing_align != 0 {
+            fscache = fscache.with_backing_align(settings.backing_align);
+        }
+        if settings.skip_tail_below != 0 {
+            fscache = fscache.with_skip_tail_below(settings.skip_tail_below);
+        }
+        if settings.max_read != 0 {
+            fscache = fscache.with_max_read(settings.max_read);
+        }
+        if settings.backing_bw != 0 {
+            fscache = fscache.with_backing_rate_limit(settings.backing_bw);
+        }
+        if settings.max_inflight != 0 {
+            fscache = fscache.with_max_inflight(settings.max_inflight);
+        }
+        if settings.flush_interval != 0 {
+            fscache = fscache.with_deferred_state(true);
+        }
+        if settings.passthrough_after != 0 {
+            fscache = fscache.with_passthrough_after(settings.passthrough_after);
+        }
+        if settings.stale_while_revalidate != 0 {
+            fscache = fscache.with_stale_while_revalidate(
+                Duration::from_secs(settings.stale_while_revalidate));
+        }
+
         Self {
-            fscache: FsCache::new(map, store, settings.block_size),
+            fscache: Arc::new(fscache),
+            backing_layers: settings.backing_layers(),
             settings,
             uid,
+            passthrough_fhs: Mutex::new(HashMap::new()),
+            dir_cache: Mutex::new(HashMap::new()),
+            hashing: Mutex::new(HashMap::new()),
+            file_hashes: Mutex::new(HashMap::new()),
+            fake_fhs: Mutex::new(HashMap::new()),
+            next_fake_fh: AtomicU64::new(FAKE_FILE_FH_BASE),
+            control_socket: Mutex::new(None),
+            refresh_queue: Arc::new(Mutex::new(RefreshQueue::default())),
+            stat_errors: AtomicU64::new(0),
+            xattr_errors: AtomicU64::new(0),
         }
     }
 
-    fn real_path<T: AsRef<OsStr>>(&self, partial: &T) -> OsString {
-        PathBuf::from(&self.settings.backing_fs)
-                .join(Path::new(partial).strip_prefix("/").unwrap())
-                .into_os_string()
+    pub fn metrics(&self) -> BackfsMetrics {
+        BackfsMetrics {
+            cache: self.fscache.metrics(),
+            stat_errors: self.stat_errors.load(Ordering::Relaxed),
+            xattr_errors: self.xattr_errors.load(Ordering::Relaxed),
+        }
     }
 
+    /// Whether this is the control file's path and it has been turned off with `-o no_control`,
+    /// in which case it should look like it doesn't exist.
+    fn is_disabled_control_file(&self, path: &Path) -> bool {
+        self.settings.no_control && path == Path::new(BACKFS_CONTROL_FILE_PATH)
+    }
 
-    fn stat_real<T: AsRef<OsStr> + ::std::fmt::Debug>(&self, path: &T, fh: Option<u64>) 
-        -> Result<FileAttr, libc::c_int>
-    {
-        let real: OsString = self.real_path(path);
-        debug!("stat_real: {:?} (fh={:?})", real, fh);
+    /// What a fake file says right now. Everything that reports on a fake file's contents or
+    /// size has to get them from here (or from a snapshot of it, with `fake_file_snapshot`).
+    fn fake_file_data(&self, path: &Path) -> Option<Vec<u8>> {
+        match path.to_str() {
+            Some(BACKFS_CONTROL_FILE_PATH) => {
+                let mut data = BACKFS_CONTROL_FILE_HELP.as_bytes().to_vec();
+                data.extend_from_slice(
+                    format!("cache used: {} bytes\n", self.fscache.used_size()).as_bytes());
+                Some(data)
+            },
+            Some(BACKFS_VERSION_FILE_PATH) => Some(version_string().into_bytes()),
+            _ => None,
+        }
+    }
 
-        let result = if let Some(fh) = fh {
-            // NOTE: Currently rust-fuse doesn't ever pass us a fh because it targets too old of a
-            // FUSE ABI.)
-            libc_wrappers::fstat(fh as usize)
-        } else {
-            libc_wrappers::lstat(real)
-        };
+    /// The contents of the fake file as of when the handle was opened, or if there's no handle (or
+    /// it isn't one of ours), as of right now.
+    fn fake_file_snapshot(&self, path: &Path, fh: Option<u64>) -> Option<Arc<Vec<u8>>> {
+        if !is_backfs_fake_file(path) {
+            return None;
+        }
+        if let Some(data) = fh.and_then(|fh| self.fake_fhs.lock().unwrap().get(&fh).cloned()) {
+            return Some(data);
+        }
+        self.fake_file_data(path).map(Arc::new)
+    }
 
-        let stat = result.inspect_err(|&errno| {
-            let msg = format!("lstat: {:?}: {}", path, io::Error::from_raw_os_error(errno));
-            if errno == libc::ENOENT {
-                // avoid being overly noisy
-                debug!("{}", msg);
-            } else {
-                error!("{}", msg);
-            }
-        })?;
+    /// Read part of a fake file, from the handle's snapshot of it.
+    fn read_fake(&self, path: &Path, fh: u64, offset: u64, size: u32) -> Option<Vec<u8>> {
+        let data = self.fake_file_snapshot(path, Some(fh))?;
+        let start = cmp::min(offset, data.len() as u64) as usize;
+        let end = cmp::min(data.len(), start + size as usize);
+        Some(data[start .. end].to_vec())
+    }
 
-        let kind = mode_to_filetype(stat.st_mode)?;
+    /// With `-o cache_tree`, the path in the mount which a path under `/.backfs_cache` mirrors.
+    fn cache_tree_target(&self, path: &Path) -> Option<PathBuf> {
+        if !self.settings.cache_tree {
+            return None;
+        }
+        path.strip_prefix(BACKFS_CACHE_TREE_PATH).ok().map(|rest| Path::new("/").join(rest))
+    }
 
-        let mut mode = stat.st_mode & 0o7777; // st_mode encodes the type AND the mode.
-        if !self.settings.rw {
-            mode &= !0o222; // disable the write bits if we're not in RW mode.
+    /// Find what the cache tree has at `target`: a cached file, or a directory with some below it.
+    fn cache_tree_lookup(&self, target: &Path) -> Result<CacheTreeNode, libc::c_int> {
+        let errno = |e: io::Error| e.raw_os_error().unwrap_or(libc::EIO);
+        if target != Path::new("/") {
+            let mtime = self.fscache.cached_file_mtime(target.as_os_str()).map_err(errno)?;
+            if let Some(mtime) = mtime {
+                return Ok(CacheTreeNode::File { mtime });
+            }
+        }
+        // Only what's under the target is looked at, except for the root, which is everything.
+        let files = self.fscache.cached_files_under(target.as_os_str()).map_err(errno)?;
+        let mut children: BTreeMap<OsString, FileType> = BTreeMap::new();
+        for file in &files {
+            if let Ok(rest) = Path::new(file).strip_prefix(target) {
+                let mut components = rest.components();
+                if let Some(first) = components.next() {
+                    let kind = if components.next().is_some() {
+                        FileType::Directory
+                    } else {
+                        FileType::RegularFile
+                    };
+                    children.insert(first.as_os_str().to_owned(), kind);
+                }
+            }
+        }
+        if children.is_empty() && target != Path::new("/") {
+            return Err(libc::ENOENT);
         }
+        Ok(CacheTreeNode::Dir(children.into_iter()
+            .map(|(name, kind)| DirectoryEntry { name, kind })
+            .collect()))
+    }
 
+    fn cache_tree_attr(&self, target: &Path) -> Result<FileAttr, libc::c_int> {
+        let (kind, perm, size, time) = match self.cache_tree_lookup(target)? {
+            CacheTreeNode::File { mtime } => {
+                let size = self.fscache.cached_file_size(target.as_os_str())
+                    .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+                (FileType::RegularFile, 0o400, size, epoch_time(mtime, 0))
+            },
+            CacheTreeNode::Dir(_) => {
+                (FileType::Directory, 0o500, 0, epoch_time(crate::BUILD_TIME, 0))
+            },
+        };
         Ok(FileAttr {
-            size: stat.st_size as u64,
-            blocks: stat.st_blocks as u64,
-            atime: epoch_time(stat.st_atime as i64, stat.st_atime_nsec as u32),
-            mtime: epoch_time(stat.st_mtime as i64, stat.st_mtime_nsec as u32),
-            ctime: epoch_time(stat.st_ctime as i64, stat.st_ctime_nsec as u32),
-            crtime: std::time::UNIX_EPOCH,
+            size,
+            blocks: size.div_ceil(512),
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: time,
             kind,
-            perm: mode as u16,
-            nlink: stat.st_nlink as u32,
-            uid: stat.st_uid,
-            gid: stat.st_gid,
-            rdev: stat.st_rdev as u32,
+            perm,
+            nlink: 1,
+            uid: self.uid,
+            gid: 0,
+            rdev: 0,
             flags: 0,
         })
     }
 
-    fn backfs_control_file_write(&self, data: &[u8]) -> ResultWrite {
-        // remove a trailing newline if it exists
-        let data_trimmed = if data.last() == Some(&0x0A) {
-            &data[..data.len() - 1]
-        } else {
-            data
-        };
+    /// Map a path in the mount to the backing filesystem. With multiple backing layers, this is
+    /// the path in the first layer where it exists, or in the first layer if it exists in none.
+    fn real_path<T: AsRef<OsStr>>(&self, partial: &T) -> OsString {
+        layered_path(&self.backing_layers, partial)
+    }
 
-        let first_space = data_trimmed.iter().position(|x| *x == 0x20)
-                .unwrap_or(data_trimmed.len());
-        let (command_bytes, arg_bytes) = data_trimmed.split_at(first_space);
-        let command = str::from_utf8(command_bytes).unwrap_or("[invalid utf8]");
+    /// List the directory's entries in the backing filesystem (and its counterparts in any other
+    /// backing layers).
+    /// If `stats` is given, the results of any lstats done along the way are kept in it, by entry
+    /// name.
+    fn readdir_real(&self, path: &Path, fh: u64,
+                    mut stats: Option<&mut HashMap<OsString, StatX>>)
+        -> Result<Vec<DirectoryEntry>, libc::c_int>
+    {
+        let mut entries: Vec<DirectoryEntry> = vec![];
 
-        let arg_start = if arg_bytes.is_empty() { 0 } else { 1 }; // skip over the space delimiter if there is one
-        let arg = OsStr::from_bytes(&arg_bytes[arg_start..]);
+        loop {
+            match libc_wrappers::readdir(fh as usize) {
+                Ok(Some(entry)) => {
+                    let name_c = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
+                    let name = OsStr::from_bytes(name_c.to_bytes()).to_owned();
 
-        debug!("command: {:?}, arg: {:?}", command, arg);
-
-        match command {
-            "test" => {
-                return Err(libc::EXDEV);
-            },
-            "noop" => (),
-            "invalidate" => {
-                let _ignore_errors = self.fscache.invalidate_path(arg);
-            },
-            "free_block" => {
-                let path_and_block = Path::new(arg);
-                let path = path_and_block.parent()
-                    .ok_or_else(|| { warn!("bad path: no parent"); libc::EINVAL })?;
-                let block: u64 = path_and_block.file_name()
-                    .ok_or_else(|| { warn!("no filename given"); libc::EINVAL })?
-                    .to_str()
-                    .ok_or_else(|| { warn!("bad UTF-8"); libc::EINVAL })?
-                    .parse()
-                    .map_err(|e| { warn!("doesn't end in a valid number: {}", e); libc::EINVAL })?;
-                match self.fscache.free_block(path.as_os_str(), block) {
-                    Ok(Some(n)) => debug!("{:?}/{}: {} bytes freed", path, block, n),
-                    Ok(None) => debug!("{:?}/{} file or block not found", path, block),
-                    Err(e) => error!("error freeing block {} of {:?}: {}", block, path, e),
-                }
-            },
-            "free_orphans" => {
-                let _ignore_errors = self.fscache.free_orphaned_buckets();
-            },
-            _ => {
-                return Err(libc::EBADMSG);
-            }
-        }
-
-        Ok(data.len() as u32)
-    }
-
-    fn internal_init(&self) -> io::Result<()> {
-        println!("BackFS: Initializing cache and scanning existing cache directory...");
-
-        if let Err(e) = self.fscache.init() {
-            println!("Error: Failed to initialize cache: {}", e);
-            return Err(e);
-        }
-
-        let max_cache = if self.settings.cache_size == 0 {
-            unsafe {
-                let path_bytes = Vec::from(self.settings.cache.as_os_str().as_bytes());
-                let path_c = CString::from_vec_unchecked(path_bytes);
-                let mut statbuf: libc::statvfs = mem::zeroed();
-                if -1 == libc::statvfs(path_c.into_raw(), &mut statbuf as *mut libc::statvfs) {
-                    let e = io::Error::last_os_error();
-                    println!("Error: failed to statvfs on the cache filesystem: {}", e);
-                    return Err(e);
-                } else {
-                    statbuf.f_bsize as u64 * statbuf.f_blocks as u64
-                }
-            }
-        } else {
-            self.settings.cache_size
-        };
-
-        println!("BackFS: Cache: {} used out of {} ({:.2} %).",
-                 human_number(self.fscache.used_size()),
-                 human_number(max_cache),
-                 (self.fscache.used_size() as f64 / max_cache as f64 * 100.));
-
-        Ok(())
-    }
-}
-
-impl FilesystemMT for BackFs {
-    fn init(&self, _req: RequestInfo) -> ResultEmpty {
-        debug!("init");
-
-        if let Err(e) = self.internal_init() {
-            println!("Error initializing BackFS: {}", e);
-            panic!("{}", e);
-        }
-
-        println!("BackFS: Ready.");
-
-        if !self.settings.foreground {
-            println!("BackFS: Going to background.");
-            if let Err(e) = Daemonize::new().working_directory("/").start() {
-                let msg = format!("Error forking to background: {}", e);
-                error!("{}", msg);
-                panic!("{}", msg);
-            }
-        }
-
-        Ok(())
-    }
-
-    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
-        debug!("getattr: {:?}", path);
-
-        if let Some(attr) = backfs_fake_file_attr(path.to_str(), self.uid) {
-            return Ok((TTL, attr));
-        }
-
-        let attr = self.stat_real(&path, fh)
-            .inspect_err(|&errno| {
-                let msg = format!("getattr: {:?}: {}", path, io::Error::from_raw_os_error(errno));
-                if errno == libc::ENOENT {
-                    debug!("{}", msg);
-                } else {
-                    error!("{}", msg);
-                }
-            })?;
-
-        Ok((TTL, attr))
-    }
-
-    fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
-        debug!("opendir: {:?}", path);
-
-        let real: OsString = self.real_path(&path);
-        debug!("opendir: real = {:?}", real);
-
-        match libc_wrappers::opendir(real) {
-            Ok(fh) => Ok((fh as u64, 0)),
-            Err(e) => Err(e)
-        }
-    }
-
-    fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
-        debug!("readdir: {:?}", path);
-        let mut entries: Vec<DirectoryEntry> = vec![];
-
-        if fh == 0 {
-            error!("readdir: missing fh");
-            return Err(libc::EINVAL);
-        }
-
-        let is_root = path == Path::new("/");
-
-        if is_root {
-            entries.push(DirectoryEntry{
-                name: OsString::from(BACKFS_CONTROL_FILE_NAME),
-                kind: FileType::RegularFile
-            });
-            entries.push(DirectoryEntry{
-                name: OsString::from(BACKFS_VERSION_FILE_NAME),
-                kind: FileType::RegularFile
-            });
-        }
-
-        loop {
-            match libc_wrappers::readdir(fh as usize) {
-                Ok(Some(entry)) => {
-                    let name_c = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
-                    let name = OsStr::from_bytes(name_c.to_bytes()).to_owned();
-
-                    let entry_path = PathBuf::from(path).join(&name);
+                    let entry_path = PathBuf::from(path).join(&name);
 
                     let filetype = match entry.d_type {
                         libc::DT_DIR => FileType::Directory,
@@ -430,8 +1111,14 @@ impl FilesystemMT for BackFs {
                                       entry.d_type,
                                       real_path);
                             }
-                            match libc_wrappers::lstat(real_path) {
-                                Ok(stat64) => mode_to_filetype(stat64.st_mode)?,
+                            match libc_wrappers::lstatx(real_path) {
+                                Ok(statx) => {
+                                    let kind = mode_to_filetype(statx.0.st_mode)?;
+                                    if let Some(ref mut stats) = stats {
+                                        stats.insert(name.clone(), statx);
+                                    }
+                                    kind
+                                },
                                 Err(errno) => {
                                     let ioerr = io::Error::from_raw_os_error(errno);
                                     panic!("lstat failed after readdir_r gave no file type for {:?}: {}",
@@ -456,137 +1143,1194 @@ impl FilesystemMT for BackFs {
             }
         }
 
+        if self.backing_layers.len() > 1 {
+            self.readdir_other_layers(path, &mut entries);
+        }
+
         Ok(entries)
     }
 
-    fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
-        debug!("releasedir: {:?}", path);
-        match libc_wrappers::closedir(fh as usize) {
-            Ok(()) => { Ok(()) }
-            Err(e) => {
-                error!("closedir({:?}): {}", path, io::Error::from_raw_os_error(e));
-                Err(e)
-            }
+    /// The entries of a directory, for `readdir` and `readdirplus`. If `stats` is given, the
+    /// backing filesystem's stats of entries, where they had to be looked up, are kept in it.
+    fn list_dir(&self, path: &Path, fh: u64, stats: Option<&mut HashMap<OsString, StatX>>)
+        -> ResultReaddir
+    {
+        let mut entries: Vec<DirectoryEntry> = vec![];
+
+        if fh == 0 {
+            error!("readdir: missing fh");
+            return Err(libc::EINVAL);
         }
-    }
 
-    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
-        debug!("open: {:?} flags={:#x}", path, flags);
+        if let Some(target) = self.cache_tree_target(path) {
+            return match self.cache_tree_lookup(&target)? {
+                CacheTreeNode::Dir(children) => {
+                    entries.extend(dot_entries());
+                    entries.extend(children);
+                    Ok(entries)
+                },
+                CacheTreeNode::File { .. } => Err(libc::ENOTDIR),
+            };
+        }
+
+        let is_root = path == Path::new("/");
+
+        if is_root {
+            if !self.settings.no_control {
+                entries.push(DirectoryEntry{
+                    name: OsString::from(BACKFS_CONTROL_FILE_NAME),
+                    kind: FileType::RegularFile
+                });
+            }
+            entries.push(DirectoryEntry{
+                name: OsString::from(BACKFS_VERSION_FILE_NAME),
+                kind: FileType::RegularFile
+            });
+            if self.settings.cache_tree {
+                entries.push(DirectoryEntry{
+                    name: OsString::from(BACKFS_CACHE_TREE_NAME),
+                    kind: FileType::Directory
+                });
+            }
 
-        if let Some(path) = path.to_str() {
-            if path == BACKFS_CONTROL_FILE_PATH || path == BACKFS_VERSION_FILE_PATH {
-                return Ok((0, flags));
+            if fh == FAKE_DIR_FH {
+                entries.extend(dot_entries());
+                return Ok(entries);
             }
         }
 
-        let real_path = self.real_path(&path);
+        // Only the first backing layer's mtime is checked, so don't cache merged listings.
+        let cache_mtime = if self.settings.cache_dirs && self.backing_layers.len() == 1 {
+            libc_wrappers::lstat(self.real_path(&path)).ok().map(|stat| stat.st_mtime)
+        } else {
+            None
+        };
 
-        match libc_wrappers::open(real_path, flags as libc::c_int) {
-            Ok(fh) => { Ok((fh as u64, flags)) },
-            Err(e) => {
-                error!("open({:?}): {}", path, io::Error::from_raw_os_error(e));
-                Err(e)
+        if let Some(mtime) = cache_mtime {
+            let dir_cache = self.dir_cache.lock().unwrap();
+            if let Some((cached_mtime, cached)) = dir_cache.get(path.as_os_str()) {
+                if *cached_mtime == mtime {
+                    debug!("readdir: using cached listing of {:?}", path);
+                    entries.extend(cached.iter().cloned());
+                    return Ok(entries);
+                }
             }
         }
-    }
 
-    fn release(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
-        debug!("release: {:?}", path);
-
-        if is_backfs_fake_file(path) {
-            // we didn't open any real file
-            return Ok(());
+        let real_entries = self.readdir_real(path, fh, stats)?;
+        if let Some(mtime) = cache_mtime {
+            self.dir_cache.lock().unwrap()
+                .insert(path.as_os_str().to_owned(), (mtime, real_entries.clone()));
         }
+        entries.extend(real_entries);
+        Ok(entries)
+    }
 
-        match libc_wrappers::close(fh as usize) {
-            Ok(()) => { Ok(()) },
-            Err(e) => {
-                error!("close({:?}): {}", path, io::Error::from_raw_os_error(e));
-                Err(e)
+    /// Add entries from the directory's counterparts in the other backing layers, skipping names
+    /// already listed by an earlier layer.
+    fn readdir_other_layers(&self, path: &Path, entries: &mut Vec<DirectoryEntry>) {
+        let primary = PathBuf::from(self.real_path(&path));
+        let relative = path.strip_prefix("/").unwrap();
+        let mut seen: HashSet<OsString> = entries.iter().map(|entry| entry.name.clone()).collect();
+        for layer in &self.backing_layers {
+            let dir = layer.join(relative);
+            if dir == primary {
+                continue;
+            }
+            let iter = match fs::read_dir(&dir) {
+                Ok(iter) => iter,
+                Err(_) => continue,
+            };
+            for entry in iter.filter_map(Result::ok) {
+                let name = entry.file_name();
+                if seen.contains(&name) {
+                    continue;
+                }
+                let kind = match entry.metadata() {
+                    Ok(metadata) => match mode_to_filetype(metadata.mode() as libc::mode_t) {
+                        Ok(kind) => kind,
+                        Err(_) => continue,
+                    },
+                    Err(e) => {
+                        warn!("readdir: {:?}: {}", entry.path(), e);
+                        continue;
+                    }
+                };
+                debug!("readdir: adding entry {:?} of type {:?} from {:?}", name, kind, layer);
+                seen.insert(name.clone());
+                entries.push(DirectoryEntry { name, kind });
             }
         }
     }
 
-    fn read(
-        &self,
-        _req: RequestInfo,
-        path: &Path,
-        fh: u64,
-        offset: u64,
-        size: u32,
-        result: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
-    ) -> CallbackResult {
-        debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
 
-        let fake_data: Option<Vec<u8>> = match path.to_str() {
-            Some(BACKFS_CONTROL_FILE_PATH) => Some(BACKFS_CONTROL_FILE_HELP.bytes().collect()),
-            Some(BACKFS_VERSION_FILE_PATH) => Some(backfs_version_str().into_bytes()),
-            _ => None
+    fn stat_real<T: AsRef<OsStr> + ::std::fmt::Debug>(&self, path: &T, fh: Option<u64>) 
+        -> Result<FileAttr, libc::c_int>
+    {
+        let real: OsString = self.real_path(path);
+        debug!("stat_real: {:?} (fh={:?})", real, fh);
+
+        // The kernel only sends a fh with getattr for regular files (which it got from our open,
+        // so it's a real fd). fuser didn't start passing it through until 0.14, so with older
+        // versions (see FUSER_VERSION) this is always None and we always lstat.
+        let (result, call) = if let Some(fh) = fh {
+            (libc_wrappers::fstatx(fh as usize), "fstat")
+        } else {
+            (libc_wrappers::lstatx(real), "lstat")
         };
 
-        if let Some(data) = fake_data {
-            return if offset as usize >= data.len() {
-                // Request out of range; return empty result.
-                result(Ok(&[]))
+        let (stat, btime) = result.inspect_err(|&errno| {
+            let msg = format!("{}: {:?}: {}", call, path, io::Error::from_raw_os_error(errno));
+            if errno == libc::ENOENT {
+                // avoid being overly noisy
+                debug!("{}", msg);
             } else {
-                let offset = offset as usize;
-                let size = size as usize;
-                let end = cmp::min(data.len(), offset + size);
-                result(Ok(&data[offset .. end]))
+                error!("{}", msg);
+                self.stat_errors.fetch_add(1, Ordering::Relaxed);
             }
-        }
+        })?;
 
-        let mut real_file = unsafe { File::from_raw_fd(fh as libc::c_int) };
+        self.stat_to_attr(path, &stat, btime)
+    }
 
-        let mtime = match real_file.metadata() {
-            Ok(metadata) => metadata.mtime(),
-            Err(e) => {
-                error!("unable to get metadata from {:?}: {}", path, e);
-                return result(Err(e.raw_os_error().unwrap()));
-            }
+    /// How long the kernel can keep what's returned for files in the backing filesystem. Lookups
+    /// go through getattr, so this is how long it keeps directory entries too.
+    fn attr_ttl(&self) -> Duration {
+        Duration::from_secs(self.settings.attr_ttl)
+    }
+
+    /// The attributes to report for a file, given its stat and birth time (if known) from the
+    /// backing filesystem.
+    fn stat_to_attr<T: AsRef<OsStr>>(&self, path: &T, stat: &libc::stat64,
+                                      btime: Option<(i64, u32)>)
+        -> Result<FileAttr, libc::c_int>
+    {
+        let kind = mode_to_filetype(stat.st_mode)?;
+
+        let mut mode = stat.st_mode & 0o7777; // st_mode encodes the type AND the mode.
+        let mode_override = if kind == FileType::Directory {
+            self.settings.dir_mode
+        } else {
+            self.settings.file_mode
         };
+        if let Some(override_mode) = mode_override {
+            mode = override_mode as libc::mode_t;
+        }
+        if !self.settings.rw && !self.settings.show_real_mode {
+            mode &= !0o222; // disable the write bits if we're not in RW mode.
+        }
 
-        let ret = match self.fscache.fetch(path.as_os_str(), offset, size as u64, &mut real_file, mtime) {
-            Ok(data) => {
-                result(Ok(&data))
-            },
-            Err(e) => {
-                result(Err(e.raw_os_error().unwrap()))
+        let mut atime = epoch_time(stat.st_atime as i64, stat.st_atime_nsec as u32);
+        if self.settings.track_atime && kind == FileType::RegularFile {
+            if let Some(tracked) = self.fscache.get_file_atime(path.as_ref()) {
+                atime = epoch_time(tracked, 0);
             }
+        }
+
+        // With -o blocks=cached, report how much of the file is in the cache (in 512-byte units,
+        // like st_blocks) so du shows cache residency. This has to count the file's cached blocks.
+        let blocks = if self.settings.cached_blocks && kind == FileType::RegularFile {
+            self.fscache.count_cached_bytes(path.as_ref()).div_ceil(512)
+        } else {
+            stat.st_blocks as u64
         };
 
-        // Release control of the file descriptor, so it is not closed when this function
-        // returns.
-        let _ = real_file.into_raw_fd();
+        let crtime = match btime {
+            Some((secs, nanos)) => epoch_time(secs, nanos),
+            None => std::time::UNIX_EPOCH,
+        };
 
-        ret
-    }
+        // Only what's reported is fixed; the cache still goes by the real mtime.
+        let (mtime, ctime) = match self.settings.fixed_mtime {
+            Some(secs) => {
+                let time = std::time::UNIX_EPOCH + Duration::from_secs(secs);
+                (time, time)
+            },
+            None => (epoch_time(stat.st_mtime as i64, stat.st_mtime_nsec as u32),
+                     epoch_time(stat.st_ctime as i64, stat.st_ctime_nsec as u32)),
+        };
 
-    fn write(&self, _req: RequestInfo, path: &Path, _fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
-        debug!("write: {:?} {:#x}@{:#x}", path, data.len(), offset);
+        Ok(FileAttr {
+            size: stat.st_size as u64,
+            blocks,
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            kind,
+            perm: mode as u16,
+            nlink: stat.st_nlink as u32,
+            uid: self.settings.uid.unwrap_or(stat.st_uid),
+            gid: self.settings.gid.unwrap_or(stat.st_gid),
+            rdev: stat.st_rdev as u32,
+            flags: 0,
+        })
+    }
 
-        match path.to_str() {
-            Some(BACKFS_CONTROL_FILE_PATH) => {
-                return self.backfs_control_file_write(&data);
-            },
-            Some(BACKFS_VERSION_FILE_PATH) => {
-                return Err(libc::EACCES);
-            }
-            _ => ()
+    /// What to reply to a read with. Errors without an errno (e.g. a malformed cache file) are
+    /// reported as EIO.
+    fn read_reply(&self, path: &Path, fh: u64, offset: u64, size: u32)
+        -> Result<Vec<u8>, libc::c_int>
+    {
+        if self.is_disabled_control_file(path) {
+            return Err(libc::ENOENT);
         }
 
-        if !self.settings.rw {
-            return Err(libc::EROFS);
+        if let Some(data) = self.read_fake(path, fh, offset, size) {
+            return Ok(data);
         }
 
-        // TODO
-        Err(libc::ENOSYS)
+        let result = match self.cache_tree_target(path) {
+            Some(target) => self.fscache.read_cached(target.as_os_str(), offset, size as u64),
+            None => self.read_real(path, fh, offset, size),
+        };
+        result.map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))
     }
 
-    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
-        debug!("readlink: {:?}", path);
+    fn read_real(&self, path: &Path, fh: u64, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let mut real_file = unsafe { File::from_raw_fd(fh as libc::c_int) };
 
-        let real_path = self.real_path(&path);
+        let passthrough = self.passthrough_fhs.lock().unwrap().get(&fh).cloned();
+        let result = match passthrough {
+            Some(Passthrough::Positional) => {
+                debug!("read: bypassing cache for {:?}", path);
+                self.fscache.check_read_size(path.as_os_str(), size as u64)
+                    .and_then(|()| read_uncached(&real_file, offset, size))
+            },
+            Some(Passthrough::Stream) => {
+                debug!("read: streaming {:?} without caching", path);
+                self.fscache.check_read_size(path.as_os_str(), size as u64).and_then(|()| {
+                    let mut buf = vec![0u8; size as usize];
+                    real_file.read(&mut buf).map(|n| { buf.truncate(n); buf })
+                })
+            },
+            None => match real_file.metadata() {
+                Ok(metadata) => {
+                    match self.fscache.fetch_stale(path.as_os_str(), offset, size as u64,
+                                                   metadata.mtime()) {
+                        Ok(Some(data)) => {
+                            self.start_refresh(path);
+                            let _ = real_file.into_raw_fd();
+                            return Ok(data);
+                        },
+                        Ok(None) => (),
+                        Err(e) => warn!("failed to read stale cached data of {:?}: {}", path, e),
+                    }
+                    let result = self.fscache.fetch(path.as_os_str(), offset, size as u64,
+                                                    &mut real_file, metadata.mtime());
+                    if self.settings.track_atime && result.is_ok() {
+                        let now = SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+                            .map(|d| d.as_secs() as i64)
+                            .unwrap_or(0);
+                        let _ignore_errors = self.fscache.set_file_atime(path.as_os_str(), now);
+                    }
+                    if let Ok(ref data) = result {
+                        self.hash_read(path, fh, offset, data, &metadata);
+                    }
+                    result
+                },
+                Err(e) => {
+                    error!("unable to get metadata from {:?}: {}", path, e);
+                    Err(e)
+                }
+            },
+        };
+
+        // Release control of the file descriptor, so it is not closed when this function
+        // returns.
+        let _ = real_file.into_raw_fd();
+
+        result
+    }
+
+    /// Add what was read to the file handle's whole-file hash, if it carries on from where the
+    /// last read left off, and if that was the end of the file, record the hash.
+    fn hash_read(&self, path: &Path, fh: u64, offset: u64, data: &[u8], metadata: &fs::Metadata) {
+        let mut hashing = self.hashing.lock().unwrap();
+        let state = match hashing.get_mut(&fh) {
+            Some(state) => state,
+            None => return,
+        };
+        if offset == 0 && state.next_offset != 0 {
+            *state = FileHashState::new();
+        }
+        if offset != state.next_offset {
+            if state.hasher.take().is_some() {
+                debug!("read: {:?} isn't being read in order; not hashing it", path);
+            }
+            return;
+        }
+        let hasher = match state.hasher {
+            Some(ref mut hasher) => hasher,
+            None => return,
+        };
+        hasher.update(data);
+        state.next_offset += data.len() as u64;
+        if state.next_offset >= metadata.len() {
+            let digest = format!("{:x}", hashing.remove(&fh).unwrap().hasher.unwrap().finalize());
+            debug!("read: all of {:?} read in order; sha256 is {}", path, digest);
+            self.file_hashes.lock().unwrap()
+                .insert(path.as_os_str().to_owned(), (metadata.mtime(), digest));
+        }
+    }
+
+    /// The whole-file hash of the file, if it's been read all the way through since it last
+    /// changed.
+    fn file_hash(&self, path: &Path) -> Option<String> {
+        let (mtime, digest) = self.file_hashes.lock().unwrap().get(path.as_os_str()).cloned()?;
+        match libc_wrappers::lstat(self.real_path(&path)) {
+            Ok(stat) if stat.st_mtime == mtime => Some(digest),
+            _ => None,
+        }
+    }
+
+    /// Check that the file's contents haven't changed since they were cached, beyond what its size
+    /// and mtime show.
+    fn revalidate_sample(&self, path: &Path, fh: u64, mtime: i64) {
+        let mut real_file = unsafe { File::from_raw_fd(fh as libc::c_int) };
+        if let Err(e) = self.fscache.revalidate(path.as_os_str(), &mut real_file, mtime) {
+            warn!("open: error revalidating {:?}: {}", path, e);
+        }
+        let _ = real_file.into_raw_fd();
+    }
+
+    /// Small files get read into the cache in their entirety when they are opened, because for
+    /// them the cost of fetching a block at a time on demand is mostly round trips.
+    fn prefetch_small_file(&self, path: &Path, fh: u64, size: u64, mtime: i64) {
+        if size == 0 || size > self.settings.prefetch_blocks * self.settings.block_size {
+            return;
+        }
+        if matches!(self.fscache.policy(path.as_os_str()), Some(policy) if !policy.cache) {
+            return;
+        }
+        if size < cmp::min(self.settings.block_size, self.settings.skip_tail_below) {
+            // It's all tail, which wouldn't be cached.
+            return;
+        }
+        if let Some(max) = self.fscache.max_size() {
+            if size > max {
+                return;
+            }
+        }
+
+        debug!("open: prefetching all {} bytes of {:?}", size, path);
+        let mut real_file = unsafe { File::from_raw_fd(fh as libc::c_int) };
+        if let Err(e) = self.fscache.prefetch(path.as_os_str(), 0, size, &mut real_file, mtime) {
+            warn!("open: error prefetching {:?}: {}", path, e);
+        }
+        let _ = real_file.into_raw_fd();
+    }
+
+    fn backfs_control_file_write(&self, data: &[u8]) -> ResultWrite {
+        let (command, arg) = parse_command(data)?;
+        run_command(&self.fscache, &self.backing_layers, Path::new(&self.settings.cache), command,
+                    arg)?;
+        Ok(data.len() as u32)
+    }
+
+    /// Preallocate space for part of a file (or with `FALLOC_FL_PUNCH_HOLE`, deallocate it) in the
+    /// backing filesystem, and drop any cached blocks of it which overlap that part.
+    ///
+    /// fuse_mt doesn't have a hook for fallocate, so until it does this can't be reached through
+    /// the mount, and applications calling fallocate get ENOSYS from FUSE.
+    #[cfg(target_os = "linux")]
+    pub fn fallocate(&self, path: &Path, fh: u64, offset: u64, length: u64, mode: libc::c_int)
+        -> ResultEmpty
+    {
+        debug!("fallocate: {:?} {:#x}@{:#x} mode={:#x}", path, length, offset, mode);
+
+        if is_backfs_fake_file(path) || self.cache_tree_target(path).is_some() {
+            return Err(libc::EACCES);
+        }
+
+        if !self.settings.rw {
+            return Err(libc::EROFS);
+        }
+
+        if let Err(e) = libc_wrappers::fallocate(fh as usize, mode, offset, length) {
+            error!("fallocate({:?}): {}", path, io::Error::from_raw_os_error(e));
+            return Err(e);
+        }
+
+        // Whether the range was allocated, punched out, or zeroed, what's cached of it may no
+        // longer match the backing file.
+        if let Err(e) = self.fscache.free_range(path.as_os_str(), offset, length) {
+            error!("fallocate: failed to drop cached blocks of {:?}: {}", path, e);
+            return Err(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+
+        Ok(())
+    }
+
+    /// List a directory along with each entry's attributes, as `getattr` would give them, so that
+    /// the kernel can fill its attribute cache instead of following up with a `getattr` of every
+    /// entry. Where listing the directory already had to look an entry up, that's used; the rest
+    /// are looked up now. Entries which disappear in the meantime are left out.
+    ///
+    /// fuse_mt doesn't have a hook for readdirplus, so until it does this can't be reached through
+    /// the mount, and the kernel uses `readdir` and `getattr` instead.
+    pub fn readdirplus(&self, req: RequestInfo, path: &Path, fh: u64)
+        -> Result<Vec<(DirectoryEntry, FileAttr)>, libc::c_int>
+    {
+        debug!("readdirplus: {:?}", path);
+        let mut stats = HashMap::new();
+        let entries = self.list_dir(path, fh, Some(&mut stats))?;
+
+        let mut result = Vec::with_capacity(entries.len());
+        for entry in entries {
+            let entry_path = match entry.name.to_str() {
+                Some(".") => path.to_owned(),
+                Some("..") => path.parent().unwrap_or(path).to_owned(),
+                _ => path.join(&entry.name),
+            };
+            let attr = match stats.remove(&entry.name) {
+                Some((stat, btime)) => self.stat_to_attr(&entry_path, &stat, btime),
+                None => self.getattr(req, &entry_path, None).map(|(_, attr)| attr),
+            };
+            match attr {
+                Ok(attr) => result.push((entry, attr)),
+                Err(libc::ENOENT) => debug!("readdirplus: {:?} is gone", entry_path),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Check the cache format version recorded in the cache directory against the one this build
+    /// uses, upgrading older caches (unless that's disabled) and refusing newer ones.
+    fn check_cache_version(&self) -> io::Result<()> {
+        let cache = Path::new(&self.settings.cache);
+        let version_path = cache.join("version");
+        let version = match utils::read_number_file(&version_path, None::<u32>) {
+            Ok(Some(version)) => version,
+            Ok(None) => {
+                // Caches from before the version file existed have a bucket_size file already;
+                // brand new ones don't have anything yet.
+                if cache.join("buckets").join("bucket_size").exists() {
+                    1
+                } else {
+                    return utils::write_number_file(&version_path, &crate::CACHE_FORMAT_VERSION);
+                }
+            },
+            Err(e) => {
+                return Err(io::Error::new(e.kind(),
+                    format!("error reading cache format version from {:?}: {}", version_path, e)));
+            },
+        };
+
+        if version > crate::CACHE_FORMAT_VERSION {
+            let msg = format!("the cache at {:?} has format version {}, which is newer than this \
+                               version of BackFS supports ({}); upgrade BackFS or use a different \
+                               cache directory",
+                              cache, version, crate::CACHE_FORMAT_VERSION);
+            error!("{}", msg);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+
+        if version < crate::CACHE_FORMAT_VERSION {
+            if self.settings.no_cache_upgrade {
+                let msg = format!("the cache at {:?} has format version {}, which is older than \
+                                   this version of BackFS uses ({}), and no_cache_upgrade was given",
+                                  cache, version, crate::CACHE_FORMAT_VERSION);
+                error!("{}", msg);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            // Everything versions 2, 3 and 5 added is optional and created on demand, so there's
+            // nothing to migrate for them beyond recording the new version.
+            println!("BackFS: Upgrading cache format from version {} to {}.",
+                     version, crate::CACHE_FORMAT_VERSION);
+            if version < 4 {
+                let files = block_map::upgrade_legacy_meta_files(cache.join("map"))?;
+                debug!("moved the metadata of {} files into meta files", files);
+            }
+            utils::write_number_file(&version_path, &crate::CACHE_FORMAT_VERSION)?;
+        }
+
+        Ok(())
+    }
+
+    /// Refuse to cache a backing filesystem directory on the same device as the cache, where the
+    /// cache can't make anything faster, unless `allow_same_device` was given.
+    fn check_same_device(&self) -> io::Result<()> {
+        if self.settings.allow_same_device {
+            return Ok(());
+        }
+        let cache_dev = fs::metadata(&self.settings.cache)?.dev();
+        for layer in &self.backing_layers {
+            if fs::metadata(layer)?.dev() == cache_dev {
+                let msg = format!("the cache directory {:?} is on the same device as the backing \
+                                   filesystem {:?}, so caching it wouldn't make it any faster; use \
+                                   -o allow_same_device to mount anyway",
+                                  self.settings.cache, layer);
+                error!("{}", msg);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+        Ok(())
+    }
+
+    /// What identifies the backing filesystem: the `backing_id` option if one was given, or else
+    /// the device IDs of the backing directories.
+    fn backing_fingerprint(&self) -> io::Result<String> {
+        if let Some(ref id) = self.settings.backing_id {
+            return Ok(format!("id={}\n", id));
+        }
+        let devs = self.backing_layers.iter()
+            .map(|layer| fs::metadata(layer).map(|metadata| metadata.dev().to_string()))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(format!("dev={}\n", devs.join(",")))
+    }
+
+    /// Make sure the cache is being used with the same backing filesystem it was made with, in case
+    /// it's been pointed at the wrong one. If not, warn, or with `reset_on_backing_change`, empty
+    /// the cache. Either way, the new backing filesystem is recorded.
+    fn check_backing_fingerprint(&self) -> io::Result<()> {
+        let path = Path::new(&self.settings.cache).join("backing_fingerprint");
+        let fingerprint = self.backing_fingerprint()?;
+        let recorded = match fs::read_to_string(&path) {
+            Ok(recorded) => Some(recorded),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(io::Error::new(e.kind(),
+                    format!("error reading backing fingerprint from {:?}: {}", path, e)));
+            }
+        };
+
+        match recorded {
+            Some(ref recorded) if *recorded == fingerprint => return Ok(()),
+            Some(recorded) => {
+                let msg = format!("the backing filesystem ({}) isn't the one the cache was used \
+                                   with before ({})", fingerprint.trim(), recorded.trim());
+                warn!("{}", msg);
+                if self.settings.reset_on_backing_change {
+                    println!("BackFS: Warning: {}; emptying the cache.", msg);
+                    self.fscache.reset()?;
+                } else {
+                    println!("BackFS: Warning: {}; its contents might not match.", msg);
+                }
+            },
+            None => (),
+        }
+
+        fs::write(&path, fingerprint)
+    }
+
+    /// Write out deferred access times and read latencies every `interval`, until the filesystem
+    /// goes away.
+    fn start_flusher(&self, interval: Duration) {
+        let fscache = Arc::downgrade(&self.fscache);
+        let result = thread::Builder::new()
+            .name("backfs-flush".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                match fscache.upgrade() {
+                    Some(fscache) => { fscache.flush_state(); },
+                    None => break,
+                }
+            });
+        if let Err(e) = result {
+            error!("failed to start the state flushing thread: {}", e);
+        }
+    }
+
+    /// Drop the cached data of files which have changed in the backing filesystem every
+    /// `interval`, until the filesystem goes away.
+    fn start_sweeper(&self, interval: Duration) {
+        let fscache = Arc::downgrade(&self.fscache);
+        let backing_layers = self.backing_layers.clone();
+        let result = thread::Builder::new()
+            .name("backfs-sweep".to_owned())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let fscache = match fscache.upgrade() {
+                    Some(fscache) => fscache,
+                    None => break,
+                };
+                match fscache.sweep(|path| backing_file_state(&backing_layers, path)) {
+                    Ok(0) => (),
+                    Ok(n) => info!("sweep: dropped the cached data of {} changed files", n),
+                    Err(e) => error!("sweep: {}", e),
+                }
+            });
+        if let Err(e) = result {
+            error!("failed to start the sweeping thread: {}", e);
+        }
+    }
+
+    /// Listen for control commands on a Unix socket at `path`, in a thread of its own. Each
+    /// connection is served in a thread of its own too, and only the control user can connect.
+    fn start_control_socket(&self, path: &Path) -> io::Result<()> {
+        // One left behind by an unclean unmount would keep the socket from being made again.
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+
+        // The socket is made in a directory only we can get into, so that nobody else can connect
+        // to it before its permissions are set, and then moved into place.
+        let mut private_dir = path.as_os_str().to_owned();
+        private_dir.push(format!(".{}", std::process::id()));
+        let private_dir = PathBuf::from(private_dir);
+        fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+        let private_path = private_dir.join("socket");
+        let control_uid = self.uid;
+        let result = UnixListener::bind(&private_path)
+            .and_then(|listener| {
+                // Only the control user gets to control the cache, as with the control file.
+                fs::set_permissions(&private_path, fs::Permissions::from_mode(0o600))?;
+                if control_uid != unsafe { libc::geteuid() } {
+                    std::os::unix::fs::chown(&private_path, Some(control_uid), None)?;
+                }
+                fs::rename(&private_path, path)?;
+                Ok(listener)
+            });
+        if let Err(e) = fs::remove_dir(&private_dir) {
+            warn!("failed to remove {:?}: {}", private_dir, e);
+        }
+        let listener = result?;
+
+        let fscache = Arc::downgrade(&self.fscache);
+        let backing_layers = self.backing_layers.clone();
+        let cache_dir = PathBuf::from(&self.settings.cache);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name("backfs-control".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let fscache = match fscache.upgrade() {
+                        Some(fscache) => fscache,
+                        None => break,
+                    };
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("control socket: {}", e);
+                            continue;
+                        }
+                    };
+                    match libc_wrappers::peer_uid(stream.as_raw_fd() as usize) {
+                        Ok(uid) if uid == control_uid => (),
+                        Ok(uid) => {
+                            warn!("control socket: refusing a connection from uid {}", uid);
+                            continue;
+                        },
+                        Err(e) => {
+                            warn!("control socket: can't tell who connected: {}",
+                                  io::Error::from_raw_os_error(e));
+                            continue;
+                        }
+                    }
+                    let backing_layers = backing_layers.clone();
+                    let cache_dir = cache_dir.clone();
+                    let result = thread::Builder::new()
+                        .name("backfs-control-client".to_owned())
+                        .spawn(move || {
+                            let result = serve_control_connection(&fscache, &backing_layers,
+                                                                  &cache_dir, stream);
+                            if let Err(e) = result {
+                                warn!("control socket: {}", e);
+                            }
+                        });
+                    if let Err(e) = result {
+                        warn!("control socket: failed to start a thread for a client: {}", e);
+                    }
+                }
+            })?;
+
+        *self.control_socket.lock().unwrap() = Some(ControlSocket {
+            path: path.to_owned(),
+            stop,
+            thread,
+        });
+        Ok(())
+    }
+
+    fn stop_control_socket(&self) {
+        let socket = match self.control_socket.lock().unwrap().take() {
+            Some(socket) => socket,
+            None => return,
+        };
+        socket.stop.store(true, Ordering::SeqCst);
+        // Wake the listener up from waiting for a connection, so it sees it has been stopped.
+        // Clients already connected are left to finish (or time out) on their own threads. If the
+        // socket can't be connected to, the listener is left to finish on its own.
+        if UnixStream::connect(&socket.path).is_ok() && socket.thread.join().is_err() {
+            error!("the control socket thread panicked");
+        }
+        if let Err(e) = fs::remove_file(&socket.path) {
+            warn!("failed to remove control socket {:?}: {}", socket.path, e);
+        }
+    }
+
+    /// Refresh the file's stale cached data in the background, unless it's already waiting for
+    /// that (or too many files are). One thread works through the files in turn, while there are
+    /// any.
+    fn start_refresh(&self, path: &Path) {
+        let path = path.as_os_str().to_owned();
+        let mut queue = self.refresh_queue.lock().unwrap();
+        if queue.queued.contains(&path) {
+            return;
+        }
+        if queue.pending.len() >= REFRESH_QUEUE_LIMIT {
+            debug!("not refreshing {:?}: too many files are waiting to be refreshed", path);
+            return;
+        }
+        queue.queued.insert(path.clone());
+        queue.pending.push_back(path);
+        if queue.running {
+            return;
+        }
+
+        let fscache = Arc::clone(&self.fscache);
+        let backing_layers = self.backing_layers.clone();
+        let thread_queue = Arc::clone(&self.refresh_queue);
+        let result = thread::Builder::new()
+            .name("backfs-refresh".to_owned())
+            .spawn(move || loop {
+                let path = {
+                    let mut queue = thread_queue.lock().unwrap();
+                    match queue.pending.pop_front() {
+                        Some(path) => path,
+                        None => {
+                            queue.running = false;
+                            break;
+                        }
+                    }
+                };
+                let result = File::open(layered_path(&backing_layers, &path)).and_then(|mut file| {
+                    let mtime = file.metadata()?.mtime();
+                    fscache.refresh(&path, &mut file, mtime)
+                });
+                if let Err(e) = result {
+                    warn!("failed to refresh stale cached data of {:?}: {}", path, e);
+                }
+                thread_queue.lock().unwrap().queued.remove(&path);
+            });
+        match result {
+            Ok(_) => queue.running = true,
+            Err(e) => {
+                error!("failed to start the refresh thread: {}", e);
+                queue.pending.clear();
+                queue.queued.clear();
+            }
+        }
+    }
+
+    /// Warm the cache from a manifest, in the background.
+    fn start_warming(&self, manifest: PathBuf) {
+        let fscache = Arc::downgrade(&self.fscache);
+        let backing_layers = self.backing_layers.clone();
+        let block_size = self.settings.block_size;
+        let result = thread::Builder::new()
+            .name("backfs-warm".to_owned())
+            .spawn(move || {
+                let entries = match File::open(&manifest)
+                    .and_then(|file| read_manifest(io::BufReader::new(file)))
+                {
+                    Ok(entries) => entries,
+                    Err(e) => {
+                        error!("warm: failed to read manifest {:?}: {}", manifest, e);
+                        return;
+                    }
+                };
+                info!("warm: prefetching {} files listed in {:?}", entries.len(), manifest);
+                warm_cache(&fscache, &backing_layers, block_size, &entries);
+            });
+        if let Err(e) = result {
+            error!("failed to start the cache warming thread: {}", e);
+        }
+    }
+
+    fn internal_init(&self) -> io::Result<()> {
+        if let Err(e) = self.check_same_device() {
+            println!("Error: {}", e);
+            return Err(e);
+        }
+
+        if let Err(e) = self.check_cache_version() {
+            println!("Error: {}", e);
+            return Err(e);
+        }
+
+        println!("BackFS: Initializing cache and scanning existing cache directory...");
+
+        if let Err(e) = self.fscache.init() {
+            println!("Error: Failed to initialize cache: {}", e);
+            return Err(e);
+        }
+
+        if let Err(e) = self.check_backing_fingerprint() {
+            println!("Error: Failed to check the backing filesystem: {}", e);
+            return Err(e);
+        }
+
+        if self.settings.check_cache {
+            println!("BackFS: Checking cache consistency...");
+            if let Err(e) = self.fscache.check_consistency() {
+                println!("Error: Failed to check cache consistency: {}", e);
+                return Err(e);
+            }
+        }
+
+        let max_cache = if self.settings.cache_size == 0 {
+            unsafe {
+                let path_bytes = Vec::from(self.settings.cache.as_os_str().as_bytes());
+                let path_c = CString::from_vec_unchecked(path_bytes);
+                let mut statbuf: libc::statvfs = mem::zeroed();
+                if -1 == libc::statvfs(path_c.into_raw(), &mut statbuf as *mut libc::statvfs) {
+                    let e = io::Error::last_os_error();
+                    println!("Error: failed to statvfs on the cache filesystem: {}", e);
+                    return Err(e);
+                } else {
+                    statbuf.f_bsize as u64 * statbuf.f_blocks as u64
+                }
+            }
+        } else {
+            self.settings.cache_size
+        };
+
+        println!("BackFS: Cache: {} used out of {} ({:.2} %).",
+                 human_number(self.fscache.used_size()),
+                 human_number(max_cache),
+                 (self.fscache.used_size() as f64 / max_cache as f64 * 100.));
+
+        Ok(())
+    }
+}
+
+impl FilesystemMT for BackFs {
+    fn init(&self, _req: RequestInfo) -> ResultEmpty {
+        debug!("init");
+
+        if let Err(e) = self.internal_init() {
+            println!("Error initializing BackFS: {}", e);
+            panic!("{}", e);
+        }
+
+        println!("BackFS: Ready.");
+
+        if !self.settings.foreground {
+            println!("BackFS: Going to background.");
+            if let Err(e) = Daemonize::new().working_directory("/").start() {
+                let msg = format!("Error forking to background: {}", e);
+                error!("{}", msg);
+                panic!("{}", msg);
+            }
+        }
+
+        // Threads don't survive daemonizing, so this has to start afterwards.
+        if self.settings.flush_interval != 0 {
+            self.start_flusher(Duration::from_secs(self.settings.flush_interval));
+        }
+        if self.settings.sweep_interval != 0 {
+            self.start_sweeper(Duration::from_secs(self.settings.sweep_interval));
+        }
+        if let Some(ref manifest) = self.settings.warm_from {
+            self.start_warming(PathBuf::from(manifest));
+        }
+        if let Some(ref path) = self.settings.control_socket {
+            if let Err(e) = self.start_control_socket(Path::new(path)) {
+                error!("failed to start the control socket at {:?}: {}", path, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn destroy(&self) {
+        debug!("destroy");
+        self.stop_control_socket();
+        if let Err(e) = self.fscache.shutdown() {
+            error!("destroy: failed to shut down the cache cleanly: {}", e);
+        }
+    }
+
+    fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
+        debug!("getattr: {:?}", path);
+        trace_span!("getattr", path = ?path);
+
+        if self.is_disabled_control_file(path) {
+            return Err(libc::ENOENT);
+        }
+
+        if let Some(data) = self.fake_file_snapshot(path, fh) {
+            let attr = backfs_fake_file_attr(path.to_str(), self.uid, data.len() as u64).unwrap();
+            return Ok((TTL, attr));
+        }
+
+        if let Some(target) = self.cache_tree_target(path) {
+            return self.cache_tree_attr(&target).map(|attr| (TTL, attr));
+        }
+
+        let attr = self.stat_real(&path, fh)
+            .inspect_err(|&errno| {
+                let msg = format!("getattr: {:?}: {}", path, io::Error::from_raw_os_error(errno));
+                if errno == libc::ENOENT {
+                    debug!("{}", msg);
+                } else {
+                    error!("{}", msg);
+                }
+            })?;
+
+        Ok((self.attr_ttl(), attr))
+    }
+
+    fn opendir(&self, req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
+        debug!("opendir: {:?}", path);
+
+        if let Some(target) = self.cache_tree_target(path) {
+            // It shows the contents of files no matter who's allowed to read them.
+            if req.uid != self.uid {
+                return Err(libc::EACCES);
+            }
+            return match self.cache_tree_lookup(&target)? {
+                CacheTreeNode::Dir(_) => Ok((FAKE_DIR_FH, 0)),
+                CacheTreeNode::File { .. } => Err(libc::ENOTDIR),
+            };
+        }
+
+        let real: OsString = self.real_path(&path);
+        debug!("opendir: real = {:?}", real);
+
+        match libc_wrappers::opendir(real) {
+            Ok(fh) => Ok((fh as u64, 0)),
+            Err(e) if path == Path::new("/") => {
+                // The control and version files have to stay reachable, so the root can still be
+                // listed; it just won't have anything from the backing filesystem in it.
+                warn!("opendir: can't open the backing root: {}; listing only BackFS's own files",
+                      io::Error::from_raw_os_error(e));
+                Ok((FAKE_DIR_FH, 0))
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
+        debug!("readdir: {:?}", path);
+        trace_span!("readdir", path = ?path);
+        self.list_dir(path, fh, None)
+    }
+
+    fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
+        debug!("releasedir: {:?}", path);
+        if fh == FAKE_DIR_FH {
+            return Ok(());
+        }
+        match libc_wrappers::closedir(fh as usize) {
+            Ok(()) => { Ok(()) }
+            Err(e) => {
+                error!("closedir({:?}): {}", path, io::Error::from_raw_os_error(e));
+                Err(e)
+            }
+        }
+    }
+
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+        debug!("open: {:?} flags={:#x}", path, flags);
+        trace_span!("open", path = ?path, flags);
+
+        if self.is_disabled_control_file(path) {
+            return Err(libc::ENOENT);
+        }
+
+        if let Some(data) = self.fake_file_data(path) {
+            let fh = self.next_fake_fh.fetch_add(1, Ordering::Relaxed);
+            self.fake_fhs.lock().unwrap().insert(fh, Arc::new(data));
+            return Ok((fh, 0));
+        }
+
+        let mut open_flags = flags as libc::c_int;
+        if let Some(target) = self.cache_tree_target(path) {
+            if req.uid != self.uid {
+                return Err(libc::EACCES);
+            }
+            if (open_flags & libc::O_ACCMODE) != libc::O_RDONLY || (open_flags & libc::O_TRUNC) != 0 {
+                return Err(libc::EROFS);
+            }
+            return match self.cache_tree_lookup(&target)? {
+                CacheTreeNode::File { .. } => Ok((0, 0)),
+                CacheTreeNode::Dir(_) => Err(libc::EISDIR),
+            };
+        }
+
+        if !self.settings.rw
+                && ((open_flags & libc::O_ACCMODE) != libc::O_RDONLY
+                    || (open_flags & libc::O_TRUNC) != 0) {
+            debug!("open: {:?} opened for writing on a read-only mount", path);
+            return Err(libc::EROFS);
+        }
+
+        let real_path = self.real_path(&path);
+
+        let uncached = self.settings.honor_odirect && (open_flags & UNCACHED_OPEN_FLAGS) != 0;
+        if uncached {
+            // We do the uncached reads ourselves, into buffers that O_DIRECT on the backing file
+            // would reject for not being aligned.
+            open_flags &= !O_DIRECT;
+        }
+
+        match libc_wrappers::open(real_path, open_flags) {
+            Ok(fh) => {
+                // Only regular files get cached. Reads of anything else (pipes, devices, etc.)
+                // are passed straight through to the backing file.
+                let (stat, kind) = libc_wrappers::fstat(fh)
+                    .and_then(|stat| mode_to_filetype(stat.st_mode).map(|kind| (stat, kind)))
+                    .inspect_err(|&e| {
+                        error!("open: fstat({:?}): {}", path, io::Error::from_raw_os_error(e));
+                        let _ = libc_wrappers::close(fh);
+                    })?;
+                let passthrough = match kind {
+                    FileType::RegularFile if uncached => Some(Passthrough::Positional),
+                    FileType::RegularFile => None,
+                    FileType::BlockDevice => Some(Passthrough::Positional),
+                    _ => Some(Passthrough::Stream),
+                };
+                let mut reply_flags = 0;
+                if let Some(passthrough) = passthrough {
+                    debug!("open: reads of {:?} ({:?}) will bypass the cache", path, kind);
+                    self.passthrough_fhs.lock().unwrap().insert(fh as u64, passthrough);
+                } else if open_flags & libc::O_ACCMODE != libc::O_WRONLY {
+                    if self.settings.revalidate_sample {
+                        self.revalidate_sample(path, fh as u64, stat.st_mtime);
+                    }
+                    // If the file hasn't changed since it was cached, whatever the kernel read
+                    // through an earlier open is still good. Writable mounts can change files
+                    // behind its back, so they always start over.
+                    if !self.settings.rw
+                            && self.fscache.is_current(path.as_os_str(), stat.st_mtime) {
+                        debug!("open: {:?} is unchanged; keeping the kernel's cache", path);
+                        reply_flags |= FOPEN_KEEP_CACHE;
+                    }
+                    if self.settings.file_hash {
+                        self.hashing.lock().unwrap().insert(fh as u64, FileHashState::new());
+                    }
+                    self.prefetch_small_file(path, fh as u64, stat.st_size as u64, stat.st_mtime);
+                }
+                Ok((fh as u64, reply_flags))
+            },
+            Err(e) => {
+                error!("open({:?}): {}", path, io::Error::from_raw_os_error(e));
+                Err(e)
+            }
+        }
+    }
+
+    fn release(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32, _lock_owner: u64, _flush: bool) -> ResultEmpty {
+        debug!("release: {:?}", path);
+
+        if is_backfs_fake_file(path) || self.cache_tree_target(path).is_some() {
+            // we didn't open any real file
+            self.fake_fhs.lock().unwrap().remove(&fh);
+            return Ok(());
+        }
+
+        self.passthrough_fhs.lock().unwrap().remove(&fh);
+        self.hashing.lock().unwrap().remove(&fh);
+
+        match libc_wrappers::close(fh as usize) {
+            Ok(()) => { Ok(()) },
+            Err(e) => {
+                error!("close({:?}): {}", path, io::Error::from_raw_os_error(e));
+                Err(e)
+            }
+        }
+    }
+
+    fn read(
+        &self,
+        _req: RequestInfo,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: u32,
+        result: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
+    ) -> CallbackResult {
+        debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
+        trace_span!("read", path = ?path, offset, size);
+
+        match self.read_reply(path, fh, offset, size) {
+            Ok(data) => result(Ok(&data)),
+            Err(errno) => result(Err(errno)),
+        }
+    }
+
+    fn write(&self, req: RequestInfo, path: &Path, _fh: u64, offset: u64, data: Vec<u8>, _flags: u32) -> ResultWrite {
+        debug!("write: {:?} {:#x}@{:#x}", path, data.len(), offset);
+
+        if self.is_disabled_control_file(path) {
+            return Err(libc::ENOENT);
+        }
+
+        match path.to_str() {
+            Some(BACKFS_CONTROL_FILE_PATH) => {
+                // With allow_other, other users can get here despite the file's permissions.
+                if req.uid != self.uid {
+                    warn!("write: uid {} is not allowed to use the control file", req.uid);
+                    return Err(libc::EACCES);
+                }
+                return self.backfs_control_file_write(&data);
+            },
+            Some(BACKFS_VERSION_FILE_PATH) => {
+                return Err(libc::EACCES);
+            }
+            _ => ()
+        }
+
+        if self.cache_tree_target(path).is_some() {
+            return Err(libc::EROFS);
+        }
+
+        if !self.settings.rw {
+            return Err(libc::EROFS);
+        }
+
+        // TODO
+        Err(libc::ENOSYS)
+    }
+
+    // The cache is keyed by path, so the names of a hard-linked file each get their own cached
+    // copy of it. That's wasteful, but not wrong: a change through any of them changes the mtime
+    // they all share, which makes every copy stale.
+    fn link(&self, _req: RequestInfo, path: &Path, newparent: &Path, newname: &OsStr) -> ResultEntry {
+        debug!("link: {:?} -> {:?}/{:?}", path, newparent, newname);
+
+        let new_path = newparent.join(newname);
+        if is_backfs_fake_file(path) || is_backfs_fake_file(&new_path)
+                || self.cache_tree_target(path).is_some()
+                || self.cache_tree_target(&new_path).is_some() {
+            return Err(libc::EACCES);
+        }
+
+        if !self.settings.rw {
+            return Err(libc::EROFS);
+        }
+
+        // Hard links can't cross filesystems, so make the new name in whichever backing layer the
+        // file is in.
+        let real = PathBuf::from(self.real_path(&path));
+        let new_real = match self.backing_layers.iter().find(|layer| real.starts_with(layer)) {
+            Some(layer) => layer.join(new_path.strip_prefix("/").unwrap()),
+            None => PathBuf::from(self.real_path(&new_path)),
+        };
+
+        if let Err(e) = fs::hard_link(&real, &new_real) {
+            error!("link({:?}, {:?}): {}", real, new_real, e);
+            return Err(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+
+        let attr = self.stat_real(&new_path, None)?;
+        Ok((self.attr_ttl(), attr))
+    }
+
+    fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
+        debug!("readlink: {:?}", path);
+
+        let real_path = self.real_path(&path);
 
         match fs::read_link(&real_path) {
             Ok(path) => {
@@ -594,7 +2338,7 @@ impl FilesystemMT for BackFs {
             },
             Err(e) => {
                 error!("readlink({:?}): {}", real_path, e);
-                Err(e.raw_os_error().unwrap())
+                Err(e.raw_os_error().unwrap_or(libc::EIO))
             }
         }
     }
@@ -612,7 +2356,7 @@ impl FilesystemMT for BackFs {
         if -1 == result {
             let e = io::Error::last_os_error();
             error!("statfs({:?}): {}", path, e);
-            Err(e.raw_os_error().unwrap())
+            Err(e.raw_os_error().unwrap_or(libc::EIO))
         } else {
             Ok(statfs_to_fuse(buf))
         }
@@ -620,20 +2364,37 @@ impl FilesystemMT for BackFs {
 
     fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
         debug!("listxattr: {:?}", path);
+        if self.settings.xattr == XattrMode::Off {
+            return Err(libc::ENOTSUP);
+        }
 
-        let extra = b"user.backfs.in_cache\0";
+        let mut extra = b"user.backfs.in_cache\0".to_vec();
+        if self.settings.file_hash {
+            extra.extend_from_slice(FILE_HASH_XATTR.as_bytes());
+            extra.push(0);
+        }
 
         let real = self.real_path(&path);
+        let list_backing = |buf: &mut [u8]| {
+            if self.settings.xattr != XattrMode::Passthrough {
+                return Ok(0);
+            }
+            match libc_wrappers::llistxattr(real.clone(), buf) {
+                Ok(nbytes) => Ok(nbytes),
+                Err(libc::ENOTSUP) => Ok(0),
+                Err(e) => Err(backing_xattr_error("llistxattr", path, e, &self.xattr_errors)),
+            }
+        };
         if size == 0 {
-            let mut nbytes = libc_wrappers::llistxattr(real, &mut[]).unwrap_or(0);
-            nbytes += extra.len();
+            let nbytes = list_backing(&mut [])? + extra.len();
             Ok(Xattr::Size(nbytes as u32))
+        } else if (size as usize) < extra.len() {
+            Err(libc::ERANGE)
         } else {
             let mut data = Vec::<u8>::with_capacity(size as usize);
-            data.extend_from_slice(extra);
+            data.extend_from_slice(&extra);
             unsafe { data.set_len(size as usize) };
-            let nread = libc_wrappers::llistxattr(real, &mut data.as_mut_slice()[extra.len()..])
-                .unwrap_or(0);
+            let nread = list_backing(&mut data.as_mut_slice()[extra.len()..])?;
             data.truncate(nread + extra.len());
             Ok(Xattr::Data(data))
         }
@@ -641,15 +2402,30 @@ impl FilesystemMT for BackFs {
 
     fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
         debug!("getxattr: {:?} {:?} {}", path, name, size);
+        if self.settings.xattr == XattrMode::Off {
+            return Err(libc::ENOTSUP);
+        }
 
         let extra = OsStr::new("user.backfs.in_cache");
 
+        let cached_range = parse_cached_range_xattr(name);
+        let file_hash = self.settings.file_hash && name == FILE_HASH_XATTR;
+
         let real = self.real_path(&path);
+        let get_backing = |buf: &mut [MaybeUninit<u8>]| {
+            if self.settings.xattr != XattrMode::Passthrough {
+                return Err(libc_wrappers::ENOATTR);
+            }
+            libc_wrappers::lgetxattr(real.clone(), name.to_owned(), buf)
+                .map_err(|e| backing_xattr_error("lgetxattr", path, e, &self.xattr_errors))
+        };
         if size == 0 {
-            if name == extra {
+            if name == extra || cached_range.is_some() {
                 Ok(Xattr::Size(21)) // number of digits in 2^64, plus null byte
+            } else if file_hash {
+                Ok(Xattr::Size(64)) // hex digits of a SHA-256
             } else {
-                let nbytes = libc_wrappers::lgetxattr(real, name.to_owned(), &mut[])?;
+                let nbytes = get_backing(&mut [])?;
                 Ok(Xattr::Size(nbytes as u32))
             }
         } else if name == extra {
@@ -657,10 +2433,20 @@ impl FilesystemMT for BackFs {
             let mut data = format!("{}", nbytes).into_bytes();
             data.truncate(size as usize);
             Ok(Xattr::Data(data))
+        } else if file_hash {
+            let mut data = self.file_hash(path)
+                .unwrap_or_else(|| "incomplete".to_owned())
+                .into_bytes();
+            data.truncate(size as usize);
+            Ok(Xattr::Data(data))
+        } else if let Some((offset, len)) = cached_range {
+            let nbytes = self.fscache.count_cached_bytes_in_range(path.as_os_str(), offset, len);
+            let mut data = format!("{}", nbytes).into_bytes();
+            data.truncate(size as usize);
+            Ok(Xattr::Data(data))
         } else {
             let mut data = Vec::<u8>::with_capacity(size as usize);
-            let nread = libc_wrappers::lgetxattr(
-                real, name.to_owned(), data.spare_capacity_mut())?;
+            let nread = get_backing(data.spare_capacity_mut())?;
             unsafe { data.set_len(nread) };
             Ok(Xattr::Data(data))
         }
@@ -668,3 +2454,1538 @@ impl FilesystemMT for BackFs {
 
     // TODO: implement the rest of the syscalls needed
 }
+
+#[cfg(test)]
+fn scratch_base(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("backfs-test-{}-{}", std::process::id(), name))
+}
+
+#[cfg(test)]
+fn scratch_backfs(name: &str, options: &[&str]) -> (BackFs, PathBuf) {
+    let base = scratch_base(name);
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("backing")).unwrap();
+    fs::create_dir_all(base.join("cache")).unwrap();
+
+    let mut args = vec![OsString::from("backfs"), OsString::from("-o")];
+    let mut cache_opt = OsString::from("cache=");
+    cache_opt.push(base.join("cache"));
+    args.push(cache_opt);
+    for option in options {
+        args.push(OsString::from("-o"));
+        args.push(OsString::from(option));
+    }
+    if !options.iter().any(|option| option.starts_with("backing_fs=")) {
+        args.push(base.join("backing").into_os_string());
+    }
+    args.push(base.join("mnt").into_os_string());
+
+    let backfs = BackFs::new(BackfsSettings::parse(&args).unwrap());
+    backfs.fscache.init().unwrap();
+    (backfs, base)
+}
+
+#[cfg(test)]
+const TEST_REQ: RequestInfo = RequestInfo { unique: 0, uid: 0, gid: 0, pid: 0 };
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_honor_odirect() {
+    let (backfs, base) = scratch_backfs("odirect", &["honor_odirect"]);
+    fs::write(base.join("backing/file"), b"some file contents").unwrap();
+    let path = Path::new("/file");
+
+    let (fh, _) = backfs.open(TEST_REQ, path, (libc::O_RDONLY | O_DIRECT) as u32).unwrap();
+    let data = backfs.read_real(path, fh, 0, 4096).unwrap();
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    assert_eq!(&data, b"some file contents");
+    assert_eq!(backfs.fscache.used_size(), 0);
+
+    // The same read without O_DIRECT goes through the cache.
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    let data = backfs.read_real(path, fh, 0, 4096).unwrap();
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    assert_eq!(&data, b"some file contents");
+    assert_eq!(backfs.fscache.used_size(), data.len() as u64);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_fifo_passthrough() {
+    use std::io::Write;
+
+    let (backfs, base) = scratch_backfs("fifo", &[]);
+    let fifo = CString::new(base.join("backing/fifo").into_os_string().into_vec()).unwrap();
+    assert_eq!(0, unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) });
+    let path = Path::new("/fifo");
+
+    // Non-blocking so the open doesn't wait for a writer.
+    let (fh, _) = backfs.open(TEST_REQ, path, (libc::O_RDONLY | libc::O_NONBLOCK) as u32).unwrap();
+    let mut writer = fs::OpenOptions::new().write(true).open(base.join("backing/fifo")).unwrap();
+    writer.write_all(b"through the pipe").unwrap();
+
+    let data = backfs.read_real(path, fh, 0, 4096).unwrap();
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    assert_eq!(&data, b"through the pipe");
+    assert_eq!(backfs.fscache.used_size(), 0);
+    assert!(!base.join("cache/map/fifo").exists());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_getattr_with_fh() {
+    let (backfs, base) = scratch_backfs("getattr_fh", &[]);
+    fs::write(base.join("backing/file"), b"still here").unwrap();
+    let path = Path::new("/file");
+
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    fs::remove_file(base.join("backing/file")).unwrap();
+
+    // With a fh, getattr uses fstat on the open file, which still works after it's unlinked.
+    let (_, attr) = backfs.getattr(TEST_REQ, path, Some(fh)).unwrap();
+    assert_eq!(attr.size, 10);
+    assert_eq!(backfs.getattr(TEST_REQ, path, None).unwrap_err(), libc::ENOENT);
+
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_getattr_crtime() {
+    let (backfs, base) = scratch_backfs("getattr_crtime", &[]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    let path = Path::new("/file");
+
+    // The crtime is the backing file's birth time, if its filesystem has one.
+    let expected = match libc_wrappers::lstatx(base.join("backing/file").into_os_string()) {
+        Ok((_, Some((secs, nanos)))) => epoch_time(secs, nanos),
+        _ => std::time::UNIX_EPOCH,
+    };
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert_eq!(attr.crtime, expected);
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    let (_, attr) = backfs.getattr(TEST_REQ, path, Some(fh)).unwrap();
+    assert_eq!(attr.crtime, expected);
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_prefetch_small_file() {
+    let (backfs, base) = scratch_backfs("prefetch", &["block_size=10"]);
+    fs::write(base.join("backing/small"), b"0123456789abcdefghijABCDE").unwrap();
+    fs::write(base.join("backing/big"), [0u8; 50]).unwrap();
+
+    // Three blocks: cached in full as soon as it's opened.
+    let path = Path::new("/small");
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 25);
+    assert_eq!(backfs.fscache.used_size(), 25);
+
+    // Five blocks: bigger than the default of 4, so nothing is read until asked for.
+    let path = Path::new("/big");
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 0);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_open_keep_cache() {
+    let opts = ["block_size=10", "prefetch_blocks=0"];
+    let (backfs, base) = scratch_backfs("keep_cache", &opts);
+    let backing = base.join("backing/file");
+    fs::write(&backing, b"0123456789abcde").unwrap();
+    let path = Path::new("/file");
+    let open_and_read = |backfs: &BackFs| -> u32 {
+        let (fh, flags) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(backfs.read_real(path, fh, 0, 100).unwrap(), b"0123456789abcde");
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+        flags
+    };
+
+    // The first time, there's nothing cached for the kernel to keep.
+    assert_eq!(open_and_read(&backfs), 0);
+    assert_eq!(open_and_read(&backfs), FOPEN_KEEP_CACHE);
+
+    // Once the file changes, the kernel has to read it again.
+    let mtime = backing.metadata().unwrap().modified().unwrap();
+    File::options().write(true).open(&backing).unwrap()
+        .set_modified(mtime + Duration::from_secs(1)).unwrap();
+    assert_eq!(open_and_read(&backfs), 0);
+    assert_eq!(open_and_read(&backfs), FOPEN_KEEP_CACHE);
+    fs::remove_dir_all(base).unwrap();
+
+    // Files on a writable mount are never kept.
+    let (backfs, base) = scratch_backfs("keep_cache_rw", &["rw", opts[0], opts[1]]);
+    fs::write(base.join("backing/file"), b"0123456789abcde").unwrap();
+    assert_eq!(open_and_read(&backfs), 0);
+    assert_eq!(open_and_read(&backfs), 0);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_revalidate_sample() {
+    let opts = ["block_size=10", "prefetch_blocks=0", "revalidate=sample"];
+    let (backfs, base) = scratch_backfs("revalidate_sample", &opts);
+    let backing = base.join("backing/file");
+    fs::write(&backing, b"0123456789abcde").unwrap();
+    let path = Path::new("/file");
+    let open_and_read = |backfs: &BackFs| -> (Vec<u8>, u32) {
+        let (fh, flags) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        let data = backfs.read_real(path, fh, 0, 100).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+        (data, flags)
+    };
+    assert_eq!(open_and_read(&backfs), (b"0123456789abcde".to_vec(), 0));
+    assert_eq!(open_and_read(&backfs), (b"0123456789abcde".to_vec(), FOPEN_KEEP_CACHE));
+
+    // Rewritten in place, with the mtime put back: the new contents are read, and the kernel
+    // doesn't get to keep what it had.
+    let mtime = backing.metadata().unwrap().modified().unwrap();
+    fs::write(&backing, b"0123456789ABCDE").unwrap();
+    File::options().write(true).open(&backing).unwrap().set_modified(mtime).unwrap();
+    assert_eq!(open_and_read(&backfs), (b"0123456789ABCDE".to_vec(), 0));
+    assert_eq!(open_and_read(&backfs), (b"0123456789ABCDE".to_vec(), FOPEN_KEEP_CACHE));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_sparse_file_holes() {
+    let (backfs, base) = scratch_backfs("sparse", &["block_size=10", "prefetch_blocks=0"]);
+    let file = fs::File::create(base.join("backing/sparse")).unwrap();
+    file.write_all_at(b"0123456789", 0).unwrap();
+    file.write_all_at(b"abcde", 30).unwrap();
+    let path = Path::new("/sparse");
+
+    // Blocks 1 and 2 are a hole.
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    let mut expected = b"0123456789".to_vec();
+    expected.extend_from_slice(&[0; 20]);
+    expected.extend_from_slice(b"abcde");
+    assert_eq!(backfs.read_real(path, fh, 0, 100).unwrap(), expected);
+
+    // Only the data blocks took up any space in the cache.
+    assert_eq!(backfs.fscache.used_size(), 15);
+    assert!(base.join("cache/map/sparse/1.hole").exists());
+
+    // And the holes read back as zeros from the cache.
+    assert_eq!(backfs.read_real(path, fh, 5, 10).unwrap(), b"56789\0\0\0\0\0");
+    assert_eq!(backfs.fscache.used_size(), 15);
+
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_track_atime() {
+    let (backfs, base) = scratch_backfs("atime", &["track_atime"]);
+    let backing = base.join("backing/file");
+    fs::write(&backing, b"hello").unwrap();
+
+    // An atime in the future, so the kernel won't update it on read even under relatime.
+    let backing_atime = SystemTime::now() + Duration::from_secs(10 * 24 * 60 * 60);
+    fs::File::options().write(true).open(&backing).unwrap()
+        .set_times(fs::FileTimes::new().set_accessed(backing_atime))
+        .unwrap();
+    let backing_atime = fs::metadata(&backing).unwrap().accessed().unwrap();
+
+    // Not read through BackFS yet: the backing atime shows through.
+    let path = Path::new("/file");
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert_eq!(attr.atime, backing_atime);
+
+    let before_read = SystemTime::now() - Duration::from_secs(1);
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(backfs.read_real(path, fh, 0, 5).unwrap(), b"hello");
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert!(attr.atime >= before_read && attr.atime <= SystemTime::now());
+    assert_eq!(fs::metadata(&backing).unwrap().accessed().unwrap(), backing_atime);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_fixed_mtime() {
+    let (backfs, base) = scratch_backfs("fixed_mtime",
+                                        &["fixed_mtime=1234567890", "track_atime", "block_size=10"]);
+    fs::create_dir(base.join("backing/dir")).unwrap();
+    fs::write(base.join("backing/dir/file"), b"hello").unwrap();
+    fs::write(base.join("backing/other"), b"world").unwrap();
+    let fixed = std::time::UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+
+    for path in &["/", "/dir", "/dir/file", "/other"] {
+        let (_, attr) = backfs.getattr(TEST_REQ, Path::new(path), None).unwrap();
+        assert_eq!((attr.mtime, attr.ctime), (fixed, fixed), "{}", path);
+    }
+
+    // The cache still notices when a file changes.
+    let path = Path::new("/dir/file");
+    let read = || {
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        let data = backfs.read_real(path, fh, 0, 10).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+        data
+    };
+    let before_read = SystemTime::now() - Duration::from_secs(1);
+    assert_eq!(read(), b"hello");
+    let backing = base.join("backing/dir/file");
+    let mtime = fs::metadata(&backing).unwrap().modified().unwrap();
+    fs::write(&backing, b"HELLO").unwrap();
+    File::options().write(true).open(&backing).unwrap()
+        .set_modified(mtime + Duration::from_secs(1)).unwrap();
+    assert_eq!(read(), b"HELLO");
+
+    // Access times are still tracked.
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert_eq!(attr.mtime, fixed);
+    assert!(attr.atime >= before_read && attr.atime <= SystemTime::now());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_attr_ttl() {
+    let (backfs, base) = scratch_backfs("attr_ttl", &[]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    let (ttl, _) = backfs.getattr(TEST_REQ, Path::new("/file"), None).unwrap();
+    assert_eq!(ttl, Duration::from_secs(1));
+    fs::remove_dir_all(base).unwrap();
+
+    let (backfs, base) = scratch_backfs("attr_ttl", &["attr_ttl=3600"]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    for path in &["/", "/file"] {
+        let (ttl, _) = backfs.getattr(TEST_REQ, Path::new(path), None).unwrap();
+        assert_eq!(ttl, Duration::from_secs(3600), "{}", path);
+    }
+
+    // The control file's size changes, so the kernel still has to keep asking about it.
+    let (ttl, _) = backfs.getattr(TEST_REQ, Path::new(BACKFS_CONTROL_FILE_PATH), None).unwrap();
+    assert_eq!(ttl, TTL);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_open_for_write_read_only() {
+    let (backfs, base) = scratch_backfs("rdonly", &[]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    let path = Path::new("/file");
+
+    for flags in &[libc::O_WRONLY, libc::O_RDWR, libc::O_RDONLY | libc::O_TRUNC] {
+        assert_eq!(backfs.open(TEST_REQ, path, *flags as u32).unwrap_err(), libc::EROFS);
+    }
+    assert_eq!(fs::read(base.join("backing/file")).unwrap(), b"hello");
+
+    // The control file still takes writes.
+    let control = Path::new(BACKFS_CONTROL_FILE_PATH);
+    backfs.open(TEST_REQ, control, libc::O_WRONLY as u32).unwrap();
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_mode_overrides() {
+    let (backfs, base) = scratch_backfs("modes", &["file_mode=664", "dir_mode=0775"]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    fs::create_dir(base.join("backing/dir")).unwrap();
+
+    // Read-only: the write bits are still masked off.
+    let (_, attr) = backfs.getattr(TEST_REQ, Path::new("/file"), None).unwrap();
+    assert_eq!(attr.perm, 0o444);
+    let (_, attr) = backfs.getattr(TEST_REQ, Path::new("/dir"), None).unwrap();
+    assert_eq!(attr.perm, 0o555);
+    fs::remove_dir_all(base).unwrap();
+
+    let (backfs, base) = scratch_backfs("modes_rw", &["rw", "file_mode=600", "dir_mode=700"]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    fs::create_dir(base.join("backing/dir")).unwrap();
+
+    let (_, attr) = backfs.getattr(TEST_REQ, Path::new("/file"), None).unwrap();
+    assert_eq!(attr.perm, 0o600);
+    let (_, attr) = backfs.getattr(TEST_REQ, Path::new("/dir"), None).unwrap();
+    assert_eq!(attr.perm, 0o700);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_owner_overrides() {
+    let (backfs, base) = scratch_backfs("owner", &["uid=1234", "gid=5678"]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+
+    let (_, attr) = backfs.getattr(TEST_REQ, Path::new("/file"), None).unwrap();
+    assert_eq!((attr.uid, attr.gid), (1234, 5678));
+    fs::remove_dir_all(base).unwrap();
+
+    // Without the options, the real owner shows through.
+    let (backfs, base) = scratch_backfs("owner_real", &[]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    let metadata = fs::metadata(base.join("backing/file")).unwrap();
+
+    let (_, attr) = backfs.getattr(TEST_REQ, Path::new("/file"), None).unwrap();
+    assert_eq!((attr.uid, attr.gid), (metadata.uid(), metadata.gid()));
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_backing_layers() {
+    let base = scratch_base("layers");
+    let option = format!("backing_fs={}:{}",
+                         base.join("upper").display(), base.join("lower").display());
+    let (backfs, base) = scratch_backfs("layers", &[&option]);
+    fs::create_dir_all(base.join("upper/dir")).unwrap();
+    fs::create_dir_all(base.join("lower/dir")).unwrap();
+    fs::write(base.join("upper/dir/shadowed"), b"upper").unwrap();
+    fs::write(base.join("lower/dir/shadowed"), b"lower!").unwrap();
+    fs::write(base.join("upper/dir/upper_only"), b"up").unwrap();
+    fs::write(base.join("lower/dir/lower_only"), b"down").unwrap();
+
+    // A file in only one layer is found there, whichever layer it is.
+    for (path, contents) in &[("/dir/upper_only", &b"up"[..]), ("/dir/lower_only", &b"down"[..])] {
+        let path = Path::new(path);
+        let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+        assert_eq!(attr.size, contents.len() as u64);
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(&backfs.read_real(path, fh, 0, 4096).unwrap(), contents);
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    }
+
+    // The earlier layer wins.
+    let path = Path::new("/dir/shadowed");
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert_eq!(attr.size, 5);
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(&backfs.read_real(path, fh, 0, 4096).unwrap(), b"upper");
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+
+    // Listings are merged, with no duplicates.
+    let path = Path::new("/dir");
+    let (fh, _) = backfs.opendir(TEST_REQ, path, 0).unwrap();
+    let mut names: Vec<OsString> = backfs.readdir(TEST_REQ, path, fh).unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    backfs.releasedir(TEST_REQ, path, fh, 0).unwrap();
+    names.sort();
+    assert_eq!(names, [".", "..", "lower_only", "shadowed", "upper_only"]);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_manifest() {
+    let (backfs, base) = scratch_backfs("manifest", &["block_size=10", "prefetch_blocks=0"]);
+    fs::create_dir(base.join("backing/dir")).unwrap();
+    fs::write(base.join("backing/dir/one"), [1u8; 100]).unwrap();
+    fs::write(base.join("backing/two words"), [2u8; 100]).unwrap();
+    fs::write(base.join("backing/uncached"), [3u8; 100]).unwrap();
+
+    // Blocks 0-2 and 7 of one file, and block 5 of another.
+    let reads = [("/dir/one", 0, 30), ("/dir/one", 75, 1), ("/two words", 55, 2)];
+    for &(path, offset, size) in &reads {
+        let path = Path::new(path);
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        backfs.read_real(path, fh, offset, size).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    }
+
+    let entries = backfs.fscache.manifest().unwrap();
+    let mtime = |name| fs::metadata(base.join("backing").join(name)).unwrap().mtime();
+    assert_eq!(entries, vec![
+        ManifestEntry {
+            path: "/dir/one".into(),
+            mtime: mtime("dir/one"),
+            blocks: vec![0, 1, 2, 7],
+        },
+        ManifestEntry {
+            path: "/two words".into(),
+            mtime: mtime("two words"),
+            blocks: vec![5],
+        },
+    ]);
+
+    backfs.backfs_control_file_write(b"manifest").unwrap();
+    assert_eq!(fs::read_to_string(base.join("cache/manifest")).unwrap(),
+               format!("{} 0-2,7 /dir/one\n{} 5 /two words\n",
+                       mtime("dir/one"), mtime("two words")));
+
+    // It can't be written anywhere else.
+    let mut command = b"manifest ".to_vec();
+    command.extend_from_slice(base.join("manifest.txt").as_os_str().as_bytes());
+    assert_eq!(backfs.backfs_control_file_write(&command), Err(libc::EINVAL));
+    assert!(!base.join("manifest.txt").exists());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_warm_from_manifest() {
+    let (backfs, base) = scratch_backfs("warm", &["block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), b"0123456789abcdefghijABCDEFGHIJklmno").unwrap();
+    let manifest = b"1 1-2,9 /file\n1 0 /gone\n";
+    let entries = read_manifest(&manifest[..]).unwrap();
+    assert_eq!(entries[0].blocks, vec![1, 2, 9]);
+
+    warm_cache(&Arc::downgrade(&backfs.fscache), &backfs.backing_layers, 10, &entries);
+    // Block 9 is past the end of the file now, and the other file isn't there at all.
+    let cached = backfs.fscache.manifest().unwrap();
+    assert_eq!(cached.len(), 1);
+    assert_eq!(cached[0].path, OsString::from("/file"));
+    assert_eq!(cached[0].blocks, vec![1, 2]);
+    assert_eq!(backfs.fscache.used_size(), 20);
+
+    assert_eq!(read_manifest(&b"1 x /file\n"[..]).unwrap_err().kind(),
+               io::ErrorKind::InvalidData);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_control_file_binary_commands() {
+    let (backfs, base) = scratch_backfs("control_binary", &["prefetch_blocks=0"]);
+    let names = [OsStr::new("with spaces "), OsStr::from_bytes(b"not\xffutf8\n")];
+    for name in &names {
+        fs::write(base.join("backing").join(name), b"some file contents").unwrap();
+    }
+
+    for name in &names {
+        let path = Path::new("/").join(name);
+        let (fh, _) = backfs.open(TEST_REQ, &path, libc::O_RDONLY as u32).unwrap();
+        backfs.read_real(&path, fh, 0, 4096).unwrap();
+        backfs.release(TEST_REQ, &path, fh, 0, 0, false).unwrap();
+        assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 18);
+
+        // The trailing space and newline would get mangled by the text format.
+        let mut command = b"\0invalidate\0".to_vec();
+        command.extend_from_slice(path.as_os_str().as_bytes());
+        command.push(0);
+        assert_eq!(backfs.backfs_control_file_write(&command), Ok(command.len() as u32));
+        assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 0);
+    }
+
+    // Unknown commands are still rejected.
+    assert_eq!(backfs.backfs_control_file_write(b"\0bogus\0"), Err(libc::EBADMSG));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_control_commands_non_utf8() {
+    let (backfs, base) = scratch_backfs("control_non_utf8", &["block_size=10", "prefetch_blocks=0"]);
+    let dir = OsStr::from_bytes(b"dir\xff");
+    let name = OsStr::from_bytes(b"na\xfeme");
+    fs::create_dir(base.join("backing").join(dir)).unwrap();
+    fs::write(base.join("backing").join(dir).join(name), [b'x'; 40]).unwrap();
+    let path = Path::new("/").join(dir).join(name);
+    let cache_it = || {
+        let (fh, _) = backfs.open(TEST_REQ, &path, libc::O_RDONLY as u32).unwrap();
+        backfs.read_real(&path, fh, 0, 40).unwrap();
+        backfs.release(TEST_REQ, &path, fh, 0, 0, false).unwrap();
+        assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 40);
+    };
+    let command = |prefix: &[u8], arg: &Path, suffix: &[u8]| {
+        let mut command = prefix.to_vec();
+        command.extend_from_slice(arg.as_os_str().as_bytes());
+        command.extend_from_slice(suffix);
+        command
+    };
+    let cached_blocks = || {
+        backfs.fscache.manifest().unwrap().into_iter()
+            .find(|entry| entry.path == path.as_os_str())
+            .map(|entry| entry.blocks)
+            .unwrap_or_default()
+    };
+
+    // Both formats, for both commands.
+    cache_it();
+    let text = command(b"free_block ", &path.join("1"), b"\n");
+    assert_eq!(backfs.backfs_control_file_write(&text), Ok(text.len() as u32));
+    let binary = command(b"\0free_block\0", &path.join("2-3"), b"\0");
+    assert_eq!(backfs.backfs_control_file_write(&binary), Ok(binary.len() as u32));
+    assert_eq!(cached_blocks(), vec![0]);
+    let text = command(b"invalidate ", &path, b"\n");
+    assert_eq!(backfs.backfs_control_file_write(&text), Ok(text.len() as u32));
+    assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 0);
+
+    cache_it();
+    let binary = command(b"\0invalidate\0", &path, b"\0\n");
+    assert_eq!(backfs.backfs_control_file_write(&binary), Ok(binary.len() as u32));
+    assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 0);
+
+    // Anything that could be cut short or run together with something else is refused.
+    cache_it();
+    for bad in [command(b"\0invalidate\0", &path, b""),
+                command(b"\0invalidate\0", &path, b"\0/other\0"),
+                command(b"invalidate ", &path, b"\0\n")] {
+        assert_eq!(backfs.backfs_control_file_write(&bad), Err(libc::EINVAL));
+    }
+    assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 40);
+
+    // The command itself has to be UTF-8, in either format.
+    for bad in [command(b"invalidat\xe9 ", &path, b""), command(b"\0invalidat\xe9\0", &path, b"\0")] {
+        assert_eq!(backfs.backfs_control_file_write(&bad), Err(libc::EBADMSG));
+    }
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_free_block_range() {
+    let (backfs, base) = scratch_backfs("free_range", &["block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), [b'x'; 60]).unwrap();
+    let path = Path::new("/file");
+
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    backfs.read_real(path, fh, 0, 60).unwrap();
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    assert_eq!(backfs.fscache.used_size(), 60);
+
+    backfs.backfs_control_file_write(b"free_block /file/2-4").unwrap();
+    let entries = backfs.fscache.manifest().unwrap();
+    assert_eq!(entries[0].blocks, vec![0, 1, 5]);
+    assert_eq!(backfs.fscache.used_size(), 30);
+
+    assert_eq!(backfs.backfs_control_file_write(b"free_block /file/5-1"), Err(libc::EINVAL));
+    assert_eq!(backfs.backfs_control_file_write(b"free_block /file/1-x"), Err(libc::EINVAL));
+    assert_eq!(backfs.fscache.manifest().unwrap()[0].blocks, vec![0, 1, 5]);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_index_map_format() {
+    let (backfs, base) = scratch_backfs("index_map",
+                                        &["map_format=index", "block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), b"0123456789abcdefghijABCDE").unwrap();
+    assert_eq!(MapFormat::of_cache(base.join("cache")).unwrap(), Some(MapFormat::Index));
+    let path = Path::new("/file");
+
+    for _ in 0 .. 2 {
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        let data = backfs.read_real(path, fh, 0, 4096).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+        assert_eq!(&data, b"0123456789abcdefghijABCDE");
+    }
+    let metrics = backfs.fscache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (3, 3));
+    assert!(base.join("cache/map/file/blocks").exists());
+    assert!(!base.join("cache/map/file/0").exists());
+    backfs.fscache.check_consistency().unwrap();
+
+    // Evicting a block goes through the bucket's parent link to the index entry.
+    backfs.fscache.set_max_size(Some(20)).unwrap();
+    assert_eq!(backfs.fscache.manifest().unwrap()[0].blocks.len(), 2);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_read_error_without_errno() {
+    let (backfs, base) = scratch_backfs("read_no_errno", &[]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    let path = Path::new("/file");
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(backfs.read_reply(path, fh, 0, 4096).unwrap(), b"hello");
+
+    // A mangled meta file makes an error with no errno of its own.
+    fs::write(base.join("cache/map/file/meta"), b"mtime yesterday\n").unwrap();
+    let e = backfs.read_real(path, fh, 0, 4096).unwrap_err();
+    assert_eq!(e.raw_os_error(), None);
+    assert_eq!(backfs.read_reply(path, fh, 0, 4096), Err(libc::EIO));
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_error_metrics() {
+    let (backfs, base) = scratch_backfs("error_metrics", &[]);
+    fs::write(base.join("backing/file"), b"hello").unwrap();
+    let counts = |backfs: &BackFs| {
+        let metrics = backfs.metrics();
+        (metrics.stat_errors, metrics.xattr_errors)
+    };
+
+    // Files which aren't there are nothing out of the ordinary.
+    assert_eq!(backfs.getattr(TEST_REQ, Path::new("/missing"), None).err(), Some(libc::ENOENT));
+    assert_eq!(backfs.getxattr(TEST_REQ, Path::new("/file"), OsStr::new("user.nonexistent"), 0)
+                   .err(),
+               Some(libc_wrappers::ENOATTR));
+    assert_eq!(counts(&backfs), (0, 0));
+
+    // But paths through a file are.
+    let bad = Path::new("/file/child");
+    assert_eq!(backfs.getattr(TEST_REQ, bad, None).err(), Some(libc::ENOTDIR));
+    assert_eq!(counts(&backfs), (1, 0));
+    assert_eq!(backfs.getxattr(TEST_REQ, bad, OsStr::new("user.test"), 0).err(),
+               Some(libc::ENOTDIR));
+    assert_eq!(backfs.listxattr(TEST_REQ, bad, 0).err(), Some(libc::ENOTDIR));
+    assert_eq!(counts(&backfs), (1, 2));
+    assert_eq!(backfs.metrics().cache, backfs.fscache.metrics());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_packed_meta_format() {
+    let (backfs, base) = scratch_backfs("packed_meta",
+                                        &["meta_format=packed", "block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), b"0123456789abcdefghijABCDE").unwrap();
+    assert_eq!(MetaFormat::of_cache(base.join("cache")).unwrap(), Some(MetaFormat::Packed));
+    let path = Path::new("/file");
+
+    for _ in 0 .. 2 {
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        let data = backfs.read_real(path, fh, 0, 4096).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+        assert_eq!(&data, b"0123456789abcdefghijABCDE");
+    }
+    let metrics = backfs.fscache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (3, 3));
+    assert!(base.join("cache/map/.backfs_meta").exists());
+    assert!(!base.join("cache/map/file/meta").exists());
+    backfs.fscache.check_consistency().unwrap();
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_version_string() {
+    let version = version_string();
+    assert!(version.contains(&format!("\nCache format version: {}\n", crate::CACHE_FORMAT_VERSION)),
+            "{}", version);
+    assert!(version.contains(&format!("\nBuild time: {}\n", utils::format_utc_time(crate::BUILD_TIME))));
+
+    // The version file has the same contents, and the right size.
+    let (backfs, base) = scratch_backfs("version", &[]);
+    let path = Path::new(BACKFS_VERSION_FILE_PATH);
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert_eq!(attr.size, version.len() as u64);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_same_device() {
+    // The scratch cache and backing directories are both under the same temp directory.
+    let (backfs, _) = scratch_backfs("same_device", &[]);
+    let err = backfs.internal_init().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(err.to_string().contains("allow_same_device"), "{}", err);
+
+    let (backfs, base) = scratch_backfs("same_device", &["allow_same_device"]);
+    backfs.internal_init().unwrap();
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_cache_version() {
+    // scratch_backfs initializes the cache, which makes it look like one from before the version
+    // file existed.
+    let (backfs, base) = scratch_backfs("cache_version", &["no_cache_upgrade", "allow_same_device"]);
+    let version_path = base.join("cache").join("version");
+    let err = backfs.internal_init().unwrap_err();
+    assert!(err.to_string().contains("format version 1, which is older"), "{}", err);
+    assert!(!version_path.exists());
+
+    let (backfs, base) = scratch_backfs("cache_version", &["allow_same_device"]);
+    backfs.check_cache_version().unwrap();
+    assert_eq!(fs::read_to_string(&version_path).unwrap(),
+               crate::CACHE_FORMAT_VERSION.to_string());
+
+    fs::write(&version_path, (crate::CACHE_FORMAT_VERSION + 1).to_string()).unwrap();
+    let err = backfs.internal_init().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains(&format!("format version {}, which is newer",
+                                              crate::CACHE_FORMAT_VERSION + 1)),
+            "{}", err);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_fallocate() {
+    let (backfs, base) = scratch_backfs("fallocate", &["block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), [b'x'; 30]).unwrap();
+    let path = Path::new("/file");
+
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    backfs.read_real(path, fh, 0, 30).unwrap();
+    assert_eq!(backfs.fallocate(path, fh, 30, 20, 0), Err(libc::EROFS));
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+
+    let (backfs, base) = scratch_backfs("fallocate", &["rw", "block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), [b'x'; 30]).unwrap();
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDWR as u32).unwrap();
+    backfs.read_real(path, fh, 0, 30).unwrap();
+    assert_eq!(backfs.fscache.manifest().unwrap()[0].blocks, vec![0, 1, 2]);
+
+    // Preallocating past the end grows the file, and drops the block it overlaps.
+    backfs.fallocate(path, fh, 25, 25, 0).unwrap();
+    assert_eq!(fs::metadata(base.join("backing/file")).unwrap().len(), 50);
+    assert_eq!(backfs.fscache.manifest().unwrap()[0].blocks, vec![0, 1]);
+
+    // Punching a hole drops the blocks in it. Not all filesystems can do this.
+    match backfs.fallocate(path, fh, 5, 10,
+                           libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) {
+        Ok(()) => {
+            assert!(backfs.fscache.manifest().unwrap().is_empty());
+            assert_eq!(fs::read(base.join("backing/file")).unwrap()[..20],
+                       *b"xxxxx\0\0\0\0\0\0\0\0\0\0xxxxx");
+        },
+        Err(libc::EOPNOTSUPP) => (),
+        Err(e) => panic!("punching a hole failed: {}", io::Error::from_raw_os_error(e)),
+    }
+    assert_eq!(fs::metadata(base.join("backing/file")).unwrap().len(), 50);
+
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_map_block_size_mismatch() {
+    let (backfs, base) = scratch_backfs("map_block_size", &["block_size=10"]);
+    fs::write(base.join("cache/map/.backfs_block_size"), "20").unwrap();
+    let err = backfs.fscache.init().unwrap_err();
+    assert!(err.to_string().contains("block size in map (20) doesn't match the size in the options (10)"),
+            "{}", err);
+
+    fs::write(base.join("cache/map/.backfs_block_size"), "10").unwrap();
+    fs::write(base.join("cache/buckets/bucket_size"), "20").unwrap();
+    let err = backfs.fscache.init().unwrap_err();
+    assert!(err.to_string().contains("block size in cache (20)"), "{}", err);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_show_real_mode() {
+    use std::os::unix::fs::PermissionsExt;
+    let (backfs, base) = scratch_backfs("real_mode", &[]);
+    fs::write(base.join("backing/file"), b"data").unwrap();
+    fs::set_permissions(base.join("backing/file"), fs::Permissions::from_mode(0o664)).unwrap();
+    let path = Path::new("/file");
+
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert_eq!(attr.perm, 0o444);
+
+    let (backfs, base) = scratch_backfs("real_mode", &["show_real_mode"]);
+    fs::write(base.join("backing/file"), b"data").unwrap();
+    fs::set_permissions(base.join("backing/file"), fs::Permissions::from_mode(0o664)).unwrap();
+    let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+    assert_eq!(attr.perm, 0o664);
+
+    // It's still read-only, though.
+    assert_eq!(backfs.open(TEST_REQ, path, libc::O_WRONLY as u32), Err(libc::EROFS));
+    assert_eq!(backfs.write(TEST_REQ, path, 0, 0, b"more".to_vec(), 0), Err(libc::EROFS));
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_link() {
+    let (backfs, base) = scratch_backfs("link", &[]);
+    fs::write(base.join("backing/file"), b"data").unwrap();
+    assert_eq!(backfs.link(TEST_REQ, Path::new("/file"), Path::new("/"), OsStr::new("other"))
+                   .unwrap_err(),
+               libc::EROFS);
+    assert!(!base.join("backing/other").exists());
+
+    let (backfs, base) = scratch_backfs("link", &["rw"]);
+    fs::write(base.join("backing/file"), b"data").unwrap();
+    let (_, attr) = backfs.link(TEST_REQ, Path::new("/file"), Path::new("/"), OsStr::new("other"))
+        .unwrap();
+    assert_eq!(attr.size, 4);
+    assert_eq!(attr.nlink, 2);
+
+    for name in &["/file", "/other"] {
+        let path = Path::new(name);
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(backfs.read_real(path, fh, 0, 4).unwrap(), b"data");
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    }
+
+    assert_eq!(backfs.link(TEST_REQ, Path::new("/file"), Path::new("/"), OsStr::new("other"))
+                   .unwrap_err(),
+               libc::EEXIST);
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_cache_dirs() {
+    let (backfs, base) = scratch_backfs("cache_dirs", &["cache_dirs"]);
+    fs::create_dir(base.join("backing/dir")).unwrap();
+    fs::write(base.join("backing/dir/a"), b"").unwrap();
+    let path = Path::new("/dir");
+    let list = || {
+        let (fh, _) = backfs.opendir(TEST_REQ, path, 0).unwrap();
+        let mut names = backfs.readdir(TEST_REQ, path, fh).unwrap().into_iter()
+            .map(|entry| entry.name)
+            .filter(|name| name != "." && name != "..")
+            .collect::<Vec<_>>();
+        backfs.releasedir(TEST_REQ, path, fh, 0).unwrap();
+        names.sort();
+        names
+    };
+    assert_eq!(list(), vec![OsString::from("a")]);
+
+    // While the directory's mtime stays the same, the listing comes from the cache, so a new file
+    // doesn't show up.
+    let mtime = fs::metadata(base.join("backing/dir")).unwrap().modified().unwrap();
+    fs::write(base.join("backing/dir/b"), b"").unwrap();
+    File::open(base.join("backing/dir")).unwrap().set_modified(mtime).unwrap();
+    assert_eq!(list(), vec![OsString::from("a")]);
+
+    File::open(base.join("backing/dir")).unwrap()
+        .set_modified(mtime + std::time::Duration::from_secs(1)).unwrap();
+    assert_eq!(list(), vec![OsString::from("a"), OsString::from("b")]);
+
+    // The root listing gets the magic files added either way.
+    let (fh, _) = backfs.opendir(TEST_REQ, Path::new("/"), 0).unwrap();
+    backfs.readdir(TEST_REQ, Path::new("/"), fh).unwrap();
+    backfs.releasedir(TEST_REQ, Path::new("/"), fh, 0).unwrap();
+    let (fh, _) = backfs.opendir(TEST_REQ, Path::new("/"), 0).unwrap();
+    let names = backfs.readdir(TEST_REQ, Path::new("/"), fh).unwrap().into_iter()
+        .map(|entry| entry.name)
+        .collect::<Vec<_>>();
+    backfs.releasedir(TEST_REQ, Path::new("/"), fh, 0).unwrap();
+    assert!(names.contains(&OsString::from(BACKFS_CONTROL_FILE_NAME)));
+    assert!(names.contains(&OsString::from(BACKFS_VERSION_FILE_NAME)));
+    assert!(names.contains(&OsString::from("dir")));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_max_per_file() {
+    let (backfs, base) = scratch_backfs("max_per_file",
+                                        &["block_size=10", "max_per_file=30", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/small"), [b's'; 20]).unwrap();
+    fs::write(base.join("backing/big"), [b'b'; 100]).unwrap();
+
+    for (name, size) in &[("/small", 20), ("/big", 100)] {
+        let path = Path::new(name);
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        for offset in (0 .. *size).step_by(10) {
+            assert_eq!(backfs.read_real(path, fh, offset, 10).unwrap().len(), 10);
+        }
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    }
+
+    // The big file only keeps its last 30 bytes, and the small one is untouched.
+    let entries = backfs.fscache.manifest().unwrap();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].path, OsString::from("/big"));
+    assert_eq!(entries[0].blocks, vec![7, 8, 9]);
+    assert_eq!(entries[1].path, OsString::from("/small"));
+    assert_eq!(entries[1].blocks, vec![0, 1]);
+    assert_eq!(backfs.fscache.used_size(), 50);
+    assert_eq!(backfs.fscache.metrics().eviction_count, 7);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_cached_blocks() {
+    for &(option, expected) in &[("blocks=backing", None), ("blocks=cached", Some(2))] {
+        let (backfs, base) = scratch_backfs("cached_blocks",
+                                            &[option, "block_size=512", "prefetch_blocks=0"]);
+        fs::write(base.join("backing/file"), [b'x'; 4096]).unwrap();
+        let path = Path::new("/file");
+
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        backfs.read_real(path, fh, 1000, 100).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+
+        let (_, attr) = backfs.getattr(TEST_REQ, path, None).unwrap();
+        assert_eq!(attr.size, 4096);
+        let backing_blocks = fs::metadata(base.join("backing/file")).unwrap().blocks();
+        assert_eq!(attr.blocks, expected.unwrap_or(backing_blocks));
+        fs::remove_dir_all(base).unwrap();
+    }
+}
+
+#[test]
+fn test_no_control() {
+    let (backfs, base) = scratch_backfs("no_control", &["no_control"]);
+    let control = Path::new(BACKFS_CONTROL_FILE_PATH);
+    let version = Path::new(BACKFS_VERSION_FILE_PATH);
+
+    assert_eq!(backfs.getattr(TEST_REQ, control, None).unwrap_err(), libc::ENOENT);
+    assert_eq!(backfs.open(TEST_REQ, control, libc::O_WRONLY as u32), Err(libc::ENOENT));
+    assert_eq!(backfs.write(TEST_REQ, control, 0, 0, b"reset".to_vec(), 0), Err(libc::ENOENT));
+
+    let (fh, _) = backfs.opendir(TEST_REQ, Path::new("/"), 0).unwrap();
+    let names = backfs.readdir(TEST_REQ, Path::new("/"), fh).unwrap().into_iter()
+        .map(|entry| entry.name)
+        .collect::<Vec<_>>();
+    backfs.releasedir(TEST_REQ, Path::new("/"), fh, 0).unwrap();
+    assert!(!names.contains(&OsString::from(BACKFS_CONTROL_FILE_NAME)));
+    assert!(names.contains(&OsString::from(BACKFS_VERSION_FILE_NAME)));
+
+    // The version file is still there.
+    backfs.getattr(TEST_REQ, version, None).unwrap();
+    backfs.open(TEST_REQ, version, libc::O_RDONLY as u32).unwrap();
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_control_uid() {
+    let (backfs, base) = scratch_backfs("control_uid", &["control_uid=1234"]);
+    let control = Path::new(BACKFS_CONTROL_FILE_PATH);
+    let owner = RequestInfo { uid: 1234, ..TEST_REQ };
+    let other = RequestInfo { uid: 4321, ..TEST_REQ };
+
+    assert_eq!(backfs.getattr(TEST_REQ, control, None).unwrap().1.uid, 1234);
+    assert_eq!(backfs.write(other, control, 0, 0, b"noop".to_vec(), 0), Err(libc::EACCES));
+    assert_eq!(backfs.write(owner, control, 0, 0, b"noop".to_vec(), 0), Ok(4));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_control_allow_other() {
+    let (backfs, base) = scratch_backfs("control_allow_other", &["allow_other", "cache_tree"]);
+    assert!(backfs.settings.allows_other_users());
+    let control = Path::new(BACKFS_CONTROL_FILE_PATH);
+    let owner = RequestInfo { uid: unsafe { libc::getuid() }, ..TEST_REQ };
+    let other = RequestInfo { uid: owner.uid.wrapping_add(1), ..TEST_REQ };
+
+    // Without control_uid, it's limited to the user BackFS runs as.
+    assert_eq!(backfs.write(other, control, 0, 0, b"reset".to_vec(), 0), Err(libc::EACCES));
+    assert_eq!(backfs.opendir(other, Path::new(BACKFS_CACHE_TREE_PATH), 0), Err(libc::EACCES));
+    assert_eq!(backfs.write(owner, control, 0, 0, b"noop".to_vec(), 0), Ok(4));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_no_auto_cache() {
+    let (backfs, base) = scratch_backfs("no_auto_cache", &["no_auto_cache", "block_size=10"]);
+    fs::write(base.join("backing/file"), [b'x'; 100]).unwrap();
+    let path = Path::new("/file");
+
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(backfs.read_real(path, fh, 0, 100).unwrap().len(), 100);
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    assert_eq!(backfs.fscache.metrics().bucket_count, 0);
+
+    backfs.backfs_control_file_write(b"prefetch /file").unwrap();
+    assert_eq!(backfs.fscache.metrics().bucket_count, 10);
+    assert_eq!(backfs.fscache.count_cached_bytes(path.as_os_str()), 100);
+    assert_eq!(backfs.backfs_control_file_write(b"prefetch file"), Err(libc::EINVAL));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_dedup() {
+    let (backfs, base) = scratch_backfs("dedup", &["dedup", "block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/a"), b"0123456789abcdefghij").unwrap();
+    fs::write(base.join("backing/b"), b"0123456789ABCDEFGHIJ").unwrap();
+    for name in &["/a", "/b"] {
+        let path = Path::new(name);
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        assert_eq!(backfs.read_real(path, fh, 0, 20).unwrap().len(), 20);
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    }
+
+    // The first blocks are the same, so there are only three buckets.
+    assert_eq!(backfs.fscache.metrics().bucket_count, 3);
+    assert_eq!(backfs.fscache.used_size(), 30);
+
+    // Sharing isn't mistaken for an inconsistency.
+    backfs.fscache.check_consistency().unwrap();
+    assert_eq!(backfs.fscache.metrics().bucket_count, 3);
+
+    // Invalidating one file leaves the other's copy of the shared block alone.
+    backfs.fscache.invalidate_path(Path::new("/a")).unwrap();
+    assert_eq!(backfs.fscache.metrics().bucket_count, 2);
+    assert_eq!(backfs.fscache.count_cached_bytes(OsStr::new("/b")), 20);
+    backfs.fscache.check_consistency().unwrap();
+    assert_eq!(backfs.fscache.count_cached_bytes(OsStr::new("/b")), 20);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_backing_fingerprint() {
+    let (mut backfs, base) = scratch_backfs("fingerprint", &["block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), [b'x'; 20]).unwrap();
+    let fingerprint_path = base.join("cache/backing_fingerprint");
+    let path = Path::new("/file");
+    let fill_cache = |backfs: &BackFs| {
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        backfs.read_real(path, fh, 0, 20).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    };
+
+    backfs.check_backing_fingerprint().unwrap();
+    let dev = fs::metadata(base.join("backing")).unwrap().dev();
+    assert_eq!(fs::read_to_string(&fingerprint_path).unwrap(), format!("dev={}\n", dev));
+    fill_cache(&backfs);
+
+    // A different backing filesystem only gets a warning by default.
+    backfs.settings.backing_id = Some("one".to_owned());
+    backfs.check_backing_fingerprint().unwrap();
+    assert_eq!(fs::read_to_string(&fingerprint_path).unwrap(), "id=one\n");
+    assert_eq!(backfs.fscache.used_size(), 20);
+
+    // The same one again is fine either way.
+    backfs.settings.reset_on_backing_change = true;
+    backfs.check_backing_fingerprint().unwrap();
+    assert_eq!(backfs.fscache.used_size(), 20);
+
+    backfs.settings.backing_id = Some("two".to_owned());
+    backfs.check_backing_fingerprint().unwrap();
+    assert_eq!(fs::read_to_string(&fingerprint_path).unwrap(), "id=two\n");
+    assert_eq!(backfs.fscache.used_size(), 0);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_selftest() {
+    let (backfs, base) = scratch_backfs("selftest", &[]);
+    assert_eq!(backfs.backfs_control_file_write(b"selftest"), Ok(8));
+    assert_eq!(backfs.backfs_control_file_write(b"selftest\n"), Ok(9));
+    assert_eq!(backfs.fscache.used_size(), 0);
+    assert!(backfs.fscache.manifest().unwrap().is_empty());
+
+    // Break the freed bucket the next test will reuse, by putting a directory where its data
+    // file goes.
+    for entry in fs::read_dir(base.join("cache").join("buckets")).unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path();
+        if entry.file_type().unwrap().is_dir() {
+            let _ = fs::remove_file(path.join("data"));
+            fs::create_dir(path.join("data")).unwrap();
+        }
+    }
+    assert_eq!(backfs.backfs_control_file_write(b"selftest"), Err(libc::EISDIR));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_slab_store() {
+    let (backfs, base) = scratch_backfs("slab_store",
+                                        &["store=slabfile", "block_size=10", "prefetch_blocks=0"]);
+    fs::write(base.join("backing/file"), b"0123456789abcdefghijABCDE").unwrap();
+    assert_eq!(StoreFormat::of_cache(base.join("cache")).unwrap(), Some(StoreFormat::Slab));
+    let path = Path::new("/file");
+
+    for _ in 0 .. 2 {
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        let data = backfs.read_real(path, fh, 0, 4096).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+        assert_eq!(&data, b"0123456789abcdefghijABCDE");
+    }
+    let metrics = backfs.fscache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (3, 3));
+    assert_eq!(fs::metadata(base.join("cache/buckets/slab")).unwrap().len(), 25);
+    backfs.fscache.check_consistency().unwrap();
+
+    // Evicting a block goes through the slot's parent to the map.
+    backfs.fscache.set_max_size(Some(20)).unwrap();
+    assert_eq!(backfs.fscache.manifest().unwrap()[0].blocks.len(), 2);
+    backfs.fscache.free_orphaned_buckets().unwrap();
+    assert_eq!(backfs.fscache.used_size(), 15);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_unreadable_backing_root() {
+    let (backfs, base) = scratch_backfs("unreadable_root", &[]);
+    fs::remove_dir(base.join("backing")).unwrap();
+
+    // The root can still be listed, with just the control and version files in it.
+    let root = Path::new("/");
+    let (fh, _) = backfs.opendir(TEST_REQ, root, 0).unwrap();
+    let mut names = backfs.readdir(TEST_REQ, root, fh).unwrap().into_iter()
+        .map(|entry| entry.name)
+        .collect::<Vec<_>>();
+    names.sort();
+    backfs.releasedir(TEST_REQ, root, fh, 0).unwrap();
+    assert_eq!(names, vec![OsString::from("."), OsString::from(".."),
+                           OsString::from(BACKFS_CONTROL_FILE_NAME),
+                           OsString::from(BACKFS_VERSION_FILE_NAME)]);
+
+    // Which still work.
+    let control = Path::new(BACKFS_CONTROL_FILE_PATH);
+    let (fh, _) = backfs.open(TEST_REQ, control, libc::O_WRONLY as u32).unwrap();
+    backfs.backfs_control_file_write(b"noop").unwrap();
+    backfs.release(TEST_REQ, control, fh, 0, 0, false).unwrap();
+
+    // Other directories just fail.
+    assert_eq!(backfs.opendir(TEST_REQ, Path::new("/dir"), 0).unwrap_err(), libc::ENOENT);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_cache_tree() {
+    let (backfs, base) = scratch_backfs("cache_tree",
+                                        &["cache_tree", "block_size=10", "prefetch_blocks=0"]);
+    fs::create_dir(base.join("backing/dir")).unwrap();
+    fs::write(base.join("backing/dir/file"), b"0123456789abcdefghijABCDE").unwrap();
+    fs::write(base.join("backing/other"), b"not cached").unwrap();
+
+    // Cache just the middle block of the file.
+    let path = Path::new("/dir/file");
+    let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+    assert_eq!(backfs.read_real(path, fh, 12, 5).unwrap(), b"cdefg");
+    backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+
+    let list = |path: &str| -> Vec<(OsString, FileType)> {
+        let path = Path::new(path);
+        let (fh, _) = backfs.opendir(TEST_REQ, path, 0).unwrap();
+        let mut entries = backfs.readdir(TEST_REQ, path, fh).unwrap().into_iter()
+            .map(|entry| (entry.name, entry.kind))
+            .collect::<Vec<_>>();
+        backfs.releasedir(TEST_REQ, path, fh, 0).unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    };
+
+    assert!(list("/").contains(
+        &(OsString::from(BACKFS_CACHE_TREE_NAME), FileType::Directory)));
+    // Only what has something cached shows up.
+    assert_eq!(list("/.backfs_cache"), vec![
+        (OsString::from("."), FileType::Directory),
+        (OsString::from(".."), FileType::Directory),
+        (OsString::from("dir"), FileType::Directory),
+    ]);
+    assert_eq!(list("/.backfs_cache/dir"), vec![
+        (OsString::from("."), FileType::Directory),
+        (OsString::from(".."), FileType::Directory),
+        (OsString::from("file"), FileType::RegularFile),
+    ]);
+
+    let tree_path = Path::new("/.backfs_cache/dir/file");
+    let (_, attr) = backfs.getattr(TEST_REQ, tree_path, None).unwrap();
+    assert_eq!((attr.kind, attr.size, attr.perm), (FileType::RegularFile, 25, 0o400));
+    assert_eq!(backfs.getattr(TEST_REQ, Path::new("/.backfs_cache/other"), None).unwrap_err(),
+               libc::ENOENT);
+    // Nor is anything the map keeps for the file.
+    for inside in &["/.backfs_cache/dir/file/1", "/.backfs_cache/dir/file/meta",
+                    "/.backfs_cache/dir/file/meta/x"] {
+        assert_eq!(backfs.getattr(TEST_REQ, Path::new(inside), None).unwrap_err(), libc::ENOENT,
+                   "{}", inside);
+    }
+
+    // Reading it gives what's cached, and zeros for the rest.
+    let (fh, _) = backfs.open(TEST_REQ, tree_path, libc::O_RDONLY as u32).unwrap();
+    let mut expected = vec![0u8; 10];
+    expected.extend_from_slice(b"abcdefghij");
+    expected.extend_from_slice(&[0; 5]);
+    assert_eq!(backfs.fscache.read_cached(OsStr::new("/dir/file"), 0, 100).unwrap(), expected);
+    backfs.release(TEST_REQ, tree_path, fh, 0, 0, false).unwrap();
+
+    // It can't be changed, and it's only for the control file's user.
+    assert_eq!(backfs.open(TEST_REQ, tree_path, libc::O_RDWR as u32).unwrap_err(), libc::EROFS);
+    assert_eq!(backfs.write(TEST_REQ, tree_path, 0, 0, b"x".to_vec(), 0).unwrap_err(),
+               libc::EROFS);
+    let other_user = RequestInfo { uid: backfs.uid + 1, ..TEST_REQ };
+    assert_eq!(backfs.open(other_user, tree_path, libc::O_RDONLY as u32).unwrap_err(),
+               libc::EACCES);
+    assert_eq!(backfs.opendir(other_user, Path::new("/.backfs_cache"), 0).unwrap_err(),
+               libc::EACCES);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_file_hash() {
+    let (backfs, base) = scratch_backfs("file_hash",
+                                        &["file_hash", "block_size=10", "prefetch_blocks=0"]);
+    let backing = base.join("backing/file");
+    fs::write(&backing, b"0123456789abcdefghijABCDE").unwrap();
+    let path = Path::new("/file");
+    let sha256 = || -> String {
+        match backfs.getxattr(TEST_REQ, path, OsStr::new(FILE_HASH_XATTR), 64).unwrap() {
+            Xattr::Data(data) => String::from_utf8(data).unwrap(),
+            Xattr::Size(_) => panic!("expected data"),
+        }
+    };
+    let read_at = |offsets: &[u64]| {
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        for &offset in offsets {
+            backfs.read_real(path, fh, offset, 10).unwrap();
+        }
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+    };
+
+    // Skipping around doesn't count.
+    read_at(&[0, 20, 10]);
+    assert_eq!(sha256(), "incomplete");
+
+    // From `sha256sum`.
+    read_at(&[0, 10, 20]);
+    assert_eq!(sha256(), "05f20a21618ae29b08fa42b7f8b6091dc64d2a00e39c7416c6d9f14baf24d67f");
+
+    // Once the file changes, it has to be read again.
+    let mtime = backing.metadata().unwrap().modified().unwrap();
+    File::options().write(true).open(&backing).unwrap()
+        .set_modified(mtime + Duration::from_secs(1)).unwrap();
+    assert_eq!(sha256(), "incomplete");
+
+    // Starting over from the beginning is fine.
+    read_at(&[0, 10, 0, 10, 20]);
+    assert_eq!(sha256(), "05f20a21618ae29b08fa42b7f8b6091dc64d2a00e39c7416c6d9f14baf24d67f");
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_xattr_errors() {
+    let (mut backfs, base) = scratch_backfs("xattr_errors", &[]);
+    let backing = base.join("backing/file");
+    fs::write(&backing, b"data").unwrap();
+    let path = Path::new("/file");
+    let names = |backfs: &BackFs| match backfs.listxattr(TEST_REQ, path, 4096).unwrap() {
+        Xattr::Data(data) => data,
+        Xattr::Size(_) => panic!("expected data"),
+    };
+
+    // An attribute the backing file doesn't have.
+    let get = |backfs: &BackFs, name: &str| {
+        backfs.getxattr(TEST_REQ, path, OsStr::new(name), 0).err()
+    };
+    assert_eq!(get(&backfs, "user.nonexistent"), Some(libc_wrappers::ENOATTR));
+
+    // Linux doesn't support xattrs outside the namespaces it knows, so this is what a backing
+    // filesystem without xattr support looks like.
+    assert_eq!(get(&backfs, "bogus.attr"), Some(libc_wrappers::ENOATTR));
+
+    // The synthetic attributes are always listed, and there has to be room for them.
+    assert!(names(&backfs).starts_with(b"user.backfs.in_cache\0"));
+    assert_eq!(backfs.listxattr(TEST_REQ, path, 5).err(), Some(libc::ERANGE));
+
+    // Not every cache filesystem supports user xattrs.
+    let backing_c = CString::new(backing.into_os_string().into_vec()).unwrap();
+    let name_c = CString::new("user.test").unwrap();
+    let has_user_xattrs = unsafe {
+        libc::setxattr(backing_c.as_ptr(), name_c.as_ptr(), b"x".as_ptr().cast(), 1, 0)
+    } == 0;
+    if has_user_xattrs {
+        assert!(backfs.getxattr(TEST_REQ, path, OsStr::new("user.test"), 0).is_ok());
+        backfs.settings.xattr = XattrMode::SyntheticOnly;
+        assert_eq!(get(&backfs, "user.test"), Some(libc_wrappers::ENOATTR));
+        assert_eq!(names(&backfs), b"user.backfs.in_cache\0");
+    }
+
+    backfs.settings.xattr = XattrMode::Off;
+    assert_eq!(get(&backfs, "user.backfs.in_cache"), Some(libc::ENOTSUP));
+    assert_eq!(backfs.listxattr(TEST_REQ, path, 0).err(), Some(libc::ENOTSUP));
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_fake_file_snapshots() {
+    let (backfs, base) = scratch_backfs("fake_snapshots", &["block_size=10", "prefetch_blocks=0"]);
+    for i in 0 .. 3 {
+        fs::write(base.join(format!("backing/{}", i)), b"0123456789").unwrap();
+    }
+    let control = Path::new(BACKFS_CONTROL_FILE_PATH);
+    let control_data = |used: u64| {
+        let mut data = BACKFS_CONTROL_FILE_HELP.as_bytes().to_vec();
+        data.extend_from_slice(format!("cache used: {} bytes\n", used).as_bytes());
+        data
+    };
+
+    let mut handles = vec![];
+    for i in 0 .. 3 {
+        let (fh, _) = backfs.open(TEST_REQ, control, libc::O_RDONLY as u32).unwrap();
+        handles.push(fh);
+
+        // The cache usage changes after the open...
+        let path = PathBuf::from(format!("/{}", i));
+        let (file_fh, _) = backfs.open(TEST_REQ, &path, libc::O_RDONLY as u32).unwrap();
+        backfs.read_real(&path, file_fh, 0, 10).unwrap();
+        backfs.release(TEST_REQ, &path, file_fh, 0, 0, false).unwrap();
+
+        // ...but the handle keeps what it saw when it was opened.
+        let expected = control_data(i * 10);
+        let (_, attr) = backfs.getattr(TEST_REQ, control, Some(fh)).unwrap();
+        assert_eq!(attr.size, expected.len() as u64);
+        assert_eq!(backfs.read_fake(control, fh, 0, 4096).unwrap(), expected);
+        assert_eq!(backfs.read_fake(control, fh, attr.size / 2, 4096).unwrap(),
+                   &expected[attr.size as usize / 2 ..]);
+        assert!(backfs.read_fake(control, fh, attr.size, 4096).unwrap().is_empty());
+        assert!(backfs.read_fake(control, fh, attr.size + 100, 4096).unwrap().is_empty());
+
+        // Without a handle, it's whatever it says now.
+        let (_, attr) = backfs.getattr(TEST_REQ, control, None).unwrap();
+        assert_eq!(attr.size, control_data((i + 1) * 10).len() as u64);
+    }
+
+    for fh in handles {
+        backfs.release(TEST_REQ, control, fh, 0, 0, false).unwrap();
+    }
+    assert!(backfs.fake_fhs.lock().unwrap().is_empty());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_control_socket() {
+    let socket_path = scratch_base("control_socket.sock");
+    let mut option = OsString::from("control_socket=");
+    option.push(&socket_path);
+    let (backfs, base) = scratch_backfs("control_socket", &[option.to_str().unwrap()]);
+    backfs.start_control_socket(&socket_path).unwrap();
+
+    let stream = UnixStream::connect(&socket_path).unwrap();
+    let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut ask = move |command: &str| {
+        writeln!(writer, "{}", command).unwrap();
+        let mut reply = String::new();
+        reader.read_line(&mut reply).unwrap();
+        reply
+    };
+
+    // It's only for the control user, and nobody else gets a look in while it's being set up.
+    assert_eq!(fs::symlink_metadata(&socket_path).unwrap().permissions().mode() & 0o777, 0o600);
+    let mut private_dir = socket_path.clone().into_os_string();
+    private_dir.push(format!(".{}", std::process::id()));
+    assert!(!Path::new(&private_dir).exists());
+
+    assert_eq!(ask("noop"), "OK\n");
+    assert!(ask("bogus").starts_with("error: "));
+    assert_eq!(ask("health"), "OK\n");
+
+    // Reading from a file opened only for writing fails, like a broken backing store would.
+    fs::write(base.join("backing/file"), "data").unwrap();
+    let mut unreadable = File::options().write(true).open(base.join("backing/file")).unwrap();
+    assert!(backfs.fscache.fetch(OsStr::new("/file"), 0, 10, &mut unreadable, 1).is_err());
+    assert_eq!(ask("health"), "degraded: backing read errors\n");
+
+    // A client which connects and then says nothing doesn't hold up anyone else, or the unmount.
+    let _idle = UnixStream::connect(&socket_path).unwrap();
+    let mut other = UnixStream::connect(&socket_path).unwrap();
+    writeln!(other, "noop").unwrap();
+    let mut reply = String::new();
+    io::BufReader::new(other).read_line(&mut reply).unwrap();
+    assert_eq!(reply, "OK\n");
+
+    backfs.destroy();
+    assert!(!socket_path.exists());
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_control_socket_other_user() {
+    let socket_path = scratch_base("control_socket_other_user.sock");
+    let (backfs, base) = scratch_backfs("control_socket_other_user", &["control_uid=12345"]);
+    backfs.start_control_socket(&socket_path).unwrap();
+
+    // Connections from anyone but the control user are closed without running anything.
+    let mut stream = UnixStream::connect(&socket_path).unwrap();
+    let _ = writeln!(stream, "manifest");
+    let mut reply = String::new();
+    assert_eq!(io::BufReader::new(stream).read_line(&mut reply).unwrap_or(0), 0);
+    assert!(!base.join("cache/manifest").exists());
+
+    backfs.destroy();
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_stale_while_revalidate() {
+    let opts = ["block_size=10", "prefetch_blocks=0", "stale_while_revalidate=60"];
+    let (backfs, base) = scratch_backfs("stale_while_revalidate", &opts);
+    let backing = base.join("backing/file");
+    fs::write(&backing, b"0123456789abcde").unwrap();
+    let path = Path::new("/file");
+    let read = |backfs: &BackFs| {
+        let (fh, _) = backfs.open(TEST_REQ, path, libc::O_RDONLY as u32).unwrap();
+        let data = backfs.read_real(path, fh, 0, 100).unwrap();
+        backfs.release(TEST_REQ, path, fh, 0, 0, false).unwrap();
+        data
+    };
+    assert_eq!(read(&backfs), b"0123456789abcde");
+
+    let mtime = backing.metadata().unwrap().modified().unwrap();
+    fs::write(&backing, b"ABCDEFGHIJKLMNO").unwrap();
+    File::options().write(true).open(&backing).unwrap()
+        .set_modified(mtime + Duration::from_secs(1)).unwrap();
+
+    // The old data comes back right away, and the cache is refreshed in the background.
+    assert_eq!(read(&backfs), b"0123456789abcde");
+    let started = std::time::Instant::now();
+    while backfs.refresh_queue.lock().unwrap().running {
+        assert!(started.elapsed() < Duration::from_secs(10), "the refresh never finished");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(backfs.fscache.is_current(path.as_os_str(), backing.metadata().unwrap().mtime()));
+    assert_eq!(read(&backfs), b"ABCDEFGHIJKLMNO");
+    assert_eq!(backfs.fscache.used_size(), 15);
+
+    // While the refresh thread is busy, each file is queued once, and only so many are.
+    backfs.refresh_queue.lock().unwrap().running = true;
+    backfs.start_refresh(Path::new("/a"));
+    backfs.start_refresh(Path::new("/b"));
+    backfs.start_refresh(Path::new("/a"));
+    assert_eq!(backfs.refresh_queue.lock().unwrap().pending, ["/a", "/b"]);
+    for i in 2 .. REFRESH_QUEUE_LIMIT {
+        backfs.start_refresh(Path::new(&format!("/{}", i)));
+    }
+    backfs.start_refresh(Path::new("/c"));
+    let queue = backfs.refresh_queue.lock().unwrap();
+    assert_eq!(queue.pending.len(), REFRESH_QUEUE_LIMIT);
+    assert!(!queue.queued.contains(OsStr::new("/c")));
+    drop(queue);
+
+    fs::remove_dir_all(base).unwrap();
+}
+
+#[test]
+fn test_readdirplus() {
+    let (backfs, base) = scratch_backfs("readdirplus", &["cache_tree"]);
+    fs::create_dir(base.join("backing/dir")).unwrap();
+    fs::write(base.join("backing/dir/file"), b"some file contents").unwrap();
+    std::os::unix::fs::symlink("file", base.join("backing/dir/link")).unwrap();
+
+    for path in &[Path::new("/"), Path::new("/dir")] {
+        let (fh, _) = backfs.opendir(TEST_REQ, path, 0).unwrap();
+        let entries = backfs.readdirplus(TEST_REQ, path, fh).unwrap();
+        backfs.releasedir(TEST_REQ, path, fh, 0).unwrap();
+
+        let mut names = entries.iter().map(|(entry, _)| entry.name.clone()).collect::<Vec<_>>();
+        names.sort();
+        let expected: &[&str] = if *path == Path::new("/") {
+            &[".", "..", BACKFS_CACHE_TREE_NAME, BACKFS_CONTROL_FILE_NAME,
+              BACKFS_VERSION_FILE_NAME, "dir"]
+        } else {
+            &[".", "..", "file", "link"]
+        };
+        assert_eq!(names, expected);
+
+        for (entry, attr) in &entries {
+            let entry_path = match entry.name.to_str() {
+                Some(".") => path.to_path_buf(),
+                Some("..") => path.parent().unwrap_or(path).to_path_buf(),
+                _ => path.join(&entry.name),
+            };
+            let (_, expected) = backfs.getattr(TEST_REQ, &entry_path, None).unwrap();
+            assert_eq!(attr.kind, entry.kind, "{:?}", entry_path);
+            assert_eq!((attr.kind, attr.size, attr.perm, attr.nlink, attr.uid, attr.gid),
+                       (expected.kind, expected.size, expected.perm, expected.nlink,
+                        expected.uid, expected.gid),
+                       "{:?}", entry_path);
+            assert_eq!((attr.mtime, attr.ctime, attr.crtime),
+                       (expected.mtime, expected.ctime, expected.crtime), "{:?}", entry_path);
+        }
+    }
+
+    fs::remove_dir_all(base).unwrap();
+}
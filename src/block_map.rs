@@ -3,10 +3,14 @@
 // Copyright 2016-2021 by William R. Fraser
 //
 
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io;
-use std::path::{Path, PathBuf};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::str;
+use std::sync::Mutex;
 
 use crate::link;
 use crate::utils;
@@ -42,40 +46,321 @@ pub enum CacheBlockMapFileResult {
     NotPresent,
 }
 
+/// Everything the map records about a cached file besides its blocks. Unknown fields (from a newer
+/// version of BackFS) are kept as they are, so that reading and writing back a record doesn't
+/// lose them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMeta {
+    /// The backing file's modification time, which the cached data is from.
+    pub mtime: Option<i64>,
+    /// The access time to report for the file, with `-o track_atime`.
+    pub atime: Option<i64>,
+    /// The backing file's size, which the cached data is from.
+    pub size: Option<u64>,
+    /// An average of how long reads from the backing file take, in microseconds, for cost-aware
+    /// eviction.
+    pub latency: Option<u64>,
+    /// A hash of a sample of the backing file's contents, for checking it hasn't changed.
+    pub sample: Option<u64>,
+    unknown: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl FileMeta {
+    /// Read a record written by `to_bytes`: a line of `<name> <value>` per field which is set.
+    pub fn parse(bytes: &[u8]) -> io::Result<FileMeta> {
+        fn number<N: str::FromStr>(name: &[u8], value: &[u8]) -> io::Result<Option<N>> {
+            match str::from_utf8(value).ok().and_then(|s| s.parse::<N>().ok()) {
+                Some(n) => Ok(Some(n)),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("bad {} in file metadata: {:?}",
+                            String::from_utf8_lossy(name), String::from_utf8_lossy(value)))),
+            }
+        }
+
+        let mut meta = FileMeta::default();
+        for line in bytes.split(|byte| *byte == b'\n').filter(|line| !line.is_empty()) {
+            let mut fields = line.splitn(2, |byte| *byte == b' ');
+            let name = fields.next().unwrap_or_default();
+            let value = fields.next().unwrap_or_default();
+            match name {
+                b"mtime" => meta.mtime = number(name, value)?,
+                b"atime" => meta.atime = number(name, value)?,
+                b"size" => meta.size = number(name, value)?,
+                b"latency" => meta.latency = number(name, value)?,
+                b"sample" => meta.sample = number(name, value)?,
+                _ => meta.unknown.push((name.to_vec(), value.to_vec())),
+            }
+        }
+        Ok(meta)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let known = [("mtime", self.mtime.map(|n| n.to_string())),
+                     ("atime", self.atime.map(|n| n.to_string())),
+                     ("size", self.size.map(|n| n.to_string())),
+                     ("latency", self.latency.map(|n| n.to_string())),
+                     ("sample", self.sample.map(|n| n.to_string()))];
+        for (name, value) in &known {
+            if let Some(value) = value {
+                bytes.extend_from_slice(format!("{} {}\n", name, value).as_bytes());
+            }
+        }
+        for (name, value) in &self.unknown {
+            bytes.extend_from_slice(name);
+            bytes.push(b' ');
+            bytes.extend_from_slice(value);
+            bytes.push(b'\n');
+        }
+        bytes
+    }
+
+    /// Read a record written by `to_compact_bytes`.
+    pub fn parse_compact(mut bytes: &[u8]) -> io::Result<FileMeta> {
+        let mut meta = FileMeta::default();
+        while let Some((&tag, rest)) = bytes.split_first() {
+            bytes = rest;
+            match tag {
+                COMPACT_MTIME => meta.mtime = Some(unzigzag(take_varint(&mut bytes)?)),
+                COMPACT_ATIME => meta.atime = Some(unzigzag(take_varint(&mut bytes)?)),
+                COMPACT_SIZE => meta.size = Some(take_varint(&mut bytes)?),
+                COMPACT_LATENCY => meta.latency = Some(take_varint(&mut bytes)?),
+                COMPACT_SAMPLE => meta.sample = Some(take_varint(&mut bytes)?),
+                COMPACT_NAMED => {
+                    let name = take_bytes(&mut bytes)?.to_vec();
+                    let value = take_bytes(&mut bytes)?.to_vec();
+                    meta.unknown.push((name, value));
+                },
+                _ => return Err(bad_compact(&format!("unknown field tag {}", tag))),
+            }
+        }
+        Ok(meta)
+    }
+
+    /// The same record as `to_bytes`, in a binary form a fraction of the size: a tag byte for each
+    /// field which is set, followed by its value as a variable-length integer. Fields from newer
+    /// versions are kept by name.
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        let known = [(COMPACT_MTIME, self.mtime.map(zigzag)),
+                     (COMPACT_ATIME, self.atime.map(zigzag)),
+                     (COMPACT_SIZE, self.size),
+                     (COMPACT_LATENCY, self.latency),
+                     (COMPACT_SAMPLE, self.sample)];
+        for (tag, value) in &known {
+            if let Some(value) = value {
+                bytes.push(*tag);
+                push_varint(&mut bytes, *value);
+            }
+        }
+        for (name, value) in &self.unknown {
+            bytes.push(COMPACT_NAMED);
+            push_bytes(&mut bytes, name);
+            push_bytes(&mut bytes, value);
+        }
+        bytes
+    }
+}
+
+// Field tags in `FileMeta::to_compact_bytes`.
+const COMPACT_NAMED: u8 = 0;
+const COMPACT_MTIME: u8 = 1;
+const COMPACT_ATIME: u8 = 2;
+const COMPACT_SIZE: u8 = 3;
+const COMPACT_LATENCY: u8 = 4;
+const COMPACT_SAMPLE: u8 = 5;
+
+fn bad_compact(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad compact file metadata: {}", what))
+}
+
+// Signed numbers are zigzag-encoded, so small negative ones stay short too.
+fn zigzag(n: i64) -> u64 {
+    ((n << 1) ^ (n >> 63)) as u64
+}
+
+fn unzigzag(n: u64) -> i64 {
+    ((n >> 1) as i64) ^ -((n & 1) as i64)
+}
+
+// LEB128: seven bits at a time, low bits first, with the high bit set on all but the last byte.
+fn push_varint(bytes: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        bytes.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.push(n as u8);
+}
+
+fn take_varint(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut n = 0;
+    for shift in (0 .. 64).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or_else(|| bad_compact("truncated"))?;
+        *bytes = rest;
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(bad_compact("number too long"))
+}
+
+// A length, and then that many bytes.
+fn push_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    push_varint(bytes, data.len() as u64);
+    bytes.extend_from_slice(data);
+}
+
+fn take_bytes<'a>(bytes: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let len = take_varint(bytes)?;
+    if len > bytes.len() as u64 {
+        return Err(bad_compact("truncated"));
+    }
+    let (data, rest) = bytes.split_at(len as usize);
+    *bytes = rest;
+    Ok(data)
+}
+
+/// The per-file getters and setters are shorthands for reading the file's `FileMeta`, and
+/// writing it back with one field changed; maps only have to implement `read_file_meta` and
+/// `write_file_meta`.
 pub trait CacheBlockMap {
-    fn check_file_mtime(&self, path: &OsStr, mtime: i64) -> io::Result<CacheBlockMapFileResult>;
-    fn set_file_mtime(&mut self, path: &OsStr, mtime: i64) -> io::Result<()>;
+    /// Check that the map was made for blocks of the given size (recording it if the map is new).
+    fn init(&mut self, block_size: u64) -> io::Result<()>;
+    /// Everything recorded about the file besides its blocks. Nothing is set if it isn't cached.
+    fn read_file_meta(&self, path: &OsStr) -> io::Result<FileMeta>;
+    /// Replace everything recorded about the file besides its blocks, all at once.
+    fn write_file_meta(&mut self, path: &OsStr, meta: &FileMeta) -> io::Result<()>;
+    fn check_file_mtime(&self, path: &OsStr, mtime: i64) -> io::Result<CacheBlockMapFileResult> {
+        Ok(match self.read_file_meta(path)?.mtime {
+            Some(n) if n == mtime => CacheBlockMapFileResult::Current,
+            Some(_) => CacheBlockMapFileResult::Stale,
+            None => CacheBlockMapFileResult::NotPresent,
+        })
+    }
+    fn set_file_mtime(&mut self, path: &OsStr, mtime: i64) -> io::Result<()> {
+        let mut meta = self.read_file_meta(path)?;
+        meta.mtime = Some(mtime);
+        self.write_file_meta(path, &meta)
+    }
+    fn get_file_atime(&self, path: &OsStr) -> io::Result<Option<i64>> {
+        Ok(self.read_file_meta(path)?.atime)
+    }
+    fn set_file_atime(&mut self, path: &OsStr, atime: i64) -> io::Result<()> {
+        let mut meta = self.read_file_meta(path)?;
+        meta.atime = Some(atime);
+        self.write_file_meta(path, &meta)
+    }
+    fn get_file_size(&self, path: &OsStr) -> io::Result<Option<u64>> {
+        Ok(self.read_file_meta(path)?.size)
+    }
+    fn set_file_size(&mut self, path: &OsStr, size: u64) -> io::Result<()> {
+        let mut meta = self.read_file_meta(path)?;
+        meta.size = Some(size);
+        self.write_file_meta(path, &meta)
+    }
+    fn get_file_latency(&self, path: &OsStr) -> io::Result<Option<u64>> {
+        Ok(self.read_file_meta(path)?.latency)
+    }
+    fn set_file_latency(&mut self, path: &OsStr, micros: u64) -> io::Result<()> {
+        let mut meta = self.read_file_meta(path)?;
+        meta.latency = Some(micros);
+        self.write_file_meta(path, &meta)
+    }
+    /// The latency of the file which the block belongs to.
+    fn get_block_file_latency(&self, block_path: &OsStr) -> io::Result<Option<u64>>;
+    fn get_file_sample(&self, path: &OsStr) -> io::Result<Option<u64>> {
+        Ok(self.read_file_meta(path)?.sample)
+    }
+    fn set_file_sample(&mut self, path: &OsStr, hash: u64) -> io::Result<()> {
+        let mut meta = self.read_file_meta(path)?;
+        meta.sample = Some(hash);
+        self.write_file_meta(path, &meta)
+    }
+    fn get_blocks(&self, path: &OsStr) -> io::Result<Vec<u64>>;
     fn get_block(&self, path: &OsStr, block: u64) -> io::Result<Option<OsString>>;
     fn put_block(&mut self, path: &OsStr, block: u64, bucket_path: &OsStr) -> io::Result<()>;
     fn get_block_path(&self, path: &OsStr, block: u64) -> OsString;
     fn invalidate_path<F>(&mut self, path: &OsStr, delete_handler: F) -> io::Result<()>
-        where F: FnMut(&OsStr) -> io::Result<()>;
+        where F: FnMut(/* block path */ &OsStr, /* bucket path */ &OsStr) -> io::Result<()>;
     fn unmap_block(&mut self, block_path: &OsStr) -> io::Result<()>;
     fn is_block_mapped(&self, block_path: &OsStr) -> io::Result<bool>;
     fn for_each_block_under_path<F>(&self, path: &OsStr, handler: F) -> io::Result<()>
-        where F: FnMut(&OsStr) -> io::Result<()>;
+        where F: FnMut(/* block path */ &OsStr, /* bucket path */ &OsStr) -> io::Result<()>;
+    fn for_each_block<F>(&self, handler: F) -> io::Result<()>
+        where F: FnMut(/* block path */ &OsStr, /* bucket path */ &OsStr) -> io::Result<()>;
+    fn for_each_file<F>(&self, handler: F) -> io::Result<()>
+        where F: FnMut(/* path */ &OsStr, /* mtime */ i64, /* blocks */ &[u64]) -> io::Result<()>;
+    // Like for_each_file, but without looking at any of the blocks, for when only the paths of
+    // the cached files are needed.
+    fn for_each_cached_file<F>(&self, handler: F) -> io::Result<()>
+        where F: FnMut(/* path */ &OsStr) -> io::Result<()>;
+    // Like for_each_cached_file, but only for the files under the directory `path`. Maps which
+    // can find them without going through every file should override this.
+    fn for_each_cached_file_under<F>(&self, path: &OsStr, mut handler: F) -> io::Result<()>
+        where F: FnMut(/* path */ &OsStr) -> io::Result<()>
+    {
+        let dir = Path::new(path);
+        self.for_each_cached_file(|file| {
+            if Path::new(file) != dir && Path::new(file).starts_with(dir) {
+                handler(file)
+            } else {
+                Ok(())
+            }
+        })
+    }
+    fn clear(&mut self) -> io::Result<()>;
+
+    // Blocks which are entirely zero (i.e. holes in sparse files) aren't given a bucket; instead
+    // the map just records that the block is a hole of the given length.
+    fn put_hole(&mut self, path: &OsStr, block: u64, len: u64) -> io::Result<()>;
+    fn get_hole(&self, path: &OsStr, block: u64) -> io::Result<Option<u64>>;
+    fn unmap_hole(&mut self, path: &OsStr, block: u64) -> io::Result<bool>;
 }
 
 pub struct FsCacheBlockMap {
     map_dir: PathBuf,
+    meta_format: MetaFormat,
 }
 
 impl FsCacheBlockMap {
     pub fn new(map_dir: OsString) -> Self {
         Self {
             map_dir: PathBuf::from(map_dir),
+            meta_format: MetaFormat::Text,
         }
     }
 
+    /// Keep files' metadata in the given format instead of text meta files.
+    pub fn with_meta_format(mut self, meta_format: MetaFormat) -> Self {
+        self.meta_format = meta_format;
+        self
+    }
+
     fn map_path(&self, path: &OsStr) -> PathBuf {
-        let path: &Path = Path::new(path);
-        let relative_path: &Path = if path.is_absolute() {
-            path.strip_prefix("/").unwrap()
-        } else {
-            path
-        };
+        let mut map_path = self.map_dir.clone();
+        for component in Path::new(path).components() {
+            match component {
+                Component::RootDir => (),
+                Component::Normal(name) if name.as_bytes().starts_with(ESCAPE_PREFIX) => {
+                    let mut escaped = OsString::from(OsStr::from_bytes(ESCAPE_PREFIX));
+                    escaped.push(name);
+                    map_path.push(escaped);
+                },
+                other => map_path.push(other),
+            }
+        }
+        map_path
+    }
 
-        self.map_dir.join(relative_path)
+    // The reverse of map_path: the path of the file a map directory is for.
+    fn file_path(&self, file_map_dir: &Path) -> PathBuf {
+        let mut path = PathBuf::from("/");
+        for component in file_map_dir.strip_prefix(&self.map_dir).unwrap() {
+            let name = component.as_bytes();
+            path.push(OsStr::from_bytes(name.strip_prefix(ESCAPE_PREFIX).unwrap_or(name)));
+        }
+        path
     }
 
     fn prune_empty_directories(&self, mut start: PathBuf) -> io::Result<()> {
@@ -97,6 +382,66 @@ impl FsCacheBlockMap {
         Ok(())
     }
 
+    fn hole_path(&self, path: &OsStr, block: u64) -> PathBuf {
+        self.map_path(path).join(format!("{}.hole", block))
+    }
+
+    // Once the last block of a file is unmapped, remove its meta file and any directories that are
+    // now empty.
+    fn cleanup_file_map_dir(&self, file_map_dir: PathBuf) -> io::Result<()> {
+        let has_any_blocks = Self::has_any_blocks(&file_map_dir)
+            .unwrap_or_else(|e| {
+                error!("error checking {:?} for any blocks: {}", file_map_dir, e);
+                false
+            });
+        if !has_any_blocks {
+            if let Err(e) = self.remove_meta(&file_map_dir) {
+                warn!("error removing metadata of {:?}: {}", file_map_dir, e);
+            }
+        }
+
+        self.prune_empty_directories(file_map_dir)
+    }
+
+    fn walk_blocks<F>(&self, map_path: PathBuf, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        for entry_result in WalkDir::new(&map_path) {
+            match entry_result {
+                Ok(entry) => {
+                    let entry_path = entry.path();
+                    if entry.file_type().is_symlink() {
+                        let bucket_path = match link::getlink("", entry_path) {
+                            Ok(Some(path)) => path,
+                            Err(e) => {
+                                error!("walk_blocks: error reading link {:?}: {}",
+                                     entry.path(), e);
+                                continue;
+                            },
+                            Ok(None) => unreachable!()
+                        };
+
+                        trylog!(f(entry_path.as_os_str(), bucket_path.as_os_str()),
+                                "walk_blocks: callback returned error");
+                    }
+                },
+                Err(e) => {
+                    let is_start = e.path() == Some(&map_path);
+                    let os_err = e.io_error().and_then(io::Error::raw_os_error);
+                    if is_start && os_err == Some(libc::ENOENT) {
+                        // If the map directory doesn't exist, there's nothing to do.
+                        return Ok(())
+                    } else {
+                        let ioerr = io::Error::from(e);
+                        error!("walk_blocks: error reading directory entry from {:?}: {}",
+                               map_path, ioerr);
+                        return Err(ioerr)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     fn has_any_blocks(path: &Path) -> io::Result<bool> {
         for entry in fs::read_dir(path)? {
             let entry = entry?;
@@ -104,46 +449,381 @@ impl FsCacheBlockMap {
             if &name == "." || &name == ".." {
                 continue;
             }
-            if &name == "mtime" && entry.file_type()?.is_file() {
+            if name == META_FILE_NAME && entry.file_type()?.is_file() {
                 continue;
             }
             return Ok(true);
         }
         Ok(false)
     }
-}
 
-impl CacheBlockMap for FsCacheBlockMap {
-    fn check_file_mtime(&self, path: &OsStr, mtime: i64) -> io::Result<CacheBlockMapFileResult> {
-        let mtime_file = self.map_path(path).join("mtime");
-        match utils::read_number_file(&mtime_file, None::<i64>) {
-            Ok(Some(n)) => {
-                if n == mtime {
-                    Ok(CacheBlockMapFileResult::Current)
+    // A file's own meta file, in text or binary.
+    fn read_meta_file(file_map_dir: &Path, format: MetaFormat) -> io::Result<FileMeta> {
+        let meta_path = file_map_dir.join(META_FILE_NAME);
+        match fs::read(&meta_path) {
+            Ok(contents) => {
+                let meta = if format == MetaFormat::Text {
+                    FileMeta::parse(&contents)
                 } else {
-                    Ok(CacheBlockMapFileResult::Stale)
-                }
+                    FileMeta::parse_compact(&contents)
+                };
+                Ok(trylog!(meta, "problem with meta file {:?}", meta_path))
             },
-            Ok(None) => Ok(CacheBlockMapFileResult::NotPresent),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(FileMeta::default()),
             Err(e) => {
-                error!("problem with mtime file {:?}: {}", &mtime_file, e);
+                error!("error reading meta file {:?}: {}", meta_path, e);
                 Err(e)
             }
         }
     }
 
-    fn set_file_mtime(&mut self, path: &OsStr, mtime: i64) -> io::Result<()> {
-        let file_map_dir = self.map_path(path);
-        trylog!(fs::create_dir_all(&file_map_dir),
-                "set_file_mtime: error creating {:?}", file_map_dir);
+    fn write_meta_file(file_map_dir: &Path, meta: &FileMeta, format: MetaFormat)
+        -> io::Result<()>
+    {
+        let contents = if format == MetaFormat::Text {
+            meta.to_bytes()
+        } else {
+            meta.to_compact_bytes()
+        };
+        // Write it to the side and then rename it into place, so it's never seen half-written.
+        let meta_path = file_map_dir.join(META_FILE_NAME);
+        let temp_path = file_map_dir.join(format!("{}.new", META_FILE_NAME));
+        trylog!(fs::write(&temp_path, contents), "error writing meta file {:?}", temp_path);
+        trylog!(fs::rename(&temp_path, &meta_path),
+                "error renaming {:?} to {:?}", temp_path, meta_path);
+        Ok(())
+    }
+
+    // With packed metadata, where a file's is: its directory's packed meta file, and its name in
+    // that. None for the other formats.
+    fn packed_location<'a>(&self, file_map_dir: &'a Path) -> Option<(PathBuf, &'a OsStr)> {
+        if self.meta_format != MetaFormat::Packed || file_map_dir == self.map_dir {
+            return None;
+        }
+        Some((file_map_dir.parent()?.join(PACKED_META_FILE_NAME), file_map_dir.file_name()?))
+    }
+
+    // A packed meta file is a list of records, each a file's name and its compact metadata, both
+    // with their lengths in front. They're kept encoded, since usually only one of them is wanted.
+    // Changes are appended, so a later record for a name replaces an earlier one, and one with no
+    // metadata (which is what a file with nothing set would have anyway) removes it. A file which
+    // has been rewritten starts with a record with no name, whose metadata is the length of the
+    // records after it.
+    fn read_packed_file(packed_path: &Path) -> io::Result<PackedMeta> {
+        let contents = match fs::read(packed_path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(PackedMeta::new()),
+            Err(e) => {
+                error!("error reading packed meta file {:?}: {}", packed_path, e);
+                return Err(e);
+            }
+        };
+        let mut packed = PackedMeta::new();
+        let mut rest = &contents[..];
+        while !rest.is_empty() {
+            let valid_len = (contents.len() - rest.len()) as u64;
+            let record = take_bytes(&mut rest).and_then(|name| Ok((name, take_bytes(&mut rest)?)));
+            let (name, meta) = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    // Only the last record can be cut short, if BackFS stopped while adding it.
+                    // It's cut off, so that records added after it can be read. (Nothing else is
+                    // being added meanwhile: that needs the map to be borrowed mutably.)
+                    warn!("cutting off the end of packed meta file {:?}: {}", packed_path, e);
+                    trylog!(fs::OpenOptions::new().write(true).open(packed_path)
+                                .and_then(|file| file.set_len(valid_len)),
+                            "error truncating packed meta file {:?}", packed_path);
+                    break;
+                }
+            };
+            if name.is_empty() {
+                continue;
+            } else if meta.is_empty() {
+                packed.remove(OsStr::from_bytes(name));
+            } else {
+                packed.insert(OsStr::from_bytes(name).to_owned(), meta.to_vec());
+            }
+        }
+        Ok(packed)
+    }
+
+    fn write_packed_file(packed_path: &Path, packed: &PackedMeta) -> io::Result<()> {
+        if packed.is_empty() {
+            return match fs::remove_file(packed_path) {
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+                Err(e) => {
+                    error!("error removing packed meta file {:?}: {}", packed_path, e);
+                    Err(e)
+                },
+                Ok(()) => Ok(()),
+            };
+        }
+        let mut records = vec![];
+        for (name, meta) in packed {
+            push_bytes(&mut records, name.as_bytes());
+            push_bytes(&mut records, meta);
+        }
+        let mut contents = vec![];
+        push_bytes(&mut contents, b"");
+        let mut header = vec![];
+        push_varint(&mut header, records.len() as u64);
+        push_bytes(&mut contents, &header);
+        contents.extend_from_slice(&records);
+        let dir = packed_path.parent().unwrap();
+        trylog!(fs::create_dir_all(dir), "error creating {:?}", dir);
+        let temp_path = dir.join(format!("{}.new", PACKED_META_FILE_NAME));
+        trylog!(fs::write(&temp_path, &contents), "error writing packed meta file {:?}", temp_path);
+        trylog!(fs::rename(&temp_path, packed_path),
+                "error renaming {:?} to {:?}", temp_path, packed_path);
+        Ok(())
+    }
 
-        let mtime_file = file_map_dir.join("mtime");
-        trylog!(utils::write_number_file(&mtime_file, &mtime),
-                "failed to write mtime file {:?}", mtime_file);
+    // Add a record to the end of a packed meta file, and rewrite it if it's now mostly records
+    // which have been replaced since it was last rewritten.
+    fn append_packed_record(packed_path: &Path, name: &OsStr, meta: &[u8]) -> io::Result<()> {
+        let mut record = vec![];
+        push_bytes(&mut record, name.as_bytes());
+        push_bytes(&mut record, meta);
+        let mut file = trylog!(fs::OpenOptions::new().read(true).append(true).create(true)
+                                   .open(packed_path),
+                               "error opening packed meta file {:?}", packed_path);
+        let mut start = [0; 12];
+        let start_len = trylog!(file.read(&mut start),
+                                "error reading packed meta file {:?}", packed_path);
+        trylog!(file.write_all(&record), "error writing packed meta file {:?}", packed_path);
+        let len = trylog!(file.metadata(), "error reading packed meta file {:?}", packed_path)
+            .len();
 
+        let mut rest = &start[..start_len];
+        let compacted_len = match (take_bytes(&mut rest), take_bytes(&mut rest)) {
+            (Ok(b""), Ok(mut header)) => take_varint(&mut header).unwrap_or(0),
+            _ => 0,
+        };
+        if len > compacted_len * 2 + PACKED_META_COMPACT_SLACK {
+            debug!("rewriting packed meta file {:?}", packed_path);
+            Self::write_packed_file(packed_path, &Self::read_packed_file(packed_path)?)?;
+        }
         Ok(())
     }
 
+    fn read_meta(&self, file_map_dir: &Path) -> io::Result<FileMeta> {
+        let (packed_path, name) = match self.packed_location(file_map_dir) {
+            Some(location) => location,
+            None => return Self::read_meta_file(file_map_dir, self.meta_format),
+        };
+        match Self::read_packed_file(&packed_path)?.get(name) {
+            Some(bytes) => Ok(trylog!(FileMeta::parse_compact(bytes),
+                                      "problem with {:?} in packed meta file {:?}",
+                                      name, packed_path)),
+            None => Ok(FileMeta::default()),
+        }
+    }
+
+    fn write_meta(&self, file_map_dir: &Path, meta: &FileMeta) -> io::Result<()> {
+        // Even with packed metadata, the map directory is made now, for the blocks to go in.
+        trylog!(fs::create_dir_all(file_map_dir), "error creating {:?}", file_map_dir);
+        let (packed_path, name) = match self.packed_location(file_map_dir) {
+            Some(location) => location,
+            None => return Self::write_meta_file(file_map_dir, meta, self.meta_format),
+        };
+        Self::append_packed_record(&packed_path, name, &meta.to_compact_bytes())
+    }
+
+    // Returns whether there was any metadata to remove.
+    fn remove_meta(&self, file_map_dir: &Path) -> io::Result<bool> {
+        let (packed_path, name) = match self.packed_location(file_map_dir) {
+            Some(location) => location,
+            None => {
+                return match fs::remove_file(file_map_dir.join(META_FILE_NAME)) {
+                    Ok(()) => Ok(true),
+                    Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+                    Err(e) => Err(e),
+                };
+            }
+        };
+        // This has to read the whole file, to know whether it's the last one, and the file (and
+        // then maybe its directory) can go.
+        let mut packed = Self::read_packed_file(&packed_path)?;
+        if packed.remove(name).is_none() {
+            return Ok(false);
+        }
+        if packed.is_empty() {
+            Self::write_packed_file(&packed_path, &packed)?;
+        } else {
+            Self::append_packed_record(&packed_path, name, &[])?;
+        }
+        Ok(true)
+    }
+
+    // Call the function with the map directory of every file with metadata, along with the
+    // metadata if it had to be read just to find the file (as it does when it's packed).
+    fn walk_file_metas<F>(&self, f: F) -> io::Result<()>
+            where F: FnMut(&Path, Option<FileMeta>) -> io::Result<()> {
+        self.walk_file_metas_under(&self.map_dir, f)
+    }
+
+    // Like walk_file_metas, but only for the files under `dir`, a directory in the map. Anything
+    // else (including a block link) has nothing under it.
+    fn walk_file_metas_under<F>(&self, dir: &Path, mut f: F) -> io::Result<()>
+            where F: FnMut(&Path, Option<FileMeta>) -> io::Result<()> {
+        match fs::symlink_metadata(dir) {
+            Ok(metadata) if metadata.is_dir() => (),
+            Ok(_) => return Ok(()),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT)
+                || e.raw_os_error() == Some(libc::ENOTDIR) => return Ok(()),
+            Err(e) => {
+                error!("error looking at map directory {:?}: {}", dir, e);
+                return Err(e);
+            }
+        }
+        for entry_result in WalkDir::new(dir).min_depth(1) {
+            let entry = trylog!(entry_result.map_err(io::Error::from),
+                                "error walking map directory {:?}", dir);
+            if self.meta_format != MetaFormat::Packed {
+                if entry.file_type().is_dir() && entry.path().join(META_FILE_NAME).is_file() {
+                    f(entry.path(), None)?;
+                }
+                continue;
+            }
+            if !entry.file_type().is_file() || entry.file_name() != PACKED_META_FILE_NAME {
+                continue;
+            }
+            let dir = entry.path().parent().unwrap();
+            for (name, bytes) in Self::read_packed_file(entry.path())? {
+                let meta = trylog!(FileMeta::parse_compact(&bytes),
+                                   "problem with {:?} in packed meta file {:?}",
+                                   name, entry.path());
+                f(&dir.join(name), Some(meta))?;
+            }
+        }
+        Ok(())
+    }
+
+    // The block numbers of all the block links and hole files in a file's map directory, sorted.
+    fn list_blocks(file_map_dir: &Path) -> io::Result<Vec<u64>> {
+        let mut blocks = vec![];
+        for entry in fs::read_dir(file_map_dir)? {
+            let name = entry?.file_name();
+            let number = match name.to_str() {
+                Some(name) => name.strip_suffix(".hole").unwrap_or(name),
+                None => continue,
+            };
+            if let Ok(block) = number.parse::<u64>() {
+                blocks.push(block);
+            }
+        }
+        blocks.sort_unstable();
+        Ok(blocks)
+    }
+}
+
+// The map directory mirrors the backing filesystem, except that names starting with this are
+// given it again in front, so that no file's map directory is named like BackFS's own files there.
+const ESCAPE_PREFIX: &[u8] = b".backfs_";
+
+// At the root of the map directory.
+const BLOCK_SIZE_FILE_NAME: &str = ".backfs_block_size";
+
+// Each file's `FileMeta`, in its map directory.
+const META_FILE_NAME: &str = "meta";
+
+// With `MetaFormat::Packed`, the `FileMeta` of all the files in a directory, in the directory's
+// map directory.
+const PACKED_META_FILE_NAME: &str = ".backfs_meta";
+
+// How many bytes more than twice its length when it was last rewritten a packed meta file can get
+// before it's rewritten again.
+const PACKED_META_COMPACT_SLACK: u64 = 4096;
+
+// Compact `FileMeta`s by file name.
+type PackedMeta = BTreeMap<OsString, Vec<u8>>;
+
+// What `FileMeta` used to be kept in, a file per field, before cache format version 4.
+const LEGACY_META_FILE_NAMES: [&str; 5] = ["mtime", "atime", "size", "latency", "sample"];
+
+/// Move the per-field files that caches before format version 4 kept file metadata in (`mtime`,
+/// `atime`, and so on) into a meta file for each file, for either map format. Returns how many
+/// files' metadata was moved.
+pub fn upgrade_legacy_meta_files<P: AsRef<Path>>(map_dir: P) -> io::Result<u64> {
+    let mut count = 0;
+    if !map_dir.as_ref().is_dir() {
+        return Ok(count);
+    }
+    for entry_result in WalkDir::new(map_dir.as_ref()).min_depth(1) {
+        let entry = trylog!(entry_result.map_err(io::Error::from),
+                            "upgrade: error walking map directory {:?}", map_dir.as_ref());
+        if !entry.file_type().is_dir() {
+            continue;
+        }
+        let file_map_dir = entry.path();
+        let legacy = LEGACY_META_FILE_NAMES.iter()
+            .map(|name| file_map_dir.join(name))
+            .filter(|path| path.is_file())
+            .collect::<Vec<_>>();
+        if legacy.is_empty() {
+            continue;
+        }
+
+        let mut meta = FsCacheBlockMap::read_meta_file(file_map_dir, MetaFormat::Text)?;
+        let read = |name: &str| utils::read_number_file(&file_map_dir.join(name), None::<u64>);
+        meta.mtime = meta.mtime.or(utils::read_number_file(&file_map_dir.join("mtime"), None)?);
+        meta.atime = meta.atime.or(utils::read_number_file(&file_map_dir.join("atime"), None)?);
+        meta.size = meta.size.or(read("size")?);
+        meta.latency = meta.latency.or(read("latency")?);
+        meta.sample = meta.sample.or(read("sample")?);
+        FsCacheBlockMap::write_meta_file(file_map_dir, &meta, MetaFormat::Text)?;
+        for path in legacy {
+            trylog!(fs::remove_file(&path), "upgrade: error removing {:?}", path);
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+impl CacheBlockMap for FsCacheBlockMap {
+    fn init(&mut self, block_size: u64) -> io::Result<()> {
+        let path = self.map_dir.join(BLOCK_SIZE_FILE_NAME);
+        match utils::read_number_file(&path, Some(block_size)) {
+            Ok(Some(size)) if size != block_size => {
+                let msg = format!(
+                    "block size in map ({}) doesn't match the size in the options ({})",
+                    size,
+                    block_size);
+                error!("{}", msg);
+                Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+            },
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let msg = format!("error reading map block size file {:?}: {}", path, e);
+                error!("{}", msg);
+                Err(io::Error::new(e.kind(), msg))
+            },
+        }
+    }
+
+    fn read_file_meta(&self, path: &OsStr) -> io::Result<FileMeta> {
+        self.read_meta(&self.map_path(path))
+    }
+
+    fn write_file_meta(&mut self, path: &OsStr, meta: &FileMeta) -> io::Result<()> {
+        self.write_meta(&self.map_path(path), meta)
+    }
+
+    fn get_block_file_latency(&self, block_path: &OsStr) -> io::Result<Option<u64>> {
+        // Block paths are the block's number in the file's map directory.
+        match Path::new(block_path).parent() {
+            Some(file_map_dir) => Ok(self.read_meta(file_map_dir)?.latency),
+            None => Ok(None),
+        }
+    }
+
+    fn get_blocks(&self, path: &OsStr) -> io::Result<Vec<u64>> {
+        match Self::list_blocks(&self.map_path(path)) {
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(vec![]),
+            result => result,
+        }
+    }
+
     fn get_block(&self, path: &OsStr, block: u64) -> io::Result<Option<OsString>> {
         let file_map_dir = self.map_path(path);
         match link::getlink(&file_map_dir, &format!("{}", block)) {
@@ -159,8 +839,13 @@ impl CacheBlockMap for FsCacheBlockMap {
         trylog!(link::makelink("", &file_block, Some(bucket_path)),
                 "error making map link from {:?} to {:?}", &file_block, bucket_path);
 
-        // this makes assumptions on the bucket store implementation
-        debug_assert_eq!(link::getlink(bucket_path, "parent").unwrap(), Some(file_block));
+        // this makes assumptions on the bucket store implementation: with a directory per bucket,
+        // one of the bucket's parent links (there's more than one if it's shared, with dedup)
+        // points back here. Slab buckets aren't directories, and keep their parents elsewhere.
+        debug_assert!(!Path::new(bucket_path).is_dir() || fs::read_dir(bucket_path).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .filter(|name| name.to_string_lossy().starts_with("parent"))
+            .any(|name| link::getlink(bucket_path, &name).unwrap().as_ref() == Some(&file_block)));
 
         Ok(())
     }
@@ -170,10 +855,14 @@ impl CacheBlockMap for FsCacheBlockMap {
     }
 
     fn invalidate_path<F>(&mut self, path: &OsStr, f: F) -> io::Result<()>
-            where F: FnMut(&OsStr) -> io::Result<()> {
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
         self.for_each_block_under_path(path, f)?;
 
         let mut map_path = self.map_path(path);
+        if self.meta_format == MetaFormat::Packed {
+            // Packed metadata isn't under the map path.
+            trylog!(self.remove_meta(&map_path), "Error removing metadata of {:?}", path);
+        }
         trylog!(fs::remove_dir_all(&map_path),
                 "Error removing map path {:?}", map_path);
 
@@ -190,23 +879,7 @@ impl CacheBlockMap for FsCacheBlockMap {
 
         let mut parent = PathBuf::from(map_block_path);
         parent.pop();
-
-        let has_any_blocks = Self::has_any_blocks(&parent)
-            .unwrap_or_else(|e| {
-                error!("error checking {:?} for any blocks: {}", parent, e);
-                false
-            });
-        if !has_any_blocks {
-            let mtime = parent.join("mtime");
-            if let Err(e) = fs::remove_file(&mtime) {
-                if e.raw_os_error() != Some(libc::ENOENT) {
-                    warn!("error removing mtime file {:?}: {}", mtime, e);
-                }
-            }
-        }
-
-        self.prune_empty_directories(parent)?;
-        Ok(())
+        self.cleanup_file_map_dir(parent)
     }
 
     fn is_block_mapped(&self, block_path: &OsStr) -> io::Result<bool> {
@@ -216,42 +889,777 @@ impl CacheBlockMap for FsCacheBlockMap {
     }
 
     fn for_each_block_under_path<F>(&self, path: &OsStr, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        self.walk_blocks(self.map_path(path), |block_path, bucket_path| f(block_path, bucket_path))
+    }
+
+    fn for_each_block<F>(&self, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        self.walk_blocks(self.map_dir.clone(), |block_path, bucket_path| f(block_path, bucket_path))
+    }
+
+    fn for_each_file<F>(&self, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, i64, &[u64]) -> io::Result<()> {
+        // Every file with anything cached has its mtime in its metadata; its blocks are the
+        // symlinks and hole files in its map directory.
+        self.walk_file_metas(|file_map_dir, meta| {
+            let meta = match meta {
+                Some(meta) => meta,
+                None => self.read_meta(file_map_dir)?,
+            };
+            let mtime = match meta.mtime {
+                Some(mtime) => mtime,
+                None => return Ok(()),
+            };
+
+            let blocks = match Self::list_blocks(file_map_dir) {
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => vec![],
+                result => trylog!(result, "for_each_file: error listing {:?}", file_map_dir),
+            };
+
+            let path = self.file_path(file_map_dir);
+            trylog!(f(path.as_os_str(), mtime, &blocks), "for_each_file: callback returned error");
+            Ok(())
+        })
+    }
+
+    fn for_each_cached_file<F>(&self, f: F) -> io::Result<()>
             where F: FnMut(&OsStr) -> io::Result<()> {
-        let map_path: PathBuf = self.map_path(path);
-        for entry_result in WalkDir::new(&map_path) {
-            match entry_result {
-                Ok(entry) => {
-                    let entry_path = entry.path();
-                    if entry.file_type().is_symlink() {
-                        let bucket_path = match link::getlink("", entry_path) {
-                            Ok(Some(path)) => path,
-                            Err(e) => {
-                                error!("for_each_block_under_path: error reading link {:?}: {}",
-                                     entry.path(), e);
-                                continue;
-                            },
-                            Ok(None) => unreachable!()
-                        };
+        self.for_each_cached_file_under(OsStr::new("/"), f)
+    }
+
+    fn for_each_cached_file_under<F>(&self, path: &OsStr, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        // The walk still lists the block symlinks, but never stats or reads them.
+        self.walk_file_metas_under(&self.map_path(path), |file_map_dir, _meta| {
+            let path = self.file_path(file_map_dir);
+            trylog!(f(path.as_os_str()), "for_each_cached_file: callback returned error");
+            Ok(())
+        })
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        let readdir = trylog!(fs::read_dir(&self.map_dir),
+                              "error listing map directory {:?}", self.map_dir);
+        for entry_result in readdir {
+            let entry = trylog!(entry_result, "error reading map directory entry");
+            if entry.file_name() == BLOCK_SIZE_FILE_NAME {
+                continue;
+            }
+            let path = entry.path();
+            let result = if entry.file_type()?.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            trylog!(result, "error removing map entry {:?}", path);
+        }
+        Ok(())
+    }
+
+    fn put_hole(&mut self, path: &OsStr, block: u64, len: u64) -> io::Result<()> {
+        debug!("mapping {:?}/{} as a hole of {} bytes", path, block, len);
+        let hole_path = self.hole_path(path, block);
+        trylog!(utils::write_number_file(&hole_path, &len),
+                "error writing hole file {:?}", hole_path);
+        Ok(())
+    }
+
+    fn get_hole(&self, path: &OsStr, block: u64) -> io::Result<Option<u64>> {
+        let hole_path = self.hole_path(path, block);
+        utils::read_number_file(&hole_path, None::<u64>)
+    }
+
+    fn unmap_hole(&mut self, path: &OsStr, block: u64) -> io::Result<bool> {
+        let hole_path = self.hole_path(path, block);
+        match fs::remove_file(&hole_path) {
+            Ok(()) => {
+                debug!("unmapped hole {:?}", hole_path);
+                self.cleanup_file_map_dir(self.map_path(path))?;
+                Ok(true)
+            },
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+            Err(e) => {
+                error!("unable to remove hole file {:?}: {}", hole_path, e);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Which `CacheBlockMap` implementation a cache's map uses, chosen by the `map_format` option.
+/// The choice is recorded in a `map_format` file in the cache directory, since the two can't read
+/// each other's maps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapFormat {
+    /// `FsCacheBlockMap`: a symlink per block.
+    Links,
+    /// `FsCacheIndexBlockMap`: an index file per file.
+    Index,
+}
+
+impl MapFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            MapFormat::Links => "links",
+            MapFormat::Index => "index",
+        }
+    }
+
+    /// The format of the map in the given cache directory, or None if it doesn't have a map yet.
+    /// Caches made before there was a choice of format have no `map_format` file, and use links.
+    pub fn of_cache<P: AsRef<Path>>(cache_dir: P) -> io::Result<Option<MapFormat>> {
+        let cache_dir = cache_dir.as_ref();
+        match fs::read_to_string(cache_dir.join("map_format")) {
+            Ok(name) => match name.trim() {
+                "links" => Ok(Some(MapFormat::Links)),
+                "index" => Ok(Some(MapFormat::Index)),
+                other => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                            format!("unknown map format {:?}", other))),
+            },
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                if cache_dir.join("map").exists() {
+                    Ok(Some(MapFormat::Links))
+                } else {
+                    Ok(None)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
 
-                        trylog!(f(bucket_path.as_os_str()),
-                                "for_each_block_under_path: callback returned error");
+    pub fn mark_cache<P: AsRef<Path>>(self, cache_dir: P) -> io::Result<()> {
+        fs::write(cache_dir.as_ref().join("map_format"), format!("{}\n", self.name()))
+    }
+}
+
+/// How the map keeps each file's `FileMeta`. Like the map format, this is fixed when a cache is
+/// made, and recorded in a file in the cache directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaFormat {
+    /// A text meta file in each file's map directory.
+    Text,
+    /// A binary meta file in each file's map directory, a fraction of the size.
+    Binary,
+    /// The binary form, for all the files in a directory together in one file in its map
+    /// directory, so there isn't a meta file per file.
+    Packed,
+}
+
+impl MetaFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            MetaFormat::Text => "text",
+            MetaFormat::Binary => "binary",
+            MetaFormat::Packed => "packed",
+        }
+    }
+
+    /// The format of the file metadata in the given cache directory, or None if it doesn't have a
+    /// map yet. Caches made before there was a choice of format have no `meta_format` file, and
+    /// use text.
+    pub fn of_cache<P: AsRef<Path>>(cache_dir: P) -> io::Result<Option<MetaFormat>> {
+        let cache_dir = cache_dir.as_ref();
+        match fs::read_to_string(cache_dir.join("meta_format")) {
+            Ok(name) => match name.trim() {
+                "text" => Ok(Some(MetaFormat::Text)),
+                "binary" => Ok(Some(MetaFormat::Binary)),
+                "packed" => Ok(Some(MetaFormat::Packed)),
+                other => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                            format!("unknown meta format {:?}", other))),
+            },
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                if cache_dir.join("map").exists() {
+                    Ok(Some(MetaFormat::Text))
+                } else {
+                    Ok(None)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn mark_cache<P: AsRef<Path>>(self, cache_dir: P) -> io::Result<()> {
+        fs::write(cache_dir.as_ref().join("meta_format"), format!("{}\n", self.name()))
+    }
+}
+
+const INDEX_FILE_NAME: &str = "blocks";
+
+// How many files' indexes `FsCacheIndexBlockMap` keeps parsed in memory.
+const INDEX_CACHE_CAPACITY: usize = 256;
+
+// How many superseded lines an index file can have beyond its live ones before it's rewritten.
+const INDEX_COMPACT_SLACK: usize = 16;
+
+#[derive(Clone)]
+enum IndexEntry {
+    Bucket(OsString),
+    Hole(u64),
+}
+
+/// A file's index, as read from its index file.
+#[derive(Clone, Default)]
+struct Index {
+    entries: BTreeMap<u64, IndexEntry>,
+    // The directory numbered buckets are in, as of the end of the file.
+    buckets_dir: Option<OsString>,
+    // How many lines the file has, including superseded ones.
+    lines: usize,
+}
+
+impl Index {
+    /// Add the line(s) recording `entry` (or with None, the block being unmapped) to `out`.
+    fn write_entry(&mut self, out: &mut Vec<u8>, block: u64, entry: Option<&IndexEntry>) {
+        match entry {
+            Some(IndexEntry::Bucket(bucket_path)) => match numbered_bucket(bucket_path) {
+                Some((dir, number)) => {
+                    if self.buckets_dir.as_deref() != Some(dir) {
+                        out.extend_from_slice(b"buckets ");
+                        out.extend_from_slice(dir.as_bytes());
+                        out.push(b'\n');
+                        self.buckets_dir = Some(dir.to_owned());
+                        self.lines += 1;
                     }
+                    out.extend_from_slice(format!("{} {}\n", block, number).as_bytes());
+                },
+                None => {
+                    out.extend_from_slice(format!("{} bucket ", block).as_bytes());
+                    out.extend_from_slice(bucket_path.as_bytes());
+                    out.push(b'\n');
+                },
+            },
+            Some(IndexEntry::Hole(len)) => {
+                out.extend_from_slice(format!("{} hole {}\n", block, len).as_bytes());
+            },
+            None => out.extend_from_slice(format!("{} none\n", block).as_bytes()),
+        }
+        self.lines += 1;
+    }
+
+    fn needs_compacting(&self) -> bool {
+        self.lines > 2 * self.entries.len() + INDEX_COMPACT_SLACK
+    }
+}
+
+// Split a bucket path into its directory and number, if it's a number that can be put back
+// together the same way.
+fn numbered_bucket(bucket_path: &OsStr) -> Option<(&OsStr, u64)> {
+    let path = Path::new(bucket_path);
+    let name = path.file_name()?.to_str()?;
+    let number = name.parse::<u64>().ok().filter(|n| n.to_string() == name)?;
+    Some((path.parent()?.as_os_str(), number))
+}
+
+/// The indexes most recently used, so that they don't have to be read for every block.
+#[derive(Default)]
+struct IndexCache {
+    indexes: HashMap<PathBuf, Index>,
+    // Oldest first. Indexes removed from the cache are left in here until they come up.
+    order: VecDeque<PathBuf>,
+}
+
+impl IndexCache {
+    fn insert(&mut self, file_map_dir: PathBuf, index: Index) {
+        if self.indexes.insert(file_map_dir.clone(), index).is_none() {
+            self.order.push_back(file_map_dir);
+            while self.indexes.len() > INDEX_CACHE_CAPACITY {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.indexes.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    fn remove_under(&mut self, map_path: &Path) {
+        self.indexes.retain(|file_map_dir, _| !file_map_dir.starts_with(map_path));
+        self.order.retain(|file_map_dir| !file_map_dir.starts_with(map_path));
+    }
+}
+
+/// A block map which keeps each file's block -> bucket mapping in a single index file in its map
+/// directory, instead of a symlink per block like `FsCacheBlockMap`. Files with lots of cached
+/// blocks take up far fewer inodes this way, and are quicker to enumerate.
+///
+/// The index is only ever appended to, a line per change, until most of it is superseded lines;
+/// then it's rewritten with only the current ones. Each line is one of:
+///   * `buckets <directory>`: where the numbered buckets on the following lines are.
+///   * `<block> <bucket number>`
+///   * `<block> bucket <bucket path>`, for a bucket which isn't named with a number.
+///   * `<block> hole <length>`
+///   * `<block> none`: the block isn't cached any more.
+///
+/// The most recently used indexes are also kept parsed in memory.
+///
+/// The map directories and the meta files are the same as for `FsCacheBlockMap`. Block paths (as
+/// given to the bucket store) are also the same, but only name an entry in the index rather than
+/// an actual file.
+pub struct FsCacheIndexBlockMap {
+    // Handles everything besides the blocks themselves.
+    dirs: FsCacheBlockMap,
+    cache: Mutex<IndexCache>,
+}
+
+impl FsCacheIndexBlockMap {
+    pub fn new(map_dir: OsString) -> Self {
+        Self {
+            dirs: FsCacheBlockMap::new(map_dir),
+            cache: Mutex::new(IndexCache::default()),
+        }
+    }
+
+    /// Keep files' metadata in the given format instead of text meta files.
+    pub fn with_meta_format(mut self, meta_format: MetaFormat) -> Self {
+        self.dirs = self.dirs.with_meta_format(meta_format);
+        self
+    }
+
+    fn read_index(file_map_dir: &Path) -> io::Result<Index> {
+        let index_path = file_map_dir.join(INDEX_FILE_NAME);
+        let contents = match fs::read(&index_path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(Index::default()),
+            Err(e) => {
+                error!("error reading index file {:?}: {}", index_path, e);
+                return Err(e);
+            }
+        };
+
+        let mut index = Index::default();
+        let mut lines: Vec<&[u8]> = contents.split(|byte| *byte == b'\n').collect();
+        // Whatever follows the last newline is a line which was being added when BackFS stopped.
+        if let Some(torn) = lines.pop().filter(|line| !line.is_empty()) {
+            warn!("ignoring incomplete last line of index file {:?}: {:?}", index_path,
+                  String::from_utf8_lossy(torn));
+        }
+        for line in lines.into_iter().filter(|line| !line.is_empty()) {
+            index.lines += 1;
+            if let Some(dir) = line.strip_prefix(b"buckets ") {
+                index.buckets_dir = Some(OsStr::from_bytes(dir).to_owned());
+                continue;
+            }
+            let mut fields = line.splitn(3, |byte| *byte == b' ');
+            let block = fields.next()
+                .and_then(|s| str::from_utf8(s).ok())
+                .and_then(|s| s.parse::<u64>().ok());
+            let number = |s: &[u8]| str::from_utf8(s).ok().and_then(|s| s.parse::<u64>().ok());
+            let entry = match (fields.next(), fields.next()) {
+                (Some(b"bucket"), Some(path)) => {
+                    Some(Some(IndexEntry::Bucket(OsStr::from_bytes(path).to_owned())))
                 },
+                (Some(b"hole"), Some(len)) => number(len).map(|len| Some(IndexEntry::Hole(len))),
+                (Some(b"none"), None) => Some(None),
+                (Some(n), None) => number(n).and_then(|n| {
+                    let dir = index.buckets_dir.as_ref()?;
+                    Some(Some(IndexEntry::Bucket(
+                        Path::new(dir).join(format!("{}", n)).into_os_string())))
+                }),
+                _ => None,
+            };
+            match (block, entry) {
+                (Some(block), Some(Some(entry))) => { index.entries.insert(block, entry); },
+                (Some(block), Some(None)) => { index.entries.remove(&block); },
+                _ => {
+                    error!("bad line in index file {:?}: {:?}", index_path,
+                           String::from_utf8_lossy(line));
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "bad index file line"));
+                }
+            }
+        }
+        Ok(index)
+    }
+
+    /// Call `f` with the file's index, from memory if it's there.
+    fn with_index<F, T>(&self, file_map_dir: PathBuf, f: F) -> io::Result<T>
+            where F: FnOnce(&Index) -> T {
+        if let Some(index) = self.cache.lock().unwrap().indexes.get(&file_map_dir) {
+            return Ok(f(index));
+        }
+        // Other files' indexes can still be used while this one is read.
+        let index = Self::read_index(&file_map_dir)?;
+        let result = f(&index);
+        self.cache.lock().unwrap().insert(file_map_dir, index);
+        Ok(result)
+    }
+
+    /// Rewrite the whole index file with only the current entries, or remove it (and the file's
+    /// map directory, if that's all there was) if there aren't any.
+    fn write_index(&self, file_map_dir: &Path, index: &mut Index) -> io::Result<()> {
+        let index_path = file_map_dir.join(INDEX_FILE_NAME);
+        if index.entries.is_empty() {
+            if let Err(e) = fs::remove_file(&index_path) {
+                if e.raw_os_error() != Some(libc::ENOENT) {
+                    error!("unable to remove index file {:?}: {}", index_path, e);
+                    return Err(e);
+                }
+            }
+            *index = Index::default();
+            return self.dirs.cleanup_file_map_dir(file_map_dir.to_owned());
+        }
+
+        let mut contents = vec![];
+        let mut compacted = Index { entries: BTreeMap::new(), buckets_dir: None, lines: 0 };
+        for (block, entry) in &index.entries {
+            compacted.write_entry(&mut contents, *block, Some(entry));
+        }
+        compacted.entries = std::mem::take(&mut index.entries);
+
+        // Write it to the side and then rename it into place, so it's never seen half-written.
+        trylog!(fs::create_dir_all(file_map_dir),
+                "write_index: error creating {:?}", file_map_dir);
+        let temp_path = file_map_dir.join(format!("{}.new", INDEX_FILE_NAME));
+        trylog!(fs::write(&temp_path, &contents), "error writing index file {:?}", temp_path);
+        trylog!(fs::rename(&temp_path, &index_path),
+                "error renaming {:?} to {:?}", temp_path, index_path);
+        *index = compacted;
+        Ok(())
+    }
+
+    /// Map the block to `entry`, or unmap it with None, by adding a line to the file's index.
+    fn update_index(&mut self, file_map_dir: PathBuf, block: u64, entry: Option<IndexEntry>)
+        -> io::Result<()>
+    {
+        // Taken out of the cache until it's written, so that if anything fails it's read again.
+        let cached = self.cache.get_mut().unwrap().indexes.remove(&file_map_dir);
+        let mut index = match cached {
+            Some(index) => index,
+            None => Self::read_index(&file_map_dir)?,
+        };
+
+        let mut line = vec![];
+        index.write_entry(&mut line, block, entry.as_ref());
+        match entry {
+            Some(entry) => { index.entries.insert(block, entry); },
+            None => { index.entries.remove(&block); },
+        }
+
+        if index.entries.is_empty() || index.needs_compacting() {
+            self.write_index(&file_map_dir, &mut index)?;
+        } else {
+            let index_path = file_map_dir.join(INDEX_FILE_NAME);
+            trylog!(fs::create_dir_all(&file_map_dir),
+                    "update_index: error creating {:?}", file_map_dir);
+            trylog!(fs::OpenOptions::new().append(true).create(true).open(&index_path)
+                        .and_then(|mut file| file.write_all(&line)),
+                    "error appending to index file {:?}", index_path);
+        }
+
+        if !index.entries.is_empty() {
+            self.cache.get_mut().unwrap().insert(file_map_dir, index);
+        }
+        Ok(())
+    }
+
+    // Split a block path into the file's map directory and the block number.
+    fn parse_block_path(block_path: &OsStr) -> io::Result<(PathBuf, u64)> {
+        let path = Path::new(block_path);
+        match (path.parent(), path.file_name().and_then(OsStr::to_str).map(str::parse::<u64>)) {
+            (Some(parent), Some(Ok(block))) => Ok((parent.to_owned(), block)),
+            _ => {
+                error!("invalid block path {:?}", block_path);
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid block path"))
+            }
+        }
+    }
+
+    // Call the function with each file map directory under the given one which has an index.
+    fn walk_indexes<F>(&self, map_path: PathBuf, mut f: F) -> io::Result<()>
+            where F: FnMut(&Path, Index) -> io::Result<()> {
+        for entry_result in WalkDir::new(&map_path) {
+            let entry = match entry_result {
+                Ok(entry) => entry,
                 Err(e) => {
                     let is_start = e.path() == Some(&map_path);
                     let os_err = e.io_error().and_then(io::Error::raw_os_error);
                     if is_start && os_err == Some(libc::ENOENT) {
                         // If the map directory doesn't exist, there's nothing to do.
-                        return Ok(())
-                    } else {
-                        let ioerr = io::Error::from(e);
-                        error!("for_each_block_under_path: error reading directory entry from {:?}: {}",
-                               map_path, ioerr);
-                        return Err(ioerr)
+                        return Ok(());
                     }
+                    let ioerr = io::Error::from(e);
+                    error!("walk_indexes: error reading directory entry from {:?}: {}",
+                           map_path, ioerr);
+                    return Err(ioerr);
                 }
+            };
+            if entry.file_type().is_file() && entry.file_name() == INDEX_FILE_NAME {
+                let file_map_dir = entry.path().parent().unwrap();
+                let index = Self::read_index(file_map_dir)?;
+                trylog!(f(file_map_dir, index), "walk_indexes: callback returned error");
             }
         }
         Ok(())
     }
 }
+
+impl CacheBlockMap for FsCacheIndexBlockMap {
+    fn init(&mut self, block_size: u64) -> io::Result<()> {
+        self.dirs.init(block_size)
+    }
+
+    fn read_file_meta(&self, path: &OsStr) -> io::Result<FileMeta> {
+        self.dirs.read_file_meta(path)
+    }
+
+    fn write_file_meta(&mut self, path: &OsStr, meta: &FileMeta) -> io::Result<()> {
+        self.dirs.write_file_meta(path, meta)
+    }
+
+    fn get_block_file_latency(&self, block_path: &OsStr) -> io::Result<Option<u64>> {
+        self.dirs.get_block_file_latency(block_path)
+    }
+
+    fn get_blocks(&self, path: &OsStr) -> io::Result<Vec<u64>> {
+        self.with_index(self.dirs.map_path(path), |index| index.entries.keys().cloned().collect())
+    }
+
+    fn get_block(&self, path: &OsStr, block: u64) -> io::Result<Option<OsString>> {
+        self.with_index(self.dirs.map_path(path), |index| match index.entries.get(&block) {
+            Some(IndexEntry::Bucket(bucket_path)) => Some(bucket_path.clone()),
+            _ => None,
+        })
+    }
+
+    fn put_block(&mut self, path: &OsStr, block: u64, bucket_path: &OsStr) -> io::Result<()> {
+        debug!("mapping {:?}/{} to {:?}", path, block, bucket_path);
+        let entry = IndexEntry::Bucket(bucket_path.to_owned());
+        self.update_index(self.dirs.map_path(path), block, Some(entry))
+    }
+
+    fn get_block_path(&self, path: &OsStr, block: u64) -> OsString {
+        self.dirs.get_block_path(path, block)
+    }
+
+    fn invalidate_path<F>(&mut self, path: &OsStr, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        self.for_each_block_under_path(path, f)?;
+        self.cache.get_mut().unwrap().remove_under(&self.dirs.map_path(path));
+        // This removes the map directories; there aren't any block links for it to find.
+        self.dirs.invalidate_path(path, |_, _| Ok(()))
+    }
+
+    fn unmap_block(&mut self, block_path: &OsStr) -> io::Result<()> {
+        debug!("unmapping {:?}", block_path);
+        let (file_map_dir, block) = Self::parse_block_path(block_path)?;
+        let mapped = self.with_index(file_map_dir.clone(), |index| {
+            matches!(index.entries.get(&block), Some(IndexEntry::Bucket(_)))
+        })?;
+        if !mapped {
+            error!("unable to unmap block {:?}: not in the index", block_path);
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        self.update_index(file_map_dir, block, None)
+    }
+
+    fn is_block_mapped(&self, block_path: &OsStr) -> io::Result<bool> {
+        let (file_map_dir, block) = Self::parse_block_path(block_path)?;
+        self.with_index(file_map_dir, |index| {
+            matches!(index.entries.get(&block), Some(IndexEntry::Bucket(_)))
+        })
+    }
+
+    fn for_each_block_under_path<F>(&self, path: &OsStr, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        self.walk_indexes(self.dirs.map_path(path), |file_map_dir, index| {
+            for (block, entry) in &index.entries {
+                if let IndexEntry::Bucket(bucket_path) = entry {
+                    let block_path = file_map_dir.join(format!("{}", block));
+                    f(block_path.as_os_str(), bucket_path)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn for_each_block<F>(&self, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        self.walk_indexes(self.dirs.map_dir.clone(), |file_map_dir, index| {
+            for (block, entry) in &index.entries {
+                if let IndexEntry::Bucket(bucket_path) = entry {
+                    let block_path = file_map_dir.join(format!("{}", block));
+                    f(block_path.as_os_str(), bucket_path)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn for_each_file<F>(&self, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, i64, &[u64]) -> io::Result<()> {
+        self.dirs.walk_file_metas(|file_map_dir, meta| {
+            let meta = match meta {
+                Some(meta) => meta,
+                None => self.dirs.read_meta(file_map_dir)?,
+            };
+            let mtime = match meta.mtime {
+                Some(mtime) => mtime,
+                None => return Ok(()),
+            };
+            let blocks: Vec<u64> = self.with_index(file_map_dir.to_owned(), |index| {
+                index.entries.keys().cloned().collect()
+            })?;
+            let path = self.dirs.file_path(file_map_dir);
+            f(path.as_os_str(), mtime, &blocks)
+        })
+    }
+
+    fn for_each_cached_file<F>(&self, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        // No need to read any of the indexes.
+        self.dirs.for_each_cached_file(f)
+    }
+
+    fn for_each_cached_file_under<F>(&self, path: &OsStr, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        self.dirs.for_each_cached_file_under(path, f)
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        *self.cache.get_mut().unwrap() = IndexCache::default();
+        self.dirs.clear()
+    }
+
+    fn put_hole(&mut self, path: &OsStr, block: u64, len: u64) -> io::Result<()> {
+        debug!("mapping {:?}/{} as a hole of {} bytes", path, block, len);
+        self.update_index(self.dirs.map_path(path), block, Some(IndexEntry::Hole(len)))
+    }
+
+    fn get_hole(&self, path: &OsStr, block: u64) -> io::Result<Option<u64>> {
+        self.with_index(self.dirs.map_path(path), |index| match index.entries.get(&block) {
+            Some(IndexEntry::Hole(len)) => Some(*len),
+            _ => None,
+        })
+    }
+
+    fn unmap_hole(&mut self, path: &OsStr, block: u64) -> io::Result<bool> {
+        let file_map_dir = self.dirs.map_path(path);
+        let is_hole = self.with_index(file_map_dir.clone(), |index| {
+            matches!(index.entries.get(&block), Some(IndexEntry::Hole(_)))
+        })?;
+        if !is_hole {
+            return Ok(false);
+        }
+        self.update_index(file_map_dir, block, None)?;
+        debug!("unmapped hole {:?}/{}", path, block);
+        Ok(true)
+    }
+}
+
+/// One of the `CacheBlockMap` implementations, picked at runtime according to a `MapFormat`.
+pub enum AnyBlockMap {
+    Links(FsCacheBlockMap),
+    Index(FsCacheIndexBlockMap),
+}
+
+impl AnyBlockMap {
+    pub fn new(format: MapFormat, map_dir: OsString) -> Self {
+        match format {
+            MapFormat::Links => AnyBlockMap::Links(FsCacheBlockMap::new(map_dir)),
+            MapFormat::Index => AnyBlockMap::Index(FsCacheIndexBlockMap::new(map_dir)),
+        }
+    }
+
+    /// Keep files' metadata in the given format instead of text meta files.
+    pub fn with_meta_format(self, meta_format: MetaFormat) -> Self {
+        match self {
+            AnyBlockMap::Links(map) => AnyBlockMap::Links(map.with_meta_format(meta_format)),
+            AnyBlockMap::Index(map) => AnyBlockMap::Index(map.with_meta_format(meta_format)),
+        }
+    }
+}
+
+macro_rules! dispatch {
+    ($self:expr, $map:ident => $e:expr) => {
+        match $self {
+            AnyBlockMap::Links($map) => $e,
+            AnyBlockMap::Index($map) => $e,
+        }
+    }
+}
+
+impl CacheBlockMap for AnyBlockMap {
+    fn init(&mut self, block_size: u64) -> io::Result<()> {
+        dispatch!(self, map => map.init(block_size))
+    }
+
+    fn read_file_meta(&self, path: &OsStr) -> io::Result<FileMeta> {
+        dispatch!(self, map => map.read_file_meta(path))
+    }
+
+    fn write_file_meta(&mut self, path: &OsStr, meta: &FileMeta) -> io::Result<()> {
+        dispatch!(self, map => map.write_file_meta(path, meta))
+    }
+
+    fn get_block_file_latency(&self, block_path: &OsStr) -> io::Result<Option<u64>> {
+        dispatch!(self, map => map.get_block_file_latency(block_path))
+    }
+
+    fn get_blocks(&self, path: &OsStr) -> io::Result<Vec<u64>> {
+        dispatch!(self, map => map.get_blocks(path))
+    }
+
+    fn get_block(&self, path: &OsStr, block: u64) -> io::Result<Option<OsString>> {
+        dispatch!(self, map => map.get_block(path, block))
+    }
+
+    fn put_block(&mut self, path: &OsStr, block: u64, bucket_path: &OsStr) -> io::Result<()> {
+        dispatch!(self, map => map.put_block(path, block, bucket_path))
+    }
+
+    fn get_block_path(&self, path: &OsStr, block: u64) -> OsString {
+        dispatch!(self, map => map.get_block_path(path, block))
+    }
+
+    fn invalidate_path<F>(&mut self, path: &OsStr, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        dispatch!(self, map => map.invalidate_path(path, f))
+    }
+
+    fn unmap_block(&mut self, block_path: &OsStr) -> io::Result<()> {
+        dispatch!(self, map => map.unmap_block(block_path))
+    }
+
+    fn is_block_mapped(&self, block_path: &OsStr) -> io::Result<bool> {
+        dispatch!(self, map => map.is_block_mapped(block_path))
+    }
+
+    fn for_each_block_under_path<F>(&self, path: &OsStr, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        dispatch!(self, map => map.for_each_block_under_path(path, f))
+    }
+
+    fn for_each_block<F>(&self, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        dispatch!(self, map => map.for_each_block(f))
+    }
+
+    fn for_each_file<F>(&self, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, i64, &[u64]) -> io::Result<()> {
+        dispatch!(self, map => map.for_each_file(f))
+    }
+
+    fn for_each_cached_file<F>(&self, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        dispatch!(self, map => map.for_each_cached_file(f))
+    }
+
+    fn for_each_cached_file_under<F>(&self, path: &OsStr, f: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        dispatch!(self, map => map.for_each_cached_file_under(path, f))
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        dispatch!(self, map => map.clear())
+    }
+
+    fn put_hole(&mut self, path: &OsStr, block: u64, len: u64) -> io::Result<()> {
+        dispatch!(self, map => map.put_hole(path, block, len))
+    }
+
+    fn get_hole(&self, path: &OsStr, block: u64) -> io::Result<Option<u64>> {
+        dispatch!(self, map => map.get_hole(path, block))
+    }
+
+    fn unmap_hole(&mut self, path: &OsStr, block: u64) -> io::Result<bool> {
+        dispatch!(self, map => map.unmap_hole(path, block))
+    }
+}
@@ -3,12 +3,22 @@
 // Copyright 2016-2021 by William R. Fraser
 //
 
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ffi::{OsStr, OsString};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::Mutex;
 
-use crate::fsll::PathLinkedList;
+use crate::fsll::{Fsll, PathLinkedList};
+#[cfg(target_os = "linux")]
+use crate::libc_wrappers;
 use crate::link;
 use crate::utils;
 
@@ -16,16 +26,189 @@ pub trait CacheBucketStore {
     fn init<F>(&mut self, delete_handler: F) -> io::Result<()>
         where F: FnMut(/* deleted bucket parent path */ &OsStr) -> io::Result<()>;
     fn get(&self, bucket_path: &OsStr) -> io::Result<Vec<u8>>;
+    /// Like `get`, but append the bucket's data to `buf` instead of returning a new buffer, so a
+    /// read of several buckets can be put together without copying each one again. Returns how
+    /// many bytes were appended.
+    fn get_into(&self, bucket_path: &OsStr, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let data = self.get(bucket_path)?;
+        buf.extend_from_slice(&data);
+        Ok(data.len())
+    }
     fn put<F>(&mut self, parent: &OsStr, data: &[u8], delete_handler: F) -> io::Result<OsString>
         where F: FnMut(/* deleted bucket parent path */ &OsStr) -> io::Result<()>;
     fn free_bucket(&mut self, bucket_path: &OsStr) -> io::Result<u64>;
-    fn delete_something(&mut self) -> io::Result<(OsString, u64)>;
+    /// Drop `parent`'s reference to the bucket, freeing it if that was the last one. Returns how
+    /// many bytes were freed. Buckets only have more than one parent with dedup.
+    fn release_bucket(&mut self, bucket_path: &OsStr, parent: &OsStr) -> io::Result<u64>;
+    /// Free the least recently used bucket. Returns all of its parents, and how many bytes were
+    /// freed.
+    fn delete_something(&mut self) -> io::Result<(Vec<OsString>, u64)>;
+    /// Free up to `count` of the least recently used buckets. Like `delete_something`, returns the
+    /// parents of all of them, and how many bytes were freed. Stores which can free several buckets
+    /// more cheaply than one at a time should override this.
+    fn delete_batch(&mut self, count: usize) -> io::Result<(Vec<OsString>, u64)> {
+        let mut parents = vec![];
+        let mut bytes_freed = 0;
+        for i in 0 .. count {
+            if i > 0 && self.used_buckets() == 0 {
+                break;
+            }
+            match self.delete_something() {
+                Ok((bucket_parents, n)) => {
+                    parents.extend(bucket_parents);
+                    bytes_freed += n;
+                },
+                // The parents of what was already freed still have to be handed back.
+                Err(e) if i > 0 => {
+                    error!("delete_batch: stopping after {} buckets: {}", i, e);
+                    break;
+                },
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((parents, bytes_freed))
+    }
+    /// Up to `count` of the least recently used buckets, least recent first, with their parents.
+    fn lru_buckets(&self, count: usize) -> io::Result<Vec<(OsString, OsString)>>;
     fn used_bytes(&self) -> u64;
+    fn used_buckets(&self) -> u64;
     fn max_bytes(&self) -> Option<u64>;
+    fn set_max_bytes<F>(&mut self, max_bytes: Option<u64>, delete_handler: F) -> io::Result<()>
+        where F: FnMut(/* deleted bucket parent path */ &OsStr) -> io::Result<()>;
+    /// Calls the handler for each bucket, or for a bucket with more than one parent, once for each
+    /// of them.
     fn enumerate_buckets<F>(&self, handler: F) -> io::Result<()>
         where F: FnMut(/* bucket path */ &OsStr,
                        /* parent path */ Option<&OsStr>) -> io::Result<()>;
     fn get_size(&self, bucket_path: &OsStr) -> io::Result<u64>;
+    /// Throw away the order of the LRU list and make a new, consistent one out of every bucket in
+    /// use, for when it has been corrupted. Returns how many buckets are on it. Stores which can't
+    /// get their list out of step with their buckets don't need to override this.
+    fn rebuild_lru(&mut self) -> io::Result<u64> {
+        Ok(self.used_buckets())
+    }
+    /// Save anything which has to outlast this run, as the cache is shut down cleanly. Nothing
+    /// more will be put in the store after this.
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How the bucket store gets a bucket for new data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BucketAlloc {
+    /// Re-use buckets that have been freed, and only make new ones when there are none. This keeps
+    /// the number of bucket directories (and inodes) down.
+    Reuse,
+    /// Always make a new bucket. Freed buckets are deleted instead of being kept for re-use.
+    New,
+}
+
+// How many buckets `put` will free in response to ENOSPC errors from the cache filesystem before
+// giving up. This keeps one write from evicting the entire cache when space is being consumed by
+// something other than the cache.
+const MAX_ENOSPC_RETRIES: u32 = 64;
+
+// Written at a clean shutdown with the cache's used bytes and buckets, and removed at startup, so
+// it's only there if the last run didn't crash.
+const CLEAN_SHUTDOWN_FILE: &str = "clean_shutdown";
+
+/// FNV-1a, for finding duplicate blocks. It's stored in the cache directory, so unlike std's
+/// hashers it has to stay the same between builds. Matches are compared in full before being used.
+pub(crate) fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Record the bucket size in a new cache, or check that an existing one's matches.
+fn check_bucket_size(buckets_dir: &Path, bucket_size: u64) -> io::Result<()> {
+    match utils::read_number_file(&buckets_dir.join("bucket_size"), Some(bucket_size)) {
+        Ok(Some(size)) => {
+            if size != bucket_size {
+                let msg = format!(
+                    "block size in cache ({}) doesn't match the size in the options ({})",
+                    size,
+                    bucket_size);
+                error!("{}", msg);
+                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            }
+        },
+        Err(e) => {
+            let msg = format!("error reading bucket_size file: {}", e);
+            error!("{}", msg);
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        },
+        Ok(None) => unreachable!()
+    }
+    Ok(())
+}
+
+/// Data of recently read buckets, kept in memory so hot blocks don't have to be read from disk
+/// every time, up to `max_bytes` of it. The least recently used is dropped to make room.
+struct MemTier {
+    max_bytes: u64,
+    used_bytes: u64,
+    clock: u64,
+    by_use: BTreeMap<u64, PathBuf>,
+    entries: HashMap<PathBuf, (u64, Vec<u8>)>,
+}
+
+impl MemTier {
+    fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            used_bytes: 0,
+            clock: 0,
+            by_use: BTreeMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    fn get(&mut self, bucket_path: &Path) -> Option<Vec<u8>> {
+        self.touch(bucket_path).map(|data| data.to_vec())
+    }
+
+    /// Append the bucket's data to `buf`, if it's here. Returns how many bytes were appended.
+    fn get_into(&mut self, bucket_path: &Path, buf: &mut Vec<u8>) -> Option<usize> {
+        let data = self.touch(bucket_path)?;
+        buf.extend_from_slice(data);
+        Some(data.len())
+    }
+
+    fn touch(&mut self, bucket_path: &Path) -> Option<&[u8]> {
+        self.clock += 1;
+        let (last_use, data) = self.entries.get_mut(bucket_path)?;
+        self.by_use.remove(last_use);
+        *last_use = self.clock;
+        self.by_use.insert(self.clock, bucket_path.to_owned());
+        Some(data)
+    }
+
+    fn insert(&mut self, bucket_path: &Path, data: &[u8]) {
+        self.remove(bucket_path);
+        if data.len() as u64 > self.max_bytes {
+            return;
+        }
+        while self.used_bytes + data.len() as u64 > self.max_bytes {
+            let oldest = match self.by_use.values().next() {
+                Some(path) => path.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+        self.clock += 1;
+        self.by_use.insert(self.clock, bucket_path.to_owned());
+        self.entries.insert(bucket_path.to_owned(), (self.clock, data.to_vec()));
+        self.used_bytes += data.len() as u64;
+    }
+
+    fn remove(&mut self, bucket_path: &Path) {
+        if let Some((last_use, data)) = self.entries.remove(bucket_path) {
+            self.by_use.remove(&last_use);
+            self.used_bytes -= data.len() as u64;
+        }
+    }
 }
 
 pub struct FsCacheBucketStore<LL: PathLinkedList> {
@@ -33,11 +216,24 @@ pub struct FsCacheBucketStore<LL: PathLinkedList> {
     used_list: LL,
     free_list: LL,
     used_bytes: u64,
+    used_buckets: u64,
     max_bytes: Option<u64>,
+    max_buckets: Option<u64>,
     bucket_size: u64,
     next_bucket_number: u64,
+    alloc: BucketAlloc,
+    init_progress: Option<(u64, InitProgressCallback)>,
+    fast_init: bool,
+    // With dedup, which bucket holds the data with each content hash.
+    dedup: bool,
+    hashes: HashMap<u64, PathBuf>,
+    mem_tier: Option<Mutex<MemTier>>,
 }
 
+/// Called periodically while scanning the cache at startup, with the number of buckets scanned and
+/// the number of bytes in them so far.
+pub type InitProgressCallback = Box<dyn Fn(u64, u64) + Send + Sync>;
+
 macro_rules! trylog {
     ($e:expr, $fmt:expr) => {
         match $e {
@@ -67,12 +263,62 @@ impl<LL: PathLinkedList> FsCacheBucketStore<LL> {
             used_list,
             free_list,
             used_bytes: 0,
+            used_buckets: 0,
             max_bytes,
+            max_buckets: None,
             bucket_size: block_size,
             next_bucket_number: 0,
+            alloc: BucketAlloc::Reuse,
+            init_progress: None,
+            fast_init: false,
+            dedup: false,
+            hashes: HashMap::new(),
+            mem_tier: None,
         }
     }
 
+    /// Scanning a large cache at startup can take a long time, so call `callback` every `interval`
+    /// buckets to report progress.
+    pub fn with_init_progress(mut self, interval: u64, callback: InitProgressCallback) -> Self {
+        self.init_progress = Some((interval, callback));
+        self
+    }
+
+    /// If the cache was shut down cleanly last time, trust the usage it saved then instead of
+    /// scanning every bucket at startup. With dedup, the scan is still needed to find the buckets'
+    /// hashes.
+    pub fn with_fast_init(mut self, fast_init: bool) -> Self {
+        self.fast_init = fast_init;
+        self
+    }
+
+    /// Limit how many buckets there can be, no matter how few bytes are in them. Each bucket takes
+    /// a few inodes, which can run out before the space does when blocks are small.
+    pub fn with_max_buckets(mut self, max_buckets: u64) -> Self {
+        self.max_buckets = Some(max_buckets);
+        self
+    }
+
+    pub fn with_alloc(mut self, alloc: BucketAlloc) -> Self {
+        self.alloc = alloc;
+        self
+    }
+
+    /// Give blocks with identical contents the same bucket, instead of a copy each. Shared buckets
+    /// have a parent link for each block using them, and are only freed when the last of those is
+    /// released.
+    pub fn with_dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    /// Keep up to `max_bytes` of the most recently read buckets' data in memory as well, so hits
+    /// on them don't have to read the bucket from disk.
+    pub fn with_mem_cache(mut self, max_bytes: u64) -> Self {
+        self.mem_tier = Some(Mutex::new(MemTier::new(max_bytes)));
+        self
+    }
+
     fn read_next_bucket_number(&self) -> io::Result<u64> {
         let path = PathBuf::from(&self.buckets_dir).join("next_bucket_number");
         utils::read_number_file(&path, Some(0u64)).map(|r| r.unwrap())
@@ -83,6 +329,109 @@ impl<LL: PathLinkedList> FsCacheBucketStore<LL> {
         utils::write_number_file(path, &bucket_number)
     }
 
+    /// The (bytes, buckets) saved at the last clean shutdown, if there was one. They can only be
+    /// used once: if this run crashes, the next one has to scan.
+    fn take_clean_shutdown_usage(&self) -> io::Result<Option<(u64, u64)>> {
+        let path = PathBuf::from(&self.buckets_dir).join(CLEAN_SHUTDOWN_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                error!("failed to read {:?}: {}", path, e);
+                return Err(e);
+            }
+        };
+        trylog!(fs::remove_file(&path), "failed to remove {:?}", path);
+
+        // Without the newline at the end, it might have been cut short.
+        let mut numbers = contents.split_whitespace().map(str::parse::<u64>);
+        match (numbers.next(), numbers.next()) {
+            (Some(Ok(bytes)), Some(Ok(buckets))) if contents.ends_with('\n') => {
+                Ok(Some((bytes, buckets)))
+            },
+            _ => {
+                warn!("ignoring malformed {:?}: {:?}", path, contents);
+                Ok(None)
+            }
+        }
+    }
+
+    fn free_until_within_limit<F>(&mut self, delete_handler: &mut F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        while self.is_over_limit() {
+            let excess_bytes = self.max_bytes.map_or(0, |max| self.used_bytes.saturating_sub(max));
+            let excess_buckets = self.max_buckets
+                .map_or(0, |max| self.used_buckets.saturating_sub(max)) as usize;
+            let count = cmp::max(self.buckets_to_free(excess_bytes), excess_buckets);
+            let (map_paths, _) = self.delete_batch(count)?;
+            for map_path in map_paths {
+                trylog!(delete_handler(&map_path),
+                        "delete handler returned error");
+            }
+        }
+        Ok(())
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.max_bytes.is_some_and(|max| self.used_bytes > max)
+            || self.max_buckets.is_some_and(|max| self.used_buckets > max)
+    }
+
+    /// How many buckets to free at once to make room for `bytes`. Buckets that aren't full might
+    /// make this too few, but never too many.
+    fn buckets_to_free(&self, bytes: u64) -> usize {
+        bytes.div_ceil(self.bucket_size) as usize
+    }
+
+    /// Everything involved in freeing a bucket, other than taking it off the used list and putting
+    /// it on the free list. Returns how many bytes were freed.
+    fn remove_bucket_data(&mut self, bucket_path: &OsStr) -> io::Result<u64> {
+        if let Some(ref mem_tier) = self.mem_tier {
+            mem_tier.lock().unwrap().remove(Path::new(bucket_path));
+        }
+
+        let data_path = PathBuf::from(bucket_path).join("data");
+        let data_size: u64 = match fs::metadata(&data_path) {
+            Ok(metadata) => {
+                trylog!(fs::remove_file(&data_path),
+                        "error removing bucket data file {:?}", &data_path);
+                metadata.len()
+            },
+            Err(e) => {
+                debug!("error getting data file metadata of {:?}: {}", &data_path, e);
+                0
+            }
+        };
+
+        let parent_link = PathBuf::from(bucket_path).join("parent");
+        trylog!(fs::remove_file(&parent_link),
+                "unable to remove block parent link {:?}", parent_link);
+        for (link_path, _) in trylog!(Self::parent_links(Path::new(bucket_path)),
+                                      "unable to list parent links of {:?}", bucket_path) {
+            trylog!(fs::remove_file(&link_path),
+                    "unable to remove block parent link {:?}", link_path);
+        }
+
+        let hash_path = PathBuf::from(bucket_path).join("hash");
+        if let Some(hash) = utils::read_number_file(&hash_path, None::<u64>).unwrap_or(None) {
+            if self.hashes.get(&hash).map(|path| path.as_os_str()) == Some(bucket_path) {
+                self.hashes.remove(&hash);
+            }
+            trylog!(fs::remove_file(&hash_path), "unable to remove {:?}", hash_path);
+        }
+
+        if self.alloc == BucketAlloc::New {
+            trylog!(fs::remove_dir_all(bucket_path),
+                    "unable to remove bucket directory {:?}", bucket_path);
+        }
+
+        info!("freed {} bytes", data_size);
+        self.used_bytes -= data_size;
+        // A bucket whose put failed part-way might not have been counted.
+        self.used_buckets = self.used_buckets.saturating_sub(1);
+        Ok(data_size)
+    }
+
     fn for_each_bucket<F>(&self, mut handler: F) -> io::Result<()>
             where F: FnMut(&OsStr) -> io::Result<()> {
         let readdir = trylog!(fs::read_dir(Path::new(&self.buckets_dir)),
@@ -115,14 +464,26 @@ impl<LL: PathLinkedList> FsCacheBucketStore<LL> {
         Ok(())
     }
 
-    fn compute_cache_used_size(&mut self) -> io::Result<u64> {
+    fn compute_cache_used_size(&mut self) -> io::Result<(u64, u64)> {
         let mut size = 0u64;
+        let mut count = 0u64;
+        let mut scanned = 0u64;
+        let mut next_report = self.init_progress.as_ref().map_or(0, |(interval, _)| *interval);
+        let mut hashes = vec![];
 
         self.for_each_bucket(|bucket_path| {
             let path = PathBuf::from(bucket_path).join("data");
 
             let len = match fs::File::open(&path) {
                 Ok(file) => {
+                    count += 1;
+                    if self.dedup {
+                        let hash_path = PathBuf::from(bucket_path).join("hash");
+                        if let Some(hash) = trylog!(utils::read_number_file(&hash_path, None),
+                                                    "failed to read {:?}", hash_path) {
+                            hashes.push((hash, PathBuf::from(bucket_path)));
+                        }
+                    }
                     trylog!(file.metadata().map(|m| m.len()),
                             "failed to get data file metadata from {:?}", path)
                 },
@@ -137,16 +498,24 @@ impl<LL: PathLinkedList> FsCacheBucketStore<LL> {
             };
 
             size += len;
+            scanned += 1;
+            if let Some((interval, ref callback)) = self.init_progress {
+                if scanned == next_report {
+                    callback(scanned, size);
+                    next_report += interval;
+                }
+            }
             Ok(())
         })?;
 
-        info!("cache used size: {} bytes", size);
+        info!("cache used size: {} bytes in {} buckets", size, count);
+        self.hashes.extend(hashes);
 
-        Ok(size)
+        Ok((size, count))
     }
 
     fn get_bucket(&mut self) -> io::Result<PathBuf> {
-        if self.free_list.is_empty() {
+        if self.alloc == BucketAlloc::New || self.free_list.is_empty() {
             debug!("making a new bucket");
             self.new_bucket()
         } else {
@@ -171,6 +540,50 @@ impl<LL: PathLinkedList> FsCacheBucketStore<LL> {
         Ok(bucket_path)
     }
 
+    /// The bucket's parent links and what they point to. The first parent is `parent`, and any
+    /// others with dedup are `parent.1`, `parent.2`, etc.
+    fn parent_links(bucket_path: &Path) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        let mut links = vec![];
+        for entry_result in fs::read_dir(bucket_path)? {
+            let name = entry_result?.file_name();
+            let is_parent = name.to_str()
+                .is_some_and(|name| name == "parent" || name.starts_with("parent."));
+            if is_parent {
+                if let Some(target) = link::getlink(bucket_path, &name)? {
+                    links.push((bucket_path.join(&name), target));
+                }
+            }
+        }
+        // Keep `parent` first.
+        links.sort_by_key(|(link_path, _)| link_path.file_name() != Some(OsStr::new("parent")));
+        Ok(links)
+    }
+
+    /// Count the buckets in use and the bytes in them, like `init` does, but without changing
+    /// anything in the cache. Returns (bytes, buckets).
+    pub fn inspect(&mut self) -> io::Result<(u64, u64)> {
+        self.compute_cache_used_size()
+    }
+
+    /// With dedup, the bucket which already holds exactly `data`, if any.
+    fn find_duplicate(&self, hash: u64, data: &[u8]) -> Option<PathBuf> {
+        let bucket_path = self.hashes.get(&hash)?;
+        match self.get(bucket_path.as_os_str()) {
+            Ok(ref existing) if existing == data => Some(bucket_path.clone()),
+            _ => None,
+        }
+    }
+
+    fn add_parent(&self, bucket_path: &Path, parent: &OsStr) -> io::Result<()> {
+        let name = (1 ..)
+            .map(|n| format!("parent.{}", n))
+            .find(|name| fs::symlink_metadata(bucket_path.join(name)).is_err())
+            .unwrap();
+        trylog!(link::makelink(bucket_path, &name, Some(parent)),
+                "failed to write parent link from bucket {:?} to {:?}", bucket_path, parent);
+        Ok(())
+    }
+
     fn free_bytes_needed_for_write(&self, size: u64) -> u64 {
         if self.max_bytes.is_none() || self.used_bytes + size <= self.max_bytes.unwrap() {
             0
@@ -178,6 +591,15 @@ impl<LL: PathLinkedList> FsCacheBucketStore<LL> {
             self.used_bytes + size - self.max_bytes.unwrap()
         }
     }
+
+    fn free_buckets_needed_for_write(&self) -> u64 {
+        match self.max_buckets {
+            Some(max_buckets) if self.used_buckets >= max_buckets => {
+                self.used_buckets + 1 - max_buckets
+            },
+            _ => 0,
+        }
+    }
 }
 
 impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
@@ -186,35 +608,29 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
         self.next_bucket_number = self.read_next_bucket_number()?;
         info!("next bucket number: {}", self.next_bucket_number);
 
-        match utils::read_number_file(&PathBuf::from(&self.buckets_dir).join("bucket_size"),
-                                      Some(self.bucket_size)) {
-            Ok(Some(size)) => {
-                if size != self.bucket_size {
-                    let msg = format!(
-                        "block size in cache ({}) doesn't match the size in the options ({})",
-                        size,
-                        self.bucket_size);
-                    error!("{}", msg);
-                    return Err(io::Error::new(io::ErrorKind::Other, msg));
-                }
+        check_bucket_size(Path::new(&self.buckets_dir), self.bucket_size)?;
+
+        // This is taken even without fast_init, so that it's never left over from an older run.
+        let saved_usage = self.take_clean_shutdown_usage()?;
+        let (used_bytes, used_buckets) = match saved_usage {
+            Some((bytes, buckets)) if self.fast_init && !self.dedup => {
+                info!("cache used size (from the last clean shutdown): {} bytes in {} buckets",
+                      bytes, buckets);
+                (bytes, buckets)
             },
-            Err(e) => {
-                let msg = format!("error reading bucket_size file: {}", e);
-                error!("{}", msg);
-                return Err(io::Error::new(io::ErrorKind::Other, msg));
+            _ => {
+                if self.fast_init && !self.dedup {
+                    info!("the cache wasn't shut down cleanly; scanning it");
+                }
+                self.compute_cache_used_size()?
             },
-            Ok(None) => unreachable!()
-        }
-
-        self.used_bytes = self.compute_cache_used_size()?;
+        };
+        self.used_bytes = used_bytes;
+        self.used_buckets = used_buckets;
 
-        if self.max_bytes.is_some() && self.used_bytes > self.max_bytes.unwrap() {
+        if self.is_over_limit() {
             warn!("cache is over-size; freeing buckets until it is within limits");
-            while self.used_bytes > self.max_bytes.unwrap() {
-                let (map_path, _) = self.delete_something()?;
-                trylog!(delete_handler(&map_path),
-                        "delete handler returned error");
-            }
+            self.free_until_within_limit(&mut delete_handler)?;
         }
 
         Ok(())
@@ -224,6 +640,13 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
         trylog!(self.used_list.to_head(bucket_path),
                 "Error promoting bucket {:?} to head", bucket_path);
 
+        if let Some(ref mem_tier) = self.mem_tier {
+            if let Some(data) = mem_tier.lock().unwrap().get(Path::new(bucket_path)) {
+                debug!("cached_block: got {:#x} bytes from memory", data.len());
+                return Ok(data);
+            }
+        }
+
         let data_path = PathBuf::from(bucket_path).join("data");
         let mut block_file: File = trylog!(File::open(&data_path),
             "cached_block error opening bucket data file {:?}", data_path);
@@ -232,6 +655,9 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
         match block_file.read_to_end(&mut data) {
             Ok(nread) => {
                 debug!("cached_block: read {:#x} bytes from cache", nread);
+                if let Some(ref mem_tier) = self.mem_tier {
+                    mem_tier.lock().unwrap().insert(Path::new(bucket_path), &data);
+                }
                 Ok(data)
             },
             Err(e) => {
@@ -241,6 +667,39 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
         }
     }
 
+    fn get_into(&self, bucket_path: &OsStr, buf: &mut Vec<u8>) -> io::Result<usize> {
+        trylog!(self.used_list.to_head(bucket_path),
+                "Error promoting bucket {:?} to head", bucket_path);
+
+        if let Some(ref mem_tier) = self.mem_tier {
+            if let Some(nread) = mem_tier.lock().unwrap().get_into(Path::new(bucket_path), buf) {
+                debug!("cached_block: got {:#x} bytes from memory", nread);
+                return Ok(nread);
+            }
+        }
+
+        let data_path = PathBuf::from(bucket_path).join("data");
+        let mut block_file: File = trylog!(File::open(&data_path),
+            "cached_block error opening bucket data file {:?}", data_path);
+
+        let start = buf.len();
+        buf.reserve(self.bucket_size as usize);
+        match block_file.read_to_end(buf) {
+            Ok(nread) => {
+                debug!("cached_block: read {:#x} bytes from cache", nread);
+                if let Some(ref mem_tier) = self.mem_tier {
+                    mem_tier.lock().unwrap().insert(Path::new(bucket_path), &buf[start ..]);
+                }
+                Ok(nread)
+            },
+            Err(e) => {
+                warn!("cached_block reading from data file {:?}: {}", data_path, e);
+                buf.truncate(start);
+                Err(e)
+            }
+        }
+    }
+
     #[allow(clippy::cognitive_complexity)] // the retry loops really blow this up
     fn put<F>(&mut self, parent: &OsStr, data: &[u8], mut delete_handler: F) -> io::Result<OsString>
             where F: FnMut(&OsStr) -> io::Result<()>
@@ -258,6 +717,7 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
             ($e:expr, $($errlog:tt)*) => {
                 {
                     let retval;
+                    let mut retries = 0;
                     loop {
                         match $e {
                             Ok(x) => {
@@ -266,10 +726,18 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
                             },
                             Err(ref e) if e.raw_os_error() == Some(libc::ENOSPC) => {
                                 innerlog!(log::Level::Info, e, $($errlog)*);
-                                let (map_path, n) = trylog!(self.delete_something(),
-                                                            "put: error freeing up space");
-                                trylog!(delete_handler(&map_path),
-                                        "put: delete handler returned error");
+                                if self.used_list.is_empty() || retries == MAX_ENOSPC_RETRIES {
+                                    warn!("put: the cache filesystem is full and freeing {} buckets \
+                                           didn't make enough room; giving up", retries);
+                                    return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+                                }
+                                retries += 1;
+                                let (map_paths, n) = trylog!(self.delete_something(),
+                                                             "put: error freeing up space");
+                                for map_path in map_paths {
+                                    trylog!(delete_handler(&map_path),
+                                            "put: delete handler returned error");
+                                }
                                 info!("freed {} bytes; trying again", n);
                             },
                             Err(e) => {
@@ -283,14 +751,25 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
             }
         }
 
+        let hash = if self.dedup { Some(content_hash(data)) } else { None };
+        if let Some(bucket_path) = hash.and_then(|hash| self.find_duplicate(hash, data)) {
+            debug!("put: sharing bucket {:?} with identical contents", bucket_path);
+            self.add_parent(&bucket_path, parent)?;
+            return Ok(bucket_path.into_os_string());
+        }
+
         loop {
             let bytes_needed = self.free_bytes_needed_for_write(data.len() as u64);
-            if bytes_needed > 0 {
-                info!("put: need to free {} bytes", bytes_needed);
-                let (map_path, _) = trylog!(self.delete_something(),
-                                               "put: error freeing up space");
-                trylog!(delete_handler(&map_path),
-                        "put: delete handler returned error");
+            let buckets_needed = self.free_buckets_needed_for_write();
+            if bytes_needed > 0 || buckets_needed > 0 {
+                info!("put: need to free {} bytes and {} buckets", bytes_needed, buckets_needed);
+                let count = cmp::max(self.buckets_to_free(bytes_needed), buckets_needed as usize);
+                let (map_paths, _) = trylog!(self.delete_batch(count),
+                                                "put: error freeing up space");
+                for map_path in map_paths {
+                    trylog!(delete_handler(&map_path),
+                            "put: delete handler returned error");
+                }
             } else {
                 break;
             }
@@ -301,6 +780,10 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
                       "put: failed to write parent link from bucket {:?} to {:?}",
                       bucket_path, parent);
 
+        if let Some(ref mem_tier) = self.mem_tier {
+            mem_tier.lock().unwrap().remove(&bucket_path);
+        }
+
         let data_path = bucket_path.join("data");
         let mut data_file = retry_enospc!(
             OpenOptions::new()
@@ -314,7 +797,14 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
         retry_enospc!(data_file.seek(SeekFrom::Start(0)).and_then(|_| data_file.write_all(data)),
                       "put: failed to write to cache data file {:?}", data_path);
 
+        if let Some(hash) = hash {
+            retry_enospc!(utils::write_number_file(bucket_path.join("hash"), &hash),
+                          "put: failed to write hash of bucket {:?}", bucket_path);
+            self.hashes.insert(hash, bucket_path.clone());
+        }
+
         self.used_bytes += data.len() as u64;
+        self.used_buckets += 1;
         debug!("used space now {} bytes", self.used_bytes);
 
         Ok(bucket_path.into_os_string())
@@ -325,32 +815,41 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
 
         trylog!(self.used_list.disconnect(bucket_path),
                 "error disconnecting bucket from used list {:?}", bucket_path);
-        trylog!(self.free_list.insert_as_tail(bucket_path),
-                "error inserting bucket into free list {:?}", bucket_path);
+        if self.alloc == BucketAlloc::Reuse {
+            trylog!(self.free_list.insert_as_tail(bucket_path),
+                    "error inserting bucket into free list {:?}", bucket_path);
+        }
 
-        let data_path = PathBuf::from(bucket_path).join("data");
-        let data_size: u64 = match fs::metadata(&data_path) {
-            Ok(metadata) => {
-                trylog!(fs::remove_file(&data_path),
-                        "error removing bucket data file {:?}", &data_path);
-                metadata.len()
-            },
-            Err(e) => {
-                debug!("error getting data file metadata of {:?}: {}", &data_path, e);
-                0
-            }
-        };
+        self.remove_bucket_data(bucket_path)
+    }
 
-        let parent_link = PathBuf::from(bucket_path).join("parent");
-        trylog!(fs::remove_file(&parent_link),
-                "unable to remove block parent link {:?}", parent_link);
+    fn release_bucket(&mut self, bucket_path: &OsStr, parent: &OsStr) -> io::Result<u64> {
+        // This doesn't depend on dedup being on now; it might have been on before.
+        let links = trylog!(Self::parent_links(Path::new(bucket_path)),
+                            "unable to list parent links of {:?}", bucket_path);
+        if links.len() <= 1 {
+            return self.free_bucket(bucket_path);
+        }
 
-        info!("freed {} bytes", data_size);
-        self.used_bytes -= data_size;
-        Ok(data_size)
+        debug!("releasing {:?}'s reference to shared bucket {:?}", parent, bucket_path);
+        let (link_path, _) = match links.iter().find(|(_, target)| target == Path::new(parent)) {
+            Some(link) => link,
+            None => {
+                warn!("release_bucket: {:?} isn't a parent of {:?}", parent, bucket_path);
+                return Ok(0);
+            }
+        };
+        trylog!(fs::remove_file(link_path), "unable to remove block parent link {:?}", link_path);
+        if link_path.file_name() == Some(OsStr::new("parent")) {
+            // Everything else only looks at `parent`, so it has to stay.
+            let (other_link, _) = links.iter().find(|(other, _)| other != link_path).unwrap();
+            trylog!(fs::rename(other_link, link_path),
+                    "unable to rename {:?} to {:?}", other_link, link_path);
+        }
+        Ok(0)
     }
 
-    fn delete_something(&mut self) -> io::Result<(OsString, u64)> {
+    fn delete_something(&mut self) -> io::Result<(Vec<OsString>, u64)> {
         let bucket_path: PathBuf = match self.used_list.get_tail() {
             Some(path) => path,
             None => {
@@ -358,39 +857,113 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
                 return Err(io::Error::from_raw_os_error(libc::EINVAL));
             },
         };
-        let parent: PathBuf = match link::getlink(&bucket_path, "parent") {
-            Ok(Some(path)) => path,
-            Ok(None) => {
+        let parents: Vec<OsString> = match Self::parent_links(&bucket_path) {
+            Ok(ref links) if links.is_empty() => {
                 error!("delete_something: bucket {:?} has no parent", bucket_path);
                 return Err(io::Error::from_raw_os_error(libc::EINVAL));
             },
+            Ok(links) => links.into_iter().map(|(_, parent)| parent.into_os_string()).collect(),
             Err(e) => {
-                error!("delete_something: error reading parent link for {:?}: {}",
+                error!("delete_something: error reading parent links for {:?}: {}",
                        bucket_path, e);
                 return Err(e);
             }
         };
         let bytes_freed = trylog!(self.free_bucket(bucket_path.as_os_str()),
                                   "error freeing bucket {:?}", bucket_path);
-        Ok((parent.into_os_string(), bytes_freed))
+        Ok((parents, bytes_freed))
+    }
+
+    fn delete_batch(&mut self, count: usize) -> io::Result<(Vec<OsString>, u64)> {
+        // Taking a whole run off the end of the used list and adding it to the free list in one go
+        // only touches the links at either end, instead of each bucket's neighbors.
+        let buckets = trylog!(self.used_list.detach_tail(count),
+                              "error detaching buckets from used list");
+        if buckets.is_empty() {
+            error!("can't free anything; the used list is empty!");
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        debug!("freeing {} buckets", buckets.len());
+
+        if self.alloc == BucketAlloc::Reuse {
+            trylog!(self.free_list.extend_tail(&buckets),
+                    "error inserting buckets into free list");
+        }
+
+        // The buckets are off the used list now, so they have to be freed and their parents
+        // handed back even if something goes wrong with one of them.
+        let mut parents = vec![];
+        let mut bytes_freed = 0;
+        for bucket_path in &buckets {
+            match Self::parent_links(bucket_path) {
+                Ok(ref links) if links.is_empty() => {
+                    warn!("delete_batch: bucket {:?} has no parent", bucket_path);
+                },
+                Ok(links) => parents.extend(
+                    links.into_iter().map(|(_, parent)| parent.into_os_string())),
+                Err(e) => {
+                    error!("delete_batch: error reading parent links for {:?}: {}",
+                           bucket_path, e);
+                }
+            }
+            match self.remove_bucket_data(bucket_path.as_os_str()) {
+                Ok(n) => bytes_freed += n,
+                Err(e) => error!("delete_batch: error freeing bucket {:?}: {}", bucket_path, e),
+            }
+        }
+        Ok((parents, bytes_freed))
+    }
+
+    fn lru_buckets(&self, count: usize) -> io::Result<Vec<(OsString, OsString)>> {
+        let mut buckets = vec![];
+        let mut next = self.used_list.get_tail();
+        while let Some(bucket_path) = next {
+            if buckets.len() == count {
+                break;
+            }
+            let parent = trylog!(link::getlink(&bucket_path, "parent"),
+                                 "lru_buckets: error reading parent link for {:?}", bucket_path);
+            next = trylog!(link::getlink(&bucket_path, "prev"),
+                           "lru_buckets: error reading prev link for {:?}", bucket_path);
+            if let Some(parent) = parent {
+                buckets.push((bucket_path.into_os_string(), parent.into_os_string()));
+            }
+        }
+        Ok(buckets)
     }
 
     fn used_bytes(&self) -> u64 {
         self.used_bytes
     }
 
+    fn used_buckets(&self) -> u64 {
+        self.used_buckets
+    }
+
     fn max_bytes(&self) -> Option<u64> {
         self.max_bytes
     }
 
+    fn set_max_bytes<F>(&mut self, max_bytes: Option<u64>, mut delete_handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        info!("changing max cache size from {:?} to {:?}", self.max_bytes, max_bytes);
+        self.max_bytes = max_bytes;
+        self.free_until_within_limit(&mut delete_handler)
+    }
+
     fn enumerate_buckets<F>(&self, mut handler: F) -> io::Result<()>
             where F: FnMut(&OsStr, Option<&OsStr>) -> io::Result<()> {
 
         self.for_each_bucket(|bucket_path| {
-            let parent_opt: Option<PathBuf> = trylog!(link::getlink(bucket_path, "parent"),
-                    "Failed to read parent link for {:?}", bucket_path);
-            let parent_osstr_opt: Option<&OsStr> = parent_opt.as_ref().map(AsRef::as_ref);
-            trylog!(handler(bucket_path, parent_osstr_opt), "enumerate_buckets: handler returned");
+            let links = trylog!(Self::parent_links(Path::new(bucket_path)),
+                    "Failed to read parent links for {:?}", bucket_path);
+            if links.is_empty() {
+                trylog!(handler(bucket_path, None), "enumerate_buckets: handler returned");
+            }
+            for (_, parent) in links {
+                trylog!(handler(bucket_path, Some(parent.as_os_str())),
+                        "enumerate_buckets: handler returned");
+            }
             Ok(())
         })?;
 
@@ -402,4 +975,622 @@ impl<LL: PathLinkedList> CacheBucketStore for FsCacheBucketStore<LL> {
         let metadata = fs::metadata(data_path)?;
         Ok(metadata.len())
     }
+
+    fn rebuild_lru(&mut self) -> io::Result<u64> {
+        // The used and free lists share the buckets' next and prev links, so both have to be
+        // rebuilt. Buckets with a parent are in use; the rest are free.
+        let mut used = vec![];
+        let mut free = vec![];
+        self.for_each_bucket(|bucket_path| {
+            let bucket_path = PathBuf::from(bucket_path);
+            let links = trylog!(Self::parent_links(&bucket_path),
+                                "rebuild_lru: error reading parent links for {:?}", bucket_path);
+            if links.is_empty() {
+                free.push(bucket_path);
+            } else {
+                // Moving a bucket in the list rewrites its links, so the directory's mtime is
+                // roughly when it was last used.
+                let mtime = trylog!(fs::metadata(&bucket_path).and_then(|m| m.modified()),
+                                    "rebuild_lru: error getting mtime of {:?}", bucket_path);
+                used.push((mtime, bucket_path));
+            }
+            Ok(())
+        })?;
+
+        // Most recently used first, so the oldest ends up as the tail.
+        used.sort_by(|a, b| b.cmp(a));
+        let used: Vec<PathBuf> = used.into_iter().map(|(_, bucket_path)| bucket_path).collect();
+        free.sort();
+
+        // Inserting a bucket overwrites its old links, wherever they pointed.
+        trylog!(self.used_list.clear(), "rebuild_lru: error clearing used list");
+        trylog!(self.free_list.clear(), "rebuild_lru: error clearing free list");
+        trylog!(self.used_list.extend_tail(&used), "rebuild_lru: error rebuilding used list");
+        trylog!(self.free_list.extend_tail(&free), "rebuild_lru: error rebuilding free list");
+
+        info!("rebuilt LRU list: {} buckets in use, {} free", used.len(), free.len());
+        Ok(used.len() as u64)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        let path = PathBuf::from(&self.buckets_dir).join(CLEAN_SHUTDOWN_FILE);
+        trylog!(fs::write(&path, format!("{} {}\n", self.used_bytes, self.used_buckets)),
+                "failed to write {:?}", path);
+        Ok(())
+    }
+}
+
+/// Which `CacheBucketStore` implementation a cache uses, chosen by the `store` option. Like the
+/// map format, the choice is recorded in the cache directory (in a `store_format` file), since
+/// the two can't read each other's buckets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreFormat {
+    /// `FsCacheBucketStore`: a directory per bucket.
+    Dirs,
+    /// `SlabBucketStore`: every bucket in one big sparse file.
+    Slab,
+}
+
+impl StoreFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            StoreFormat::Dirs => "dirs",
+            StoreFormat::Slab => "slabfile",
+        }
+    }
+
+    /// The format of the bucket store in the given cache directory, or None if it doesn't have
+    /// one yet. Caches made before there was a choice have no `store_format` file, and use dirs.
+    pub fn of_cache<P: AsRef<Path>>(cache_dir: P) -> io::Result<Option<StoreFormat>> {
+        let cache_dir = cache_dir.as_ref();
+        match fs::read_to_string(cache_dir.join("store_format")) {
+            Ok(name) => match name.trim() {
+                "dirs" => Ok(Some(StoreFormat::Dirs)),
+                "slabfile" => Ok(Some(StoreFormat::Slab)),
+                other => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                            format!("unknown store format {:?}", other))),
+            },
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                if cache_dir.join("buckets").exists() {
+                    Ok(Some(StoreFormat::Dirs))
+                } else {
+                    Ok(None)
+                }
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn mark_cache<P: AsRef<Path>>(self, cache_dir: P) -> io::Result<()> {
+        fs::write(cache_dir.as_ref().join("store_format"), format!("{}\n", self.name()))
+    }
+}
+
+const SLAB_FILE_NAME: &str = "slab";
+const SLAB_JOURNAL_NAME: &str = "slab_journal";
+
+// The journal is rewritten with just the buckets in use once it has this many more records than
+// that.
+const SLAB_JOURNAL_SLACK: u64 = 4096;
+
+struct Slot {
+    len: u64,
+    parent: OsString,
+}
+
+/// When each slot in use was last used, so the least recently used can be found.
+#[derive(Default)]
+struct SlabLru {
+    clock: u64,
+    by_use: BTreeMap<u64, u64>,
+    last_use: HashMap<u64, u64>,
+}
+
+impl SlabLru {
+    fn touch(&mut self, slot: u64) {
+        self.remove(slot);
+        self.clock += 1;
+        self.by_use.insert(self.clock, slot);
+        self.last_use.insert(slot, self.clock);
+    }
+
+    fn remove(&mut self, slot: u64) {
+        if let Some(time) = self.last_use.remove(&slot) {
+            self.by_use.remove(&time);
+        }
+    }
+
+    /// Slots in use, least recently used first.
+    fn oldest(&self) -> impl Iterator<Item = u64> + '_ {
+        self.by_use.values().cloned()
+    }
+}
+
+/// A bucket store which keeps all the buckets in one sparse file (`slab`), each in a fixed-size
+/// slot at `slot number * bucket size`, instead of a directory each like `FsCacheBucketStore`.
+/// This takes a handful of inodes no matter how big the cache is.
+///
+/// Which slots are in use, how much data they hold, and their parents are kept in memory, and
+/// recorded in a journal file (`slab_journal`) of NUL-terminated `put <slot> <length> <parent>`
+/// and `free <slot>` records. Data is written before its `put` record, so a crash can at worst
+/// leave a slot unaccounted for, which is then re-used. Freeing a slot punches a hole in the file
+/// to give the space back.
+///
+/// Which buckets were used most recently is only kept in memory. After a restart, they're taken
+/// to have been used in the order they were written.
+pub struct SlabBucketStore {
+    buckets_dir: PathBuf,
+    slab: Option<File>,
+    journal: Option<File>,
+    journal_records: u64,
+    bucket_size: u64,
+    max_bytes: Option<u64>,
+    used_bytes: u64,
+    slots: HashMap<u64, Slot>,
+    free_slots: BTreeSet<u64>,
+    next_slot: u64,
+    lru: Mutex<SlabLru>,
+}
+
+impl SlabBucketStore {
+    pub fn new(buckets_dir: OsString, block_size: u64, max_bytes: Option<u64>) -> Self {
+        Self {
+            buckets_dir: PathBuf::from(buckets_dir),
+            slab: None,
+            journal: None,
+            journal_records: 0,
+            bucket_size: block_size,
+            max_bytes,
+            used_bytes: 0,
+            slots: HashMap::new(),
+            free_slots: BTreeSet::new(),
+            next_slot: 0,
+            lru: Mutex::new(SlabLru::default()),
+        }
+    }
+
+    /// Count the buckets in use and the bytes in them, without changing anything in the cache.
+    /// Returns (bytes, buckets).
+    pub fn inspect(&mut self) -> io::Result<(u64, u64)> {
+        self.replay_journal()?;
+        Ok((self.used_bytes, self.slots.len() as u64))
+    }
+
+    fn slab(&self) -> io::Result<&File> {
+        self.slab.as_ref().ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+
+    fn bucket_path(&self, slot: u64) -> OsString {
+        self.buckets_dir.join(SLAB_FILE_NAME).join(format!("{}", slot)).into_os_string()
+    }
+
+    fn parse_bucket_path(&self, bucket_path: &OsStr) -> io::Result<u64> {
+        Path::new(bucket_path).strip_prefix(self.buckets_dir.join(SLAB_FILE_NAME)).ok()
+            .and_then(|slot| slot.to_str())
+            .and_then(|slot| slot.parse::<u64>().ok())
+            .ok_or_else(|| {
+                error!("{:?} isn't a slab bucket path", bucket_path);
+                io::Error::from_raw_os_error(libc::EINVAL)
+            })
+    }
+
+    fn put_record(slot: u64, len: u64, parent: &OsStr) -> Vec<u8> {
+        let mut record = format!("put {} {} ", slot, len).into_bytes();
+        record.extend_from_slice(parent.as_bytes());
+        record.push(0);
+        record
+    }
+
+    /// Rebuild the slots from the journal. A record cut short by a crash is ignored.
+    fn replay_journal(&mut self) -> io::Result<()> {
+        let path = self.buckets_dir.join(SLAB_JOURNAL_NAME);
+        let journal = match fs::read(&path) {
+            Ok(journal) => journal,
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => vec![],
+            Err(e) => {
+                error!("failed to read slab journal {:?}: {}", path, e);
+                return Err(e);
+            }
+        };
+
+        let mut records: Vec<&[u8]> = journal.split(|&byte| byte == 0).collect();
+        // Everything after the last NUL is either nothing or a partial record.
+        records.pop();
+
+        let mut lru = SlabLru::default();
+        for record in records {
+            let fields: Vec<&[u8]> = record.splitn(4, |&byte| byte == b' ').collect();
+            let number = |field: &[u8]| str::from_utf8(field).ok()
+                .and_then(|field| field.parse::<u64>().ok());
+            match (fields.first().cloned(), fields.get(1).and_then(|f| number(f))) {
+                (Some(b"put"), Some(slot)) if fields.len() == 4 => {
+                    let len = match number(fields[2]) {
+                        Some(len) => len,
+                        None => {
+                            warn!("ignoring bad slab journal record {:?}", OsStr::from_bytes(record));
+                            continue;
+                        }
+                    };
+                    let parent = OsStr::from_bytes(fields[3]).to_owned();
+                    self.slots.insert(slot, Slot { len, parent });
+                    lru.touch(slot);
+                    self.next_slot = cmp::max(self.next_slot, slot + 1);
+                },
+                (Some(b"free"), Some(slot)) => {
+                    self.slots.remove(&slot);
+                    lru.remove(slot);
+                    self.next_slot = cmp::max(self.next_slot, slot + 1);
+                },
+                _ => warn!("ignoring bad slab journal record {:?}", OsStr::from_bytes(record)),
+            }
+        }
+
+        self.free_slots = (0 .. self.next_slot).filter(|slot| !self.slots.contains_key(slot)).collect();
+        self.used_bytes = self.slots.values().map(|slot| slot.len).sum();
+        *self.lru.get_mut().unwrap() = lru;
+        Ok(())
+    }
+
+    /// Replace the journal with one that just has the slots in use, least recently used first so
+    /// that the order survives a restart.
+    fn compact_journal(&mut self) -> io::Result<()> {
+        let path = self.buckets_dir.join(SLAB_JOURNAL_NAME);
+        let tmp_path = self.buckets_dir.join(format!("{}.tmp", SLAB_JOURNAL_NAME));
+        let mut contents = vec![];
+        for slot in self.lru.lock().unwrap().oldest() {
+            let entry = &self.slots[&slot];
+            contents.extend(Self::put_record(slot, entry.len, &entry.parent));
+        }
+        trylog!(fs::write(&tmp_path, &contents), "failed to write {:?}", tmp_path);
+        trylog!(fs::rename(&tmp_path, &path), "failed to replace {:?}", path);
+
+        let journal = trylog!(OpenOptions::new().append(true).create(true).open(&path),
+                              "failed to open slab journal {:?}", path);
+        self.journal = Some(journal);
+        self.journal_records = self.slots.len() as u64;
+        Ok(())
+    }
+
+    fn append_record(&mut self, record: &[u8]) -> io::Result<()> {
+        let journal = self.journal.as_mut()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+        // One write per record, so a crash can only cut off the last one.
+        trylog!(journal.write_all(record), "failed to write to the slab journal");
+        self.journal_records += 1;
+        if self.journal_records > self.slots.len() as u64 * 2 + SLAB_JOURNAL_SLACK {
+            self.compact_journal()?;
+        }
+        Ok(())
+    }
+
+    fn punch_hole(&self, slot: u64) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let fd = self.slab()?.as_raw_fd() as usize;
+            let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+            if let Err(e) = libc_wrappers::fallocate(fd, mode, slot * self.bucket_size, self.bucket_size) {
+                return Err(io::Error::from_raw_os_error(e));
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = slot;
+        Ok(())
+    }
+
+    fn free_until_within_limit<F>(&mut self, delete_handler: &mut F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        if let Some(max_bytes) = self.max_bytes {
+            while self.used_bytes > max_bytes {
+                let (map_paths, _) = self.delete_something()?;
+                for map_path in map_paths {
+                    trylog!(delete_handler(&map_path),
+                            "delete handler returned error");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_slot<F>(&mut self, slot: u64, data: &[u8], delete_handler: &mut F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        let mut retries = 0;
+        loop {
+            match self.slab()?.write_all_at(data, slot * self.bucket_size) {
+                Ok(()) => return Ok(()),
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOSPC) => {
+                    if self.slots.is_empty() || retries == MAX_ENOSPC_RETRIES {
+                        warn!("put: the cache filesystem is full and freeing {} buckets \
+                               didn't make enough room; giving up", retries);
+                        return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+                    }
+                    retries += 1;
+                    let (map_paths, n) = trylog!(self.delete_something(),
+                                                 "put: error freeing up space");
+                    for map_path in map_paths {
+                        trylog!(delete_handler(&map_path), "put: delete handler returned error");
+                    }
+                    info!("freed {} bytes; trying again", n);
+                },
+                Err(e) => {
+                    error!("put: failed to write to slot {} of the slab: {}", slot, e);
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+impl CacheBucketStore for SlabBucketStore {
+    fn init<F>(&mut self, mut delete_handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        check_bucket_size(&self.buckets_dir, self.bucket_size)?;
+
+        let slab_path = self.buckets_dir.join(SLAB_FILE_NAME);
+        let slab = trylog!(OpenOptions::new().read(true).write(true).create(true).truncate(false)
+                               .open(&slab_path),
+                           "failed to open slab file {:?}", slab_path);
+        self.slab = Some(slab);
+
+        self.replay_journal()?;
+        self.compact_journal()?;
+        info!("cache used size: {} bytes in {} buckets", self.used_bytes, self.slots.len());
+
+        if self.max_bytes.is_some() && self.used_bytes > self.max_bytes.unwrap() {
+            warn!("cache is over-size; freeing buckets until it is within limits");
+            self.free_until_within_limit(&mut delete_handler)?;
+        }
+
+        Ok(())
+    }
+
+    fn get(&self, bucket_path: &OsStr) -> io::Result<Vec<u8>> {
+        let slot = self.parse_bucket_path(bucket_path)?;
+        let len = match self.slots.get(&slot) {
+            Some(entry) => entry.len,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+        let mut data = vec![0; len as usize];
+        trylog!(self.slab()?.read_exact_at(&mut data, slot * self.bucket_size),
+                "failed to read slot {} of the slab", slot);
+        self.lru.lock().unwrap().touch(slot);
+        Ok(data)
+    }
+
+    fn get_into(&self, bucket_path: &OsStr, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let slot = self.parse_bucket_path(bucket_path)?;
+        let len = match self.slots.get(&slot) {
+            Some(entry) => entry.len as usize,
+            None => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        };
+        let start = buf.len();
+        buf.resize(start + len, 0);
+        if let Err(e) = self.slab().and_then(|slab| {
+            slab.read_exact_at(&mut buf[start ..], slot * self.bucket_size)
+        }) {
+            error!("failed to read slot {} of the slab: {}", slot, e);
+            buf.truncate(start);
+            return Err(e);
+        }
+        self.lru.lock().unwrap().touch(slot);
+        Ok(len)
+    }
+
+    fn put<F>(&mut self, parent: &OsStr, data: &[u8], mut delete_handler: F) -> io::Result<OsString>
+            where F: FnMut(&OsStr) -> io::Result<()>
+    {
+        if data.len() as u64 > self.bucket_size {
+            error!("put: {} bytes don't fit in a {}-byte bucket", data.len(), self.bucket_size);
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            while self.used_bytes + data.len() as u64 > max_bytes {
+                let (map_paths, _) = trylog!(self.delete_something(),
+                                             "put: error freeing up space");
+                for map_path in map_paths {
+                    trylog!(delete_handler(&map_path), "put: delete handler returned error");
+                }
+            }
+        }
+
+        // Take the lowest free slot, to keep the slab file as small as possible.
+        let slot = match self.free_slots.iter().next().cloned() {
+            Some(slot) => {
+                self.free_slots.remove(&slot);
+                slot
+            },
+            None => {
+                self.next_slot += 1;
+                self.next_slot - 1
+            }
+        };
+
+        let result = self.write_slot(slot, data, &mut delete_handler)
+            .and_then(|()| self.append_record(&Self::put_record(slot, data.len() as u64, parent)));
+        if let Err(e) = result {
+            self.free_slots.insert(slot);
+            return Err(e);
+        }
+
+        self.slots.insert(slot, Slot { len: data.len() as u64, parent: parent.to_owned() });
+        self.lru.get_mut().unwrap().touch(slot);
+        self.used_bytes += data.len() as u64;
+        debug!("used space now {} bytes", self.used_bytes);
+        Ok(self.bucket_path(slot))
+    }
+
+    fn free_bucket(&mut self, bucket_path: &OsStr) -> io::Result<u64> {
+        debug!("freeing bucket {:?}", bucket_path);
+        let slot = self.parse_bucket_path(bucket_path)?;
+        if !self.slots.contains_key(&slot) {
+            debug!("slot {} is already free", slot);
+            return Ok(0);
+        }
+
+        self.append_record(format!("free {}\0", slot).as_bytes())?;
+        let entry = self.slots.remove(&slot).unwrap();
+        self.lru.get_mut().unwrap().remove(slot);
+        self.free_slots.insert(slot);
+        if let Err(e) = self.punch_hole(slot) {
+            // The space will be re-used by another bucket eventually anyway.
+            warn!("failed to punch a hole for slot {} of the slab: {}", slot, e);
+        }
+
+        info!("freed {} bytes", entry.len);
+        self.used_bytes -= entry.len;
+        Ok(entry.len)
+    }
+
+    fn release_bucket(&mut self, bucket_path: &OsStr, _parent: &OsStr) -> io::Result<u64> {
+        // Buckets are never shared here.
+        self.free_bucket(bucket_path)
+    }
+
+    fn delete_something(&mut self) -> io::Result<(Vec<OsString>, u64)> {
+        let slot = match self.lru.get_mut().unwrap().oldest().next() {
+            Some(slot) => slot,
+            None => {
+                error!("can't free anything; there are no buckets in use!");
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+        };
+        let parent = self.slots[&slot].parent.clone();
+        let bytes_freed = self.free_bucket(&self.bucket_path(slot))?;
+        Ok((vec![parent], bytes_freed))
+    }
+
+    fn lru_buckets(&self, count: usize) -> io::Result<Vec<(OsString, OsString)>> {
+        Ok(self.lru.lock().unwrap().oldest()
+            .take(count)
+            .map(|slot| (self.bucket_path(slot), self.slots[&slot].parent.clone()))
+            .collect())
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.used_bytes
+    }
+
+    fn used_buckets(&self) -> u64 {
+        self.slots.len() as u64
+    }
+
+    fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    fn set_max_bytes<F>(&mut self, max_bytes: Option<u64>, mut delete_handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        info!("changing max cache size from {:?} to {:?}", self.max_bytes, max_bytes);
+        self.max_bytes = max_bytes;
+        self.free_until_within_limit(&mut delete_handler)
+    }
+
+    fn enumerate_buckets<F>(&self, mut handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr, Option<&OsStr>) -> io::Result<()> {
+        let mut slots: Vec<u64> = self.slots.keys().cloned().collect();
+        slots.sort_unstable();
+        for slot in slots {
+            trylog!(handler(&self.bucket_path(slot), Some(&self.slots[&slot].parent)),
+                    "enumerate_buckets: handler returned");
+        }
+        Ok(())
+    }
+
+    fn get_size(&self, bucket_path: &OsStr) -> io::Result<u64> {
+        let slot = self.parse_bucket_path(bucket_path)?;
+        match self.slots.get(&slot) {
+            Some(entry) => Ok(entry.len),
+            None => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+        }
+    }
+}
+
+/// One of the `CacheBucketStore` implementations, picked at runtime according to a
+/// `StoreFormat`.
+pub enum AnyBucketStore {
+    Dirs(FsCacheBucketStore<Fsll>),
+    Slab(SlabBucketStore),
+}
+
+macro_rules! dispatch {
+    ($self:expr, $store:ident => $e:expr) => {
+        match $self {
+            AnyBucketStore::Dirs($store) => $e,
+            AnyBucketStore::Slab($store) => $e,
+        }
+    }
+}
+
+impl CacheBucketStore for AnyBucketStore {
+    fn init<F>(&mut self, delete_handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        dispatch!(self, store => store.init(delete_handler))
+    }
+
+    fn get(&self, bucket_path: &OsStr) -> io::Result<Vec<u8>> {
+        dispatch!(self, store => store.get(bucket_path))
+    }
+
+    fn get_into(&self, bucket_path: &OsStr, buf: &mut Vec<u8>) -> io::Result<usize> {
+        dispatch!(self, store => store.get_into(bucket_path, buf))
+    }
+
+    fn put<F>(&mut self, parent: &OsStr, data: &[u8], delete_handler: F) -> io::Result<OsString>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        dispatch!(self, store => store.put(parent, data, delete_handler))
+    }
+
+    fn free_bucket(&mut self, bucket_path: &OsStr) -> io::Result<u64> {
+        dispatch!(self, store => store.free_bucket(bucket_path))
+    }
+
+    fn release_bucket(&mut self, bucket_path: &OsStr, parent: &OsStr) -> io::Result<u64> {
+        dispatch!(self, store => store.release_bucket(bucket_path, parent))
+    }
+
+    fn delete_something(&mut self) -> io::Result<(Vec<OsString>, u64)> {
+        dispatch!(self, store => store.delete_something())
+    }
+
+    fn delete_batch(&mut self, count: usize) -> io::Result<(Vec<OsString>, u64)> {
+        dispatch!(self, store => store.delete_batch(count))
+    }
+
+    fn lru_buckets(&self, count: usize) -> io::Result<Vec<(OsString, OsString)>> {
+        dispatch!(self, store => store.lru_buckets(count))
+    }
+
+    fn used_bytes(&self) -> u64 {
+        dispatch!(self, store => store.used_bytes())
+    }
+
+    fn used_buckets(&self) -> u64 {
+        dispatch!(self, store => store.used_buckets())
+    }
+
+    fn max_bytes(&self) -> Option<u64> {
+        dispatch!(self, store => store.max_bytes())
+    }
+
+    fn set_max_bytes<F>(&mut self, max_bytes: Option<u64>, delete_handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        dispatch!(self, store => store.set_max_bytes(max_bytes, delete_handler))
+    }
+
+    fn enumerate_buckets<F>(&self, handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr, Option<&OsStr>) -> io::Result<()> {
+        dispatch!(self, store => store.enumerate_buckets(handler))
+    }
+
+    fn get_size(&self, bucket_path: &OsStr) -> io::Result<u64> {
+        dispatch!(self, store => store.get_size(bucket_path))
+    }
+
+    fn rebuild_lru(&mut self) -> io::Result<u64> {
+        dispatch!(self, store => store.rebuild_lru())
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        dispatch!(self, store => store.shutdown())
+    }
 }
@@ -4,16 +4,62 @@
 //
 
 use std::borrow::BorrowMut;
-use std::ffi::OsStr;
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
 use std::fmt::Debug;
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::marker::PhantomData;
-use std::mem::{transmute, MaybeUninit};
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 use crate::block_map::{CacheBlockMap, CacheBlockMapFileResult};
-use crate::bucket_store::CacheBucketStore;
+use crate::bucket_store::{content_hash, CacheBucketStore};
+use crate::rate_limit::{ConcurrencyLimiter, RateLimiter};
+
+// First delay before retrying a failed backing read; it doubles with each retry, up to the max.
+const READ_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+const READ_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// How many of the least recently used buckets cost-aware eviction picks from.
+const COST_EVICTION_WINDOW: usize = 16;
+
+// How many recently missed blocks admission control keeps track of.
+const ADMISSION_SEEN_CAPACITY: usize = 65536;
+
+/// How the cache picks what to evict when it's full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// Evict the least recently used block.
+    Lru,
+    /// Keep track of how long each file takes to read from the backing filesystem, and evict
+    /// blocks of whichever file is quickest to read again from among the least recently used.
+    Cost,
+}
+
+/// How the cache uses backing files' modification times to tell if cached data is stale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtimePolicy {
+    /// Cached data is stale once the file's mtime changes.
+    Check,
+    /// Ignore mtimes: cached data never goes stale, for backing files which don't change.
+    Ignore,
+    /// Like `Check`, but an mtime of 0 (which some sources report when they don't know) means
+    /// cached data is always stale, so such files are always read again.
+    ZeroIsStale,
+}
+
+/// How files with a particular extension are cached, overriding the cache's own settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// Whether reads of them are cached (even with `auto_cache` off), or never cached.
+    pub cache: bool,
+    /// How much to read from the backing file on a miss, instead of the cache's read size.
+    pub read_size: Option<u64>,
+}
 
 // FSCache has two generic parameters for each of the block map and the bucket store.
 // The {Map, Store} parameters are for a type that can be borrowed to give an implementation of
@@ -24,14 +70,68 @@ use crate::bucket_store::CacheBucketStore;
 // {Map, Store} be bounded on `BorrowMut<WhateverTrait>` directly, but because the map and block
 // traits both have functions with generic parameters themselves, Rust won't let you make a trait
 // object out of them, and so we have to explicitly parameterize over them. :(
+//
+// Locks are always taken in the order `dirty`, then `map`, then `store`, leaving out any that
+// aren't needed. The on-disk bucket lists are shared by every file, so the map and store locks
+// can't be split up per file; instead, backing file reads happen with no locks held, and the
+// locks only cover updating the cache itself.
 pub struct FsCache<Map, MapImpl, Store, StoreImpl> {
     map: RwLock<Map>,
     store: RwLock<Store>,
     block_size: u64,
+    read_size: u64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    // When reading from the backing file or writing to the cache last failed, in seconds since the
+    // epoch, or 0 if it hasn't.
+    last_backing_error: AtomicU64,
+    last_cache_error: AtomicU64,
+    backing_read_errors: AtomicU64,
+    cache_write_errors: AtomicU64,
+    // Cache writes which have failed in a row, and whether the cache has been bypassed because of
+    // them, with `with_passthrough_after`.
+    cache_write_failures: AtomicU32,
+    passthrough_after: u32,
+    passthrough: AtomicBool,
+    backing_rate_limit: Option<RateLimiter>,
+    backing_reads: ConcurrencyLimiter,
+    read_retries: u32,
+    max_per_file: Option<u64>,
+    max_read: Option<u64>,
+    skip_tail_below: u64,
+    backing_align: Option<u64>,
+    eviction: Eviction,
+    auto_cache: bool,
+    // By lowercase file extension, without the dot.
+    policies: HashMap<String, CachePolicy>,
+    mtime_policy: MtimePolicy,
+    sampling: bool,
+    stale_window: Option<Duration>,
+    admit_after: u32,
+    seen: Mutex<SeenBlocks>,
+    defer_state: bool,
+    dirty: Mutex<DirtyState>,
     _p1: PhantomData<MapImpl>,
     _p2: PhantomData<StoreImpl>,
 }
 
+/// Blocks which have missed recently, and how many times, for admission control. Once it's full,
+/// the blocks which first missed the longest ago are forgotten.
+#[derive(Default)]
+struct SeenBlocks {
+    misses: HashMap<(OsString, u64), u32>,
+    order: VecDeque<(OsString, u64)>,
+}
+
+/// Tracked access times and read latencies which haven't been written to the map yet. These
+/// aren't needed to find cached data, so losing them in a crash does no harm.
+#[derive(Default)]
+struct DirtyState {
+    atimes: HashMap<OsString, i64>,
+    latencies: HashMap<OsString, u64>,
+}
+
 macro_rules! trylog {
     ($e:expr, $fmt:expr) => {{
         let v = $e;
@@ -55,15 +155,94 @@ macro_rules! trylog {
     }}
 }
 
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Note that something just failed, for `FsCache::recent_errors`.
+fn record_error(last: &AtomicU64) {
+    last.store(unix_time(), Ordering::Relaxed);
+}
+
+/// One read through the cache, in progress; see `FsCache::fetch_step`.
+struct Fetch {
+    path: OsString,
+    offset: u64,
+    size: u64,
+    prefetch: bool,
+    passthrough: bool,
+    phase: FetchPhase,
+}
+
+enum FetchPhase {
+    /// Reading the blocks starting at `pending` for the file's sample, with `with_sampling`.
+    Sample { pending: std::vec::IntoIter<u64>, data: Vec<u8> },
+    /// Looking up this block in the cache.
+    Block(u64),
+    /// Waiting on a backing read of `len` bytes at `start`, for a miss of `block`.
+    Miss { block: u64, start: u64, len: u64 },
+    Done,
+}
+
+/// What a fetch loop has to do next, from `FsCache::fetch_step`.
+enum FetchStep {
+    /// Wait for `delay`, then read `len` bytes at `start` from the backing file and pass them to
+    /// the next step.
+    Read { start: u64, len: u64, delay: Option<Duration> },
+    /// The next piece of the data asked for.
+    Data(Vec<u8>),
+    Done,
+}
+
+/// A snapshot of cache usage and statistics, from `FsCache::metrics`.
+///
+/// Hits and misses are counted per block; evictions are buckets freed to make room for new data.
+/// Errors are counted per failed operation, after any retries.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CacheMetrics {
+    pub used_bytes: u64,
+    pub max_bytes: Option<u64>,
+    pub bucket_count: u64,
+    pub hit_count: u64,
+    pub miss_count: u64,
+    pub eviction_count: u64,
+    /// Reads from backing files which failed.
+    pub backing_read_errors: u64,
+    /// Writes to the cache which failed, leaving the data uncached.
+    pub cache_write_errors: u64,
+    /// How many cache misses are reading from their backing files right now.
+    pub backing_reads_in_flight: u64,
+    /// Fraction of block reads which were cache hits, or 0 if there haven't been any reads.
+    pub hit_ratio: f64,
+}
+
+/// What the cache holds for one file, from `FsCache::manifest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// The file's path, relative to the root of the mount (starting with a slash).
+    pub path: OsString,
+    /// The file modification time the cached data is from.
+    pub mtime: i64,
+    /// Indices of the cached blocks (including holes), in ascending order.
+    pub blocks: Vec<u64>,
+}
+
 pub trait Cache {
     fn init(&self) -> io::Result<()>;
     fn used_size(&self) -> u64;
     fn max_size(&self) -> Option<u64>;
+    fn set_max_size(&self, max_size: Option<u64>) -> io::Result<()>;
+    fn get_file_atime(&self, path: &OsStr) -> Option<i64>;
+    fn set_file_atime(&self, path: &OsStr, atime: i64) -> io::Result<()>;
     fn invalidate_path<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()>;
     fn free_orphaned_buckets(&self) -> io::Result<()>;
+    fn check_consistency(&self) -> io::Result<()>;
+    fn reset(&self) -> io::Result<()>;
     fn fetch<F: Read + Seek>(&self, path: &OsStr, offset: u64, size: u64, file: &mut F, mtime: i64)
         -> io::Result<Vec<u8>>;
     fn count_cached_bytes(&self, path: &OsStr) -> u64;
+    fn count_cached_bytes_in_range(&self, path: &OsStr, offset: u64, len: u64) -> u64;
 }
 
 impl<Map, MapImpl, Store, StoreImpl> FsCache<Map, MapImpl, Store, StoreImpl>
@@ -80,19 +259,223 @@ where
             map: RwLock::new(map),
             store: RwLock::new(store),
             block_size,
+            read_size: block_size,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            last_backing_error: AtomicU64::new(0),
+            last_cache_error: AtomicU64::new(0),
+            backing_read_errors: AtomicU64::new(0),
+            cache_write_errors: AtomicU64::new(0),
+            cache_write_failures: AtomicU32::new(0),
+            passthrough_after: 0,
+            passthrough: AtomicBool::new(false),
+            backing_rate_limit: None,
+            backing_reads: ConcurrencyLimiter::new(usize::MAX),
+            read_retries: 0,
+            max_per_file: None,
+            max_read: None,
+            skip_tail_below: 0,
+            backing_align: None,
+            eviction: Eviction::Lru,
+            auto_cache: true,
+            policies: HashMap::new(),
+            mtime_policy: MtimePolicy::Check,
+            sampling: false,
+            stale_window: None,
+            admit_after: 1,
+            seen: Mutex::new(SeenBlocks::default()),
+            defer_state: false,
+            dirty: Mutex::new(DirtyState::default()),
             _p1: PhantomData,
             _p2: PhantomData,
         }
     }
 
+    /// On a cache miss, read `read_size` bytes from the backing file instead of just one block,
+    /// and cache all the blocks in it. This must be a multiple of the block size.
+    pub fn with_read_size(mut self, read_size: u64) -> Self {
+        assert!(read_size >= self.block_size && read_size.checked_rem(self.block_size) == Some(0),
+                "read size {} is not a multiple of the block size {}", read_size, self.block_size);
+        self.read_size = read_size;
+        self
+    }
+
+    /// Retry reads from the backing file which fail with a transient error (`EIO`, `EAGAIN`, or
+    /// `EINTR`) up to `retries` times, with a backoff in between, before giving up.
+    pub fn with_read_retries(mut self, retries: u32) -> Self {
+        self.read_retries = retries;
+        self
+    }
+
+    /// Don't let any one file take up more than `max_bytes` of the cache. When caching another
+    /// block of a file would go over, other blocks of that file are evicted first.
+    pub fn with_max_per_file(mut self, max_bytes: u64) -> Self {
+        self.max_per_file = Some(max_bytes);
+        self
+    }
+
+    /// Round reads from the backing file out to multiples of `align` bytes, for backing stores
+    /// which much prefer aligned reads. Whatever whole blocks the extra bytes make up are cached
+    /// too.
+    pub fn with_backing_align(mut self, align: u64) -> Self {
+        assert!(align != 0, "backing alignment can't be 0");
+        self.backing_align = Some(align);
+        self
+    }
+
+    /// Don't cache the last block of a file if it's shorter than `min_bytes`, so small files and
+    /// short tails don't each take up a whole bucket. Reads of it always go to the backing file.
+    pub fn with_skip_tail_below(mut self, min_bytes: u64) -> Self {
+        self.skip_tail_below = min_bytes;
+        self
+    }
+
+    /// Fail reads of more than `max_bytes` at once with `EINVAL`, instead of trying to allocate a
+    /// buffer for all of it. `fetch_streaming` doesn't buffer the whole read, so isn't limited.
+    pub fn with_max_read(mut self, max_bytes: u64) -> Self {
+        self.max_read = Some(max_bytes);
+        self
+    }
+
+    /// Check a read's size against the limit set by `with_max_read`.
+    pub fn check_read_size(&self, path: &OsStr, size: u64) -> io::Result<()> {
+        match self.max_read {
+            Some(max_read) if size > max_read => {
+                error!("refusing to read {} bytes of {:?} at once; the limit is {}",
+                       size, path, max_read);
+                Err(io::Error::from_raw_os_error(libc::EINVAL))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    pub fn with_eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    /// With `auto_cache` false, cache misses are read from the backing file but not added to the
+    /// cache, so only `prefetch` puts anything in it.
+    pub fn with_auto_cache(mut self, auto_cache: bool) -> Self {
+        self.auto_cache = auto_cache;
+        self
+    }
+
+    /// Cache files whose names end in `.extension` (in any case) according to `policy`. A read
+    /// size in the policy must be a multiple of the block size.
+    pub fn with_policy(mut self, extension: &str, policy: CachePolicy) -> Self {
+        if let Some(read_size) = policy.read_size {
+            assert!(read_size >= self.block_size
+                        && read_size.checked_rem(self.block_size) == Some(0),
+                    "read size {} for .{} is not a multiple of the block size {}",
+                    read_size, extension, self.block_size);
+        }
+        self.policies.insert(extension.to_ascii_lowercase(), policy);
+        self
+    }
+
+    /// The policy for this file's extension, if there is one.
+    pub fn policy(&self, path: &OsStr) -> Option<&CachePolicy> {
+        if self.policies.is_empty() {
+            return None;
+        }
+        let extension = Path::new(path).extension()?.to_str()?.to_ascii_lowercase();
+        self.policies.get(&extension)
+    }
+
+    /// Whether misses of this file get added to the cache (subject to admission control), going
+    /// by its policy if it has one, and `auto_cache` otherwise.
+    pub fn caches(&self, path: &OsStr) -> bool {
+        self.policy(path).map(|policy| policy.cache).unwrap_or(self.auto_cache)
+    }
+
+    /// How much of this file to read from the backing file on a miss.
+    fn read_size_for(&self, path: &OsStr) -> u64 {
+        self.policy(path).and_then(|policy| policy.read_size).unwrap_or(self.read_size)
+    }
+
+    pub fn with_mtime_policy(mut self, policy: MtimePolicy) -> Self {
+        self.mtime_policy = policy;
+        self
+    }
+
+    /// Let `fetch_stale` answer reads of files which changed no more than `window` ago from their
+    /// stale cached data, for `refresh` to bring up to date afterwards.
+    pub fn with_stale_while_revalidate(mut self, window: Duration) -> Self {
+        self.stale_window = Some(window);
+        self
+    }
+
+    /// Record a hash of each file's first and last blocks when it's cached, for `revalidate` to
+    /// check against.
+    pub fn with_sampling(mut self, sampling: bool) -> Self {
+        self.sampling = sampling;
+        self
+    }
+
+    /// Only cache a block once it has missed `misses` times, so data which is only read once
+    /// doesn't push out anything more useful. A bounded number of recent misses are remembered.
+    pub fn with_admit_after(mut self, misses: u32) -> Self {
+        self.admit_after = misses;
+        self
+    }
+
+    /// Keep updates to tracked access times and read latencies in memory until `flush_state` is
+    /// called, instead of writing them to the map every time.
+    pub fn with_deferred_state(mut self, defer: bool) -> Self {
+        self.defer_state = defer;
+        self
+    }
+
+    /// After `failures` writes to the cache in a row have failed (e.g. because the cache's
+    /// filesystem went read-only), stop using the cache: reads go straight to the backing files,
+    /// and nothing more is cached, until `resume_caching` is called. 0, the default, never does.
+    pub fn with_passthrough_after(mut self, failures: u32) -> Self {
+        self.passthrough_after = failures;
+        self
+    }
+
+    /// Limit cache misses to reading `bytes_per_sec` from the backing files, across all threads.
+    /// Cache hits aren't limited.
+    pub fn with_backing_rate_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.backing_rate_limit = Some(RateLimiter::new(bytes_per_sec));
+        self
+    }
+
+    /// Limit cache misses to `max` reads from the backing files at once, across all threads. Any
+    /// more wait their turn.
+    pub fn with_max_inflight(mut self, max: usize) -> Self {
+        self.backing_reads = ConcurrencyLimiter::new(max);
+        self
+    }
+
+    /// How long to wait before reading `len` bytes from a backing file.
+    fn backing_read_delay(&self, len: u64) -> Option<Duration> {
+        let delay = self.backing_rate_limit.as_ref()?.reserve(len);
+        if delay > Duration::from_secs(0) {
+            debug!("throttling backing read of {:#x} bytes for {:?}", len, delay);
+            Some(delay)
+        } else {
+            None
+        }
+    }
+
     fn try_get_cached_block(&self, path: &OsStr, block: u64) -> io::Result<Option<Vec<u8>>> {
+        trace_span!("cache_lookup", block);
         let map = self.map.read().unwrap();
         let store = self.store.read().unwrap();
 
         let bucket_path = match (*map).borrow().get_block(path, block) {
             Ok(Some(bucket_path)) => bucket_path,
             Ok(None) => {
-                return Ok(None)
+                return match (*map).borrow().get_hole(path, block) {
+                    Ok(hole) => Ok(hole.map(|len| vec![0; len as usize])),
+                    Err(e) => {
+                        error!("failed to check for a hole at block {} of {:?}: {}", block, path, e);
+                        Err(e)
+                    }
+                };
             },
             Err(e) => {
                 error!("failed to get bucket path for block {} of {:?}: {}", block, path, e);
@@ -109,17 +492,91 @@ where
         }
     }
 
+    /// For `fetch`: when every block of the read is already cached and current, read them all
+    /// straight into one buffer, without copying each block again as the normal path does.
+    /// Returns `None` when anything would need the normal path, i.e. a miss, a stale or changed
+    /// file, or a read which goes past the cached blocks.
+    fn fetch_full_hit(&self, path: &OsStr, offset: u64, size: u64, file_size: u64, mtime: i64)
+            -> io::Result<Option<Vec<u8>>>
+    {
+        if self.is_passthrough() {
+            return Ok(None);
+        }
+        if size == 0 || offset >= file_size {
+            return Ok(None);
+        }
+        // The normal path would go on to the block after the last one, and miss.
+        let partial_block_len = file_size % self.block_size;
+        if offset + size > file_size && partial_block_len == 0 {
+            return Ok(None);
+        }
+        if self.check_file_mtime(path, mtime)? != CacheBlockMapFileResult::Current {
+            return Ok(None);
+        }
+
+        let map = self.map.read().unwrap();
+        let store = self.store.read().unwrap();
+        if (*map).borrow().get_file_size(path)? != Some(file_size) {
+            return Ok(None);
+        }
+
+        let end = cmp::min(offset + size, file_size);
+        let first_block = offset / self.block_size;
+        let last_block = (end - 1) / self.block_size;
+        let blocks_end = cmp::min((last_block + 1) * self.block_size, file_size);
+        let mut result: Vec<u8> = Vec::with_capacity((blocks_end - offset) as usize);
+        for block in first_block ..= last_block {
+            let block_start = block * self.block_size;
+            let expected = cmp::min(self.block_size, file_size - block_start) as usize;
+            let skip = offset.saturating_sub(block_start) as usize;
+            let nread = match (*map).borrow().get_block(path, block)? {
+                Some(bucket_path) if skip != 0 => {
+                    // Only the first block can start part-way in, and that has to be copied.
+                    let data = (*store).borrow().get(&bucket_path)?;
+                    if data.len() == expected {
+                        result.extend_from_slice(&data[skip ..]);
+                    }
+                    data.len()
+                },
+                Some(bucket_path) => (*store).borrow().get_into(&bucket_path, &mut result)?,
+                None => match (*map).borrow().get_hole(path, block)? {
+                    Some(len) => {
+                        result.resize(result.len() + (len as usize).saturating_sub(skip), 0);
+                        len as usize
+                    },
+                    None => return Ok(None),
+                },
+            };
+            if nread != expected {
+                debug!("block {} of {:?} has {:#x} bytes instead of {:#x}; not a full hit",
+                       block, path, nread, expected);
+                return Ok(None);
+            }
+        }
+        result.truncate((end - offset) as usize);
+
+        self.hits.fetch_add(last_block - first_block + 1, Ordering::Relaxed);
+        info!("cache hit: got {:#x} to {:#x} from {:?}", offset, end, path);
+        Ok(Some(result))
+    }
+
     fn write_block_into_cache(&self, path: &OsStr, block: u64, data: &[u8]) -> io::Result<()> {
         assert!(!data.is_empty());
         let mut map = self.map.write().unwrap();
         let mut store = self.store.write().unwrap();
 
+        if self.eviction == Eviction::Cost {
+            trylog!(self.evict_cheapest((*map).borrow_mut(), (*store).borrow_mut(),
+                                        data.len() as u64),
+                    "failed to make room in the cache");
+        }
+
         let map_path = (*map).borrow_mut().get_block_path(path, block);
         let bucket_path = trylog!(
-            (*store).borrow_mut().put(&map_path, data, |map_path| (*map)
-                .borrow_mut()
-                .unmap_block(map_path)
-                .and(Ok(()))),
+            (*store).borrow_mut().put(&map_path, data, |map_path| {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                (*map).borrow_mut().unmap_block(map_path)
+            }),
             "failed to write to cache");
         trylog!(
             (*map).borrow_mut().put_block(path, block, &bucket_path),
@@ -128,126 +585,174 @@ where
         Ok(())
     }
 
-    pub fn free_block(&self, path: &OsStr, block: u64)
-        -> io::Result<Option<u64>>
-    {
-        debug!("free_block({:?}, {})", path, block);
-        let mut map = self.map.write().unwrap();
-        let mut store = self.store.write().unwrap();
-        let block_path = (*map).borrow().get_block_path(path, block);
-        let bucket_path = (*map).borrow().get_block(path, block)?;
-        if let Some(bucket_path) = bucket_path {
-            let freed = (*store).borrow_mut().free_bucket(&bucket_path);
-            (*map).borrow_mut().unmap_block(&block_path)?;
-            freed.map(Some)
-        } else {
-            Ok(None)
+    /// Evict blocks until `len` more bytes fit in the cache, picking the ones whose files were
+    /// quickest to read from among the least recently used. Files with no recorded read latency
+    /// are taken to be quick.
+    fn evict_cheapest(&self, map: &mut MapImpl, store: &mut StoreImpl, len: u64) -> io::Result<()> {
+        while let Some(max_bytes) = store.max_bytes() {
+            if store.used_bytes() + len <= max_bytes {
+                break;
+            }
+            let candidates = store.lru_buckets(COST_EVICTION_WINDOW)?;
+            // min_by_key picks the first of equals, so ties go to the least recently used.
+            let cheapest = candidates.into_iter().min_by_key(|(_, block_path)| {
+                map.get_block_file_latency(block_path)
+                    .unwrap_or_else(|e| {
+                        warn!("failed to read latency of {:?}: {}", block_path, e);
+                        None
+                    })
+                    .unwrap_or(0)
+            });
+            let (bucket_path, block_path) = match cheapest {
+                Some(candidate) => candidate,
+                None => break,
+            };
+            debug!("evicting {:?} (bucket {:?})", block_path, bucket_path);
+            store.release_bucket(&bucket_path, &block_path)?;
+            map.unmap_block(&block_path)?;
+            self.evictions.fetch_add(1, Ordering::Relaxed);
         }
+        Ok(())
     }
-}
 
-impl<Map, MapImpl, Store, StoreImpl> Cache for FsCache<Map, MapImpl, Store, StoreImpl>
-where
-    Map: BorrowMut<MapImpl>,
-    MapImpl: CacheBlockMap,
-    Store: BorrowMut<StoreImpl>,
-    StoreImpl: CacheBucketStore,
-{
-    fn init(&self) -> io::Result<()> {
+    /// Fold another backing read time into the file's average, for cost-aware eviction.
+    fn record_read_latency(&self, path: &OsStr, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        if self.defer_state {
+            let mut dirty = self.dirty.lock().unwrap();
+            let average = match dirty.latencies.get(path) {
+                Some(&average) => Some(average),
+                None => match (*self.map.read().unwrap()).borrow().get_file_latency(path) {
+                    Ok(average) => average,
+                    Err(e) => {
+                        warn!("failed to read latency of {:?}: {}", path, e);
+                        None
+                    }
+                },
+            };
+            let average = average.map_or(sample, |average| (average * 3 + sample) / 4);
+            dirty.latencies.insert(path.to_owned(), average);
+            return;
+        }
         let mut map = self.map.write().unwrap();
-        let mut store = self.store.write().unwrap();
-        (*store).borrow_mut().init(|map_path| (*map).borrow_mut().unmap_block(map_path))
+        let result = (*map).borrow().get_file_latency(path).and_then(|average| {
+            // An exponentially weighted moving average, with each new sample counting for 1/4.
+            let average = average.map_or(sample, |average| (average * 3 + sample) / 4);
+            (*map).borrow_mut().set_file_latency(path, average)
+        });
+        if let Err(e) = result {
+            warn!("failed to record read latency of {:?}: {}", path, e);
+        }
     }
 
-    fn used_size(&self) -> u64 {
-        (*self.store.read().unwrap()).borrow().used_bytes()
+    fn check_file_mtime(&self, path: &OsStr, mtime: i64) -> io::Result<CacheBlockMapFileResult> {
+        let result = trylog!((*self.map.read().unwrap()).borrow().check_file_mtime(path, mtime),
+                             "error checking cache freshness for {:?}", path);
+        Ok(match (self.mtime_policy, result) {
+            (MtimePolicy::Ignore, CacheBlockMapFileResult::Stale) => {
+                CacheBlockMapFileResult::Current
+            },
+            (MtimePolicy::ZeroIsStale, CacheBlockMapFileResult::Current) if mtime == 0 => {
+                CacheBlockMapFileResult::Stale
+            },
+            (_, result) => result,
+        })
     }
 
-    fn max_size(&self) -> Option<u64> {
-        (*self.store.read().unwrap()).borrow().max_bytes()
+    /// Whether the cached data for the file is from when it had this mtime (as far as the mtime
+    /// policy is concerned). A file with nothing cached isn't current.
+    pub fn is_current(&self, path: &OsStr, mtime: i64) -> bool {
+        matches!(self.check_file_mtime(path, mtime), Ok(CacheBlockMapFileResult::Current))
     }
 
-    fn invalidate_path<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()> {
-        let path: &Path = path.as_ref();
-        debug!("invalidate_path: {:?}", path);
-        let mut store = self.store.write().unwrap();
-        (*self.map.write().unwrap())
-            .borrow_mut()
-            .invalidate_path(path.as_os_str(), |bucket_path| {
-                match (*store).borrow_mut().free_bucket(bucket_path) {
-                    Ok(n) => {
-                        info!("freed {} bytes from bucket {:?}", n, bucket_path);
-                        Ok(())
-                    },
-                    Err(e) => {
-                        error!("error freeing bucket {:?}: {}", bucket_path, e);
-                        Err(e)
-                    }
-                }
-            })
+    /// An empty file has nothing to cache, so its mtime and size aren't recorded either: a directory
+    /// full of them would otherwise get a map directory each. Anything cached from before it was
+    /// emptied is dropped.
+    fn forget_empty_file(&self, path: &OsStr) -> io::Result<()> {
+        if self.check_file_mtime(path, 0)? != CacheBlockMapFileResult::NotPresent {
+            info!("{:?} is empty now; dropping its cached data", path);
+            self.invalidate_path(path)?;
+        }
+        Ok(())
     }
 
-    fn free_orphaned_buckets(&self) -> io::Result<()> {
-        debug!("free_orphaned_buckets");
+    /// Note that writing to the cache failed, and stop using it if that keeps happening.
+    fn cache_write_failed(&self) {
+        record_error(&self.last_cache_error);
+        self.cache_write_errors.fetch_add(1, Ordering::Relaxed);
+        let failures = self.cache_write_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.passthrough_after != 0 && failures >= self.passthrough_after
+                && !self.passthrough.swap(true, Ordering::SeqCst) {
+            error!("{} writes to the cache have failed in a row; bypassing the cache and reading \
+                    straight from the backing filesystem until the resume_caching command is given",
+                   failures);
+        }
+    }
 
-        let mut orphans: Vec<PathBuf> = vec![];
+    /// Whether the cache is being bypassed, after too many failed writes to it.
+    pub fn is_passthrough(&self) -> bool {
+        self.passthrough.load(Ordering::Relaxed)
+    }
 
-        {
-            let map_read = self.map.read().unwrap();
-            let store_read = self.store.read().unwrap();
-            store_read.borrow().enumerate_buckets(
-                |bucket_path, parent_opt| {
-                    if let Some(parent) = parent_opt {
-                        if !(*map_read).borrow().is_block_mapped(parent)? {
-                            warn!("bucket {:?} is an orphan; it was parented to {:?}",
-                                  bucket_path, parent);
-                            orphans.push(PathBuf::from(bucket_path));
-                        }
-                    }
-                    Ok(())
-                }
-            )?;
+    /// Start using the cache again after it was bypassed. Returns whether it had been.
+    pub fn resume_caching(&self) -> bool {
+        self.cache_write_failures.store(0, Ordering::Relaxed);
+        let was = self.passthrough.swap(false, Ordering::SeqCst);
+        if was {
+            info!("resuming caching");
         }
+        was
+    }
 
-        if !orphans.is_empty() {
-            let mut store_write = self.store.write().unwrap();
-            for bucket in orphans {
-                (*store_write).borrow_mut().free_bucket(bucket.as_os_str())?;
+    /// Invalidate the file's cached data if it's stale, and record its current mtime and size.
+    /// Returns whether the recorded mtime and size were already right.
+    fn prepare_fetch(&self, path: &OsStr, mtime: i64, file_size: u64) -> io::Result<bool> {
+        let mut freshness = self.check_file_mtime(path, mtime)?;
+
+        // A file can be replaced by one of a different length without the mtime changing, so
+        // also check the size. Blocks from the old EOF onward are no good anymore.
+        let mut size_current = false;
+        if freshness == CacheBlockMapFileResult::Current {
+            let recorded_size = trylog!((*self.map.read().unwrap()).borrow().get_file_size(path),
+                                        "error checking cached file size for {:?}", path);
+            match recorded_size {
+                Some(size) if size == file_size => { size_current = true; },
+                Some(size) => {
+                    info!("size of {:?} changed from {} to {}; dropping blocks past EOF",
+                          path, size, file_size);
+                    self.free_blocks_from(path, cmp::min(size, file_size) / self.block_size)?;
+                    // Freeing the last block also removes the mtime.
+                    freshness = self.check_file_mtime(path, mtime)?;
+                },
+                None => (),
             }
         }
 
-        Ok(())
-    }
-
-    #[allow(clippy::cognitive_complexity)] // FIXME: split this up into smaller pieces
-    fn fetch<F>(&self, path: &OsStr, offset: u64, size: u64, file: &mut F, mtime: i64)
-            -> io::Result<Vec<u8>>
-            where F: Read + Seek
-    {
-        let freshness = {
-            trylog!((*self.map.read().unwrap()).borrow().check_file_mtime(path, mtime),
-                    "error checking cache freshness for {:?}", path)
-        };
-
         if freshness == CacheBlockMapFileResult::Stale {
             info!("cache data for {:?} is stale; invalidating", path);
-            let mut store = self.store.write().unwrap();
             let mut map = self.map.write().unwrap();
+            let mut store = self.store.write().unwrap();
             trylog!(
                 (*map).borrow_mut().invalidate_path(
                     path,
-                    |bucket_path| (*store).borrow_mut().free_bucket(bucket_path).and(Ok(()))
+                    |block_path, bucket_path| {
+                        (*store).borrow_mut().release_bucket(bucket_path, block_path).and(Ok(()))
+                    }
                 ),
                 "failed to invalidate stale cache data for {:?}", path);
         }
 
         if freshness != CacheBlockMapFileResult::Current {
             // TODO: make a macro for this type of retry loop
-            let mut store = self.store.write().unwrap();
             let mut map = self.map.write().unwrap();
+            let mut store = self.store.write().unwrap();
             while let Err(e) = (*map).borrow_mut().set_file_mtime(path, mtime) {
-                if e.raw_os_error() == Some(::libc::ENOSPC) {
-                    (*store).borrow_mut().delete_something()?;
+                if e.raw_os_error() == Some(::libc::ENOSPC) && (*store).borrow().used_bytes() > 0 {
+                    let (map_paths, _) = (*store).borrow_mut().delete_something()?;
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                    for map_path in map_paths {
+                        (*map).borrow_mut().unmap_block(&map_path)?;
+                    }
                 } else {
                     error!("failed to set mtime file {:?}: {}", path, e);
                     return Err(e);
@@ -255,114 +760,1287 @@ where
             }
         }
 
-        let first_block = offset / self.block_size;
-        let last_block = (offset + size - 1) / self.block_size;
+        if !size_current {
+            let mut map = self.map.write().unwrap();
+            trylog!((*map).borrow_mut().set_file_size(path, file_size),
+                    "failed to set size of {:?}", path);
+        }
 
-        debug!("fetching blocks {} to {} from {:?}", first_block, last_block, path);
+        Ok(freshness == CacheBlockMapFileResult::Current && size_current)
+    }
+
+    /// Read from the file's stale cached data, if it's stale and the file changed (i.e. got
+    /// modification time `mtime`) within the stale-while-revalidate window. The range has to be
+    /// entirely cached; if it isn't, or the data isn't stale, or it's too old, this returns None,
+    /// and the read should go through `fetch` instead. The read sees the cache all at once, so it
+    /// never mixes stale blocks with ones put in by `refresh`.
+    pub fn fetch_stale(&self, path: &OsStr, offset: u64, size: u64, mtime: i64)
+        -> io::Result<Option<Vec<u8>>>
+    {
+        let window = match self.stale_window {
+            Some(window) => window,
+            None => return Ok(None),
+        };
+        if size == 0 || self.mtime_policy == MtimePolicy::Ignore || self.is_passthrough()
+                || (unix_time() as i64).saturating_sub(mtime) > window.as_secs() as i64 {
+            return Ok(None);
+        }
+        self.check_read_size(path, size)?;
 
-        let mut result: Vec<u8> = Vec::with_capacity(size as usize);
+        let map = self.map.read().unwrap();
+        let store = self.store.read().unwrap();
+        if (*map).borrow().check_file_mtime(path, mtime)? != CacheBlockMapFileResult::Stale {
+            return Ok(None);
+        }
+        let end = match (*map).borrow().get_file_size(path)? {
+            Some(old_size) if offset < old_size => cmp::min(offset + size, old_size),
+            _ => return Ok(None),
+        };
 
+        let first_block = offset / self.block_size;
+        let last_block = (end - 1) / self.block_size;
+        let mut result = Vec::with_capacity((end - offset) as usize);
         for block in first_block ..= last_block {
-            debug!("fetching block {}", block);
-
-            let mut block_data: Vec<u8> = match self.try_get_cached_block(path, block) {
-                Ok(Some(data)) => {
-                    info!("cache hit: got {:#x} to {:#x} from {:?}",
-                          block * self.block_size,
-                          block * self.block_size + data.len() as u64,
-                          path);
-                    data
+            let data = match (*map).borrow().get_block(path, block)? {
+                Some(bucket_path) => (*store).borrow().get(&bucket_path)?,
+                None => match (*map).borrow().get_hole(path, block)? {
+                    Some(len) => vec![0; len as usize],
+                    None => return Ok(None),
                 },
-                Ok(None) => {
-                    info!("cache miss: reading {:#x} to {:#x} from {:?}",
-                          block * self.block_size,
-                          (block + 1) * self.block_size,
-                          path);
+            };
+            let block_start = block * self.block_size;
+            let start = offset.saturating_sub(block_start) as usize;
+            let stop = cmp::min(end - block_start, data.len() as u64) as usize;
+            result.extend_from_slice(data.get(start .. stop).unwrap_or(&[]));
+        }
+        self.hits.fetch_add(last_block - first_block + 1, Ordering::Relaxed);
+        info!("serving stale cached data for {:?} while it's refreshed", path);
+        Ok(Some(result))
+    }
 
-                    // TODO: try to write into a slice of `result` in place instead of writing to
-                    // a new buffer and moving the data later.
+    /// Bring the file's stale cached data up to date with `file`, which has modification time
+    /// `mtime`: the blocks that are cached are read again, as prefetches, so they go through the
+    /// same limits and eviction as any other. The stale data is dropped when the first of them is
+    /// read. Does nothing if the cached data isn't stale anymore (e.g. it has already been
+    /// refreshed or dropped).
+    pub fn refresh<F: Read + Seek>(&self, path: &OsStr, file: &mut F, mtime: i64)
+        -> io::Result<()>
+    {
+        if self.is_passthrough() {
+            return Ok(());
+        }
+        let blocks = {
+            let map = self.map.read().unwrap();
+            if (*map).borrow().check_file_mtime(path, mtime)? != CacheBlockMapFileResult::Stale {
+                debug!("refresh: {:?} isn't stale anymore", path);
+                return Ok(());
+            }
+            trylog!((*map).borrow().get_blocks(path),
+                    "failed to list cached blocks of {:?}", path)
+        };
+        info!("refreshing {} stale blocks of {:?}", blocks.len(), path);
 
-                    let mut buf: Vec<u8> = Vec::with_capacity(self.block_size as usize);
+        // Runs of consecutive blocks are read together.
+        let mut blocks = blocks.into_iter().peekable();
+        while let Some(first) = blocks.next() {
+            let mut last = first;
+            while blocks.peek() == Some(&(last + 1)) {
+                last = blocks.next().unwrap();
+            }
+            self.fetch_blocks(path, first * self.block_size, (last - first + 1) * self.block_size,
+                              file, mtime, true, |_| Ok(()))?;
+        }
+        Ok(())
+    }
 
-                    // TODO: skip this when doing contiguous reads from the file
-                    file.seek(SeekFrom::Start(block * self.block_size))?;
+    /// A hash of the file's first and last blocks, which between them catch most ways of
+    /// rewriting a file: a changed header, or a different ending.
+    fn sample_hash<F: Read + Seek>(&self, path: &OsStr, file: &mut F, file_size: u64)
+        -> io::Result<u64>
+    {
+        let mut sample = vec![];
+        for start in self.sample_starts(file_size) {
+            sample.extend_from_slice(&self.read_range(path, file, start, self.block_size)?);
+        }
+        Ok(content_hash(&sample))
+    }
 
-                    let nread = file.read(unsafe {
-                        transmute::<&mut [MaybeUninit<u8>], &mut [u8]>(buf.spare_capacity_mut())
-                    })? as u64;
-                    debug!("read {:#x} bytes", nread);
+    /// Where the blocks hashed by `sample_hash` start.
+    fn sample_starts(&self, file_size: u64) -> Vec<u64> {
+        let last_block = file_size.saturating_sub(1) / self.block_size;
+        if last_block == 0 {
+            vec![0]
+        } else {
+            vec![0, last_block * self.block_size]
+        }
+    }
 
-                    unsafe { buf.set_len(nread as usize) };
+    fn record_sample<F: Read + Seek>(&self, path: &OsStr, file: &mut F, file_size: u64)
+        -> io::Result<()>
+    {
+        let hash = trylog!(self.sample_hash(path, file, file_size),
+                           "failed to read a sample of {:?}", path);
+        self.set_sample(path, hash)
+    }
 
-                    if nread > 0 {
-                        trylog!(self.write_block_into_cache(path, block, &buf),
-                                "unhandled error writing to cache");
-                    }
+    fn set_sample(&self, path: &OsStr, hash: u64) -> io::Result<()> {
+        let mut map = self.map.write().unwrap();
+        trylog!((*map).borrow_mut().set_file_sample(path, hash),
+                "failed to record sample of {:?}", path);
+        Ok(())
+    }
 
-                    buf
-                },
-                Err(e) => {
-                    error!("error getting bucket path for block {} of {:?}: {}", block, path, e);
-                    return Err(e);
-                }
-            };
+    /// Check the backing file's size and mtime, and a hash of its first and last blocks, against
+    /// what they were when it was cached, and invalidate its cached data if any of them changed.
+    /// This catches files being rewritten in place without the mtime changing, at the cost of
+    /// reading up to two blocks from the backing file. Returns whether the file was invalidated.
+    ///
+    /// If nothing was recorded for the file's contents (i.e. it was cached without
+    /// `with_sampling`), they're recorded now, and trusted.
+    pub fn revalidate<F: Read + Seek>(&self, path: &OsStr, file: &mut F, mtime: i64)
+        -> io::Result<bool>
+    {
+        let freshness = self.check_file_mtime(path, mtime)?;
+        if freshness == CacheBlockMapFileResult::NotPresent {
+            return Ok(false);
+        }
 
-            let nread = block_data.len() as u64;
+        let file_size = file.seek(SeekFrom::End(0))?;
+        let (recorded_size, recorded_sample) = {
+            let map = self.map.read().unwrap();
+            let map = (*map).borrow();
+            (trylog!(map.get_file_size(path), "error checking cached file size for {:?}", path),
+             trylog!(map.get_file_sample(path), "error checking cached sample of {:?}", path))
+        };
 
-            let block_start = if block == first_block {
-                // read starts part-way into this block
-                offset - block * self.block_size
-            } else {
-                0
-            };
+        let changed = if freshness == CacheBlockMapFileResult::Stale {
+            Some("mtime")
+        } else if recorded_size.is_some_and(|size| size != file_size) {
+            Some("size")
+        } else {
+            let sample = trylog!(self.sample_hash(path, file, file_size),
+                                 "failed to read a sample of {:?}", path);
+            match recorded_sample {
+                Some(recorded) if recorded != sample => Some("contents"),
+                Some(_) => None,
+                None => {
+                    let mut map = self.map.write().unwrap();
+                    trylog!((*map).borrow_mut().set_file_sample(path, sample),
+                            "failed to record sample of {:?}", path);
+                    None
+                },
+            }
+        };
+
+        match changed {
+            Some(what) => {
+                info!("{} of {:?} changed; invalidating its cached data", what, path);
+                self.invalidate_path(Path::new(path))?;
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Free all cached blocks of the file, starting at `first_block`.
+    fn free_blocks_from(&self, path: &OsStr, first_block: u64) -> io::Result<()> {
+        let blocks = trylog!((*self.map.read().unwrap()).borrow().get_blocks(path),
+                             "failed to list cached blocks of {:?}", path);
+        for block in blocks.into_iter().filter(|&block| block >= first_block) {
+            self.free_block(path, block)?;
+        }
+        Ok(())
+    }
+
+    /// Free all cached blocks of the file which overlap the `length` bytes starting at `offset`.
+    pub fn free_range(&self, path: &OsStr, offset: u64, length: u64) -> io::Result<()> {
+        if length == 0 {
+            return Ok(());
+        }
+        let first_block = offset / self.block_size;
+        let last_block = (offset + length - 1) / self.block_size;
+        let blocks = trylog!((*self.map.read().unwrap()).borrow().get_blocks(path),
+                             "failed to list cached blocks of {:?}", path);
+        for block in blocks.into_iter().filter(|block| (first_block ..= last_block).contains(block)) {
+            self.free_block(path, block)?;
+        }
+        Ok(())
+    }
 
-            let mut block_end = if block == last_block {
-                // read ends part-way into this block
-                (offset + size) - (block * self.block_size)
+    /// Write `data` to the backing file at `offset`, and update the cache to match, so the blocks
+    /// written stay cached instead of having to be read back in.
+    ///
+    /// `old_mtime` is the file's modification time before the write, which is checked like in
+    /// `fetch`, and `new_mtime` is what it is afterwards, which the cache is stamped with.
+    /// Blocks only partly covered by the write are patched from the cache if they're in it, or
+    /// read back from the backing file if not.
+    pub fn write<F: Read + Write + Seek>(&self, path: &OsStr, offset: u64, data: &[u8],
+                                         file: &mut F, old_mtime: i64, new_mtime: i64)
+        -> io::Result<()>
+    {
+        debug!("write: {:?} {:#x}@{:#x}", path, data.len(), offset);
+        if data.is_empty() {
+            return Ok(());
+        }
+        if self.is_passthrough() {
+            // Whatever is cached is stale now, which is caught by the mtime check once the cache
+            // is used again.
+            file.seek(SeekFrom::Start(offset))?;
+            return file.write_all(data);
+        }
+
+        let old_size = file.seek(SeekFrom::End(0))?;
+        self.prepare_fetch(path, old_mtime, old_size)
+            .inspect_err(|_| self.cache_write_failed())?;
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        let new_size = file.seek(SeekFrom::End(0))?;
+
+        let write_end = offset + data.len() as u64;
+        let first_block = offset / self.block_size;
+        let last_block = (write_end - 1) / self.block_size;
+        let mut new_blocks = vec![];
+        for block in first_block ..= last_block {
+            let block_start = block * self.block_size;
+            let block_end = cmp::min(block_start + self.block_size, new_size);
+            let mut block_data = if offset <= block_start && write_end >= block_end {
+                vec![]
             } else {
-                self.block_size
+                // The cached copy of a block ends at the old EOF, and anything between there and
+                // the new EOF is zeroes.
+                let old_len = old_size.saturating_sub(block_start).min(self.block_size);
+                match self.try_get_cached_block(path, block)? {
+                    Some(cached) if cached.len() as u64 == old_len => cached,
+                    _ => {
+                        let mut buf = vec![];
+                        file.seek(SeekFrom::Start(block_start))?;
+                        Read::by_ref(file).take(block_end - block_start).read_to_end(&mut buf)?;
+                        buf
+                    }
+                }
             };
+            block_data.resize((block_end - block_start) as usize, 0);
+            let start = offset.saturating_sub(block_start);
+            let end = cmp::min(write_end, block_end) - block_start;
+            let data_start = (block_start + start - offset) as usize;
+            block_data[start as usize .. end as usize]
+                .copy_from_slice(&data[data_start .. data_start + (end - start) as usize]);
+            new_blocks.push((block, block_data));
+        }
+
+        // The old last block is short, and if the file grew past it, it's not anymore.
+        if new_size > old_size && old_size % self.block_size != 0
+                && old_size / self.block_size < first_block {
+            self.free_block(path, old_size / self.block_size)?;
+        }
+        for (block, _) in &new_blocks {
+            self.free_block(path, *block)?;
+        }
+
+        // Freeing the file's last block removes its mtime, so set it after.
+        {
+            let mut map = self.map.write().unwrap();
+            trylog!((*map).borrow_mut().set_file_mtime(path, new_mtime),
+                    "failed to set mtime file {:?}", path);
+            trylog!((*map).borrow_mut().set_file_size(path, new_size),
+                    "failed to set size of {:?}", path);
+        }
+        if self.sampling {
+            self.record_sample(path, file, new_size)?;
+        }
+
+        for (block, block_data) in new_blocks {
+            match self.cache_block_data(path, block, &block_data) {
+                Ok(()) => self.cache_write_failures.store(0, Ordering::Relaxed),
+                Err(e) => {
+                    self.cache_write_failed();
+                    warn!("failed to cache written block {} of {:?}: {}", block, path, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a block freshly read from the backing file to the cache.
+    fn cache_block_data(&self, path: &OsStr, block: u64, data: &[u8]) -> io::Result<()> {
+        trace_span!("cache_write", block, bytes = data.len());
+        if data.is_empty() {
+            Ok(())
+        } else if (data.len() as u64) < cmp::min(self.block_size, self.skip_tail_below) {
+            // Only a file's last block can be short.
+            debug!("not caching {:?}/{}: the tail is only {} bytes", path, block, data.len());
+            Ok(())
+        } else if data.iter().all(|&byte| byte == 0) {
+            // Probably a hole in a sparse file. Either way, there's no need to spend a bucket on
+            // it.
+            self.write_hole_into_cache(path, block, data.len() as u64)
+        } else if self.make_room_in_file(path, block, data.len() as u64)? {
+            self.write_block_into_cache(path, block, data)
+        } else {
+            debug!("not caching {:?}/{}: it's bigger than the per-file limit", path, block);
+            Ok(())
+        }
+    }
+
+    /// Evict other blocks of the file until `len` more bytes of it fit within the per-file limit.
+    /// Returns false if they never would.
+    ///
+    /// There's no record of which of a file's blocks were used least recently, so this evicts
+    /// them in order from the start of the file, which is oldest-first for a file being streamed.
+    fn make_room_in_file(&self, path: &OsStr, block: u64, len: u64) -> io::Result<bool> {
+        let max_bytes = match self.max_per_file {
+            Some(max_bytes) => max_bytes,
+            None => return Ok(true),
+        };
+        if len > max_bytes {
+            return Ok(false);
+        }
+
+        let mut used = self.count_cached_bytes(path);
+        if used + len <= max_bytes {
+            return Ok(true);
+        }
+
+        let blocks = trylog!((*self.map.read().unwrap()).borrow().get_blocks(path),
+                             "failed to list cached blocks of {:?}", path);
+        for victim in blocks.into_iter().filter(|&other| other != block) {
+            if used + len <= max_bytes {
+                break;
+            }
+            if let Some(freed) = self.free_block(path, victim)? {
+                debug!("evicted {:?}/{} ({} bytes) to stay within the per-file limit",
+                       path, victim, freed);
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+                used = used.saturating_sub(freed);
+            }
+        }
+        Ok(used + len <= max_bytes)
+    }
+
+    /// Read `len` bytes starting at `start` from the backing file, retrying transient errors.
+    fn read_range<F: Read + Seek>(&self, path: &OsStr, file: &mut F, start: u64, len: u64)
+        -> io::Result<Vec<u8>>
+    {
+        trace_span!("backing_read", offset = start, bytes = len);
+        let mut attempt = 0;
+        loop {
+            let result = file.seek(SeekFrom::Start(start))
+                .and_then(|_| {
+                    // Keep reading until the range is full or we hit EOF; a short read would
+                    // otherwise get cached as a partial block. Like `read_exact`, interrupted
+                    // reads are just tried again.
+                    let mut buf = vec![0u8; len as usize];
+                    let mut nread = 0;
+                    while nread < buf.len() {
+                        match file.read(&mut buf[nread ..]) {
+                            Ok(0) => break,
+                            Ok(n) => nread += n,
+                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    buf.truncate(nread);
+                    Ok(buf)
+                });
+            match result {
+                Ok(buf) => return Ok(buf),
+                Err(e) => {
+                    match self.read_retry_delay(path, attempt, e) {
+                        Ok(delay) => thread::sleep(delay),
+                        Err(e) => {
+                            self.backing_read_failed();
+                            return Err(e);
+                        }
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// The async version of `read_range`, for `AsyncCache::fetch_async`.
+    #[cfg(feature = "async")]
+    async fn read_range_async<F>(&self, path: &OsStr, file: &mut F, start: u64, len: u64)
+        -> io::Result<Vec<u8>>
+        where F: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin
+    {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut attempt = 0;
+        loop {
+            let result = async {
+                file.seek(SeekFrom::Start(start)).await?;
+
+                // Async sources (sockets especially) readily return short reads, so keep going
+                // until the range is full or we hit EOF; otherwise a partial block would get
+                // cached.
+                let mut buf = vec![0u8; len as usize];
+                let mut nread = 0;
+                while nread < buf.len() {
+                    match file.read(&mut buf[nread ..]).await {
+                        Ok(0) => break,
+                        Ok(n) => nread += n,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                        Err(e) => return Err(e),
+                    }
+                }
+                buf.truncate(nread);
+                Ok::<_, io::Error>(buf)
+            }.await;
+            match result {
+                Ok(buf) => return Ok(buf),
+                Err(e) => {
+                    match self.read_retry_delay(path, attempt, e) {
+                        Ok(delay) => tokio::time::sleep(delay).await,
+                        Err(e) => {
+                            self.backing_read_failed();
+                            return Err(e);
+                        }
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
+
+    /// Count a read from a backing file which failed for good, after any retries.
+    fn backing_read_failed(&self) {
+        record_error(&self.last_backing_error);
+        self.backing_read_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// How long to wait before retrying a failed read from the backing file, or the error if it
+    /// shouldn't be retried.
+    fn read_retry_delay(&self, path: &OsStr, attempt: u32, e: io::Error) -> io::Result<Duration> {
+        let transient = match e.raw_os_error() {
+            Some(libc::EIO) | Some(libc::EAGAIN) | Some(libc::EINTR) => true,
+            _ => matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock),
+        };
+        if !transient || attempt >= self.read_retries {
+            return Err(e);
+        }
+        let delay = cmp::min(READ_RETRY_BACKOFF * 2u32.pow(cmp::min(attempt, 10)),
+                             READ_RETRY_MAX_BACKOFF);
+        warn!("error reading {:?} (attempt {} of {}); retrying in {:?}: {}",
+              path, attempt + 1, self.read_retries + 1, delay, e);
+        Ok(delay)
+    }
+
+    /// Count a miss of the block, and return whether it should be cached now.
+    fn admit(&self, path: &OsStr, block: u64) -> bool {
+        if self.admit_after <= 1 {
+            return true;
+        }
+        let mut seen = self.seen.lock().unwrap();
+        let key = (path.to_owned(), block);
+        if let Some(misses) = seen.misses.get_mut(&key) {
+            *misses += 1;
+            return *misses >= self.admit_after;
+        }
+        seen.misses.insert(key.clone(), 1);
+        seen.order.push_back(key);
+        if seen.order.len() > ADMISSION_SEEN_CAPACITY {
+            let oldest = seen.order.pop_front().unwrap();
+            seen.misses.remove(&oldest);
+        }
+        false
+    }
+
+    /// The first block of the chunk of `read_size` bytes to read from the backing file on a miss
+    /// of `block`.
+    fn miss_read_start(&self, block: u64, read_size: u64) -> u64 {
+        let blocks_per_read = read_size / self.block_size;
+        block - block % blocks_per_read
+    }
+
+    /// Where to read from the backing file, and how much, for the chunk of `read_size` bytes
+    /// starting at `first_block`: the chunk, rounded out to the backing alignment.
+    fn backing_read_range(&self, first_block: u64, read_size: u64) -> (u64, u64) {
+        let start = first_block * self.block_size;
+        let end = start + read_size;
+        match self.backing_align {
+            Some(align) => {
+                let aligned_start = start - start % align;
+                (aligned_start, end.div_ceil(align) * align - aligned_start)
+            },
+            None => (start, read_size),
+        }
+    }
+
+    /// The whole blocks in `data`, read from `start` in the backing file, and the first one's
+    /// number. A partial block at the end is only included if it's the end of the file (i.e.
+    /// fewer than `len` bytes were read).
+    fn whole_blocks<'a>(&self, start: u64, len: u64, data: &'a [u8]) -> (u64, &'a [u8]) {
+        let first_block = start.div_ceil(self.block_size);
+        let skip = (first_block * self.block_size - start) as usize;
+        let mut data = data.get(skip ..).unwrap_or(&[]);
+        if (data.len() + skip) as u64 == len {
+            data = &data[.. data.len() - data.len() % self.block_size as usize];
+        }
+        (first_block, data)
+    }
+
+    fn is_block_cached(&self, path: &OsStr, block: u64) -> io::Result<bool> {
+        let map = self.map.read().unwrap();
+        let map = (*map).borrow();
+        Ok(map.get_block(path, block)?.is_some() || map.get_hole(path, block)?.is_some())
+    }
 
-            if block_end == 0 {
+    /// Add a chunk read from the backing file starting at `first_block` to the cache, one block at
+    /// a time, and return the data for `block`. Blocks other than `block` which are already cached
+    /// are left alone. With `populate` false, nothing is cached.
+    fn cache_read_data(&self, path: &OsStr, first_block: u64, data: &[u8], block: u64,
+                       populate: bool)
+        -> io::Result<Vec<u8>>
+    {
+        let mut wanted = vec![];
+        for (i, block_data) in data.chunks(self.block_size as usize).enumerate() {
+            let this_block = first_block + i as u64;
+            if this_block == block {
+                wanted = block_data.to_vec();
+            }
+            if !populate || (this_block != block && self.is_block_cached(path, this_block)?) {
                 continue;
             }
+            // The data is good even if the cache isn't; failing to cache it shouldn't fail the
+            // read.
+            match self.cache_block_data(path, this_block, block_data) {
+                Ok(()) => self.cache_write_failures.store(0, Ordering::Relaxed),
+                Err(e) => {
+                    self.cache_write_failed();
+                    warn!("failed to cache block {:?}/{}; continuing without caching it: {}",
+                          path, this_block, e);
+                }
+            }
+        }
+        Ok(wanted)
+    }
+
+    fn write_hole_into_cache(&self, path: &OsStr, block: u64, len: u64) -> io::Result<()> {
+        let mut map = self.map.write().unwrap();
+        trylog!(
+            (*map).borrow_mut().put_hole(path, block, len),
+            "failed to map hole for block {:?}/{}", path, block);
+        Ok(())
+    }
 
-            if nread < block_end {
-                // we read less than expected
-                block_end = nread;
+    pub fn metrics(&self) -> CacheMetrics {
+        let (used_bytes, max_bytes, bucket_count) = {
+            let store = self.store.read().unwrap();
+            let store = (*store).borrow();
+            (store.used_bytes(), store.max_bytes(), store.used_buckets())
+        };
+        let hit_count = self.hits.load(Ordering::Relaxed);
+        let miss_count = self.misses.load(Ordering::Relaxed);
+        let hit_ratio = if hit_count + miss_count == 0 {
+            0.
+        } else {
+            hit_count as f64 / (hit_count + miss_count) as f64
+        };
+        CacheMetrics {
+            used_bytes,
+            max_bytes,
+            bucket_count,
+            hit_count,
+            miss_count,
+            eviction_count: self.evictions.load(Ordering::Relaxed),
+            backing_read_errors: self.backing_read_errors.load(Ordering::Relaxed),
+            cache_write_errors: self.cache_write_errors.load(Ordering::Relaxed),
+            backing_reads_in_flight: self.backing_reads.in_flight() as u64,
+            hit_ratio,
+        }
+    }
+
+    /// What has gone wrong in the last `window`: reading from backing files, and/or writing to the
+    /// cache, and whether the cache is being bypassed because of that. Empty if all is well.
+    pub fn recent_errors(&self, window: Duration) -> Vec<&'static str> {
+        let since = unix_time().saturating_sub(window.as_secs());
+        let mut errors = vec![];
+        for (last, what) in [(&self.last_backing_error, "backing read errors"),
+                             (&self.last_cache_error, "cache write failures")] {
+            let last = last.load(Ordering::Relaxed);
+            if last != 0 && last >= since {
+                errors.push(what);
             }
+        }
+        if self.is_passthrough() {
+            errors.push("cache bypassed");
+        }
+        errors
+    }
 
-            debug!("block_start({:#x}) block_end({:#x}) nread({:#x})",
-                 block_start, block_end, nread);
+    /// Write any access times and read latencies kept in memory by `with_deferred_state` to the
+    /// map. Returns how many values were written.
+    pub fn flush_state(&self) -> usize {
+        let dirty = std::mem::take(&mut *self.dirty.lock().unwrap());
+        if dirty.atimes.is_empty() && dirty.latencies.is_empty() {
+            return 0;
+        }
+        let mut written = 0;
+        let mut map = self.map.write().unwrap();
+        for (path, atime) in dirty.atimes {
+            match (*map).borrow_mut().set_file_atime(&path, atime) {
+                Ok(()) => written += 1,
+                Err(e) => warn!("failed to flush atime of {:?}: {}", path, e),
+            }
+        }
+        for (path, micros) in dirty.latencies {
+            match (*map).borrow_mut().set_file_latency(&path, micros) {
+                Ok(()) => written += 1,
+                Err(e) => warn!("failed to flush read latency of {:?}: {}", path, e),
+            }
+        }
+        debug!("flushed {} deferred values to the map", written);
+        written
+    }
+
+    /// Write out everything kept in memory, as the cache is shut down cleanly. Nothing more should
+    /// be cached after this.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.flush_state();
+        let mut store = self.store.write().unwrap();
+        trylog!((*store).borrow_mut().shutdown(), "failed to shut down the bucket store");
+        Ok(())
+    }
 
-            if block_start > block_end {
-                warn!("block_start({:#x}) > block_end({:#x}): on read {:#x} @ {:#x} (block {}, nread = {:#x})",
-                      block_start, block_end, size, offset, block, nread);
-                // Return an empty result. This is the expected behavior when a client seeks past
-                // the end of a file (not an error) and does a read.
-                return Ok(vec![]);
+    /// Rebuild the bucket store's LRU list from scratch, in case it has been corrupted. Returns how
+    /// many buckets are on the new list.
+    pub fn rebuild_lru(&self) -> io::Result<u64> {
+        let mut store = self.store.write().unwrap();
+        let count = trylog!((*store).borrow_mut().rebuild_lru(), "failed to rebuild the LRU list");
+        Ok(count)
+    }
+
+    /// Free the least recently used buckets until at least `bytes` have been freed, or the cache
+    /// is empty. Returns how many bytes were actually freed.
+    pub fn free_bytes(&self, bytes: u64) -> io::Result<u64> {
+        let mut map = self.map.write().unwrap();
+        let mut store = self.store.write().unwrap();
+        let mut freed = 0;
+        while freed < bytes && (*store).borrow().used_buckets() > 0 {
+            let (map_paths, n) = trylog!((*store).borrow_mut().delete_something(),
+                                         "free_bytes: error freeing a bucket");
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            for map_path in map_paths {
+                (*map).borrow_mut().unmap_block(&map_path)?;
             }
+            freed += n;
+        }
+        info!("freed {} bytes from the cache", freed);
+        Ok(freed)
+    }
 
-            if block_start != 0 || block_end != nread {
-                // read a slice of the block
-                result.extend(&block_data[block_start as usize .. block_end as usize]);
-            } else if block == first_block && block == last_block {
-                // Optimization for the common case where we read exactly 1 block.
-                return Ok(block_data);
-            } else {
-                // Take the whole block and add it to the result set.
-                result.append(&mut block_data);
+    /// Drop the cached data of every file which has changed or gone away since it was cached,
+    /// without waiting for it to be read again. `stat` gives the current mtime and size of the
+    /// file at a path, or None if there isn't one anymore; it's called with no locks held, and a
+    /// file it fails for is left alone. Returns how many files' data was dropped.
+    pub fn sweep<F>(&self, mut stat: F) -> io::Result<u64>
+        where F: FnMut(&OsStr) -> io::Result<Option<(i64, u64)>>
+    {
+        let mut paths = vec![];
+        trylog!((*self.map.read().unwrap()).borrow().for_each_cached_file(|path| {
+            paths.push(path.to_owned());
+            Ok(())
+        }), "sweep: failed to walk the map");
+
+        let mut dropped = 0;
+        for path in paths {
+            let current = match stat(&path) {
+                Ok(current) => current,
+                Err(e) => {
+                    warn!("sweep: failed to check {:?}: {}", path, e);
+                    continue;
+                }
+            };
+            let changed = match current {
+                Some((mtime, size)) => {
+                    let recorded_size = (*self.map.read().unwrap()).borrow().get_file_size(&path)?;
+                    self.check_file_mtime(&path, mtime)? == CacheBlockMapFileResult::Stale
+                        || matches!(recorded_size, Some(recorded) if recorded != size)
+                },
+                None => true,
+            };
+            if changed {
+                info!("sweep: dropping cached data of {:?}, which has changed", path);
+                self.invalidate_path(&path)?;
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+
+    /// Check that the cache works: cache a block of known data for `scratch_path`, read it back,
+    /// and free it again. `scratch_path` has to be one which no real file can have.
+    pub fn self_test(&self, scratch_path: &OsStr) -> io::Result<()> {
+        let failed = |msg: &str| io::Error::other(format!("self test: {}", msg));
+
+        // Clean up after an earlier test which didn't finish.
+        if self.check_file_mtime(scratch_path, 0)? != CacheBlockMapFileResult::NotPresent {
+            self.invalidate_path(scratch_path)?;
+        }
+
+        let pattern: Vec<u8> = (0 .. self.block_size).map(|i| (i % 251) as u8 + 1).collect();
+        trylog!((*self.map.write().unwrap()).borrow_mut().set_file_mtime(scratch_path, 0),
+                "self test: failed to set mtime of {:?}", scratch_path);
+        self.write_block_into_cache(scratch_path, 0, &pattern)?;
+
+        let bucket_path = trylog!((*self.map.read().unwrap()).borrow().get_block(scratch_path, 0),
+                                  "self test: failed to look up {:?}", scratch_path)
+            .ok_or_else(|| failed("the block wasn't mapped after caching it"))?;
+        let data = trylog!((*self.store.read().unwrap()).borrow().get(&bucket_path),
+                           "self test: failed to read bucket {:?}", bucket_path);
+        if data != pattern {
+            return Err(failed("the data read back isn't what was written"));
+        }
+
+        if self.free_block(scratch_path, 0)?.is_none() || self.is_block_cached(scratch_path, 0)? {
+            return Err(failed("the block wasn't freed"));
+        }
+        info!("self test passed");
+        Ok(())
+    }
+
+    /// List every file with data in the cache, sorted by path.
+    pub fn manifest(&self) -> io::Result<Vec<ManifestEntry>> {
+        let mut entries = vec![];
+        let map = self.map.read().unwrap();
+        trylog!((*map).borrow().for_each_file(|path, mtime, blocks| {
+            entries.push(ManifestEntry {
+                path: path.to_owned(),
+                mtime,
+                blocks: blocks.to_vec(),
+            });
+            Ok(())
+        }), "manifest: failed to walk the map");
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// The mtime of the file the cached data is from, or None if none of it is cached.
+    pub fn cached_file_mtime(&self, path: &OsStr) -> io::Result<Option<i64>> {
+        match (*self.map.read().unwrap()).borrow().read_file_meta(path) {
+            Ok(meta) => Ok(meta.mtime),
+            // Part of the path is a file, so this can't be one.
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOTDIR) => Ok(None),
+            Err(e) => {
+                error!("failed to read the cached metadata of {:?}: {}", path, e);
+                Err(e)
             }
+        }
+    }
+
+    /// The paths of the files with cached data under the directory `path`, without going through
+    /// the rest of the cache.
+    pub fn cached_files_under(&self, path: &OsStr) -> io::Result<Vec<OsString>> {
+        let mut paths = vec![];
+        trylog!((*self.map.read().unwrap()).borrow().for_each_cached_file_under(path, |file| {
+            paths.push(file.to_owned());
+            Ok(())
+        }), "failed to list the cached files under {:?}", path);
+        Ok(paths)
+    }
 
-            if nread < self.block_size {
-                // if we read less than requested, we're done.
-                if block < last_block {
-                    warn!("read fewer blocks than requested from {:?}", path);
+    /// The size of the file the cached data is from, or if that wasn't recorded, the end of its
+    /// last cached block.
+    pub fn cached_file_size(&self, path: &OsStr) -> io::Result<u64> {
+        let map = self.map.read().unwrap();
+        if let Some(size) = (*map).borrow().get_file_size(path)? {
+            return Ok(size);
+        }
+        let blocks = (*map).borrow().get_blocks(path)?;
+        Ok(blocks.iter().max().map_or(0, |block| (block + 1) * self.block_size))
+    }
+
+    /// Read whatever of the range is in the cache, without going to the backing file. Parts which
+    /// aren't cached read as zeros. The range is cut off at `cached_file_size`.
+    pub fn read_cached(&self, path: &OsStr, offset: u64, size: u64) -> io::Result<Vec<u8>> {
+        let file_size = self.cached_file_size(path)?;
+        if size == 0 || offset >= file_size {
+            return Ok(vec![]);
+        }
+        let end = cmp::min(offset + size, file_size);
+        let mut data = vec![0; (end - offset) as usize];
+        for block in offset / self.block_size ..= (end - 1) / self.block_size {
+            if let Some(block_data) = self.try_get_cached_block(path, block)? {
+                let block_start = block * self.block_size;
+                let from = cmp::max(block_start, offset);
+                let to = cmp::min(block_start + block_data.len() as u64, end);
+                if from < to {
+                    data[(from - offset) as usize .. (to - offset) as usize].copy_from_slice(
+                        &block_data[(from - block_start) as usize .. (to - block_start) as usize]);
                 }
-                break;
             }
-        } // for block
+        }
+        Ok(data)
+    }
+
+    /// Like `Cache::fetch`, but instead of collecting the data into one buffer, pass it to
+    /// `output` a block at a time, in order, as each one is read. Returns how many bytes there
+    /// were in all. If `output` returns an error, the fetch stops there and returns it.
+    pub fn fetch_streaming<F, O>(&self, path: &OsStr, offset: u64, size: u64, file: &mut F,
+                                 mtime: i64, mut output: O)
+            -> io::Result<u64>
+            where F: Read + Seek,
+                  O: FnMut(&[u8]) -> io::Result<()>,
+    {
+        let mut total = 0;
+        self.fetch_blocks(path, offset, size, file, mtime, false, |data| {
+            total += data.len() as u64;
+            output(&data)
+        })?;
+        Ok(total)
+    }
+
+    /// Read the file's data through the cache, passing it to `output` a block at a time. With
+    /// `prefetch`, misses are always cached, regardless of `auto_cache`, policies, and admission
+    /// control.
+    #[allow(clippy::too_many_arguments)]
+    fn fetch_blocks<F, O>(&self, path: &OsStr, offset: u64, size: u64, file: &mut F, mtime: i64,
+                          prefetch: bool, mut output: O)
+            -> io::Result<()>
+            where F: Read + Seek,
+                  O: FnMut(Vec<u8>) -> io::Result<()>,
+    {
+        let file_size = file.seek(SeekFrom::End(0))?;
+        let mut fetch = self.begin_fetch(path, offset, size, mtime, file_size, prefetch)?;
+        trace_span!("fetch", path = ?path, offset, size);
+
+        let mut read = None;
+        loop {
+            match self.fetch_step(&mut fetch, read.take())? {
+                FetchStep::Read { start, len, delay } => {
+                    // No locks are held here, so other threads can carry on with cache hits
+                    // while this one waits.
+                    if let Some(delay) = delay {
+                        thread::sleep(delay);
+                    }
+
+                    // TODO: skip seeking when doing contiguous reads from the file
+                    let permit = self.backing_reads.acquire();
+                    let started = Instant::now();
+                    let buf = self.read_range(path, file, start, len)?;
+                    drop(permit);
+                    read = Some((buf, started.elapsed()));
+                },
+                FetchStep::Data(data) => output(data)?,
+                FetchStep::Done => return Ok(()),
+            }
+        }
+    }
+
+    /// Start reading `size` bytes at `offset` through the cache, from a backing file which is
+    /// `file_size` bytes long. The fetch is then driven by `fetch_step`.
+    fn begin_fetch(&self, path: &OsStr, offset: u64, size: u64, mtime: i64, file_size: u64,
+                   prefetch: bool)
+            -> io::Result<Fetch>
+    {
+        // When the cache is being bypassed, everything is read as if it were a miss which isn't
+        // to be cached.
+        let passthrough = self.is_passthrough();
+        let mut fetch = Fetch {
+            path: path.to_owned(),
+            offset,
+            size,
+            prefetch,
+            passthrough,
+            phase: FetchPhase::Done,
+        };
+        if file_size == 0 {
+            if !passthrough {
+                self.forget_empty_file(path)?;
+            }
+            return Ok(fetch);
+        }
+
+        let first_block = offset / self.block_size;
+        let last_block = (offset + size - 1) / self.block_size;
+        debug!("fetching blocks {} to {} from {:?}", first_block, last_block, path);
+        fetch.phase = FetchPhase::Block(first_block);
+
+        if !passthrough {
+            let current = self.prepare_fetch(path, mtime, file_size)
+                .inspect_err(|_| self.cache_write_failed())?;
+            if !current && self.sampling {
+                fetch.phase = FetchPhase::Sample {
+                    pending: self.sample_starts(file_size).into_iter(),
+                    data: vec![],
+                };
+            }
+        }
+        Ok(fetch)
+    }
+
+    /// Advance `fetch` by one step, given the data read from the backing file (and how long that
+    /// took) if the last step asked for a read.
+    ///
+    /// Everything but the backing reads happens here, so that the sync and async fetches behave
+    /// the same; their loops only do the reads `FetchStep::Read` asks for.
+    fn fetch_step(&self, fetch: &mut Fetch, mut read: Option<(Vec<u8>, Duration)>)
+        -> io::Result<FetchStep>
+    {
+        let path: &OsStr = &fetch.path;
+        let first_block = fetch.offset / self.block_size;
+        let last_block = (fetch.offset + fetch.size - 1) / self.block_size;
+
+        let (block, mut block_data) = loop {
+            match std::mem::replace(&mut fetch.phase, FetchPhase::Done) {
+                FetchPhase::Done => return Ok(FetchStep::Done),
+                FetchPhase::Sample { mut pending, mut data } => {
+                    if let Some((buf, _)) = read.take() {
+                        data.extend_from_slice(&buf);
+                    }
+                    if let Some(start) = pending.next() {
+                        fetch.phase = FetchPhase::Sample { pending, data };
+                        return Ok(FetchStep::Read { start, len: self.block_size, delay: None });
+                    }
+                    self.set_sample(path, content_hash(&data))?;
+                    fetch.phase = FetchPhase::Block(first_block);
+                },
+                FetchPhase::Block(block) => {
+                    debug!("fetching block {}", block);
+                    let cached = if fetch.passthrough {
+                        None
+                    } else {
+                        self.try_get_cached_block(path, block).inspect_err(|e| {
+                            error!("error getting bucket path for block {} of {:?}: {}",
+                                   block, path, e);
+                        })?
+                    };
+                    if let Some(data) = cached {
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                        info!("cache hit: got {:#x} to {:#x} from {:?}",
+                              block * self.block_size,
+                              block * self.block_size + data.len() as u64,
+                              path);
+                        break (block, data);
+                    }
+
+                    self.misses.fetch_add(1, Ordering::Relaxed);
+                    info!("cache miss: reading {:#x} to {:#x} from {:?}",
+                          block * self.block_size,
+                          (block + 1) * self.block_size,
+                          path);
+
+                    // TODO: try to write into a slice of `result` in place instead of writing to
+                    // a new buffer and moving the data later.
+                    let read_size = self.read_size_for(path);
+                    let (start, len) =
+                        self.backing_read_range(self.miss_read_start(block, read_size), read_size);
+                    fetch.phase = FetchPhase::Miss { block, start, len };
+                    return Ok(FetchStep::Read { start, len, delay: self.backing_read_delay(len) });
+                },
+                FetchPhase::Miss { block, start, len } => {
+                    let (buf, elapsed) = read.take().expect("fetch_step: missing backing read");
+                    if self.eviction == Eviction::Cost {
+                        self.record_read_latency(path, elapsed);
+                    }
+                    debug!("read {:#x} bytes at {:#x}", buf.len(), start);
+
+                    let populate = !fetch.passthrough
+                        && (fetch.prefetch || (self.caches(path) && self.admit(path, block)));
+                    let (read_first_block, data) = self.whole_blocks(start, len, &buf);
+                    break (block, self.cache_read_data(path, read_first_block, data, block,
+                                                       populate)?);
+                },
+            }
+        };
+
+        let nread = block_data.len() as u64;
+
+        let block_start = if block == first_block {
+            // read starts part-way into this block
+            fetch.offset - block * self.block_size
+        } else {
+            0
+        };
+
+        let mut block_end = if block == last_block {
+            // read ends part-way into this block
+            (fetch.offset + fetch.size) - (block * self.block_size)
+        } else {
+            self.block_size
+        };
+
+        if nread < block_end {
+            // we read less than expected
+            block_end = nread;
+        }
+
+        debug!("block_start({:#x}) block_end({:#x}) nread({:#x})",
+             block_start, block_end, nread);
+
+        if block_start > block_end {
+            warn!("block_start({:#x}) > block_end({:#x}): on read {:#x} @ {:#x} (block {}, nread = {:#x})",
+                  block_start, block_end, fetch.size, fetch.offset, block, nread);
+            // Return an empty result. This is the expected behavior when a client seeks past
+            // the end of a file (not an error) and does a read.
+            return Ok(FetchStep::Done);
+        }
+
+        if block_start != 0 || block_end != nread {
+            // only part of the block was asked for
+            block_data.truncate(block_end as usize);
+            block_data.drain(.. block_start as usize);
+        }
+
+        if nread < self.block_size {
+            // if we read less than requested, we're done.
+            if block < last_block {
+                warn!("read fewer blocks than requested from {:?}", path);
+            }
+        } else if block < last_block {
+            fetch.phase = FetchPhase::Block(block + 1);
+        }
+        Ok(FetchStep::Data(block_data))
+    }
+
+    /// Read part of a file into the cache, even with auto-caching turned off or blocks which
+    /// haven't missed enough times to be admitted.
+    pub fn prefetch<F>(&self, path: &OsStr, offset: u64, size: u64, file: &mut F, mtime: i64)
+            -> io::Result<()>
+            where F: Read + Seek
+    {
+        if self.is_passthrough() {
+            debug!("not prefetching {:?}: the cache is being bypassed", path);
+            return Ok(());
+        }
+        self.fetch_blocks(path, offset, size, file, mtime, true, |_| Ok(()))
+    }
+
+    pub fn free_block(&self, path: &OsStr, block: u64)
+        -> io::Result<Option<u64>>
+    {
+        debug!("free_block({:?}, {})", path, block);
+        let mut map = self.map.write().unwrap();
+        let mut store = self.store.write().unwrap();
+        let block_path = (*map).borrow().get_block_path(path, block);
+        let bucket_path = (*map).borrow().get_block(path, block)?;
+        if let Some(bucket_path) = bucket_path {
+            let freed = (*store).borrow_mut().release_bucket(&bucket_path, &block_path);
+            (*map).borrow_mut().unmap_block(&block_path)?;
+            freed.map(Some)
+        } else if (*map).borrow_mut().unmap_hole(path, block)? {
+            Ok(Some(0))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl<Map, MapImpl, Store, StoreImpl> Cache for FsCache<Map, MapImpl, Store, StoreImpl>
+where
+    Map: BorrowMut<MapImpl>,
+    MapImpl: CacheBlockMap,
+    Store: BorrowMut<StoreImpl>,
+    StoreImpl: CacheBucketStore,
+{
+    fn init(&self) -> io::Result<()> {
+        let mut map = self.map.write().unwrap();
+        let mut store = self.store.write().unwrap();
+        (*map).borrow_mut().init(self.block_size)?;
+        (*store).borrow_mut().init(|map_path| (*map).borrow_mut().unmap_block(map_path))
+    }
+
+    fn used_size(&self) -> u64 {
+        (*self.store.read().unwrap()).borrow().used_bytes()
+    }
+
+    fn max_size(&self) -> Option<u64> {
+        (*self.store.read().unwrap()).borrow().max_bytes()
+    }
+
+    fn set_max_size(&self, max_size: Option<u64>) -> io::Result<()> {
+        let mut map = self.map.write().unwrap();
+        let mut store = self.store.write().unwrap();
+        trylog!(
+            (*store).borrow_mut().set_max_bytes(
+                max_size,
+                |map_path| {
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                    (*map).borrow_mut().unmap_block(map_path)
+                }),
+            "failed to change the cache size limit");
+        Ok(())
+    }
+
+    fn get_file_atime(&self, path: &OsStr) -> Option<i64> {
+        if let Some(&atime) = self.dirty.lock().unwrap().atimes.get(path) {
+            return Some(atime);
+        }
+        match (*self.map.read().unwrap()).borrow().get_file_atime(path) {
+            Ok(atime) => atime,
+            Err(e) => {
+                error!("error reading tracked atime of {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    fn set_file_atime(&self, path: &OsStr, atime: i64) -> io::Result<()> {
+        if self.defer_state {
+            self.dirty.lock().unwrap().atimes.insert(path.to_owned(), atime);
+            return Ok(());
+        }
+        let mut map = self.map.write().unwrap();
+        trylog!((*map).borrow_mut().set_file_atime(path, atime),
+                "failed to record atime of {:?}", path);
+        Ok(())
+    }
+
+    fn invalidate_path<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()> {
+        let path: &Path = path.as_ref();
+        debug!("invalidate_path: {:?}", path);
+        {
+            // Don't bring back state for files which are going away.
+            let mut dirty = self.dirty.lock().unwrap();
+            dirty.atimes.retain(|p, _| !Path::new(p).starts_with(path));
+            dirty.latencies.retain(|p, _| !Path::new(p).starts_with(path));
+        }
+        let mut map = self.map.write().unwrap();
+        let mut store = self.store.write().unwrap();
+        (*map)
+            .borrow_mut()
+            .invalidate_path(path.as_os_str(), |block_path, bucket_path| {
+                match (*store).borrow_mut().release_bucket(bucket_path, block_path) {
+                    Ok(n) => {
+                        info!("freed {} bytes from bucket {:?}", n, bucket_path);
+                        Ok(())
+                    },
+                    Err(e) => {
+                        error!("error freeing bucket {:?}: {}", bucket_path, e);
+                        Err(e)
+                    }
+                }
+            })
+    }
+
+    fn free_orphaned_buckets(&self) -> io::Result<()> {
+        debug!("free_orphaned_buckets");
+
+        let mut orphans: Vec<(PathBuf, PathBuf)> = vec![];
+
+        {
+            let map_read = self.map.read().unwrap();
+            let store_read = self.store.read().unwrap();
+            store_read.borrow().enumerate_buckets(
+                |bucket_path, parent_opt| {
+                    if let Some(parent) = parent_opt {
+                        if !(*map_read).borrow().is_block_mapped(parent)? {
+                            warn!("bucket {:?} is an orphan; it was parented to {:?}",
+                                  bucket_path, parent);
+                            orphans.push((PathBuf::from(bucket_path), PathBuf::from(parent)));
+                        }
+                    }
+                    Ok(())
+                }
+            )?;
+        }
+
+        if !orphans.is_empty() {
+            let mut store_write = self.store.write().unwrap();
+            for (bucket, parent) in orphans {
+                (*store_write).borrow_mut().release_bucket(bucket.as_os_str(), parent.as_os_str())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_consistency(&self) -> io::Result<()> {
+        info!("checking the cache map and buckets for consistency");
+        let mut map = self.map.write().unwrap();
+        let mut store = self.store.write().unwrap();
+
+        // Every bucket in use, and the map entries its parent links say it belongs to.
+        let mut parents: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        trylog!(
+            (*store).borrow().enumerate_buckets(|bucket_path, parent_opt| {
+                if let Some(parent) = parent_opt {
+                    parents.entry(PathBuf::from(bucket_path)).or_default()
+                        .push(PathBuf::from(parent));
+                }
+                Ok(())
+            }),
+            "check_consistency: failed to enumerate buckets");
+
+        // Map entries have to point to a bucket which links back to them.
+        let mut dangling: Vec<PathBuf> = vec![];
+        let mut reachable: HashSet<(PathBuf, PathBuf)> = HashSet::new();
+        trylog!(
+            (*map).borrow().for_each_block(|block_path, bucket_path| {
+                let linked_back = parents.get(Path::new(bucket_path))
+                    .is_some_and(|parents| parents.iter().any(|p| p == Path::new(block_path)));
+                if linked_back {
+                    reachable.insert((PathBuf::from(bucket_path), PathBuf::from(block_path)));
+                } else {
+                    warn!("map entry {:?} points to {:?}, which isn't a bucket belonging to it",
+                          block_path, bucket_path);
+                    dangling.push(PathBuf::from(block_path));
+                }
+                Ok(())
+            }),
+            "check_consistency: failed to enumerate map entries");
+
+        for block_path in dangling {
+            trylog!((*map).borrow_mut().unmap_block(block_path.as_os_str()),
+                    "check_consistency: failed to unmap {:?}", block_path);
+        }
+
+        // And every bucket in use has to be reachable from the map, by way of all its parents.
+        for (bucket_path, bucket_parents) in parents {
+            for parent in bucket_parents {
+                if !reachable.contains(&(bucket_path.clone(), parent.clone())) {
+                    warn!("bucket {:?} is an orphan; it was parented to {:?}",
+                          bucket_path, parent);
+                    trylog!((*store).borrow_mut().release_bucket(bucket_path.as_os_str(),
+                                                                 parent.as_os_str()),
+                            "check_consistency: failed to free {:?}", bucket_path);
+                }
+            }
+        }
+
+        Ok(())
+    }
 
+    fn reset(&self) -> io::Result<()> {
+        debug!("reset");
+        // Like in `invalidate_path`, this has to be done before taking the map lock.
+        *self.dirty.lock().unwrap() = DirtyState::default();
+        let mut map = self.map.write().unwrap();
+        let mut store = self.store.write().unwrap();
+
+        // The whole map is about to be cleared, so there's no need to unmap each bucket's parent
+        // individually as they're freed.
+        while (*store).borrow().used_bytes() > 0 {
+            trylog!((*store).borrow_mut().delete_batch(usize::MAX), "reset: error freeing buckets");
+        }
+
+        trylog!((*map).borrow_mut().clear(), "reset: error clearing the map");
+        info!("cache reset");
+        Ok(())
+    }
+
+    fn fetch<F>(&self, path: &OsStr, offset: u64, size: u64, file: &mut F, mtime: i64)
+            -> io::Result<Vec<u8>>
+            where F: Read + Seek
+    {
+        self.check_read_size(path, size)?;
+        let file_size = file.seek(SeekFrom::End(0))?;
+        if let Some(result) = self.fetch_full_hit(path, offset, size, file_size, mtime)? {
+            return Ok(result);
+        }
+        let mut result: Vec<u8> = vec![];
+        self.fetch_blocks(path, offset, size, file, mtime, false, |mut data| {
+            if result.is_empty() {
+                // Saves a copy in the common case of reading exactly one block.
+                result = data;
+            } else {
+                result.reserve((size as usize).saturating_sub(result.len()));
+                result.append(&mut data);
+            }
+            Ok(())
+        })?;
         Ok(result)
     }
 
@@ -370,8 +2048,8 @@ where
         let mut sum = 0;
         let map = self.map.read().unwrap();
         let store = self.store.read().unwrap();
-        if let Err(e) = (*map).borrow().for_each_block_under_path(path, |block_path| {
-            sum += (*store).borrow().get_size(block_path)?;
+        if let Err(e) = (*map).borrow().for_each_block_under_path(path, |_block_path, bucket_path| {
+            sum += (*store).borrow().get_size(bucket_path)?;
             Ok(())
         }) {
             error!("failed to count cached bytes under {:?}: {}", path, e);
@@ -379,4 +2057,146 @@ where
         }
         sum
     }
+    fn count_cached_bytes_in_range(&self, path: &OsStr, offset: u64, len: u64) -> u64 {
+        if len == 0 {
+            return 0;
+        }
+
+        let end = offset.saturating_add(len);
+        let map = self.map.read().unwrap();
+        let store = self.store.read().unwrap();
+
+        let count = || -> io::Result<u64> {
+            let mut sum = 0;
+            for block in offset / self.block_size ..= (end - 1) / self.block_size {
+                // Holes are cached too: reading them doesn't go to the backing file.
+                let block_len = match (*map).borrow().get_block(path, block)? {
+                    Some(bucket_path) => (*store).borrow().get_size(&bucket_path)?,
+                    None => match (*map).borrow().get_hole(path, block)? {
+                        Some(len) => len,
+                        None => continue,
+                    },
+                };
+                let block_start = block * self.block_size;
+                let block_end = block_start + block_len;
+                sum += cmp::min(end, block_end).saturating_sub(cmp::max(offset, block_start));
+            }
+            Ok(sum)
+        };
+
+        match count() {
+            Ok(sum) => sum,
+            Err(e) => {
+                error!("failed to count cached bytes in {:#x} @ {:#x} of {:?}: {}",
+                       len, offset, path, e);
+                0
+            }
+        }
+    }
+}
+
+/// An asynchronous version of `Cache::fetch`, for reading from backing sources which are async
+/// (i.e. over a network).
+///
+/// Reads from the backing source are async. The cache's map and store do blocking I/O on the
+/// cache directory, so all of that is done on tokio's blocking thread pool instead of the
+/// caller's thread; that's why this is implemented for an `Arc` of the cache. Otherwise it
+/// behaves the same as `Cache::fetch`, sharing its fetch loop.
+#[cfg(feature = "async")]
+pub trait AsyncCache {
+    fn fetch_async<'a, F>(&'a self, path: &'a OsStr, offset: u64, size: u64, file: &'a mut F,
+                          mtime: i64)
+        -> impl std::future::Future<Output = io::Result<Vec<u8>>> + 'a
+        where F: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin;
+}
+
+/// Run `f` on tokio's blocking thread pool, for cache work which can't be done without blocking.
+#[cfg(feature = "async")]
+async fn run_blocking<T, F>(f: F) -> io::Result<T>
+    where T: Send + 'static,
+          F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(e) => Err(io::Error::other(e)),
+    }
+}
+
+#[cfg(feature = "async")]
+impl<Map, MapImpl, Store, StoreImpl> AsyncCache
+    for std::sync::Arc<FsCache<Map, MapImpl, Store, StoreImpl>>
+where
+    Map: BorrowMut<MapImpl> + Send + Sync + 'static,
+    MapImpl: CacheBlockMap + Send + Sync + 'static,
+    Store: BorrowMut<StoreImpl> + Send + Sync + 'static,
+    StoreImpl: CacheBucketStore + Send + Sync + 'static,
+{
+    fn fetch_async<'a, F>(&'a self, path: &'a OsStr, offset: u64, size: u64, file: &'a mut F,
+                          mtime: i64)
+        -> impl std::future::Future<Output = io::Result<Vec<u8>>> + 'a
+        where F: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin
+    {
+        use tokio::io::AsyncSeekExt;
+
+        let fetch = async move {
+            let file_size = file.seek(SeekFrom::End(0)).await?;
+            let cache = self.clone();
+            let owned_path = path.to_owned();
+            let fetch = run_blocking(move || {
+                cache.check_read_size(&owned_path, size)?;
+                match cache.fetch_full_hit(&owned_path, offset, size, file_size, mtime)? {
+                    Some(result) => Ok(Err(result)),
+                    None => cache.begin_fetch(&owned_path, offset, size, mtime, file_size, false)
+                        .map(Ok),
+                }
+            }).await?;
+            let mut fetch = match fetch {
+                Ok(fetch) => fetch,
+                Err(full_hit) => return Ok(full_hit),
+            };
+
+            let mut result: Vec<u8> = vec![];
+            let mut read = None;
+            loop {
+                let cache = self.clone();
+                let (next_fetch, step) = run_blocking(move || {
+                    let step = cache.fetch_step(&mut fetch, read)?;
+                    Ok((fetch, step))
+                }).await?;
+                fetch = next_fetch;
+                read = None;
+                match step {
+                    FetchStep::Read { start, len, delay } => {
+                        if let Some(delay) = delay {
+                            tokio::time::sleep(delay).await;
+                        }
+
+                        let permit = self.backing_reads.acquire_async().await;
+                        let started = Instant::now();
+                        let backing_read = self.read_range_async(path, file, start, len);
+                        #[cfg(feature = "tracing")]
+                        let backing_read = tracing::Instrument::instrument(backing_read,
+                            tracing::debug_span!("backing_read", offset = start, bytes = len));
+                        let buf = backing_read.await?;
+                        drop(permit);
+                        read = Some((buf, started.elapsed()));
+                    },
+                    FetchStep::Data(mut data) => {
+                        if result.is_empty() {
+                            result = data;
+                        } else {
+                            result.reserve((size as usize).saturating_sub(result.len()));
+                            result.append(&mut data);
+                        }
+                    },
+                    FetchStep::Done => return Ok(result),
+                }
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let fetch = tracing::Instrument::instrument(fetch,
+            tracing::debug_span!("fetch", path = ?path, offset, size));
+        fetch
+    }
 }
@@ -32,6 +32,14 @@ pub trait PathLinkedList {
     fn insert_as_head<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()>;
     fn insert_as_tail<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()>;
     fn disconnect<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()>;
+    /// Disconnect up to `count` entries from the tail end of the list all at once, and return
+    /// them, tail first.
+    fn detach_tail(&self, count: usize) -> io::Result<Vec<PathBuf>>;
+    /// Insert all of `paths` after the tail, in order, so the last one becomes the new tail.
+    fn extend_tail<T: AsRef<Path> + Debug>(&self, paths: &[T]) -> io::Result<()>;
+    /// Unset the head and tail, leaving the list empty. The entries' own links aren't touched, so
+    /// they have to be re-inserted (which overwrites them) or thrown away.
+    fn clear(&self) -> io::Result<()>;
 }
 
 impl Fsll {
@@ -264,4 +272,81 @@ impl PathLinkedList for Fsll {
 
         Ok(())
     }
+
+    fn detach_tail(&self, count: usize) -> io::Result<Vec<PathBuf>> {
+        debug!("detach_tail: {}", count);
+        let mut detached = vec![];
+        let mut new_tail = self.getlink(&self.base_dir, &self.tail_link)?;
+        while detached.len() < count {
+            let path = match new_tail.take() {
+                Some(path) => path,
+                None => break,
+            };
+            new_tail = self.getlink(&path, Path::new("prev"))?;
+            detached.push(path);
+        }
+
+        if detached.is_empty() {
+            return Ok(detached);
+        }
+
+        // Only the new tail and the ends of the list need updating, not every entry's neighbors.
+        if let Some(ref tail) = new_tail {
+            self.makelink(tail, Path::new("next"), None::<&Path>)?;
+            self.makelink(&self.base_dir, &self.tail_link, Some(tail))?;
+        } else {
+            self.makelink(&self.base_dir, &self.head_link, None::<&Path>)?;
+            self.makelink(&self.base_dir, &self.tail_link, None::<&Path>)?;
+        }
+
+        for path in &detached {
+            self.makelink(path, Path::new("next"), None::<&Path>)?;
+            self.makelink(path, Path::new("prev"), None::<&Path>)?;
+        }
+
+        Ok(detached)
+    }
+
+    fn extend_tail<T: AsRef<Path> + Debug>(&self, paths: &[T]) -> io::Result<()> {
+        let (first, last) = match (paths.first(), paths.last()) {
+            (Some(first), Some(last)) => (first.as_ref(), last.as_ref()),
+            _ => return Ok(()),
+        };
+        debug!("extend_tail: {:?} .. {:?}", first, last);
+
+        let maybe_head = self.getlink(&self.base_dir, &self.head_link)?;
+        let maybe_tail = self.getlink(&self.base_dir, &self.tail_link)?;
+
+        match (maybe_head, maybe_tail) {
+            (Some(ref _head), Some(ref tail)) => {
+                self.makelink(first, Path::new("prev"), Some(tail))?;
+                self.makelink(tail, Path::new("next"), Some(first))?;
+            }
+            (None, None) => {
+                self.makelink(&self.base_dir, &self.head_link, Some(first))?;
+                self.makelink(first, Path::new("prev"), None::<&Path>)?;
+                self.makelink(last, Path::new("next"), None::<&Path>)?;
+            }
+            (Some(head), None) => {
+                error_ret!("list has a head {:?} but no tail!", head);
+            }
+            (None, Some(tail)) => {
+                error_ret!("list has a tail {:?} but no head!", tail);
+            }
+        }
+
+        for pair in paths.windows(2) {
+            self.makelink(&pair[1], Path::new("prev"), Some(&pair[0]))?;
+            self.makelink(&pair[0], Path::new("next"), Some(&pair[1]))?;
+        }
+        self.makelink(&self.base_dir, &self.tail_link, Some(last))?;
+
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        debug!("clear");
+        self.makelink(&self.base_dir, &self.head_link, None::<&Path>)?;
+        self.makelink(&self.base_dir, &self.tail_link, None::<&Path>)
+    }
 }
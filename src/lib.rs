@@ -7,6 +7,15 @@
 
 #[macro_use] extern crate log;
 
+// With the `tracing` feature, enter a span (at debug level) for the rest of the enclosing block.
+// Otherwise it does nothing. Takes the same arguments as tracing's span macros.
+macro_rules! trace_span {
+    ($($args:tt)+) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($args)+).entered();
+    };
+}
+
 pub mod arg_parse;
 pub mod backfs;
 pub mod bucket_store;
@@ -16,6 +25,7 @@ pub mod fsll;
 pub mod osstrextras; // useful for test code
 mod libc_wrappers;
 mod link;
+mod rate_limit;
 mod utils;
 
 // This env variable is set by Cargo
@@ -27,7 +37,16 @@ pub const GIT_REVISION: &str = include_str!(concat!(env!("OUT_DIR"), "/git_rev.t
 #[allow(clippy::unreadable_literal)]
 pub const BUILD_TIME: i64 = include!(concat!(env!("OUT_DIR"), "/build_time.txt"));
 
+// Version of the on-disk cache layout this build reads and writes. Bump it when that changes.
+//   1: buckets with data/parent/next/prev; a map of one symlink per block, plus mtime files
+//   2: hole files, atime and size files, and the optional index-file map (see map_format)
+//   3: the optional slab file bucket store (see store_format)
+//   4: a meta file per file in the map, instead of mtime, atime, size, latency and sample files
+//   5: the optional binary and packed meta files (see meta_format)
+pub const CACHE_FORMAT_VERSION: u32 = 5;
+
 pub const FUSEMT_VERSION: &str = include!(concat!(env!("OUT_DIR"), "/fusemt_ver.txt"));
 pub const FUSER_VERSION: &str = include!(concat!(env!("OUT_DIR"), "/fuser_ver.txt"));
 
-pub use crate::backfs::BackFs;
+pub use crate::backfs::{BackFs, BackfsMetrics};
+pub use crate::fscache::{CacheMetrics, ManifestEntry};
@@ -61,6 +61,12 @@ mod libc {
     }
 }
 
+/// The error for an xattr that isn't there, which Linux calls `ENODATA`.
+#[cfg(target_os = "linux")]
+pub const ENOATTR: libc::c_int = libc::ENODATA;
+#[cfg(not(target_os = "linux"))]
+pub const ENOATTR: libc::c_int = libc::ENOATTR;
+
 pub fn opendir(path: OsString) -> Result<usize, libc::c_int> {
     let path_c = into_cstring!(path, "opendir");
 
@@ -118,6 +124,16 @@ pub fn close(fh: usize) -> Result<(), libc::c_int> {
     }
 }
 
+#[cfg(target_os = "linux")]
+pub fn fallocate(fh: usize, mode: libc::c_int, offset: u64, length: u64) -> Result<(), libc::c_int> {
+    let fd = fh as libc::c_int;
+    if -1 == unsafe { libc::fallocate64(fd, mode, offset as libc::off64_t, length as libc::off64_t) } {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
 pub fn lstat(path: OsString) -> Result<libc::stat64, libc::c_int> {
     let path_c = into_cstring!(path, "lstat");
 
@@ -138,6 +154,108 @@ pub fn fstat(fd: usize) -> Result<libc::stat64, libc::c_int> {
     Ok(buf)
 }
 
+/// Like `lstat`, along with the file's birth time (as seconds and nanoseconds since the epoch) if
+/// its filesystem records one. On Linux, both come from one `statx` call where the kernel has it.
+pub fn lstatx(path: OsString) -> Result<(libc::stat64, Option<(i64, u32)>), libc::c_int> {
+    #[cfg(target_os = "linux")]
+    {
+        let path_c = into_cstring!(path.clone(), "statx");
+        match statx(libc::AT_FDCWD, &path_c, libc::AT_SYMLINK_NOFOLLOW) {
+            Err(libc::ENOSYS) => (),
+            result => return result,
+        }
+    }
+    lstat(path).map(|stat| (stat, birthtime(&stat)))
+}
+
+/// Like `lstatx`, for an open file.
+pub fn fstatx(fd: usize) -> Result<(libc::stat64, Option<(i64, u32)>), libc::c_int> {
+    #[cfg(target_os = "linux")]
+    {
+        match statx(fd as libc::c_int, &CString::default(), libc::AT_EMPTY_PATH) {
+            Err(libc::ENOSYS) => (),
+            result => return result,
+        }
+    }
+    fstat(fd).map(|stat| (stat, birthtime(&stat)))
+}
+
+// Linux's stat doesn't have the birth time, and kernels before 4.11 don't have statx.
+#[cfg(not(target_os = "macos"))]
+fn birthtime(_stat: &libc::stat64) -> Option<(i64, u32)> {
+    None
+}
+
+#[cfg(target_os = "macos")]
+fn birthtime(stat: &libc::stat64) -> Option<(i64, u32)> {
+    Some((stat.st_birthtime as i64, stat.st_birthtime_nsec as u32))
+}
+
+#[cfg(target_os = "linux")]
+fn statx(dirfd: libc::c_int, path: &std::ffi::CStr, flags: libc::c_int)
+    -> Result<(libc::stat64, Option<(i64, u32)>), libc::c_int>
+{
+    let mut buf: libc::statx = unsafe { mem::zeroed() };
+    if -1 == unsafe {
+        libc::statx(dirfd, path.as_ptr(), flags | libc::AT_STATX_SYNC_AS_STAT,
+                    libc::STATX_BASIC_STATS | libc::STATX_BTIME, &mut buf)
+    } {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    let mut stat: libc::stat64 = unsafe { mem::zeroed() };
+    stat.st_dev = libc::makedev(buf.stx_dev_major, buf.stx_dev_minor);
+    stat.st_ino = buf.stx_ino as _;
+    stat.st_mode = buf.stx_mode as _;
+    stat.st_nlink = buf.stx_nlink as _;
+    stat.st_uid = buf.stx_uid;
+    stat.st_gid = buf.stx_gid;
+    stat.st_rdev = libc::makedev(buf.stx_rdev_major, buf.stx_rdev_minor);
+    stat.st_size = buf.stx_size as _;
+    stat.st_blksize = buf.stx_blksize as _;
+    stat.st_blocks = buf.stx_blocks as _;
+    stat.st_atime = buf.stx_atime.tv_sec as _;
+    stat.st_atime_nsec = buf.stx_atime.tv_nsec as _;
+    stat.st_mtime = buf.stx_mtime.tv_sec as _;
+    stat.st_mtime_nsec = buf.stx_mtime.tv_nsec as _;
+    stat.st_ctime = buf.stx_ctime.tv_sec as _;
+    stat.st_ctime_nsec = buf.stx_ctime.tv_nsec as _;
+
+    // Filesystems which don't have it leave the bit unset instead of failing.
+    let btime = if buf.stx_mask & libc::STATX_BTIME != 0 {
+        Some((buf.stx_btime.tv_sec, buf.stx_btime.tv_nsec))
+    } else {
+        None
+    };
+    Ok((stat, btime))
+}
+
+/// The user ID of the process on the other end of a connected Unix socket.
+#[cfg(target_os = "linux")]
+pub fn peer_uid(fd: usize) -> Result<u32, libc::c_int> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::ucred>() as libc::socklen_t;
+    if -1 == unsafe {
+        libc::getsockopt(fd as libc::c_int, libc::SOL_SOCKET, libc::SO_PEERCRED,
+                         &mut cred as *mut libc::ucred as *mut libc::c_void, &mut len)
+    } {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    Ok(cred.uid)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn peer_uid(fd: usize) -> Result<u32, libc::c_int> {
+    let mut uid: libc::uid_t = 0;
+    let mut gid: libc::gid_t = 0;
+    if -1 == unsafe { libc::getpeereid(fd as libc::c_int, &mut uid, &mut gid) } {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    Ok(uid)
+}
+
 pub fn llistxattr(path: OsString, buf: &mut [u8]) -> Result<usize, libc::c_int> {
     let path_c = into_cstring!(path, "llistxattr");
 
@@ -165,3 +283,54 @@ pub fn lgetxattr(path: OsString, name: OsString, buf: &mut [MaybeUninit<u8>]) ->
         nbytes => Ok(nbytes as usize),
     }
 }
+
+#[test]
+fn test_fstat_matches_lstat() {
+    use std::fs::{self, File};
+    use std::os::unix::io::AsRawFd;
+
+    let path = std::env::temp_dir().join(format!("backfs-test-{}-fstat", std::process::id()));
+    fs::write(&path, b"twelve bytes").unwrap();
+
+    let file = File::open(&path).unwrap();
+    let by_fd = fstat(file.as_raw_fd() as usize).unwrap();
+    let by_path = lstat(path.clone().into_os_string()).unwrap();
+    assert_eq!(by_fd.st_size, 12);
+    assert_eq!(by_fd.st_size, by_path.st_size);
+    assert_eq!(by_fd.st_ino, by_path.st_ino);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_statx() {
+    use std::fs::{self, File};
+    use std::os::unix::io::AsRawFd;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let path = std::env::temp_dir().join(format!("backfs-test-{}-statx", std::process::id()));
+    fs::write(&path, b"hello").unwrap();
+
+    let file = File::open(&path).unwrap();
+    let (by_fd, fd_btime) = fstatx(file.as_raw_fd() as usize).unwrap();
+    let (by_path, btime) = lstatx(path.clone().into_os_string()).unwrap();
+    let stat = lstat(path.clone().into_os_string()).unwrap();
+    for statx in &[by_fd, by_path] {
+        assert_eq!(statx.st_size, 5);
+        assert_eq!((statx.st_dev, statx.st_ino), (stat.st_dev, stat.st_ino));
+        assert_eq!((statx.st_mode, statx.st_nlink), (stat.st_mode, stat.st_nlink));
+        assert_eq!((statx.st_uid, statx.st_gid), (stat.st_uid, stat.st_gid));
+        assert_eq!(statx.st_blocks, stat.st_blocks);
+        assert_eq!((statx.st_mtime, statx.st_mtime_nsec), (stat.st_mtime, stat.st_mtime_nsec));
+        assert_eq!((statx.st_ctime, statx.st_ctime_nsec), (stat.st_ctime, stat.st_ctime_nsec));
+    }
+    assert_eq!(fd_btime, btime);
+    // Not every filesystem records it, but if this one does, the file was just made.
+    if let Some((secs, _nanos)) = btime {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        assert!((now - 60 ..= now).contains(&secs), "{} isn't about {}", secs, now);
+    }
+
+    assert_eq!(lstatx(path.join("nope").into_os_string()).err(), Some(libc::ENOTDIR));
+    fs::remove_file(&path).unwrap();
+}
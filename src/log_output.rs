@@ -4,12 +4,15 @@
 //
 
 use std::boxed::Box;
+#[cfg(feature = "syslog")]
 use std::sync::Mutex;
+#[cfg(feature = "syslog")]
 use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
 
 struct Log {
     global_filter: log::LevelFilter,
     target_filter: Vec<(String, log::LevelFilter)>,
+    #[cfg(feature = "syslog")]
     syslog: Option<Mutex<Logger<LoggerBackend, Formatter3164>>>,
 }
 
@@ -20,33 +23,45 @@ pub fn init(global_filter: log::LevelFilter,
 {
     log::set_max_level(global_filter);
 
-    let formatter = Formatter3164 {
-        facility: Facility::LOG_USER,
-        process: "backfs".into(),
-        hostname: None,
-        pid: 0,
-    };
-
+    #[cfg(feature = "syslog")]
     let syslog = if use_syslog {
-        match syslog::unix(formatter) {
-            Ok(writer) => Some(Mutex::new(writer)),
-            Err(e) => {
-                println!("Error opening connection to syslog: {}", e);
-                println!("Logging disabled!");
-                None
-            }
-        }
+        open_syslog()
     } else {
         None
     };
 
+    #[cfg(not(feature = "syslog"))]
+    if use_syslog {
+        println!("BackFS was built without syslog support; logging to stdout instead.");
+    }
+
     log::set_boxed_logger(Box::new(Log {
         global_filter,
         target_filter,
+        #[cfg(feature = "syslog")]
         syslog,
     }))
 }
 
+#[cfg(feature = "syslog")]
+fn open_syslog() -> Option<Mutex<Logger<LoggerBackend, Formatter3164>>> {
+    let formatter = Formatter3164 {
+        facility: Facility::LOG_USER,
+        process: "backfs".into(),
+        hostname: None,
+        pid: 0,
+    };
+
+    match syslog::unix(formatter) {
+        Ok(writer) => Some(Mutex::new(writer)),
+        Err(e) => {
+            println!("Error opening connection to syslog: {}", e);
+            println!("Logging disabled!");
+            None
+        }
+    }
+}
+
 impl log::Log for Log {
     fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
         if self.global_filter < metadata.level() {
@@ -64,6 +79,7 @@ impl log::Log for Log {
 
     fn log(&self, record: &log::Record<'_>) {
         if self.enabled(record.metadata()) {
+            #[cfg(feature = "syslog")]
             if let Some(ref syslog) = self.syslog {
                 let mut syslog = syslog.lock().unwrap();
                 let msg = format!("{}: {}", record.target(), record.args());
@@ -75,9 +91,10 @@ impl log::Log for Log {
                     Debug => syslog.debug(msg),
                     Trace => syslog.debug(msg),
                 };
-            } else {
-                println!("{}: {}: {}", record.target(), record.level(), record.args());
+                return;
             }
+
+            println!("{}: {}: {}", record.target(), record.level(), record.args());
         }
     }
 
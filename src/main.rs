@@ -7,6 +7,9 @@
 
 use backfs::BackFs;
 use backfs::arg_parse::{self, BackfsSettings};
+use backfs::block_map::{MapFormat, MetaFormat};
+use backfs::bucket_store::StoreFormat;
+use backfs::fscache::MtimePolicy;
 use backfs::osstrextras::OsStrExtras;
 
 use fuse_mt::{FuseMT, FilesystemMT};
@@ -16,8 +19,9 @@ mod log_output;
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::ops::Deref;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::process;
 
@@ -59,9 +63,94 @@ fn mount_and_exit<FS, P>(fs: FS, num_threads: usize, path: &P, options: &[&OsStr
     process::exit(0);
 }
 
+/// Send a command to a running BackFS through the control file under `mount_point`.
+fn control_and_exit(mount_point: &OsStr, command: &[OsString]) -> ! {
+    if command.is_empty() || command.len() > 2 {
+        println!("{}", arg_parse::USAGE);
+        process::exit(1);
+    }
+
+    // The control file's binary format, so the argument can be any path at all. It has to be
+    // sent in a single write.
+    let mut line = vec![0u8];
+    for field in command {
+        line.extend_from_slice(field.as_bytes());
+        line.push(0);
+    }
+    if command.len() == 1 {
+        line.push(0);
+    }
+
+    let path = Path::new(mount_point).join(".backfs_control");
+    let result = fs::OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&line));
+    if let Err(e) = result {
+        eprintln!("error sending command to {:?}: {}", path, e);
+        process::exit(1);
+    }
+    process::exit(0);
+}
+
+/// Print a report on a cache directory, without mounting or changing it.
+fn inspect_and_exit(cache_dir: &OsStr) -> ! {
+    match backfs::backfs::inspect_cache(Path::new(cache_dir)) {
+        Ok(report) => {
+            print!("{}", report);
+            process::exit(0);
+        },
+        Err(e) => {
+            eprintln!("error inspecting cache: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_help() {
+    println!("{}", arg_parse::USAGE);
+
+    if cfg!(not(target_os = "macos")) {
+        println!("\nFUSE options:");
+
+        extern "C" {
+            fn fuse_lowlevel_help();
+        }
+
+        unsafe { fuse_lowlevel_help() };
+    }
+}
+
 fn main() {
     let args = env::args_os().collect::<Vec<OsString>>();
-    let mut settings = BackfsSettings::parse(&args);
+
+    if args.get(1).map(OsString::as_os_str) == Some(OsStr::new("--inspect")) {
+        match args.get(2) {
+            Some(cache_dir) if args.len() == 3 => inspect_and_exit(cache_dir),
+            _ => {
+                println!("{}", arg_parse::USAGE);
+                process::exit(1);
+            }
+        }
+    }
+
+    if args.get(1).map(OsString::as_os_str) == Some(OsStr::new("--control")) {
+        match args.get(2) {
+            Some(mount_point) => control_and_exit(mount_point, &args[3..]),
+            None => {
+                println!("{}", arg_parse::USAGE);
+                process::exit(1);
+            }
+        }
+    }
+    let mut settings = match BackfsSettings::parse(&args) {
+        Ok(settings) => settings,
+        Err(e) => {
+            println!("{}", e);
+            print_help();
+            process::exit(1);
+        }
+    };
 
     if settings.verbose {
         println!("{:?}", settings);
@@ -69,21 +158,9 @@ fn main() {
 
     if settings.help || settings.version {
         if settings.help {
-            println!("{}", arg_parse::USAGE);
-
-            if cfg!(not(target_os = "macos")) {
-                println!("\nFUSE options:");
-
-                extern "C" {
-                    fn fuse_lowlevel_help();
-                }
-
-                unsafe { fuse_lowlevel_help() };
-            }
+            print_help();
         } else if settings.version {
-            println!("BackFS version: {} {}", backfs::VERSION, backfs::GIT_REVISION);
-            println!("FuseMT version: {}", backfs::FUSEMT_VERSION);
-            println!("fuser version: {}", backfs::FUSER_VERSION);
+            print!("{}", backfs::backfs::version_string());
         }
 
         process::exit(1);
@@ -93,11 +170,61 @@ fn main() {
             process::exit(-1);
         }
 
+        if settings.read_size != 0 && settings.read_size.checked_rem(settings.block_size) != Some(0) {
+            println!("Invalid options: the read size must be a multiple of the block size.");
+            process::exit(-1);
+        }
+
+        for (extension, policy) in &settings.policies {
+            if let Some(read_size) = policy.read_size {
+                if read_size == 0 || read_size.checked_rem(settings.block_size) != Some(0) {
+                    println!("Invalid options: the read size for .{} must be a multiple of the block size.",
+                             extension);
+                    process::exit(-1);
+                }
+            }
+        }
+
         if settings.cache.is_empty() {
             println!("Invalid options: cache directory not specified. Use the '-o cache=<directory>' option.");
             process::exit(-1);
         }
 
+        if let Err(e) = settings.check_mount_point() {
+            println!("Invalid options: {}.", e);
+            process::exit(-1);
+        }
+
+        if let Err(e) = settings.check_writeback_cache() {
+            println!("Invalid options: {}.", e);
+            process::exit(-1);
+        }
+
+        if settings.dedup && settings.store_format == StoreFormat::Slab {
+            println!("Invalid options: dedup can't be used with the slabfile store.");
+            process::exit(-1);
+        }
+
+        if settings.revalidate_sample && settings.mtime_policy == MtimePolicy::Ignore {
+            println!("Invalid options: revalidate=sample can't be used with assume_immutable.");
+            process::exit(-1);
+        }
+
+        if settings.stale_while_revalidate != 0 && settings.mtime_policy == MtimePolicy::Ignore {
+            println!("Invalid options: stale_while_revalidate can't be used with assume_immutable.");
+            process::exit(-1);
+        }
+
+        if settings.max_buckets != 0 && settings.store_format == StoreFormat::Slab {
+            println!("Invalid options: max_buckets can't be used with the slabfile store.");
+            process::exit(-1);
+        }
+
+        if settings.mem_cache != 0 && settings.store_format == StoreFormat::Slab {
+            println!("Invalid options: mem_cache can't be used with the slabfile store.");
+            process::exit(-1);
+        }
+
         if settings.foreground {
             // have FUSE automatically unmount when the process exits.
             settings.fuse_options.push(OsString::from("auto_unmount"));
@@ -109,13 +236,20 @@ fn main() {
                     process::exit(1);
                 }
             };
-            settings.backing_fs = match fs::canonicalize(settings.backing_fs) {
-                Ok(pathbuf) => pathbuf.into_os_string(),
-                Err(e) => {
-                    println!("error canonicalizing backing filesystem path: {}", e);
-                    process::exit(1);
+            let mut backing_fs = OsString::new();
+            for layer in settings.backing_layers() {
+                if !backing_fs.is_empty() {
+                    backing_fs.push(":");
                 }
-            };
+                match fs::canonicalize(&layer) {
+                    Ok(pathbuf) => backing_fs.push(pathbuf),
+                    Err(e) => {
+                        println!("error canonicalizing backing filesystem path {:?}: {}", layer, e);
+                        process::exit(1);
+                    }
+                }
+            }
+            settings.backing_fs = backing_fs;
             settings.cache = match fs::canonicalize(settings.cache) {
                 Ok(pathbuf) => pathbuf.into_os_string(),
                 Err(e) => {
@@ -125,6 +259,48 @@ fn main() {
             };
         }
 
+        match MapFormat::of_cache(&settings.cache) {
+            Ok(Some(format)) if format != settings.map_format => {
+                println!("Invalid options: the cache uses the '{}' map format, not '{}'. \
+                          Use a new cache directory to change formats.",
+                         format.name(), settings.map_format.name());
+                process::exit(-1);
+            },
+            Ok(_) => (),
+            Err(e) => {
+                println!("error reading the cache's map format: {}", e);
+                process::exit(1);
+            }
+        }
+
+        match MetaFormat::of_cache(&settings.cache) {
+            Ok(Some(format)) if format != settings.meta_format => {
+                println!("Invalid options: the cache uses the '{}' meta format, not '{}'. \
+                          Use a new cache directory to change formats.",
+                         format.name(), settings.meta_format.name());
+                process::exit(-1);
+            },
+            Ok(_) => (),
+            Err(e) => {
+                println!("error reading the cache's meta format: {}", e);
+                process::exit(1);
+            }
+        }
+
+        match StoreFormat::of_cache(&settings.cache) {
+            Ok(Some(format)) if format != settings.store_format => {
+                println!("Invalid options: the cache uses the '{}' store, not '{}'. \
+                          Use a new cache directory to change stores.",
+                         format.name(), settings.store_format.name());
+                process::exit(-1);
+            },
+            Ok(_) => (),
+            Err(e) => {
+                println!("error reading the cache's store format: {}", e);
+                process::exit(1);
+            }
+        }
+
         if settings.verbose {
             // FSLL debug messages aren't very interesting most of the time.
             let filters = vec![("backfs::fsll".to_string(), log::LevelFilter::Warn)];
@@ -140,6 +316,25 @@ fn main() {
             eprintln!("# ENABLING EXPERIMENTAL R/W MODE!! #");
             eprintln!("#                                  #");
             eprintln!("####################################");
+
+            if settings.uses_writeback_cache() {
+                // The kernel holds on to writes and reports its own sizes and mtimes for files
+                // written through the mount until it flushes them.
+                eprintln!("Warning: with '-o writeback_cache', the kernel's cached sizes and mtimes \
+                    are used instead of the backing filesystem's, so changes made to files outside \
+                    of BackFS may not be seen until their attributes time out.");
+            }
+        }
+
+        if settings.allows_other_users() {
+            // BackFS reads the backing filesystem as its own user, so unless the kernel checks
+            // permissions, other users can read anything that user can.
+            eprintln!("Warning: other users can use this mount. Only uid {} can use .backfs_control \
+                and the cache tree.", settings.control_uid());
+            if !settings.fuse_options.iter().any(|option| option == "default_permissions") {
+                eprintln!("Warning: without '-o default_permissions', they can read every file \
+                    BackFS's user can, whatever the files' permissions.");
+            }
         }
 
         if !settings.foreground {
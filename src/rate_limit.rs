@@ -0,0 +1,127 @@
+// BackFS Rate Limiter
+//
+// Copyright 2016-2021 by William R. Fraser
+//
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// A token bucket, for limiting the rate of bytes read to a fixed number per second. Up to one
+/// second's worth can be used in a burst.
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        assert!(bytes_per_sec > 0);
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes` out of the bucket, and return how long the caller has to wait before it can
+    /// use them. The bucket is allowed to go negative, so that concurrent callers queue up behind
+    /// each other instead of all waking up at once.
+    ///
+    /// The caller should do the waiting itself, without holding any locks.
+    pub fn reserve(&self, bytes: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * self.bytes_per_sec;
+        bucket.tokens = (bucket.tokens + refill).min(self.bytes_per_sec);
+        bucket.last_refill = now;
+
+        bucket.tokens -= bytes as f64;
+        if bucket.tokens >= 0. {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+        }
+    }
+}
+
+/// Limits how many of something can be going on at once, across all threads.
+pub struct ConcurrencyLimiter {
+    max: usize,
+    in_flight: Mutex<usize>,
+    released: Condvar,
+    #[cfg(feature = "async")]
+    released_async: tokio::sync::Notify,
+}
+
+/// Held while doing the limited thing; dropping it makes room for the next.
+pub struct Permit<'a> {
+    limiter: &'a ConcurrencyLimiter,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max: usize) -> Self {
+        assert!(max > 0);
+        Self {
+            max,
+            in_flight: Mutex::new(0),
+            released: Condvar::new(),
+            #[cfg(feature = "async")]
+            released_async: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Wait until fewer than the maximum are in flight, and then count one more.
+    ///
+    /// The caller mustn't hold any locks that the permit holders might need.
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut in_flight = self.released.wait_while(self.in_flight.lock().unwrap(),
+                                                      |in_flight| *in_flight >= self.max)
+            .unwrap();
+        *in_flight += 1;
+        Permit { limiter: self }
+    }
+
+    /// Like `acquire`, for async callers: the waiting doesn't block the thread.
+    #[cfg(feature = "async")]
+    pub async fn acquire_async(&self) -> Permit<'_> {
+        loop {
+            // Waiting starts before trying, so that a release in between isn't missed.
+            let released = self.released_async.notified();
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+            released.await;
+        }
+    }
+
+    /// Like `acquire`, but returns None instead of waiting.
+    #[cfg_attr(not(feature = "async"), allow(dead_code))]
+    pub fn try_acquire(&self) -> Option<Permit<'_>> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if *in_flight >= self.max {
+            return None;
+        }
+        *in_flight += 1;
+        Some(Permit { limiter: self })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.lock().unwrap()
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.limiter.in_flight.lock().unwrap() -= 1;
+        self.limiter.released.notify_one();
+        #[cfg(feature = "async")]
+        self.limiter.released_async.notify_waiters();
+    }
+}
@@ -36,7 +36,7 @@ pub fn read_number_file<N, P>(path: &P, default: Option<N>) -> io::Result<Option
                 .read(true)
                 .write(true)
                 .create(true)
-                .truncate(true)
+                .truncate(false)
                 .open(path)
                 .map_err(|e| {
                     error!("read_number_file: error creating file {:?}: {}", path, e);
@@ -143,3 +143,19 @@ pub fn create_dir_and_check_access<T>(path: T) -> io::Result<()>
 
     Ok(())
 }
+
+/// Format a Unix timestamp as a date and time in UTC, like "2021-03-14 15:09:26 UTC".
+pub fn format_utc_time(time: i64) -> String {
+    let mut tm: libc::tm = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::gmtime_r(&(time as libc::time_t), &mut tm) }.is_null() {
+        return format!("{} (seconds since the epoch)", time);
+    }
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+#[test]
+fn test_format_utc_time() {
+    assert_eq!(format_utc_time(0), "1970-01-01 00:00:00 UTC");
+    assert_eq!(format_utc_time(1_615_734_566), "2021-03-14 15:09:26 UTC");
+}
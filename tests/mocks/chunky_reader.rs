@@ -0,0 +1,44 @@
+// BackFS Filesystem Tests :: Backing File With Short Reads
+//
+// Copyright (c) 2016-2021 by William R. Fraser
+//
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+/// A backing file which returns at most `chunk` bytes per read, and has every other read
+/// interrupted, like a pipe or a network filesystem being sent signals.
+pub struct ChunkyReader {
+    pub data: Cursor<Vec<u8>>,
+    pub chunk: usize,
+    pub interruptions: u32,
+    reads: u32,
+}
+
+impl ChunkyReader {
+    pub fn new(data: &[u8], chunk: usize) -> Self {
+        Self {
+            data: Cursor::new(data.to_vec()),
+            chunk,
+            interruptions: 0,
+            reads: 0,
+        }
+    }
+}
+
+impl Read for ChunkyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        if self.reads % 2 == 1 {
+            self.interruptions += 1;
+            return Err(io::Error::from(io::ErrorKind::Interrupted));
+        }
+        let len = buf.len().min(self.chunk);
+        self.data.read(&mut buf[.. len])
+    }
+}
+
+impl Seek for ChunkyReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
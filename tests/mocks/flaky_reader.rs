@@ -0,0 +1,43 @@
+// BackFS Filesystem Tests :: Unreliable Backing File
+//
+// Copyright (c) 2016-2021 by William R. Fraser
+//
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+/// A backing file whose next `failures` reads fail with the given errno, like a flaky network
+/// filesystem. Seeks always work.
+pub struct FlakyReader {
+    pub data: Cursor<Vec<u8>>,
+    pub failures: u32,
+    pub errno: i32,
+    pub reads: u32,
+}
+
+impl FlakyReader {
+    pub fn new(data: &[u8], failures: u32, errno: i32) -> Self {
+        Self {
+            data: Cursor::new(data.to_vec()),
+            failures,
+            errno,
+            reads: 0,
+        }
+    }
+}
+
+impl Read for FlakyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(io::Error::from_raw_os_error(self.errno));
+        }
+        self.data.read(buf)
+    }
+}
+
+impl Seek for FlakyReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
@@ -1,3 +1,8 @@
+pub mod chunky_reader;
+pub mod flaky_reader;
+pub mod recording_reader;
+pub mod slow_reader;
 pub mod test_block_map;
 pub mod test_bucket_store;
+pub mod test_path_list;
 pub mod sneaky;
@@ -0,0 +1,35 @@
+// BackFS Filesystem Tests :: Backing File Which Records Reads
+//
+// Copyright (c) 2016-2021 by William R. Fraser
+//
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+/// A backing file which keeps track of where each read was, and how much it asked for.
+pub struct RecordingReader {
+    pub data: Cursor<Vec<u8>>,
+    /// The offset and length of each read.
+    pub reads: Vec<(u64, usize)>,
+}
+
+impl RecordingReader {
+    pub fn new(data: &[u8]) -> Self {
+        Self {
+            data: Cursor::new(data.to_vec()),
+            reads: vec![],
+        }
+    }
+}
+
+impl Read for RecordingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.push((self.data.position(), buf.len()));
+        self.data.read(buf)
+    }
+}
+
+impl Seek for RecordingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
@@ -0,0 +1,113 @@
+// BackFS Filesystem Tests :: Slow Backing File
+//
+// Copyright (c) 2016-2021 by William R. Fraser
+//
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// A backing file where every read takes at least `delay`, like one on a slow network filesystem.
+pub struct SlowReader {
+    pub data: Cursor<Vec<u8>>,
+    pub delay: Duration,
+    pub overlap: Option<Arc<ReadOverlap>>,
+    /// The offset and length of each read.
+    pub reads: Vec<(u64, usize)>,
+    // The delay of an async read which is under way.
+    #[cfg(feature = "async")]
+    sleep: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+/// Shared by `SlowReader`s to count how many of them are reading at once.
+#[derive(Default)]
+pub struct ReadOverlap {
+    now: AtomicUsize,
+    /// The most there have been reading at once.
+    pub max: AtomicUsize,
+}
+
+impl SlowReader {
+    pub fn new(data: &[u8], delay: Duration) -> Self {
+        Self {
+            data: Cursor::new(data.to_vec()),
+            delay,
+            overlap: None,
+            reads: vec![],
+            #[cfg(feature = "async")]
+            sleep: None,
+        }
+    }
+
+    pub fn with_overlap(mut self, overlap: Arc<ReadOverlap>) -> Self {
+        self.overlap = Some(overlap);
+        self
+    }
+}
+
+impl Read for SlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(ref overlap) = self.overlap {
+            let now = overlap.now.fetch_add(1, Ordering::SeqCst) + 1;
+            overlap.max.fetch_max(now, Ordering::SeqCst);
+        }
+        thread::sleep(self.delay);
+        if let Some(ref overlap) = self.overlap {
+            overlap.now.fetch_sub(1, Ordering::SeqCst);
+        }
+        self.reads.push((self.data.position(), buf.len()));
+        self.data.read(buf)
+    }
+}
+
+impl Seek for SlowReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+// Async reads wait without blocking the thread, so that many can be in progress on one.
+#[cfg(feature = "async")]
+impl tokio::io::AsyncRead for SlowReader {
+    fn poll_read(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>,
+                 buf: &mut tokio::io::ReadBuf<'_>)
+        -> std::task::Poll<io::Result<()>>
+    {
+        use std::future::Future;
+
+        let this = &mut *self;
+        if this.sleep.is_none() {
+            if let Some(ref overlap) = this.overlap {
+                let now = overlap.now.fetch_add(1, Ordering::SeqCst) + 1;
+                overlap.max.fetch_max(now, Ordering::SeqCst);
+            }
+            this.sleep = Some(Box::pin(tokio::time::sleep(this.delay)));
+        }
+        if this.sleep.as_mut().unwrap().as_mut().poll(cx).is_pending() {
+            return std::task::Poll::Pending;
+        }
+        this.sleep = None;
+        if let Some(ref overlap) = this.overlap {
+            overlap.now.fetch_sub(1, Ordering::SeqCst);
+        }
+        this.reads.push((this.data.position(), buf.remaining()));
+        let n = this.data.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl tokio::io::AsyncSeek for SlowReader {
+    fn start_seek(mut self: std::pin::Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+        std::pin::Pin::new(&mut self.data).start_seek(pos)
+    }
+
+    fn poll_complete(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>)
+        -> std::task::Poll<io::Result<u64>>
+    {
+        std::pin::Pin::new(&mut self.data).poll_complete(cx)
+    }
+}
@@ -5,16 +5,16 @@
 
 use std::borrow::{Borrow, BorrowMut};
 use std::cell::UnsafeCell;
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub struct Sneaky<T> {
-    inner: Rc<UnsafeCell<T>>
+    inner: Arc<UnsafeCell<T>>
 }
 
 impl<T> Sneaky<T> {
     pub fn new(inner: T) -> Sneaky<T> {
         Sneaky {
-            inner: Rc::new(UnsafeCell::new(inner))
+            inner: Arc::new(UnsafeCell::new(inner))
         }
     }
 
@@ -25,6 +25,11 @@ impl<T> Sneaky<T> {
     }
 }
 
+// For caches which go across threads, like `AsyncCache` ones. Tests peek in only while nothing
+// else is using them.
+unsafe impl<T: Send> Send for Sneaky<T> {}
+unsafe impl<T: Send + Sync> Sync for Sneaky<T> {}
+
 impl<T> Borrow<T> for Sneaky<T> {
     fn borrow(&self) -> &T {
         unsafe { &*self.inner.get() }
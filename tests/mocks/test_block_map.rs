@@ -14,7 +14,12 @@ use backfs::osstrextras::OsStrExtras;
 
 pub struct TestMapData {
     pub mtime: i64,
+    pub atime: Option<i64>,
+    pub size: Option<u64>,
+    pub latency: Option<u64>,
+    pub sample: Option<u64>,
     pub blocks: BTreeMap<u64, OsString>,
+    pub holes: BTreeMap<u64, u64>,
 }
 
 #[derive(Default)]
@@ -23,28 +28,57 @@ pub struct TestMap {
 }
 
 impl CacheBlockMap for TestMap {
-    fn check_file_mtime(&self, path: &OsStr, mtime: i64) -> io::Result<CacheBlockMapFileResult> {
-        match self.map.get(path) {
-            Some(entry) => {
-                if entry.mtime == mtime {
-                    Ok(CacheBlockMapFileResult::Current)
-                } else {
-                    Ok(CacheBlockMapFileResult::Stale)
-                }
-            },
-            None => Ok(CacheBlockMapFileResult::NotPresent)
+    fn init(&mut self, _block_size: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_file_meta(&self, path: &OsStr) -> io::Result<FileMeta> {
+        let mut meta = FileMeta::default();
+        if let Some(entry) = self.map.get(path) {
+            meta.mtime = Some(entry.mtime);
+            meta.atime = entry.atime;
+            meta.size = entry.size;
+            meta.latency = entry.latency;
+            meta.sample = entry.sample;
         }
+        Ok(meta)
     }
 
-    fn set_file_mtime(&mut self, path: &OsStr, mtime: i64) -> io::Result<()> {
-        self.map.entry(path.to_os_string())
+    fn write_file_meta(&mut self, path: &OsStr, meta: &FileMeta) -> io::Result<()> {
+        // Checking the file mtime is what creates the entry.
+        let mtime = meta.mtime.expect("you can't set metadata before checking the file mtime!");
+        let entry = self.map.entry(path.to_os_string())
                            .or_insert(TestMapData{
                                mtime,
+                               atime: None,
+                               size: None,
+                               latency: None,
+                               sample: None,
                                blocks: BTreeMap::new(),
+                               holes: BTreeMap::new(),
                            });
+        entry.mtime = mtime;
+        entry.atime = meta.atime;
+        entry.size = meta.size;
+        entry.latency = meta.latency;
+        entry.sample = meta.sample;
         Ok(())
     }
 
+    fn get_block_file_latency(&self, block_path: &OsStr) -> io::Result<Option<u64>> {
+        let parts: Vec<&[u8]> = block_path.as_bytes().rsplitn(2, |byte| *byte == b'/').collect();
+        self.get_file_latency(OsStr::from_bytes(parts[1]))
+    }
+
+    fn get_blocks(&self, path: &OsStr) -> io::Result<Vec<u64>> {
+        let mut blocks: Vec<u64> = match self.map.get(path) {
+            Some(entry) => entry.blocks.keys().chain(entry.holes.keys()).cloned().collect(),
+            None => vec![],
+        };
+        blocks.sort_unstable();
+        Ok(blocks)
+    }
+
     fn get_block(&self, path: &OsStr, block: u64) -> io::Result<Option<OsString>> {
         match self.map.get(path) {
             Some(entry) => {
@@ -75,10 +109,15 @@ impl CacheBlockMap for TestMap {
         OsString::from_vec(bytes)
     }
 
-    fn invalidate_path<F>(&mut self, _path: &OsStr, _f: F) -> io::Result<()>
-            where F: FnMut(&OsStr) -> io::Result<()> {
-        // TODO
-        unimplemented!();
+    fn invalidate_path<F>(&mut self, path: &OsStr, mut f: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        // Only whole files are supported, not directories.
+        if let Some(entry) = self.map.remove(path) {
+            for (block, bucket_path) in entry.blocks {
+                f(&self.get_block_path(path, block), &bucket_path)?;
+            }
+        }
+        Ok(())
     }
 
     /*
@@ -90,8 +129,8 @@ impl CacheBlockMap for TestMap {
 
     fn unmap_block(&mut self, block_path: &OsStr) -> io::Result<()> {
         let parts: Vec<&[u8]> = block_path.as_bytes().rsplitn(2, |byte| *byte == b'/').collect();
-        let path = OsStr::from_bytes(parts[0]);
-        let block: u64 = str::from_utf8(&parts[0][1..]).unwrap().parse().unwrap();
+        let path = OsStr::from_bytes(parts[1]);
+        let block: u64 = str::from_utf8(parts[0]).unwrap().parse().unwrap();
         let file = self.map.get_mut(path).unwrap();
         file.blocks.remove(&block);
         Ok(())
@@ -108,17 +147,68 @@ impl CacheBlockMap for TestMap {
     }
 
     fn for_each_block_under_path<F>(&self, path: &OsStr, mut handler: F) -> io::Result<()>
-            where F: FnMut(&OsStr) -> io::Result<()> {
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
         let mut check_path = path.to_owned();
         check_path.push("/");
         for (cached_path, map_data) in &self.map {
             if cached_path == path || cached_path.starts_with(&check_path) {
-                for block in map_data.blocks.keys() {
-                    let block_path = self.get_block_path(cached_path, *block);
-                    handler(&block_path)?;
+                for (block, bucket_path) in &map_data.blocks {
+                    handler(&self.get_block_path(cached_path, *block), bucket_path)?;
                 }
             }
         }
         Ok(())
     }
+
+    fn for_each_block<F>(&self, mut handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr, &OsStr) -> io::Result<()> {
+        for (path, map_data) in &self.map {
+            for (block, bucket_path) in &map_data.blocks {
+                handler(&self.get_block_path(path, *block), bucket_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each_file<F>(&self, mut handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr, i64, &[u64]) -> io::Result<()> {
+        for (path, map_data) in &self.map {
+            handler(path, map_data.mtime, &self.get_blocks(path)?)?;
+        }
+        Ok(())
+    }
+
+    fn for_each_cached_file<F>(&self, mut handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        for path in self.map.keys() {
+            handler(path)?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.map.clear();
+        Ok(())
+    }
+
+    fn put_hole(&mut self, path: &OsStr, block: u64, len: u64) -> io::Result<()> {
+        match self.map.get_mut(path) {
+            Some(entry) => {
+                entry.holes.insert(block, len);
+                Ok(())
+            },
+            // Checking the file mtime is what creates the entry.
+            None => { panic!("you can't add holes before checking the file mtime!"); }
+        }
+    }
+
+    fn get_hole(&self, path: &OsStr, block: u64) -> io::Result<Option<u64>> {
+        Ok(self.map.get(path).and_then(|entry| entry.holes.get(&block).cloned()))
+    }
+
+    fn unmap_hole(&mut self, path: &OsStr, block: u64) -> io::Result<bool> {
+        Ok(self.map.get_mut(path)
+            .map(|entry| entry.holes.remove(&block).is_some())
+            .unwrap_or(false))
+    }
 }
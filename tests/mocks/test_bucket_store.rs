@@ -20,6 +20,7 @@ pub struct TestBucketStore {
     pub free_list: VecDeque<usize>,
     pub used_bytes: u64,
     pub max_bytes: Option<u64>,
+    pub put_error: Option<i32>,
 }
 
 fn parse_path(path: &OsStr) -> usize {
@@ -41,6 +42,7 @@ impl TestBucketStore {
             free_list: VecDeque::new(),
             used_bytes: 0,
             max_bytes,
+            put_error: None,
         }
     }
 }
@@ -60,16 +62,22 @@ impl CacheBucketStore for TestBucketStore {
 
     fn put<F>(&mut self, parent: &OsStr, data: &[u8], mut delete_handler: F) -> io::Result<OsString>
             where F: FnMut(&OsStr) -> io::Result<()> {
+        if let Some(errno) = self.put_error {
+            return Err(io::Error::from_raw_os_error(errno));
+        }
+
         while self.max_bytes.is_some() && self.used_bytes + data.len() as u64 > self.max_bytes.unwrap() {
-            let (bucket_path, _) = self.delete_something().unwrap();
-            delete_handler(&bucket_path)?;
+            let (parents, _) = self.delete_something().unwrap();
+            delete_handler(&parents[0])?;
         }
 
         let index = if self.free_list.is_empty() {
             self.buckets.push(TestBucket { data: None, parent: Some(parent.to_os_string()) });
             self.buckets.len() - 1
         } else {
-            self.free_list.pop_front().unwrap()
+            let index = self.free_list.pop_front().unwrap();
+            self.buckets[index].parent = Some(parent.to_os_string());
+            index
         };
 
         self.used_list.push_front(index);
@@ -93,12 +101,17 @@ impl CacheBucketStore for TestBucketStore {
         let bucket = &mut self.buckets[number];
         let n = bucket.data.as_ref().unwrap().len() as u64;
         bucket.data = None;
+        bucket.parent = None;
 
         self.used_bytes -= n;
         Ok(n)
     }
 
-    fn delete_something(&mut self) -> io::Result<(OsString, u64)> {
+    fn release_bucket(&mut self, bucket_path: &OsStr, _parent: &OsStr) -> io::Result<u64> {
+        self.free_bucket(bucket_path)
+    }
+
+    fn delete_something(&mut self) -> io::Result<(Vec<OsString>, u64)> {
         let number = self.used_list.pop_back().unwrap();
         self.free_list.push_front(number);
 
@@ -108,17 +121,38 @@ impl CacheBucketStore for TestBucketStore {
         let parent = bucket.parent.take();
 
         self.used_bytes -= n;
-        Ok((parent.unwrap(), n))
+        Ok((vec![parent.unwrap()], n))
+    }
+
+    fn lru_buckets(&self, count: usize) -> io::Result<Vec<(OsString, OsString)>> {
+        Ok(self.used_list.iter().rev().take(count)
+            .map(|&index| (OsString::from(format!("{}", index)),
+                           self.buckets[index].parent.clone().unwrap()))
+            .collect())
     }
 
     fn used_bytes(&self) -> u64 {
         self.used_bytes
     }
 
+    fn used_buckets(&self) -> u64 {
+        self.used_list.len() as u64
+    }
+
     fn max_bytes(&self) -> Option<u64> {
         self.max_bytes
     }
 
+    fn set_max_bytes<F>(&mut self, max_bytes: Option<u64>, mut delete_handler: F) -> io::Result<()>
+            where F: FnMut(&OsStr) -> io::Result<()> {
+        self.max_bytes = max_bytes;
+        while self.max_bytes.is_some() && self.used_bytes > self.max_bytes.unwrap() {
+            let (parents, _) = self.delete_something().unwrap();
+            delete_handler(&parents[0])?;
+        }
+        Ok(())
+    }
+
     fn enumerate_buckets<F>(&self, mut handler: F) -> io::Result<()>
             where F: FnMut(&OsStr, Option<&OsStr>) -> io::Result<()> {
         for i in 0 .. self.buckets.len() {
@@ -0,0 +1,88 @@
+// BackFS Filesystem Tests :: Path Linked List
+//
+// Copyright (c) 2016-2020 by William R. Fraser
+//
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use backfs::fsll::PathLinkedList;
+
+/// An in-memory PathLinkedList which can be made to fail insertions with ENOSPC, to simulate the
+/// cache filesystem filling up. `full` is shared so the test can flip it after handing the list
+/// off to a bucket store.
+#[derive(Default)]
+pub struct TestPathList {
+    pub list: RefCell<VecDeque<PathBuf>>,
+    pub full: Rc<Cell<bool>>,
+}
+
+impl TestPathList {
+    fn check_full(&self) -> io::Result<()> {
+        if self.full.get() {
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl PathLinkedList for TestPathList {
+    fn is_empty(&self) -> bool {
+        self.list.borrow().is_empty()
+    }
+
+    fn get_tail(&self) -> Option<PathBuf> {
+        self.list.borrow().back().cloned()
+    }
+
+    fn to_head<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()> {
+        self.disconnect(path)?;
+        self.list.borrow_mut().push_front(path.as_ref().to_owned());
+        Ok(())
+    }
+
+    fn insert_as_head<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()> {
+        self.check_full()?;
+        self.list.borrow_mut().push_front(path.as_ref().to_owned());
+        Ok(())
+    }
+
+    fn insert_as_tail<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()> {
+        self.check_full()?;
+        self.list.borrow_mut().push_back(path.as_ref().to_owned());
+        Ok(())
+    }
+
+    fn disconnect<T: AsRef<Path> + ?Sized + Debug>(&self, path: &T) -> io::Result<()> {
+        let mut list = self.list.borrow_mut();
+        match list.iter().position(|entry| entry == path.as_ref()) {
+            Some(index) => {
+                list.remove(index);
+                Ok(())
+            },
+            None => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn detach_tail(&self, count: usize) -> io::Result<Vec<PathBuf>> {
+        let mut list = self.list.borrow_mut();
+        let keep = list.len().saturating_sub(count);
+        Ok(list.split_off(keep).into_iter().rev().collect())
+    }
+
+    fn extend_tail<T: AsRef<Path> + Debug>(&self, paths: &[T]) -> io::Result<()> {
+        self.check_full()?;
+        self.list.borrow_mut().extend(paths.iter().map(|path| path.as_ref().to_owned()));
+        Ok(())
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        self.list.borrow_mut().clear();
+        Ok(())
+    }
+}
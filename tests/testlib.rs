@@ -6,17 +6,29 @@
 #![deny(rust_2018_idioms)]
 
 use std::borrow::{Borrow, BorrowMut};
-use std::ffi::OsStr;
-use std::io::Cursor;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::PathBuf;
+use std::io::{self, Cursor, Write};
+use std::process;
 use std::str;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use backfs::fscache::*;
 use backfs::block_map::*;
 use backfs::bucket_store::*;
+use backfs::fsll::Fsll;
 
 mod mocks;
+use mocks::chunky_reader::*;
+use mocks::flaky_reader::*;
+use mocks::recording_reader::*;
+use mocks::slow_reader::*;
 use mocks::test_block_map::*;
 use mocks::test_bucket_store::*;
+use mocks::test_path_list::*;
 use mocks::sneaky::*;
 
 macro_rules! cmp_u8_as_str {
@@ -94,6 +106,134 @@ fn test_fscache_block_sizes() {
     }
 }
 
+fn test_fscache_slab_basic(block_size: u64) {
+    let data_str = "ABCDEFGHIJKLMN!";
+    let mut data = Cursor::new(Vec::from(data_str));
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let max_size = Some(100);
+
+    let base = std::env::temp_dir()
+        .join(format!("backfs-test-{}-slab-{}", process::id(), block_size));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    let buckets_dir = base.into_os_string();
+
+    let mut map_sneak = Sneaky::new(TestMap::default());
+    let mut store_sneak = Sneaky::new(
+        SlabBucketStore::new(buckets_dir.clone(), block_size, max_size));
+    let cache = unsafe {
+        FsCache::<_, TestMap, _, SlabBucketStore>::new(
+            map_sneak.sneak(), store_sneak.sneak(), block_size)
+    };
+    cache.init().unwrap();
+
+    let fetched: Vec<u8> = cache.fetch(filename, 0, 1024, &mut data, mtime).unwrap();
+    assert_eq!(&fetched, data.get_ref());
+
+    let num_blocks = 1 + ((data_str.len() as u64 - 1) / block_size);
+    let check_blocks = |map: &TestMap, store: &SlabBucketStore| {
+        let fileblocks = &map.map[filename];
+        assert_eq!(fileblocks.mtime, mtime);
+        for i in 0..num_blocks {
+            let cached_data = store.get(&fileblocks.blocks[&i]).unwrap();
+            let end = ::std::cmp::min((i+1) * block_size, data.get_ref().len() as u64) as usize;
+            cmp_u8_as_str!(&cached_data, &data.get_ref()[(i * block_size) as usize .. end]);
+            let mut appended = b"x".to_vec();
+            assert_eq!(store.get_into(&fileblocks.blocks[&i], &mut appended).unwrap(),
+                       cached_data.len());
+            assert_eq!(&appended[1 ..], &cached_data[..]);
+        }
+        assert_eq!(fileblocks.blocks.get(&num_blocks), None);
+    };
+    check_blocks(map_sneak.borrow(), store_sneak.borrow());
+
+    // Everything is in one file, at the block's slot.
+    let slab = PathBuf::from(&buckets_dir).join("slab");
+    assert_eq!(fs::read_dir(&buckets_dir).unwrap().count(), 3); // slab, slab_journal, bucket_size
+    assert_eq!(fs::read(&slab).unwrap().len(), data_str.len());
+
+    // The journal brings it all back after a restart.
+    let mut restarted = SlabBucketStore::new(buckets_dir.clone(), block_size, max_size);
+    restarted.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    assert_eq!(restarted.used_bytes(), data_str.len() as u64);
+    assert_eq!(restarted.used_buckets(), num_blocks);
+    check_blocks(map_sneak.borrow(), &restarted);
+
+    // Freeing a block's bucket zeroes its slot, and the slot is re-used.
+    let bucket_path = {
+        let map: &TestMap = map_sneak.borrow();
+        map.map[filename].blocks[&0].clone()
+    };
+    let first_len = ::std::cmp::min(block_size, data_str.len() as u64);
+    assert_eq!(restarted.free_bucket(&bucket_path).unwrap(), first_len);
+    assert!(fs::read(&slab).unwrap()[.. first_len as usize].iter().all(|&b| b == 0));
+    assert_eq!(restarted.put(OsStr::new("hello.txt/0"), b"A", |_| Ok(())).unwrap(), bucket_path);
+
+    fs::remove_dir_all(&buckets_dir).unwrap();
+}
+
+#[test]
+fn test_fscache_slab_block_sizes() {
+    for block_size in 1..31 {
+        eprintln!("block size {}", block_size);
+        test_fscache_slab_basic(block_size);
+    }
+}
+
+#[cfg(feature = "async")]
+async fn test_fscache_async_basic(block_size: u64) {
+    let data_str = "ABCDEFGHIJKLMN!";
+    let mut data = Cursor::new(Vec::from(data_str));
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let max_size = Some(100);
+
+    let (cache, map_sneak, store_sneak) = construct_cache(block_size, max_size);
+    cache.init().unwrap();
+    let cache = Arc::new(cache);
+
+    let map: &TestMap = map_sneak.borrow();
+    let store: &TestBucketStore = store_sneak.borrow();
+
+    let fetched: Vec<u8> = cache.fetch_async(filename, 0, 1024, &mut data, mtime).await.unwrap();
+    assert_eq!(&fetched, data.get_ref());
+
+    let fileblocks = &map.map[filename];
+    assert_eq!(fileblocks.mtime, mtime);
+
+    let num_blocks = 1 + ((data_str.len() as u64 - 1) / block_size);
+    for i in 0..num_blocks {
+        let bucket: u64 = fileblocks.blocks[&i]
+                                      .to_str().unwrap()
+                                      .parse().unwrap();
+        let cached_data: &Vec<u8> = store.buckets[bucket as usize]
+                                         .data
+                                         .as_ref()
+                                         .unwrap();
+
+        let end = ::std::cmp::min((i+1) * block_size, data.get_ref().len() as u64) as usize;
+        cmp_u8_as_str!(cached_data, &data.get_ref()[(i * block_size) as usize .. end]);
+    }
+
+    assert_eq!(fileblocks.blocks.get(&num_blocks), None);
+
+    // Now it's all cached; a partial read shouldn't read from the source at all (though it does
+    // check its size).
+    let mut other = Cursor::new(vec![b'x'; data_str.len()]);
+    let fetched = cache.fetch_async(filename, 3, 5, &mut other, mtime).await.unwrap();
+    cmp_u8_as_str!(&fetched, b"DEFGH");
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_fscache_async_block_sizes() {
+    for block_size in 1..31 {
+        eprintln!("block size {}", block_size);
+        test_fscache_async_basic(block_size).await;
+    }
+}
+
 #[test]
 fn test_fscache_out_of_range_read() {
     let data_str = "ABCDEFGHIJKLMN!";
@@ -122,6 +262,40 @@ fn test_fscache_out_of_range_read() {
     assert!(store.buckets.is_empty());
 }
 
+#[test]
+fn test_fscache_empty_file() {
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-empty-file", process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("map")).unwrap();
+    fs::create_dir_all(base.join("buckets")).unwrap();
+    let buckets_dir = base.join("buckets").into_os_string();
+    let used_list = Fsll::new(&buckets_dir, "head", "tail");
+    let free_list = Fsll::new(&buckets_dir, "free_head", "free_tail");
+    let cache = FsCache::new(
+        AnyBlockMap::new(MapFormat::Links, base.join("map").into_os_string()),
+        AnyBucketStore::Dirs(FsCacheBucketStore::new(buckets_dir, used_list, free_list, 10, None)),
+        10);
+    cache.init().unwrap();
+    let filename = OsStr::new("/empty.txt");
+
+    // Nothing at all is recorded for it: no map directory, and so no mtime or size.
+    let fetched = cache.fetch(filename, 0, 10, &mut Cursor::new(vec![]), 1).unwrap();
+    assert!(fetched.is_empty());
+    assert!(!base.join("map/empty.txt").exists());
+    assert!(!cache.is_current(filename, 1));
+
+    // If it had data cached before it was emptied, that's dropped.
+    cache.fetch(filename, 0, 20, &mut Cursor::new(Vec::from("ABCDEFGHIJKL")), 1).unwrap();
+    assert!(base.join("map/empty.txt").exists());
+    assert_eq!(cache.used_size(), 12);
+    let fetched = cache.fetch(filename, 0, 10, &mut Cursor::new(vec![]), 2).unwrap();
+    assert!(fetched.is_empty());
+    assert!(!base.join("map/empty.txt").exists());
+    assert_eq!(cache.used_size(), 0);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
 #[test]
 fn test_fscache_free_orphans() {
     let filler = "ABCDEFGHIJKLMN!";
@@ -172,3 +346,2090 @@ fn test_fscache_free_orphans() {
     assert_eq!(store.free_list.len() as u64, num_blocks_per_file);
     assert_eq!(store.used_bytes(), (filenames.len() as u64 - 1) * num_blocks_per_file * block_size);
 }
+
+#[test]
+fn test_fscache_reset() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let block_size = 10;
+    let (cache, map_sneak, store_sneak) = construct_cache(block_size, None);
+    cache.init().unwrap();
+
+    let map: &TestMap = map_sneak.borrow();
+    let store: &TestBucketStore = store_sneak.borrow();
+
+    let mut data = Cursor::new(Vec::from("ABCDEFGHIJKLMNOPQRSTUVWXYZ"));
+    let fetched = cache.fetch(filename, 0, 26, &mut data, mtime).unwrap();
+    assert_eq!(&fetched, data.get_ref());
+    assert_eq!(cache.used_size(), 26);
+
+    cache.reset().unwrap();
+    assert_eq!(cache.used_size(), 0);
+    assert!(map.map.is_empty());
+    assert!(store.used_list.is_empty());
+    assert_eq!(store.free_list.len(), 3);
+
+    // The same file with different contents (but the same mtime) has to be re-fetched now.
+    let mut data = Cursor::new(Vec::from("abcdefghijklmnopqrstuvwxyz"));
+    let fetched = cache.fetch(filename, 0, 26, &mut data, mtime).unwrap();
+    assert_eq!(&fetched, data.get_ref());
+    assert_eq!(cache.used_size(), 26);
+}
+
+#[test]
+fn test_fscache_cached_range() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let block_size = 10;
+    let (cache, _map_sneak, _store_sneak) = construct_cache(block_size, None);
+    cache.init().unwrap();
+
+    // 45 bytes: four full blocks and a half block.
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJklmnopqrstKLMNO"));
+
+    // Cache alternating blocks: 0, 2, and 4.
+    for block in &[0, 2, 4] {
+        cache.fetch(filename, block * block_size, block_size, &mut data, mtime).unwrap();
+    }
+
+    assert_eq!(cache.count_cached_bytes(filename), 25);
+    assert_eq!(cache.count_cached_bytes_in_range(filename, 0, 45), 25);
+    assert_eq!(cache.count_cached_bytes_in_range(filename, 0, 10), 10);
+    assert_eq!(cache.count_cached_bytes_in_range(filename, 10, 10), 0);
+
+    // Starts half-way into block 0, ends half-way into block 2.
+    assert_eq!(cache.count_cached_bytes_in_range(filename, 5, 20), 10);
+
+    // Past the end of the (short) last block.
+    assert_eq!(cache.count_cached_bytes_in_range(filename, 40, 100), 5);
+    assert_eq!(cache.count_cached_bytes_in_range(filename, 0, 0), 0);
+
+    // Holes count as cached, though they take no space.
+    let zeros = OsStr::new("zeros");
+    let mut data = Cursor::new(Vec::from(&b"0123456789\0\0\0\0\0\0\0\0\0\0abc"[..]));
+    cache.fetch(zeros, 0, 30, &mut data, mtime).unwrap();
+    assert_eq!(cache.count_cached_bytes(zeros), 13);
+    assert_eq!(cache.count_cached_bytes_in_range(zeros, 0, 30), 23);
+    assert_eq!(cache.count_cached_bytes_in_range(zeros, 15, 10), 8);
+}
+
+#[test]
+fn test_fscache_set_max_size() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let block_size = 10;
+    let (cache, map_sneak, _store_sneak) = construct_cache(block_size, Some(50));
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJklmnopqrstKLMNOPQRST"));
+    cache.fetch(filename, 0, 50, &mut data, mtime).unwrap();
+    assert_eq!(cache.used_size(), 50);
+
+    // Shrinking evicts the least recently used blocks until it fits.
+    cache.set_max_size(Some(25)).unwrap();
+    assert_eq!(cache.max_size(), Some(25));
+    assert!(cache.used_size() <= 25);
+    let map: &TestMap = map_sneak.borrow();
+    let blocks = &map.map.get(filename).unwrap().blocks;
+    assert_eq!(blocks.keys().cloned().collect::<Vec<u64>>(), vec![3, 4]);
+
+    // Growing doesn't touch anything.
+    cache.set_max_size(None).unwrap();
+    assert_eq!(cache.max_size(), None);
+    assert_eq!(cache.used_size(), 20);
+}
+
+#[test]
+fn test_fscache_free_bytes() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJklmnopqrstKLMNOPQRST"));
+    cache.fetch(filename, 0, 50, &mut data, mtime).unwrap();
+    assert_eq!(cache.used_size(), 50);
+
+    // Freeing part of a bucket's worth still frees the whole bucket; the least recently used go.
+    assert_eq!(cache.free_bytes(25).unwrap(), 30);
+    assert_eq!(cache.used_size(), 20);
+    let map: &TestMap = map_sneak.borrow();
+    let blocks = &map.map.get(filename).unwrap().blocks;
+    assert_eq!(blocks.keys().cloned().collect::<Vec<u64>>(), vec![3, 4]);
+    assert_eq!(cache.metrics().eviction_count, 3);
+
+    // The rest are still there.
+    let mut garbage = Cursor::new(vec![b'?'; 50]);
+    cmp_u8_as_str!(&cache.fetch(filename, 30, 20, &mut garbage, mtime).unwrap(),
+                   b"klmnopqrstKLMNOPQRST");
+
+    // Asking for more than there is empties the cache, and no more.
+    assert_eq!(cache.free_bytes(1000).unwrap(), 20);
+    assert_eq!(cache.used_size(), 0);
+    assert_eq!(cache.free_bytes(10).unwrap(), 0);
+}
+
+#[test]
+fn test_fscache_sweep() {
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    for name in &["same", "touched", "truncated", "deleted"] {
+        let mut data = Cursor::new(vec![b'x'; 20]);
+        cache.fetch(OsStr::new(name), 0, 20, &mut data, 1).unwrap();
+    }
+    assert_eq!(cache.used_size(), 80);
+
+    // Everything which changed is dropped, without any of it being read again.
+    let mut checked = vec![];
+    let dropped = cache.sweep(|path| {
+        checked.push(path.to_owned());
+        Ok(match path.to_str().unwrap() {
+            "same" => Some((1, 20)),
+            "touched" => Some((2, 20)),
+            "truncated" => Some((1, 5)),
+            "deleted" => None,
+            other => panic!("unexpected path {:?}", other),
+        })
+    }).unwrap();
+    assert_eq!(dropped, 3);
+    checked.sort();
+    assert_eq!(checked, vec!["deleted", "same", "touched", "truncated"]);
+    assert_eq!(cache.used_size(), 20);
+    let map: &TestMap = map_sneak.borrow();
+    assert_eq!(map.map.keys().collect::<Vec<_>>(), vec![OsStr::new("same")]);
+
+    // A file which can't be checked is left alone.
+    assert_eq!(cache.sweep(|_| Err(io::Error::from_raw_os_error(libc::EIO))).unwrap(), 0);
+    assert_eq!(cache.used_size(), 20);
+}
+
+#[test]
+fn test_fscache_check_dangling_map_entry() {
+    let filename = OsStr::new("hello.txt");
+    let (cache, map_sneak, mut store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghij"));
+    cache.fetch(filename, 0, 20, &mut data, 1).unwrap();
+
+    // Free block 0's bucket behind the map's back.
+    {
+        let map: &TestMap = map_sneak.borrow();
+        let bucket = map.map.get(filename).unwrap().blocks.get(&0).unwrap().clone();
+        let store: &mut TestBucketStore = store_sneak.borrow_mut();
+        store.free_bucket(&bucket).unwrap();
+    }
+
+    cache.check_consistency().unwrap();
+
+    let map: &TestMap = map_sneak.borrow();
+    let blocks = &map.map.get(filename).unwrap().blocks;
+    assert_eq!(blocks.keys().cloned().collect::<Vec<u64>>(), vec![1]);
+    assert_eq!(cache.used_size(), 10);
+}
+
+#[test]
+fn test_fscache_check_unreachable_bucket() {
+    let filename = OsStr::new("hello.txt");
+    let (cache, mut map_sneak, _store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghij"));
+    cache.fetch(filename, 0, 20, &mut data, 1).unwrap();
+    assert_eq!(cache.used_size(), 20);
+
+    // Drop block 1 from the map, leaving its bucket unreachable.
+    {
+        let map: &mut TestMap = map_sneak.borrow_mut();
+        map.map.get_mut(filename).unwrap().blocks.remove(&1);
+    }
+
+    cache.check_consistency().unwrap();
+
+    assert_eq!(cache.used_size(), 10);
+    let map: &TestMap = map_sneak.borrow();
+    let blocks = &map.map.get(filename).unwrap().blocks;
+    assert_eq!(blocks.keys().cloned().collect::<Vec<u64>>(), vec![0]);
+}
+
+#[test]
+fn test_fscache_metrics() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, Some(30));
+    cache.init().unwrap();
+
+    let metrics = cache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (0, 0));
+    assert_eq!(metrics.hit_ratio, 0.);
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJklmnopqrst"));
+
+    // Two misses, then two hits.
+    cache.fetch(filename, 0, 20, &mut data, mtime).unwrap();
+    cache.fetch(filename, 0, 20, &mut data, mtime).unwrap();
+
+    // Two more misses, which only fit by evicting the first block.
+    cache.fetch(filename, 20, 20, &mut data, mtime).unwrap();
+
+    assert_eq!(cache.metrics(), CacheMetrics {
+        used_bytes: 30,
+        max_bytes: Some(30),
+        bucket_count: 3,
+        hit_count: 2,
+        miss_count: 4,
+        eviction_count: 1,
+        backing_read_errors: 0,
+        cache_write_errors: 0,
+        backing_reads_in_flight: 0,
+        hit_ratio: 2. / 6.,
+    });
+}
+
+#[test]
+fn test_fscache_error_counts() {
+    let mtime = 1;
+    let (cache, _map_sneak, mut store_sneak) = construct_cache(10, None);
+    let cache = cache.with_read_retries(1);
+    cache.init().unwrap();
+
+    // A read which works on the retry isn't an error.
+    let mut file = FlakyReader::new(b"0123456789abcde", 1, libc::EIO);
+    cache.fetch(OsStr::new("retried.txt"), 0, 15, &mut file, mtime).unwrap();
+    assert_eq!(cache.metrics().backing_read_errors, 0);
+
+    let mut file = FlakyReader::new(b"0123456789abcde", 1, libc::EACCES);
+    cache.fetch(OsStr::new("failed.txt"), 0, 15, &mut file, mtime).unwrap_err();
+    let mut file = FlakyReader::new(b"0123456789abcde", 2, libc::EIO);
+    cache.fetch(OsStr::new("failed.txt"), 0, 15, &mut file, mtime).unwrap_err();
+    let metrics = cache.metrics();
+    assert_eq!((metrics.backing_read_errors, metrics.cache_write_errors), (2, 0));
+
+    {
+        let store: &mut TestBucketStore = store_sneak.borrow_mut();
+        store.put_error = Some(libc::EIO);
+    }
+    let mut data = Cursor::new(Vec::from("0123456789abcde"));
+    cache.fetch(OsStr::new("uncached.txt"), 0, 15, &mut data, mtime).unwrap();
+    let metrics = cache.metrics();
+    assert_eq!((metrics.backing_read_errors, metrics.cache_write_errors), (2, 2));
+}
+
+#[test]
+fn test_fscache_backing_rate_limit() {
+    let filename = OsStr::new("big.txt");
+    let mtime = 1;
+    let (cache, _map_sneak, _store_sneak) = construct_cache(100, None);
+    let cache = cache.with_backing_rate_limit(1000);
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(vec![b'x'; 1500]);
+
+    // The first second's worth is a free burst; the rest has to wait half a second.
+    let start = Instant::now();
+    assert_eq!(cache.fetch(filename, 0, 1500, &mut data, mtime).unwrap().len(), 1500);
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(450), "only took {:?}", elapsed);
+
+    // Cache hits aren't throttled, even though the budget is used up.
+    let start = Instant::now();
+    assert_eq!(cache.fetch(filename, 0, 1500, &mut data, mtime).unwrap().len(), 1500);
+    let elapsed = start.elapsed();
+    assert!(elapsed < Duration::from_millis(250), "took {:?}", elapsed);
+}
+
+#[test]
+fn test_fscache_max_inflight() {
+    let cache = Arc::new(FsCache::new(TestMap::default(), TestBucketStore::new(Some(200)), 10)
+        .with_max_inflight(2));
+    cache.init().unwrap();
+
+    // Six threads missing at once on their own files, but only two reading at a time.
+    let overlap = Arc::new(ReadOverlap::default());
+    let threads: Vec<_> = (0 .. 6).map(|i| {
+        let cache = Arc::clone(&cache);
+        let overlap = Arc::clone(&overlap);
+        thread::spawn(move || {
+            let mut file = SlowReader::new(b"0123456789abcdefghij", Duration::from_millis(50))
+                .with_overlap(overlap);
+            let path = OsString::from(format!("slow{}.txt", i));
+            cache.fetch(&path, 0, 20, &mut file, 1).unwrap()
+        })
+    }).collect();
+    for thread in threads {
+        assert_eq!(thread.join().unwrap(), b"0123456789abcdefghij");
+    }
+    assert_eq!(overlap.max.load(std::sync::atomic::Ordering::SeqCst), 2);
+    assert_eq!(cache.metrics().miss_count, 12);
+    assert_eq!(cache.metrics().backing_reads_in_flight, 0);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_fscache_async_max_inflight() {
+    let cache = Arc::new(FsCache::new(TestMap::default(), TestBucketStore::new(Some(200)), 10)
+        .with_max_inflight(2));
+    cache.init().unwrap();
+
+    // Six fetches missing at once on the one runtime thread, but only two reading at a time. The
+    // rest wait for their turn without holding up the thread.
+    let overlap = Arc::new(ReadOverlap::default());
+    let tasks: Vec<_> = (0 .. 6).map(|i| {
+        let cache = Arc::clone(&cache);
+        let overlap = Arc::clone(&overlap);
+        tokio::spawn(async move {
+            let mut file = SlowReader::new(b"0123456789abcdefghij", Duration::from_millis(50))
+                .with_overlap(overlap);
+            let path = OsString::from(format!("slow{}.txt", i));
+            cache.fetch_async(&path, 0, 20, &mut file, 1).await.unwrap()
+        })
+    }).collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), b"0123456789abcdefghij");
+    }
+    assert_eq!(overlap.max.load(std::sync::atomic::Ordering::SeqCst), 2);
+    assert_eq!(cache.metrics().miss_count, 12);
+    assert_eq!(cache.metrics().backing_reads_in_flight, 0);
+}
+
+#[test]
+fn test_fscache_read_size() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, store_sneak) = construct_cache(10, None);
+    let cache = cache.with_read_size(40);
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJklmnopqrstKLMNO"));
+
+    // One miss in the middle of the first chunk reads all four of its blocks.
+    cmp_u8_as_str!(&cache.fetch(filename, 12, 5, &mut data, mtime).unwrap(), b"cdefg");
+    {
+        let map: &TestMap = map_sneak.borrow();
+        let blocks: Vec<u64> = map.map[filename].blocks.keys().cloned().collect();
+        assert_eq!(blocks, vec![0, 1, 2, 3]);
+        let store: &TestBucketStore = store_sneak.borrow();
+        assert_eq!(store.used_bytes(), 40);
+    }
+
+    // Everything else in the chunk is now a hit.
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 40, &mut data, mtime).unwrap(),
+                   b"0123456789abcdefghijABCDEFGHIJklmnopqrst");
+    let metrics = cache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (4, 1));
+
+    // The next chunk is cut short by the end of the file.
+    cmp_u8_as_str!(&cache.fetch(filename, 40, 10, &mut data, mtime).unwrap(), b"KLMNO");
+    assert_eq!(cache.used_size(), 45);
+}
+
+#[test]
+#[should_panic(expected = "not a multiple of the block size")]
+fn test_fscache_read_size_not_multiple() {
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, None);
+    let _ = cache.with_read_size(25);
+}
+
+#[test]
+fn test_fscache_max_read() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_max_read(25);
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJ"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 25, &mut data, mtime).unwrap(),
+                   b"0123456789abcdefghijABCDE");
+
+    // Too big to read at once, even though the file isn't that big.
+    let e = cache.fetch(filename, 0, 26, &mut data, mtime).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+    let e = cache.fetch(filename, 0, u64::MAX, &mut data, mtime).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EINVAL));
+    assert_eq!(cache.metrics().hit_count + cache.metrics().miss_count, 3);
+
+    // Streaming doesn't need a buffer for the whole read, so it's fine.
+    let mut streamed = vec![];
+    cache.fetch_streaming(filename, 0, 30, &mut data, mtime, |block| {
+        streamed.extend_from_slice(block);
+        Ok(())
+    }).unwrap();
+    cmp_u8_as_str!(&streamed, b"0123456789abcdefghijABCDEFGHIJ");
+}
+
+#[test]
+fn test_fscache_policies() {
+    let mtime = 1;
+    let (cache, map_sneak, store_sneak) = construct_cache(10, None);
+    let cache = cache
+        .with_policy("tmp", CachePolicy { cache: false, read_size: None })
+        .with_policy("ISO", CachePolicy { cache: true, read_size: Some(30) })
+        .with_auto_cache(false);
+    cache.init().unwrap();
+    let contents = "0123456789abcdefghijABCDEFGHIJklmnopqrstKLMNOPQRST";
+    let buckets = || {
+        let store: &TestBucketStore = store_sneak.borrow();
+        store.used_buckets()
+    };
+    let cached_blocks = |filename: &str| -> Vec<u64> {
+        let map: &TestMap = map_sneak.borrow();
+        map.map.get(OsStr::new(filename))
+            .map(|entry| entry.blocks.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+
+    // .tmp files are never cached.
+    let mut file = RecordingReader::new(contents.as_bytes());
+    cmp_u8_as_str!(&cache.fetch(OsStr::new("scratch.tmp"), 0, 15, &mut file, mtime).unwrap(),
+                   b"0123456789abcde");
+    assert!(!cache.caches(OsStr::new("scratch.tmp")));
+    assert_eq!(buckets(), 0);
+
+    // Files with no policy follow auto_cache, which is off.
+    let mut file = RecordingReader::new(contents.as_bytes());
+    cache.fetch(OsStr::new("notes.txt"), 0, 15, &mut file, mtime).unwrap();
+    assert_eq!(buckets(), 0);
+
+    // .iso files are cached anyway, in any case, with three blocks read on each miss.
+    let mut file = RecordingReader::new(contents.as_bytes());
+    cmp_u8_as_str!(&cache.fetch(OsStr::new("disk.Iso"), 32, 5, &mut file, mtime).unwrap(),
+                   b"mnopq");
+    assert_eq!(file.reads[0], (30, 30));
+    assert_eq!(cached_blocks("disk.Iso"), vec![3, 4]);
+    assert_eq!(buckets(), 2);
+    file.reads.clear();
+    cmp_u8_as_str!(&cache.fetch(OsStr::new("disk.Iso"), 40, 10, &mut file, mtime).unwrap(),
+                   b"KLMNOPQRST");
+    assert_eq!(file.reads, vec![]);
+}
+
+#[test]
+fn test_fscache_stale_while_revalidate() {
+    let filename = OsStr::new("hello.txt");
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap()
+        .as_secs() as i64;
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_stale_while_revalidate(Duration::from_secs(60));
+    cache.init().unwrap();
+
+    let mut old_file = Cursor::new(Vec::from("0123456789abcdefghij"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 5, &mut old_file, now - 10).unwrap(), b"01234");
+    // Only current data: there's nothing stale to serve.
+    assert_eq!(cache.fetch_stale(filename, 0, 5, now - 10).unwrap(), None);
+
+    // It changed just now, so the stale data is served, but only for what's cached.
+    cmp_u8_as_str!(&cache.fetch_stale(filename, 2, 5, now).unwrap().unwrap(), b"23456");
+    cmp_u8_as_str!(&cache.fetch_stale(filename, 0, 10, now).unwrap().unwrap(), b"0123456789");
+    assert_eq!(cache.fetch_stale(filename, 5, 10, now).unwrap(), None);
+    // Changes from longer ago than the window aren't.
+    assert_eq!(cache.fetch_stale(filename, 0, 5, now - 100).unwrap(), None);
+
+    // After the refresh, the new data is cached in place of the old.
+    let mut new_file = RecordingReader::new(b"ABCDEFGHIJKLMNOPQRST");
+    cache.refresh(filename, &mut new_file, now).unwrap();
+    assert_eq!(cache.fetch_stale(filename, 0, 5, now).unwrap(), None);
+    new_file.reads.clear();
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 10, &mut new_file, now).unwrap(), b"ABCDEFGHIJ");
+    assert_eq!(new_file.reads, vec![]);
+    assert_eq!(cache.used_size(), 10);
+
+    // The refresh is held to the same limits as any other read: the new file's 3-byte tail isn't
+    // cached.
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_stale_while_revalidate(Duration::from_secs(60))
+        .with_skip_tail_below(5);
+    cache.init().unwrap();
+    let mut old_file = Cursor::new(Vec::from("0123456789abcdefghij"));
+    cache.fetch(filename, 0, 20, &mut old_file, now - 10).unwrap();
+    assert_eq!(cache.used_size(), 20);
+    let mut new_file = RecordingReader::new(b"ABCDEFGHIJxyz");
+    cache.refresh(filename, &mut new_file, now).unwrap();
+    assert_eq!(cache.used_size(), 10);
+    new_file.reads.clear();
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 20, &mut new_file, now).unwrap(), b"ABCDEFGHIJxyz");
+    assert!(new_file.reads.iter().all(|&(offset, _)| offset >= 10), "{:?}", new_file.reads);
+}
+
+#[test]
+fn test_fscache_skip_tail_below() {
+    let mtime = 1;
+    let (cache, map_sneak, store_sneak) = construct_cache(10, None);
+    let cache = cache.with_skip_tail_below(5);
+    cache.init().unwrap();
+    let cached_blocks = |filename: &str| -> Vec<u64> {
+        let map: &TestMap = map_sneak.borrow();
+        map.map.get(OsStr::new(filename))
+            .map(|entry| entry.blocks.keys().cloned().collect())
+            .unwrap_or_default()
+    };
+
+    // A 3-byte tail isn't cached, so it's read from the backing file every time.
+    let filename = OsStr::new("short_tail");
+    let mut file = RecordingReader::new(b"0123456789abcdefghijABC");
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 30, &mut file, mtime).unwrap(),
+                   b"0123456789abcdefghijABC");
+    assert_eq!(cached_blocks("short_tail"), vec![0, 1]);
+    file.reads.clear();
+    cmp_u8_as_str!(&cache.fetch(filename, 20, 10, &mut file, mtime).unwrap(), b"ABC");
+    assert_eq!(file.reads[0], (20, 10));
+
+    // An 8-byte one is.
+    let filename = OsStr::new("long_tail");
+    let mut file = RecordingReader::new(b"0123456789ABCDEFGH");
+    cache.fetch(filename, 0, 20, &mut file, mtime).unwrap();
+    assert_eq!(cached_blocks("long_tail"), vec![0, 1]);
+
+    let store: &TestBucketStore = store_sneak.borrow();
+    assert_eq!((store.used_buckets(), store.used_bytes()), (4, 38));
+}
+
+#[test]
+fn test_fscache_backing_align() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_backing_align(16);
+    cache.init().unwrap();
+    let contents = "0123456789abcdefghijABCDEFGHIJklmnopqrstKLMNOPQRSTuvwxyzUVWXYZ!@";
+    let mut file = RecordingReader::new(contents.as_bytes());
+    let cached_blocks = || -> Vec<u64> {
+        let map: &TestMap = map_sneak.borrow();
+        map.map[filename].blocks.keys().cloned().collect()
+    };
+
+    // Block 1 is 10 to 20, which rounded out to 16 bytes is 0 to 32. That's all of blocks 0 to 2,
+    // and part of 3, which is left out.
+    cmp_u8_as_str!(&cache.fetch(filename, 12, 3, &mut file, mtime).unwrap(), b"cde");
+    assert_eq!(file.reads, vec![(0, 32)]);
+    assert_eq!(cached_blocks(), vec![0, 1, 2]);
+
+    // Block 3 is 30 to 40, so 16 to 48 gets read, which has blocks 2 and 3 in it.
+    file.reads.clear();
+    cmp_u8_as_str!(&cache.fetch(filename, 30, 10, &mut file, mtime).unwrap(), b"klmnopqrst");
+    assert_eq!(file.reads, vec![(16, 32)]);
+    assert_eq!(cached_blocks(), vec![0, 1, 2, 3]);
+
+    // The file ends part-way into the last block, which is kept, even though it's short.
+    file.reads.clear();
+    cmp_u8_as_str!(&cache.fetch(filename, 60, 10, &mut file, mtime).unwrap(), b"YZ!@");
+    assert_eq!(file.reads[0], (48, 32));
+    assert_eq!(cached_blocks(), vec![0, 1, 2, 3, 5, 6]);
+
+    // And all of it comes back out of the cache right.
+    file.reads.clear();
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 40, &mut file, mtime).unwrap(),
+                   &contents.as_bytes()[.. 40]);
+    cmp_u8_as_str!(&cache.fetch(filename, 50, 20, &mut file, mtime).unwrap(),
+                   &contents.as_bytes()[50 ..]);
+    assert_eq!(file.reads, vec![]);
+}
+
+#[test]
+fn test_fscache_file_shrank() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    let mut data = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJklmno"));
+    cache.fetch(filename, 0, 35, &mut data, mtime).unwrap();
+    assert_eq!(cache.used_size(), 35);
+
+    // Replaced by a shorter file with the same mtime: the block containing the new EOF and
+    // everything after it is dropped, and the new data is read in.
+    let mut data = Cursor::new(Vec::from("0123456789abcdeXYZ"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 35, &mut data, mtime).unwrap(),
+                   b"0123456789abcdeXYZ");
+    {
+        let map: &TestMap = map_sneak.borrow();
+        let blocks: Vec<u64> = map.map[filename].blocks.keys().cloned().collect();
+        assert_eq!(blocks, vec![0, 1]);
+        assert_eq!(map.map[filename].size, Some(18));
+    }
+    assert_eq!(cache.used_size(), 18);
+
+    // Block 0 was still good, and stays cached.
+    let metrics = cache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (1, 5));
+}
+
+#[test]
+fn test_fscache_read_retries() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, store_sneak) = construct_cache(10, None);
+    let cache = cache.with_read_retries(2);
+    cache.init().unwrap();
+
+    // Fails twice, then works.
+    let mut file = FlakyReader::new(b"0123456789abcde", 2, libc::EIO);
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 15, &mut file, mtime).unwrap(), b"0123456789abcde");
+    assert_eq!(file.reads, 5); // two failures, two blocks, and EOF
+    {
+        let map: &TestMap = map_sneak.borrow();
+        let store: &TestBucketStore = store_sneak.borrow();
+        let bucket: usize = map.map[filename].blocks[&0].to_str().unwrap().parse().unwrap();
+        cmp_u8_as_str!(store.buckets[bucket].data.as_ref().unwrap(), b"0123456789");
+        assert_eq!(store.used_bytes(), 15);
+    }
+
+    // Out of retries: the error comes back, and nothing is cached.
+    let filename = OsStr::new("other.txt");
+    let mut file = FlakyReader::new(b"0123456789abcde", 3, libc::EAGAIN);
+    let e = cache.fetch(filename, 0, 15, &mut file, mtime).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EAGAIN));
+    assert_eq!(file.reads, 3);
+    assert_eq!(cache.count_cached_bytes(filename), 0);
+
+    // Permanent errors aren't retried.
+    let mut file = FlakyReader::new(b"0123456789abcde", 1, libc::EACCES);
+    let e = cache.fetch(filename, 0, 15, &mut file, mtime).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EACCES));
+    assert_eq!(file.reads, 1);
+    assert_eq!(cache.used_size(), 15);
+}
+
+#[test]
+fn test_fscache_admit_after() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_admit_after(2);
+    cache.init().unwrap();
+
+    // Block 0 is read twice and block 1 once; only block 0 gets cached.
+    let mut file = Cursor::new(Vec::from("0123456789abcdefghij"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 20, &mut file, mtime).unwrap(),
+                   b"0123456789abcdefghij");
+    assert_eq!(cache.used_size(), 0);
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 10, &mut file, mtime).unwrap(), b"0123456789");
+    {
+        let map: &TestMap = map_sneak.borrow();
+        assert_eq!(map.map[filename].blocks.keys().cloned().collect::<Vec<u64>>(), vec![0]);
+    }
+    assert_eq!(cache.used_size(), 10);
+
+    // Now it's a hit.
+    cache.fetch(filename, 0, 10, &mut file, mtime).unwrap();
+    let metrics = cache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (1, 3));
+}
+
+#[test]
+fn test_fscache_deferred_state() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_deferred_state(true).with_eviction(Eviction::Cost);
+    cache.init().unwrap();
+
+    let mut file = Cursor::new(Vec::from("0123456789"));
+    cache.fetch(filename, 0, 10, &mut file, mtime).unwrap();
+
+    // Lots of updates are only kept in memory, but are visible through the cache.
+    for atime in 100 .. 200 {
+        cache.set_file_atime(filename, atime).unwrap();
+    }
+    assert_eq!(cache.get_file_atime(filename), Some(199));
+    {
+        let map: &TestMap = map_sneak.borrow();
+        assert_eq!(map.map[filename].atime, None);
+    }
+
+    // Flushing writes them to the map once: just the latest atime, plus the read latency.
+    assert_eq!(cache.flush_state(), 2);
+    {
+        let map: &TestMap = map_sneak.borrow();
+        assert_eq!(map.map[filename].atime, Some(199));
+        assert!(map.map[filename].latency.is_some());
+    }
+    assert_eq!(cache.flush_state(), 0);
+    assert_eq!(cache.get_file_atime(filename), Some(199));
+}
+
+#[test]
+fn test_fscache_assume_immutable() {
+    let filename = OsStr::new("hello.txt");
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_mtime_policy(MtimePolicy::Ignore);
+    cache.init().unwrap();
+
+    let mut file = Cursor::new(Vec::from("0123456789"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 10, &mut file, 0).unwrap(), b"0123456789");
+
+    // The source changed, but its mtime doesn't say so (or isn't believed): the cached data
+    // is still used.
+    let mut file = Cursor::new(Vec::from("abcdefghij"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 10, &mut file, 0).unwrap(), b"0123456789");
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 10, &mut file, 5).unwrap(), b"0123456789");
+    assert_eq!(cache.metrics().hit_count, 2);
+}
+
+#[test]
+fn test_fscache_always_revalidate() {
+    let filename = OsStr::new("hello.txt");
+    let other = OsStr::new("other.txt");
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_mtime_policy(MtimePolicy::ZeroIsStale);
+    cache.init().unwrap();
+
+    // An mtime of 0 means the cached data can't be trusted, so it's read again every time.
+    let mut file = Cursor::new(Vec::from("0123456789"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 10, &mut file, 0).unwrap(), b"0123456789");
+    let mut file = Cursor::new(Vec::from("abcdefghij"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 10, &mut file, 0).unwrap(), b"abcdefghij");
+    assert_eq!(cache.metrics().hit_count, 0);
+    assert_eq!(cache.used_size(), 10);
+
+    // Files with a real mtime are cached as usual.
+    let mut file = Cursor::new(Vec::from("0123456789"));
+    cache.fetch(other, 0, 10, &mut file, 1).unwrap();
+    let mut file = Cursor::new(Vec::from("abcdefghij"));
+    cmp_u8_as_str!(&cache.fetch(other, 0, 10, &mut file, 1).unwrap(), b"0123456789");
+    assert_eq!(cache.metrics().hit_count, 1);
+}
+
+#[test]
+fn test_fscache_revalidate_sample() {
+    let filename = OsStr::new("hello.txt");
+    let original = "0123456789abcdefghijABCDEFGHIJ";
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = cache.with_sampling(true);
+    cache.init().unwrap();
+
+    // Nothing's cached yet, so there's nothing to check.
+    let mut file = Cursor::new(Vec::from(original));
+    assert!(!cache.revalidate(filename, &mut file, 1).unwrap());
+
+    let cache_it = |contents: &str| {
+        let mut file = Cursor::new(Vec::from(contents));
+        cache.fetch(filename, 0, 100, &mut file, 1).unwrap();
+        let map: &TestMap = map_sneak.borrow();
+        assert!(map.map[filename].sample.is_some());
+    };
+
+    // All the same: the cache is kept.
+    cache_it(original);
+    let mut file = Cursor::new(Vec::from(original));
+    assert!(!cache.revalidate(filename, &mut file, 1).unwrap());
+    assert_eq!(cache.used_size(), 30);
+
+    // Different mtime.
+    let mut file = Cursor::new(Vec::from(original));
+    assert!(cache.revalidate(filename, &mut file, 2).unwrap());
+    assert_eq!(cache.used_size(), 0);
+    assert!(!(map_sneak.borrow() as &TestMap).map.contains_key(filename));
+
+    // Different size.
+    cache_it(original);
+    let mut file = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJklmno"));
+    assert!(cache.revalidate(filename, &mut file, 1).unwrap());
+    assert_eq!(cache.used_size(), 0);
+
+    // Same size and mtime, but a different first block, or last block.
+    cache_it(original);
+    let mut file = Cursor::new(Vec::from("X123456789abcdefghijABCDEFGHIJ"));
+    assert!(cache.revalidate(filename, &mut file, 1).unwrap());
+    assert_eq!(cache.used_size(), 0);
+    cache_it(original);
+    let mut file = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIX"));
+    assert!(cache.revalidate(filename, &mut file, 1).unwrap());
+    assert_eq!(cache.used_size(), 0);
+
+    // Only the first and last blocks are sampled, so changes in between go unnoticed.
+    cache_it(original);
+    let mut file = Cursor::new(Vec::from("0123456789abcdefghiXABCDEFGHIJ"));
+    assert!(!cache.revalidate(filename, &mut file, 1).unwrap());
+    assert_eq!(cache.used_size(), 30);
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_fscache_async_revalidate_sample() {
+    let filename = OsStr::new("hello.txt");
+    let original = "0123456789abcdefghijABCDEFGHIJ";
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    let cache = Arc::new(cache.with_sampling(true));
+    cache.init().unwrap();
+
+    // Only the first block is read, but the sample covers the last one too.
+    let mut file = Cursor::new(Vec::from(original));
+    let fetched = cache.fetch_async(filename, 0, 5, &mut file, 1).await.unwrap();
+    cmp_u8_as_str!(&fetched, b"01234");
+    assert!((map_sneak.borrow() as &TestMap).map[filename].sample.is_some());
+
+    let mut file = Cursor::new(Vec::from(original));
+    assert!(!cache.revalidate(filename, &mut file, 1).unwrap());
+    assert_eq!(cache.used_size(), 10);
+
+    let mut file = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIX"));
+    assert!(cache.revalidate(filename, &mut file, 1).unwrap());
+    assert_eq!(cache.used_size(), 0);
+}
+
+#[test]
+fn test_fscache_self_test() {
+    let scratch = OsStr::new("/.backfs_control");
+    let (cache, _map_sneak, mut store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    cache.self_test(scratch).unwrap();
+    assert_eq!(cache.used_size(), 0);
+    cache.self_test(scratch).unwrap();
+
+    {
+        let store: &mut TestBucketStore = store_sneak.borrow_mut();
+        store.put_error = Some(libc::EIO);
+    }
+    let e = cache.self_test(scratch).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn test_fscache_streaming() {
+    let filename = OsStr::new("stream.txt");
+    let contents: Vec<u8> = (0u8 .. 100).collect();
+    for &block_size in &[1u64, 7, 10, 64, 128] {
+        let (buffered, _, _) = construct_cache(block_size, None);
+        let (streamed, _, _) = construct_cache(block_size, None);
+        buffered.init().unwrap();
+        streamed.init().unwrap();
+
+        // Twice over, so the second time comes from the cache.
+        for _ in 0 .. 2 {
+            for &(offset, size) in &[(0, 100), (0, 1), (3, 50), (95, 20), (99, 1), (150, 10)] {
+                let mut file = Cursor::new(contents.clone());
+                let expected = buffered.fetch(filename, offset, size, &mut file, 1).unwrap();
+
+                let mut chunks = vec![];
+                let total = streamed.fetch_streaming(filename, offset, size, &mut file, 1, |data| {
+                    chunks.push(data.to_vec());
+                    Ok(())
+                }).unwrap();
+                assert_eq!(chunks.concat(), expected,
+                           "block size {}, {} bytes at {}", block_size, size, offset);
+                assert_eq!(total, expected.len() as u64);
+                assert!(chunks.iter().all(|chunk| chunk.len() as u64 <= block_size));
+            }
+        }
+    }
+
+    // An error from the output stops the fetch.
+    let (cache, _, _) = construct_cache(10, None);
+    cache.init().unwrap();
+    let mut file = Cursor::new(contents);
+    let mut calls = 0;
+    let e = cache.fetch_streaming(filename, 0, 100, &mut file, 1, |_| {
+        calls += 1;
+        Err(io::Error::from_raw_os_error(libc::EPIPE))
+    }).unwrap_err();
+    assert_eq!(e.raw_os_error(), Some(libc::EPIPE));
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn test_fscache_full_hit() {
+    let filename = OsStr::new("full_hit.dat");
+    // With a run of zeros in the middle, so some of the blocks are holes.
+    let contents: Vec<u8> = (0u32 .. 100)
+        .map(|i| if (40 .. 60).contains(&i) { 0 } else { i as u8 + 1 })
+        .collect();
+    for &block_size in &[1u64, 7, 10, 20, 64, 128] {
+        for &(offset, size) in &[(0, 100), (0, 1), (3, 50), (5, 40), (40, 20), (95, 20), (99, 1),
+                                 (0, 200), (150, 10)] {
+            // One warmed up by fetching everything, then read with `fetch`, and one read by the
+            // block-at-a-time path `fetch_streaming` uses.
+            let (fast, _, _) = construct_cache(block_size, None);
+            let (slow, _, _) = construct_cache(block_size, None);
+            fast.init().unwrap();
+            slow.init().unwrap();
+            for cache in &[&fast, &slow] {
+                let mut file = Cursor::new(contents.clone());
+                cache.fetch(filename, 0, 100, &mut file, 1).unwrap();
+            }
+
+            let mut file = Cursor::new(contents.clone());
+            let fetched = fast.fetch(filename, offset, size, &mut file, 1).unwrap();
+            let mut chunks = vec![];
+            slow.fetch_streaming(filename, offset, size, &mut file, 1, |data| {
+                chunks.push(data.to_vec());
+                Ok(())
+            }).unwrap();
+            assert_eq!(fetched, chunks.concat(),
+                       "block size {}, {} bytes at {}", block_size, size, offset);
+            let (fast_metrics, slow_metrics) = (fast.metrics(), slow.metrics());
+            assert_eq!((fast_metrics.hit_count, fast_metrics.miss_count),
+                       (slow_metrics.hit_count, slow_metrics.miss_count),
+                       "block size {}, {} bytes at {}", block_size, size, offset);
+        }
+    }
+
+    // A changed file isn't a hit, and gets read again.
+    let (cache, _, _) = construct_cache(10, None);
+    cache.init().unwrap();
+    let mut file = Cursor::new(contents.clone());
+    cache.fetch(filename, 0, 100, &mut file, 1).unwrap();
+    let mut changed = Cursor::new(vec![b'x'; 100]);
+    assert_eq!(cache.fetch(filename, 0, 100, &mut changed, 2).unwrap(), vec![b'x'; 100]);
+    assert_eq!(cache.metrics().miss_count, 20);
+}
+
+#[test]
+fn test_fscache_full_hit_bench() {
+    let filename = OsStr::new("big.dat");
+    let block_size = 4096;
+    let contents: Vec<u8> = (0u32 .. 256 * 4096).map(|i| (i % 251) as u8 + 1).collect();
+    let (cache, _, _) = construct_cache(block_size, None);
+    cache.init().unwrap();
+    let mut file = Cursor::new(contents.clone());
+    cache.fetch(filename, 0, contents.len() as u64, &mut file, 1).unwrap();
+
+    let rounds = 20;
+    let started = Instant::now();
+    for _ in 0 .. rounds {
+        let fetched = cache.fetch(filename, 1, contents.len() as u64 - 1, &mut file, 1).unwrap();
+        assert_eq!(fetched, &contents[1 ..]);
+    }
+    let full_hit = started.elapsed();
+
+    let started = Instant::now();
+    for _ in 0 .. rounds {
+        let mut fetched = vec![];
+        cache.fetch_streaming(filename, 1, contents.len() as u64 - 1, &mut file, 1, |data| {
+            fetched.extend_from_slice(data);
+            Ok(())
+        }).unwrap();
+        assert_eq!(fetched, &contents[1 ..]);
+    }
+    let by_block = started.elapsed();
+
+    eprintln!("{} rounds of {} cached bytes: {:?} as one read, {:?} a block at a time",
+              rounds, contents.len(), full_hit, by_block);
+    let metrics = cache.metrics();
+    assert_eq!((metrics.hit_count, metrics.miss_count), (2 * rounds * 256, 256));
+}
+
+#[test]
+fn test_fscache_short_reads() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, map_sneak, store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    // Three bytes at a time, and interrupted in between, but it's not EOF until it says so.
+    let mut file = ChunkyReader::new(b"0123456789abcdefghijABCDE", 3);
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 25, &mut file, mtime).unwrap(),
+                   b"0123456789abcdefghijABCDE");
+    assert!(file.interruptions > 0);
+    {
+        let map: &TestMap = map_sneak.borrow();
+        let store: &TestBucketStore = store_sneak.borrow();
+        let blocks = &map.map[filename].blocks;
+        assert_eq!(blocks.keys().cloned().collect::<Vec<u64>>(), vec![0, 1, 2]);
+        for (block, expected) in &[(0, "0123456789"), (1, "abcdefghij"), (2, "ABCDE")] {
+            let bucket: usize = blocks[block].to_str().unwrap().parse().unwrap();
+            cmp_u8_as_str!(store.buckets[bucket].data.as_ref().unwrap(), expected.as_bytes());
+        }
+    }
+}
+
+#[test]
+fn test_fscache_cache_write_fails() {
+    let filename = OsStr::new("hello.txt");
+    let mtime = 1;
+    let (cache, _map_sneak, mut store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+    {
+        let store: &mut TestBucketStore = store_sneak.borrow_mut();
+        store.put_error = Some(libc::EIO);
+    }
+
+    // The data still comes back from the backing file, just without being cached.
+    let mut data = Cursor::new(Vec::from("0123456789abcde"));
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 15, &mut data, mtime).unwrap(), b"0123456789abcde");
+    cmp_u8_as_str!(&cache.fetch(filename, 5, 10, &mut data, mtime).unwrap(), b"56789abcde");
+    assert_eq!(cache.used_size(), 0);
+    assert_eq!(cache.metrics().hit_count, 0);
+}
+
+#[test]
+fn test_fscache_passthrough_after_write_failures() {
+    let mtime = 1;
+    let (cache, map_sneak, mut store_sneak) = construct_cache(10, None);
+    let cache = cache.with_passthrough_after(3);
+    cache.init().unwrap();
+    let mut data = Cursor::new(Vec::from("0123456789abcde"));
+    cache.fetch(OsStr::new("cached.txt"), 0, 15, &mut data, mtime).unwrap();
+    assert_eq!(cache.used_size(), 15);
+    {
+        let store: &mut TestBucketStore = store_sneak.borrow_mut();
+        store.put_error = Some(libc::EROFS);
+    }
+
+    // Two failures in a row aren't enough.
+    cache.fetch(OsStr::new("a.txt"), 0, 10, &mut data, mtime).unwrap();
+    cache.fetch(OsStr::new("b.txt"), 0, 10, &mut data, mtime).unwrap();
+    assert!(!cache.is_passthrough());
+    cache.fetch(OsStr::new("c.txt"), 0, 10, &mut data, mtime).unwrap();
+    assert!(cache.is_passthrough());
+    assert!(cache.recent_errors(Duration::from_secs(60)).contains(&"cache bypassed"));
+
+    // Reads still work, but don't touch the cache at all, not even for what's in it.
+    let hits = cache.metrics().hit_count;
+    let mut changed = Cursor::new(Vec::from("ABCDEFGHIJKLMNO"));
+    cmp_u8_as_str!(&cache.fetch(OsStr::new("cached.txt"), 5, 10, &mut changed, mtime).unwrap(),
+                   b"FGHIJKLMNO");
+    cmp_u8_as_str!(&cache.fetch(OsStr::new("d.txt"), 0, 15, &mut data, mtime).unwrap(),
+                   b"0123456789abcde");
+    assert_eq!(cache.metrics().hit_count, hits);
+    {
+        let map: &TestMap = map_sneak.borrow();
+        assert!(!map.map.contains_key(OsStr::new("d.txt")));
+    }
+
+    // Once resumed, the cache is used again.
+    {
+        let store: &mut TestBucketStore = store_sneak.borrow_mut();
+        store.put_error = None;
+    }
+    assert!(cache.resume_caching());
+    assert!(!cache.resume_caching());
+    cache.fetch(OsStr::new("d.txt"), 0, 15, &mut data, mtime).unwrap();
+    assert_eq!(cache.used_size(), 30);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_fscache_tracing_spans() {
+    use std::sync::{Arc, Mutex};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata};
+
+    // Just records the names of the spans made.
+    #[derive(Clone, Default)]
+    struct SpanNames(Arc<Mutex<Vec<&'static str>>>);
+
+    impl tracing::Subscriber for SpanNames {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool { true }
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut names = self.0.lock().unwrap();
+            names.push(span.metadata().name());
+            Id::from_u64(names.len() as u64)
+        }
+        fn record(&self, _span: &Id, _values: &Record<'_>) {}
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+        fn event(&self, _event: &Event<'_>) {}
+        fn enter(&self, _span: &Id) {}
+        fn exit(&self, _span: &Id) {}
+    }
+
+    let (cache, _map_sneak, _store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+    let names = SpanNames::default();
+    tracing::subscriber::with_default(names.clone(), || {
+        let mut data = Cursor::new(Vec::from("0123456789abcde"));
+        cache.fetch(OsStr::new("hello.txt"), 0, 15, &mut data, 1).unwrap();
+    });
+
+    let names = names.0.lock().unwrap();
+    assert_eq!(names[0], "fetch");
+    for name in &["cache_lookup", "backing_read", "cache_write"] {
+        assert_eq!(names.iter().filter(|n| n == &name).count(), 2, "{} in {:?}", name, names);
+    }
+}
+
+#[test]
+fn test_fscache_cost_eviction() {
+    let mtime = 1;
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, Some(40));
+    let cache = cache.with_eviction(Eviction::Cost);
+    cache.init().unwrap();
+
+    // The slow file is read first, so with LRU eviction its blocks would be the first to go.
+    let slow = OsStr::new("slow.txt");
+    let mut file = SlowReader::new(b"0123456789abcdefghij", Duration::from_millis(20));
+    cache.fetch(slow, 0, 20, &mut file, mtime).unwrap();
+    let fast = OsStr::new("fast.txt");
+    let mut file = Cursor::new(Vec::from("ABCDEFGHIJklmnopqrst"));
+    cache.fetch(fast, 0, 20, &mut file, mtime).unwrap();
+    {
+        let map: &TestMap = map_sneak.borrow();
+        assert!(map.map[slow].latency.unwrap() >= 20_000);
+        assert!(map.map[fast].latency.unwrap() < map.map[slow].latency.unwrap());
+    }
+
+    // The cache is full, so caching another block evicts one of the fast file's instead.
+    let mut file = Cursor::new(Vec::from("0123456789"));
+    cache.fetch(OsStr::new("other.txt"), 0, 10, &mut file, mtime).unwrap();
+    {
+        let map: &TestMap = map_sneak.borrow();
+        assert_eq!(map.map[slow].blocks.keys().cloned().collect::<Vec<u64>>(), vec![0, 1]);
+        assert_eq!(map.map[fast].blocks.keys().cloned().collect::<Vec<u64>>(), vec![1]);
+    }
+    assert_eq!(cache.used_size(), 40);
+    assert_eq!(cache.metrics().eviction_count, 1);
+}
+
+#[test]
+fn test_fscache_concurrent_misses() {
+    let cache = Arc::new(FsCache::new(TestMap::default(), TestBucketStore::new(Some(100)), 10));
+    cache.init().unwrap();
+
+    // Slow misses on different files at once each read their own file's blocks, once each.
+    let threads: Vec<_> = (0 .. 4).map(|i| {
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || {
+            let mut file = SlowReader::new(b"0123456789abcdefghij", Duration::from_millis(100));
+            let path = OsString::from(format!("slow{}.txt", i));
+            let data = cache.fetch(&path, 0, 20, &mut file, 1).unwrap();
+            (data, file.reads)
+        })
+    }).collect();
+    for thread in threads {
+        let (data, reads) = thread.join().unwrap();
+        assert_eq!(data, b"0123456789abcdefghij");
+        assert_eq!(reads, vec![(0, 10), (10, 10)]);
+    }
+    assert_eq!(cache.metrics().miss_count, 8);
+
+    // Misses which evict other files' blocks, and files going stale, all at once. This used to be
+    // able to deadlock, when some paths took the map lock first and others the store lock.
+    let (done_tx, done_rx) = mpsc::channel();
+    for i in 0 .. 4 {
+        let cache = Arc::clone(&cache);
+        let done_tx = done_tx.clone();
+        thread::spawn(move || {
+            for n in 0 .. 5000 {
+                let mut file = Cursor::new(Vec::from("0123456789abcdefghijABCDEFGHIJ"));
+                let path = OsString::from(format!("file{}.txt", (i + n) % 6));
+                let data = cache.fetch(&path, 0, 30, &mut file, n as i64 % 3).unwrap();
+                assert_eq!(data, b"0123456789abcdefghijABCDEFGHIJ");
+            }
+            done_tx.send(()).unwrap();
+        });
+    }
+    for _ in 0 .. 4 {
+        done_rx.recv_timeout(Duration::from_secs(30)).expect("cache deadlocked");
+    }
+    assert!(cache.used_size() <= 100);
+    cache.check_consistency().unwrap();
+}
+
+#[test]
+fn test_fscache_write() {
+    let filename = OsStr::new("hello.txt");
+    let (cache, map_sneak, _store_sneak) = construct_cache(10, None);
+    cache.init().unwrap();
+
+    let mut file = Cursor::new(Vec::from("0123456789abcdefghijABCDE"));
+    cache.fetch(filename, 0, 25, &mut file, 1).unwrap();
+    assert_eq!(cache.metrics().miss_count, 3);
+
+    // Partly overlapping blocks 0 and 1, and then past the end of block 2.
+    cache.write(filename, 8, b"XYZW", &mut file, 1, 2).unwrap();
+    cache.write(filename, 24, b"++", &mut file, 2, 3).unwrap();
+    cmp_u8_as_str!(file.get_ref(), b"01234567XYZWcdefghijABCD++");
+    {
+        let map: &TestMap = map_sneak.borrow();
+        assert_eq!(map.map[filename].blocks.keys().cloned().collect::<Vec<u64>>(), vec![0, 1, 2]);
+        assert_eq!(map.map[filename].size, Some(26));
+    }
+
+    // Everything comes from the cache, not the backing file.
+    let mut garbage = Cursor::new(vec![b'?'; 26]);
+    cmp_u8_as_str!(&cache.fetch(filename, 0, 26, &mut garbage, 3).unwrap(),
+                   b"01234567XYZWcdefghijABCD++");
+    assert_eq!(cache.metrics().miss_count, 3);
+}
+
+// A fresh directory for the test to keep a cache in, with an empty buckets directory, and a
+// function making stores of 10-byte buckets there, given their size limit. Their lists are on disk
+// too, so a store made later starts up on the same cache.
+fn scratch_store(name: &str) -> (PathBuf, impl Fn(Option<u64>) -> FsCacheBucketStore<Fsll>) {
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&base);
+    let buckets_dir = base.join("buckets");
+    fs::create_dir_all(&buckets_dir).unwrap();
+    let new_store = move |max_bytes| FsCacheBucketStore::new(OsString::from(&buckets_dir),
+        Fsll::new(&buckets_dir, "head", "tail"), Fsll::new(&buckets_dir, "free_head", "free_tail"),
+        10, max_bytes);
+    (base, new_store)
+}
+
+fn bucket_alloc_fill_and_evict(alloc: BucketAlloc, name: &str) -> (Vec<String>, OsString) {
+    let (base, new_store) = scratch_store(name);
+    let buckets_dir = base.join("buckets");
+
+    let mut store = new_store(Some(30)).with_alloc(alloc);
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+
+    // Fill it up, then one more, which evicts the first.
+    for name in &["a", "b", "c"] {
+        let parent = base.join("map").join(name);
+        store.put(parent.as_os_str(), b"0123456789", |_| panic!("nothing should be deleted"))
+            .unwrap();
+    }
+    let mut deleted = 0;
+    let bucket = store.put(base.join("map/d").as_os_str(), b"0123456789", |_| {
+            deleted += 1;
+            Ok(())
+        }).unwrap();
+    assert_eq!(deleted, 1);
+    assert_eq!(store.used_bytes(), 30);
+
+    let mut buckets: Vec<String> = fs::read_dir(&buckets_dir).unwrap()
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.file_type().unwrap().is_dir())
+        .map(|entry| entry.file_name().into_string().unwrap())
+        .collect();
+    buckets.sort();
+
+    fs::remove_dir_all(&base).unwrap();
+    (buckets, PathBuf::from(bucket).file_name().unwrap().to_owned())
+}
+
+#[test]
+fn test_bucket_store_alloc_reuse() {
+    let (buckets, last) = bucket_alloc_fill_and_evict(BucketAlloc::Reuse, "alloc-reuse");
+    // The evicted bucket was re-used for the new data.
+    assert_eq!(buckets, vec!["0", "1", "2"]);
+    assert_eq!(last, OsString::from("0"));
+}
+
+#[test]
+fn test_bucket_store_alloc_new() {
+    let (buckets, last) = bucket_alloc_fill_and_evict(BucketAlloc::New, "alloc-new");
+    // The evicted bucket was deleted, and a new one made.
+    assert_eq!(buckets, vec!["1", "2", "3"]);
+    assert_eq!(last, OsString::from("3"));
+}
+
+#[test]
+fn test_bucket_store_dedup() {
+    let (base, new_store) = scratch_store("dedup");
+    let new_store = || new_store(None).with_dedup(true);
+
+    let mut store = new_store();
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    let a = base.join("map/a/0");
+    let b = base.join("map/b/0");
+    let c = base.join("map/c/0");
+    let put = |store: &mut FsCacheBucketStore<Fsll>, parent: &PathBuf, data: &[u8]| {
+        store.put(parent.as_os_str(), data, |_| panic!("nothing should be deleted")).unwrap()
+    };
+
+    // Two files with the same block share one bucket; a different block gets its own.
+    let bucket = put(&mut store, &a, b"0123456789");
+    assert_eq!(put(&mut store, &b, b"0123456789"), bucket);
+    assert_ne!(put(&mut store, &c, b"abcdefghij"), bucket);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (2, 20));
+
+    let mut parents = vec![];
+    store.enumerate_buckets(|bucket_path, parent| {
+        if bucket_path == bucket {
+            parents.push(PathBuf::from(parent.unwrap()));
+        }
+        Ok(())
+    }).unwrap();
+    assert_eq!(parents, vec![a.clone(), b.clone()]);
+
+    // The index of contents survives a restart.
+    let mut store = new_store();
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    let d = base.join("map/d/0");
+    assert_eq!(put(&mut store, &d, b"0123456789"), bucket);
+
+    // It's only freed once the last block using it lets go.
+    assert_eq!(store.release_bucket(&bucket, a.as_os_str()).unwrap(), 0);
+    assert_eq!(store.release_bucket(&bucket, d.as_os_str()).unwrap(), 0);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (2, 20));
+    assert_eq!(store.get(&bucket).unwrap(), b"0123456789");
+    assert_eq!(store.release_bucket(&bucket, b.as_os_str()).unwrap(), 10);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (1, 10));
+
+    // Evicting a shared bucket hands back all of its parents.
+    let bucket = put(&mut store, &a, b"ABCDEFGHIJ");
+    assert_eq!(put(&mut store, &b, b"ABCDEFGHIJ"), bucket);
+    store.get(OsStr::new(&bucket)).unwrap();
+    let (evicted, n) = store.delete_something().unwrap();
+    assert_eq!(n, 10);
+    assert_eq!(evicted, vec![c.into_os_string()]);
+    let (evicted, n) = store.delete_something().unwrap();
+    assert_eq!(n, 10);
+    assert_eq!(evicted, vec![a.into_os_string(), b.into_os_string()]);
+    assert_eq!(store.used_bytes(), 0);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_bucket_store_rebuild_lru() {
+    let (base, new_store) = scratch_store("rebuild-lru");
+    let buckets_dir = base.join("buckets");
+    let mut store = new_store(None);
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    let put = |store: &mut FsCacheBucketStore<Fsll>, name: &str| {
+        let parent = base.join("map").join(name);
+        PathBuf::from(store.put(parent.as_os_str(), b"0123456789",
+                                |_| panic!("nothing should be deleted")).unwrap())
+    };
+    let a = put(&mut store, "a/0");
+    let b = put(&mut store, "b/0");
+    let c = put(&mut store, "c/0");
+    let d = put(&mut store, "d/0");
+    assert_eq!(store.free_bucket(d.as_os_str()).unwrap(), 10);
+
+    // Lose the ends of both lists, make one bucket point to itself, and another to nothing.
+    fs::remove_file(buckets_dir.join("tail")).unwrap();
+    fs::remove_file(buckets_dir.join("free_head")).unwrap();
+    fs::remove_file(a.join("prev")).unwrap();
+    std::os::unix::fs::symlink(&a, a.join("prev")).unwrap();
+    fs::remove_file(c.join("next")).unwrap();
+    std::os::unix::fs::symlink(buckets_dir.join("99"), c.join("next")).unwrap();
+    assert!(store.delete_something().is_err());
+
+    // Oldest first: b, then c, then a.
+    let now = std::time::SystemTime::now();
+    for (bucket, age) in [(&b, 30), (&c, 20), (&a, 10)] {
+        fs::File::open(bucket).unwrap().set_modified(now - Duration::from_secs(age)).unwrap();
+    }
+    assert_eq!(store.rebuild_lru().unwrap(), 3);
+    let lru = |store: &FsCacheBucketStore<Fsll>| {
+        store.lru_buckets(10).unwrap().into_iter()
+            .map(|(bucket, _)| PathBuf::from(bucket))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(lru(&store), vec![b.clone(), c.clone(), a.clone()]);
+
+    // Everything that uses the lists works again.
+    let (evicted, n) = store.delete_something().unwrap();
+    assert_eq!((evicted, n), (vec![base.join("map/b/0").into_os_string()], 10));
+    assert_eq!(put(&mut store, "e/0"), b);
+    assert_eq!(put(&mut store, "f/0"), d);
+    store.get(a.as_os_str()).unwrap();
+    assert_eq!(lru(&store), vec![c, b, d, a]);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_bucket_store_mem_cache() {
+    let (base, new_store) = scratch_store("mem-cache");
+    let mut store = new_store(None).with_mem_cache(20);
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    let put = |store: &mut FsCacheBucketStore<Fsll>, name: &str, data: &[u8]| {
+        let parent = base.join("map").join(name);
+        PathBuf::from(store.put(parent.as_os_str(), data,
+                                |_| panic!("nothing should be deleted")).unwrap())
+    };
+    let a = put(&mut store, "a/0", b"aaaaaaaaaa");
+    let b = put(&mut store, "b/0", b"bbbbbbbbbb");
+    let c = put(&mut store, "c/0", b"cccccccccc");
+
+    // Once read, a and b don't need their data files any more.
+    store.get(a.as_os_str()).unwrap();
+    store.get(b.as_os_str()).unwrap();
+    fs::remove_file(a.join("data")).unwrap();
+    fs::remove_file(b.join("data")).unwrap();
+    cmp_u8_as_str!(&store.get(a.as_os_str()).unwrap(), b"aaaaaaaaaa");
+    let mut both = vec![];
+    assert_eq!(store.get_into(a.as_os_str(), &mut both).unwrap(), 10);
+    assert_eq!(store.get_into(b.as_os_str(), &mut both).unwrap(), 10);
+    cmp_u8_as_str!(&both, b"aaaaaaaaaabbbbbbbbbb");
+
+    // There's only room for two, so reading c drops a, which was read least recently.
+    cmp_u8_as_str!(&store.get(c.as_os_str()).unwrap(), b"cccccccccc");
+    assert!(store.get(a.as_os_str()).is_err());
+    cmp_u8_as_str!(&store.get(b.as_os_str()).unwrap(), b"bbbbbbbbbb");
+
+    // A freed bucket is forgotten, and when it's re-used, its new data is read.
+    store.free_bucket(b.as_os_str()).unwrap();
+    assert_eq!(put(&mut store, "d/0", b"dddddddddd"), b);
+    cmp_u8_as_str!(&store.get(b.as_os_str()).unwrap(), b"dddddddddd");
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_bucket_store_init_progress() {
+    let (base, new_store) = scratch_store("progress");
+
+    let mut store = new_store(None);
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    for i in 0 .. 25 {
+        let parent = base.join("map").join(format!("{}", i));
+        store.put(parent.as_os_str(), b"0123456789", |_| panic!("nothing should be deleted"))
+            .unwrap();
+    }
+
+    // Start up again on the same cache, reporting progress every 10 buckets.
+    let reports = Arc::new(Mutex::new(vec![]));
+    let reports_clone = reports.clone();
+    let mut store = new_store(None)
+        .with_init_progress(10, Box::new(move |buckets, bytes| {
+            reports_clone.lock().unwrap().push((buckets, bytes));
+        }));
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+
+    assert_eq!(*reports.lock().unwrap(), vec![(10, 100), (20, 200)]);
+    assert_eq!(store.used_bytes(), 250);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_bucket_store_fast_init() {
+    let (base, new_store) = scratch_store("fast-init");
+    let buckets_dir = base.join("buckets");
+
+    // Starts up reporting progress every bucket, so it's easy to tell whether it scanned.
+    let start = || {
+        let scanned = Arc::new(Mutex::new(0));
+        let scanned_clone = scanned.clone();
+        let mut store = new_store(None)
+            .with_fast_init(true)
+            .with_init_progress(1, Box::new(move |buckets, _bytes| {
+                *scanned_clone.lock().unwrap() = buckets;
+            }));
+        store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+        let scanned = *scanned.lock().unwrap();
+        (store, scanned)
+    };
+
+    let (mut store, scanned) = start();
+    assert_eq!(scanned, 0);
+    for i in 0 .. 5 {
+        let parent = base.join("map").join(format!("{}", i));
+        store.put(parent.as_os_str(), b"0123456", |_| panic!("nothing should be deleted"))
+            .unwrap();
+    }
+
+    // After a clean shutdown, the saved usage is used instead of scanning.
+    store.shutdown().unwrap();
+    let (store, scanned) = start();
+    assert_eq!(scanned, 0);
+    assert_eq!((store.used_bytes(), store.used_buckets()), (35, 5));
+
+    // This time it "crashes", without shutting down, so the next start has to scan.
+    drop(store);
+    let (mut store, scanned) = start();
+    assert_eq!(scanned, 5);
+    assert_eq!((store.used_bytes(), store.used_buckets()), (35, 5));
+
+    // A saved usage that was cut short isn't trusted either.
+    store.shutdown().unwrap();
+    fs::write(buckets_dir.join("clean_shutdown"), "35 ").unwrap();
+    let (_store, scanned) = start();
+    assert_eq!(scanned, 5);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_bucket_store_enospc() {
+    let (base, _) = scratch_store("enospc");
+    let buckets_dir = base.join("buckets");
+
+    // Not the usual lists: the used one has to be made to fail.
+    let used_list = TestPathList::default();
+    let full = used_list.full.clone();
+    let mut store = FsCacheBucketStore::new(OsString::from(&buckets_dir),
+        used_list, TestPathList::default(), 10, None);
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+
+    let parents: Vec<OsString> = ["a", "b", "c"].iter()
+        .map(|name| base.join("map").join(name).into_os_string())
+        .collect();
+    for parent in &parents {
+        store.put(parent, b"0123456789", |_| panic!("nothing should be deleted")).unwrap();
+    }
+    assert_eq!(store.used_bytes(), 30);
+
+    // From now on, every attempt to allocate a bucket fails as if the cache filesystem is full.
+    full.set(true);
+
+    let mut deleted = vec![];
+    let parent = base.join("map").join("d").into_os_string();
+    let err = store.put(&parent, b"0123456789", |parent| {
+            deleted.push(parent.to_owned());
+            Ok(())
+        }).unwrap_err();
+
+    // It should have freed everything it could, then given up.
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    assert_eq!(deleted, parents);
+    assert_eq!(store.used_bytes(), 0);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_bucket_store_delete_batch() {
+    let (base, new_store) = scratch_store("delete-batch");
+    let buckets_dir = base.join("buckets");
+    let new_store = || new_store(None);
+
+    // The bucket names in an on-disk list, following `direction` links from `start`.
+    let walk = |start: &str, direction: &str| -> Vec<String> {
+        let mut names = vec![];
+        let mut link = buckets_dir.join(start);
+        while let Ok(bucket) = fs::canonicalize(&link) {
+            names.push(bucket.file_name().unwrap().to_str().unwrap().to_owned());
+            link = bucket.join(direction);
+        }
+        names
+    };
+    let parent = |name: &str| base.join("map").join(name).into_os_string();
+
+    let mut store = new_store();
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    for name in &["a", "b", "c", "d"] {
+        store.put(&parent(name), b"0123456789", |_| panic!("nothing should be deleted")).unwrap();
+    }
+    store.put(&parent("e"), b"01234", |_| panic!("nothing should be deleted")).unwrap();
+
+    // The least recently used ones go first, and their parents are all handed back.
+    let (parents, n) = store.delete_batch(3).unwrap();
+    assert_eq!(parents, vec![parent("a"), parent("b"), parent("c")]);
+    assert_eq!(n, 30);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (2, 15));
+    assert_eq!(walk("head", "next"), vec!["4", "3"]);
+    assert_eq!(walk("tail", "prev"), vec!["3", "4"]);
+    assert_eq!(walk("free_head", "next"), vec!["0", "1", "2"]);
+    assert_eq!(walk("free_tail", "prev"), vec!["2", "1", "0"]);
+
+    // Freed buckets get re-used.
+    for name in &["f", "g", "h"] {
+        store.put(&parent(name), b"0123456789", |_| panic!("nothing should be deleted")).unwrap();
+    }
+    assert!(walk("free_head", "next").is_empty());
+    assert_eq!(walk("head", "next"), vec!["0", "1", "2", "4", "3"]);
+
+    // Shrinking the cache frees enough buckets in one batch, counting the one that isn't full.
+    let mut deleted = vec![];
+    store.set_max_bytes(Some(12), |parent| {
+            deleted.push(parent.to_owned());
+            Ok(())
+        }).unwrap();
+    assert_eq!(deleted, vec![parent("d"), parent("e"), parent("f"), parent("g")]);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (1, 10));
+    assert_eq!(walk("free_head", "next"), vec!["3", "4", "2", "1"]);
+    assert_eq!(walk("free_tail", "prev"), vec!["1", "2", "4", "3"]);
+
+    // Asking for more than there are frees everything.
+    store.set_max_bytes(None, |_| panic!("nothing should be deleted")).unwrap();
+    let (parents, n) = store.delete_batch(10).unwrap();
+    assert_eq!((parents, n), (vec![parent("h")], 10));
+    assert_eq!((store.used_buckets(), store.used_bytes()), (0, 0));
+    assert!(walk("head", "next").is_empty());
+    assert_eq!(walk("free_head", "next"), vec!["3", "4", "2", "1", "0"]);
+    assert_eq!(store.delete_batch(1).unwrap_err().raw_os_error(), Some(libc::EINVAL));
+
+    // And it all still makes sense after a restart.
+    let mut store = new_store();
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    assert_eq!((store.used_buckets(), store.used_bytes()), (0, 0));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_bucket_store_max_buckets() {
+    let (base, new_store) = scratch_store("max-buckets");
+    let new_store = |max_buckets| new_store(Some(100)).with_max_buckets(max_buckets);
+    let parent = |name: &str| base.join("map").join(name).into_os_string();
+
+    let mut store = new_store(3);
+    store.init(|_| panic!("nothing should be deleted on init")).unwrap();
+    for name in &["a", "b", "c"] {
+        store.put(&parent(name), b"01", |_| panic!("nothing should be deleted")).unwrap();
+    }
+
+    // Far under the byte limit, but at the bucket limit, so the oldest one has to go.
+    let mut deleted = vec![];
+    store.put(&parent("d"), b"01", |parent| {
+            deleted.push(parent.to_owned());
+            Ok(())
+        }).unwrap();
+    assert_eq!(deleted, vec![parent("a")]);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (3, 6));
+
+    // The byte limit still applies too.
+    let mut deleted = vec![];
+    store.set_max_bytes(Some(3), |parent| {
+            deleted.push(parent.to_owned());
+            Ok(())
+        }).unwrap();
+    assert_eq!(deleted, vec![parent("b"), parent("c")]);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (1, 2));
+    store.set_max_bytes(Some(100), |_| panic!("nothing should be deleted")).unwrap();
+    for name in &["e", "f"] {
+        store.put(&parent(name), b"01", |_| panic!("nothing should be deleted")).unwrap();
+    }
+
+    // Starting up with a lower limit frees the excess right away.
+    let mut store = new_store(1);
+    let mut deleted = vec![];
+    store.init(|parent| {
+            deleted.push(parent.to_owned());
+            Ok(())
+        }).unwrap();
+    assert_eq!(deleted, vec![parent("d"), parent("e")]);
+    assert_eq!((store.used_buckets(), store.used_bytes()), (1, 2));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+// Runs through everything a block map does, against a real map directory.
+fn block_map_behavior<M: CacheBlockMap>(name: &str, new_map: fn(OsString) -> M) {
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&base);
+    let map_dir = base.join("map");
+    fs::create_dir_all(&map_dir).unwrap();
+    fs::create_dir_all(base.join("buckets")).unwrap();
+    let mut map = new_map(map_dir.clone().into_os_string());
+
+    // Makes a bucket with a parent link, the way the bucket store would.
+    let make_bucket = |map: &M, path: &OsStr, block: u64, n: u64| -> OsString {
+        let bucket = base.join("buckets").join(format!("{}", n));
+        fs::create_dir(&bucket).unwrap();
+        std::os::unix::fs::symlink(map.get_block_path(path, block), bucket.join("parent")).unwrap();
+        bucket.into_os_string()
+    };
+
+    let path = OsStr::new("/dir/file");
+    assert!(map.check_file_mtime(path, 100).unwrap() == CacheBlockMapFileResult::NotPresent);
+    map.set_file_mtime(path, 100).unwrap();
+    assert!(map.check_file_mtime(path, 100).unwrap() == CacheBlockMapFileResult::Current);
+    assert!(map.check_file_mtime(path, 101).unwrap() == CacheBlockMapFileResult::Stale);
+    map.set_file_atime(path, 200).unwrap();
+    assert_eq!(map.get_file_atime(path).unwrap(), Some(200));
+    map.set_file_size(path, 55).unwrap();
+    assert_eq!(map.get_file_size(path).unwrap(), Some(55));
+
+    let mut buckets = vec![];
+    for block in 0 .. 3 {
+        let bucket = make_bucket(&map, path, block, block);
+        map.put_block(path, block, &bucket).unwrap();
+        buckets.push(bucket);
+    }
+    map.put_hole(path, 5, 5).unwrap();
+
+    assert_eq!(map.get_block(path, 1).unwrap().as_ref(), Some(&buckets[1]));
+    assert_eq!(map.get_block(path, 5).unwrap(), None);
+    assert_eq!(map.get_hole(path, 5).unwrap(), Some(5));
+    assert_eq!(map.get_hole(path, 1).unwrap(), None);
+    assert_eq!(map.get_blocks(path).unwrap(), vec![0, 1, 2, 5]);
+    assert!(map.is_block_mapped(&map.get_block_path(path, 2)).unwrap());
+    assert!(!map.is_block_mapped(&map.get_block_path(path, 3)).unwrap());
+    map.set_file_latency(path, 1234).unwrap();
+    assert_eq!(map.get_block_file_latency(&map.get_block_path(path, 1)).unwrap(), Some(1234));
+
+    let mut all = vec![];
+    map.for_each_block(|block_path, bucket_path| {
+        all.push((block_path.to_owned(), bucket_path.to_owned()));
+        Ok(())
+    }).unwrap();
+    all.sort();
+    let expected: Vec<(OsString, OsString)> = (0 .. 3)
+        .map(|block| (map.get_block_path(path, block), buckets[block as usize].clone()))
+        .collect();
+    assert_eq!(all, expected);
+
+    let mut files = vec![];
+    map.for_each_file(|path, mtime, blocks| {
+        files.push((path.to_owned(), mtime, blocks.to_vec()));
+        Ok(())
+    }).unwrap();
+    assert_eq!(files, vec![(OsString::from("/dir/file"), 100, vec![0, 1, 2, 5])]);
+
+    // Just the paths, each once, including files with nothing but their metadata recorded.
+    map.set_file_mtime(OsStr::new("/dir/empty"), 150).unwrap();
+    let mut paths = vec![];
+    map.for_each_cached_file(|path| {
+        paths.push(path.to_owned());
+        Ok(())
+    }).unwrap();
+    paths.sort();
+    assert_eq!(paths, vec![OsString::from("/dir/empty"), OsString::from("/dir/file")]);
+
+    // Or just the ones under a directory.
+    let under = |dir: &str| {
+        let mut paths = vec![];
+        map.for_each_cached_file_under(OsStr::new(dir), |path| {
+            paths.push(path.to_owned());
+            Ok(())
+        }).unwrap();
+        paths.sort();
+        paths
+    };
+    assert_eq!(under("/dir"), vec![OsString::from("/dir/empty"), OsString::from("/dir/file")]);
+    assert_eq!(under("/"), under("/dir"));
+    assert!(under("/dir/file").is_empty());
+    assert!(under("/dir/file/1").is_empty());
+    assert!(under("/nowhere").is_empty());
+
+    map.unmap_block(&map.get_block_path(path, 1)).unwrap();
+    assert!(map.unmap_hole(path, 5).unwrap());
+    assert!(!map.unmap_hole(path, 5).unwrap());
+    assert_eq!(map.get_blocks(path).unwrap(), vec![0, 2]);
+
+    // Invalidating a directory hands back the blocks and buckets of everything under it.
+    let other = OsStr::new("/other");
+    map.set_file_mtime(other, 300).unwrap();
+    let other_bucket = make_bucket(&map, other, 0, 10);
+    map.put_block(other, 0, &other_bucket).unwrap();
+
+    let mut freed = vec![];
+    map.invalidate_path(OsStr::new("/dir"), |block_path, bucket_path| {
+        freed.push((block_path.to_owned(), bucket_path.to_owned()));
+        Ok(())
+    }).unwrap();
+    freed.sort();
+    assert_eq!(freed, vec![(map.get_block_path(path, 0), buckets[0].clone()),
+                           (map.get_block_path(path, 2), buckets[2].clone())]);
+    assert!(!map_dir.join("dir").exists());
+    assert!(map.check_file_mtime(path, 100).unwrap() == CacheBlockMapFileResult::NotPresent);
+    assert_eq!(map.get_block(other, 0).unwrap(), Some(other_bucket));
+
+    // Unmapping the last block cleans up the file's map directory.
+    map.unmap_block(&map.get_block_path(other, 0)).unwrap();
+    assert!(!map_dir.join("other").exists());
+
+    // Files named like the map's own files don't get mixed up with them.
+    let magic = [OsStr::new("/.backfs_block_size"), OsStr::new("/dir/.backfs_meta")];
+    for (i, path) in magic.iter().enumerate() {
+        map.set_file_mtime(path, 400 + i as i64).unwrap();
+        let bucket = make_bucket(&map, path, 0, 20 + i as u64);
+        map.put_block(path, 0, &bucket).unwrap();
+    }
+    map.set_file_mtime(OsStr::new("/dir/a"), 500).unwrap();
+    let mut files = vec![];
+    map.for_each_file(|path, mtime, blocks| {
+        files.push((path.to_owned(), mtime, blocks.to_vec()));
+        Ok(())
+    }).unwrap();
+    files.sort();
+    assert!(!map_dir.join(".backfs_block_size").exists());
+    assert_eq!(files, vec![(OsString::from("/.backfs_block_size"), 400, vec![0]),
+                           (OsString::from("/dir/.backfs_meta"), 401, vec![0]),
+                           (OsString::from("/dir/a"), 500, vec![])]);
+    for path in &magic {
+        map.invalidate_path(path, |_, _| Ok(())).unwrap();
+    }
+    assert_eq!(map.read_file_meta(OsStr::new("/dir/a")).unwrap().mtime, Some(500));
+
+    map.set_file_mtime(other, 300).unwrap();
+    map.clear().unwrap();
+    assert_eq!(fs::read_dir(&map_dir).unwrap().count(), 0);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_block_map_links() {
+    block_map_behavior("map-links", FsCacheBlockMap::new);
+}
+
+#[test]
+fn test_block_map_binary_meta() {
+    block_map_behavior("map-binary-meta",
+                       |dir| FsCacheBlockMap::new(dir).with_meta_format(MetaFormat::Binary));
+}
+
+#[test]
+fn test_block_map_packed_meta() {
+    block_map_behavior("map-packed-meta",
+                       |dir| FsCacheBlockMap::new(dir).with_meta_format(MetaFormat::Packed));
+    block_map_behavior("map-index-packed-meta",
+                       |dir| FsCacheIndexBlockMap::new(dir).with_meta_format(MetaFormat::Packed));
+
+    // All the files in a directory share one meta file, beside their (so far empty) map
+    // directories.
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-packed-meta", process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("map")).unwrap();
+    let mut map = FsCacheBlockMap::new(base.join("map").into_os_string())
+        .with_meta_format(MetaFormat::Packed);
+    for (i, name) in ["/dir/a", "/dir/b", "/dir/c"].iter().enumerate() {
+        map.set_file_mtime(OsStr::new(name), i as i64).unwrap();
+        map.set_file_size(OsStr::new(name), 1000 + i as u64).unwrap();
+    }
+    let names = |dir: &str| {
+        let mut names: Vec<OsString> = fs::read_dir(base.join(dir)).unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names("map/dir"), vec![".backfs_meta", "a", "b", "c"]);
+    assert!(names("map/dir/a").is_empty());
+    let b = OsStr::new("/dir/b");
+    assert!(map.check_file_mtime(b, 1).unwrap() == CacheBlockMapFileResult::Current);
+    assert!(map.check_file_mtime(b, 2).unwrap() == CacheBlockMapFileResult::Stale);
+    assert_eq!(map.get_file_size(b).unwrap(), Some(1001));
+
+    // Changes are added to the end of the file, which is rewritten once it's mostly replaced
+    // records.
+    let packed_path = base.join("map/dir/.backfs_meta");
+    let len = fs::metadata(&packed_path).unwrap().len();
+    map.set_file_size(b, 2000).unwrap();
+    assert!(fs::metadata(&packed_path).unwrap().len() > len);
+    for size in 0 .. 2000 {
+        map.set_file_size(b, size).unwrap();
+    }
+    assert!(fs::metadata(&packed_path).unwrap().len() < 10000);
+    let reopened = FsCacheBlockMap::new(base.join("map").into_os_string())
+        .with_meta_format(MetaFormat::Packed);
+    assert_eq!(reopened.get_file_size(b).unwrap(), Some(1999));
+    assert_eq!(reopened.get_file_size(OsStr::new("/dir/c")).unwrap(), Some(1002));
+
+    // A record cut short at the end is ignored.
+    fs::OpenOptions::new().append(true).open(&packed_path).unwrap().write_all(&[5, 1]).unwrap();
+    assert_eq!(reopened.get_file_size(b).unwrap(), Some(1999));
+
+    // Removing the last of them removes the shared file, and then the directory.
+    for name in &["/dir/a", "/dir/b", "/dir/c"] {
+        map.invalidate_path(OsStr::new(name), |_, _| panic!("no blocks to free")).unwrap();
+    }
+    assert!(map.check_file_mtime(b, 1).unwrap() == CacheBlockMapFileResult::NotPresent);
+    assert!(!base.join("map/dir").exists());
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_block_map_index() {
+    block_map_behavior("map-index", FsCacheIndexBlockMap::new);
+
+    // The index implementation keeps one file per file, not one per block.
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-map-index-file", process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("map")).unwrap();
+    let mut map = FsCacheIndexBlockMap::new(base.join("map").into_os_string());
+    let path = OsStr::new("/file");
+    map.set_file_mtime(path, 1).unwrap();
+    map.put_block(path, 0, OsStr::new("/buckets/with space")).unwrap();
+    map.put_hole(path, 1, 7).unwrap();
+    let mut names: Vec<OsString> = fs::read_dir(base.join("map/file")).unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["blocks", "meta"]);
+    assert_eq!(fs::read_to_string(base.join("map/file/blocks")).unwrap(),
+               "0 bucket /buckets/with space\n1 hole 7\n");
+    assert_eq!(map.get_block(path, 0).unwrap(), Some(OsString::from("/buckets/with space")));
+
+    // Buckets named with numbers are recorded by number. Changes are added to the end.
+    map.put_block(path, 2, OsStr::new("/buckets/12")).unwrap();
+    map.put_block(path, 3, OsStr::new("/buckets/13")).unwrap();
+    map.unmap_block(&map.get_block_path(path, 0)).unwrap();
+    assert_eq!(fs::read_to_string(base.join("map/file/blocks")).unwrap(),
+               "0 bucket /buckets/with space\n1 hole 7\nbuckets /buckets\n2 12\n3 13\n0 none\n");
+    let reread = FsCacheIndexBlockMap::new(base.join("map").into_os_string());
+    assert_eq!(reread.get_blocks(path).unwrap(), vec![1, 2, 3]);
+    assert_eq!(reread.get_block(path, 3).unwrap(), Some(OsString::from("/buckets/13")));
+
+    // A line being added when BackFS stopped is ignored.
+    fs::OpenOptions::new().append(true).open(base.join("map/file/blocks")).unwrap()
+        .write_all(b"4 1").unwrap();
+    let reread = FsCacheIndexBlockMap::new(base.join("map").into_os_string());
+    assert_eq!(reread.get_blocks(path).unwrap(), vec![1, 2, 3]);
+
+    // Once it's mostly lines which don't count any more, it's rewritten without them.
+    for n in 0 .. 100 {
+        map.put_block(path, 2, OsStr::new(&format!("/buckets/{}", n))).unwrap();
+    }
+    let index = fs::read_to_string(base.join("map/file/blocks")).unwrap();
+    assert!(index.starts_with("1 hole 7\nbuckets /buckets\n"), "{:?}", index);
+    assert!(index.lines().count() < 30, "{:?}", index);
+    let reread = FsCacheIndexBlockMap::new(base.join("map").into_os_string());
+    assert_eq!(reread.get_blocks(path).unwrap(), vec![1, 2, 3]);
+    assert_eq!(reread.get_block(path, 2).unwrap(), Some(OsString::from("/buckets/99")));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_file_meta() {
+    let mut meta = FileMeta::default();
+    assert_eq!(meta.to_bytes(), b"");
+    assert_eq!(FileMeta::parse(b"").unwrap(), meta);
+
+    meta.mtime = Some(-5);
+    meta.size = Some(1234);
+    meta.sample = Some(u64::MAX);
+    assert_eq!(str::from_utf8(&meta.to_bytes()).unwrap(),
+               "mtime -5\nsize 1234\nsample 18446744073709551615\n");
+    assert_eq!(FileMeta::parse(&meta.to_bytes()).unwrap(), meta);
+
+    // Fields from some newer version are skipped, but kept for writing back out.
+    let newer = FileMeta::parse(b"pinned yes\nmtime 7\naccess_count 3 times\n").unwrap();
+    assert_eq!(newer.mtime, Some(7));
+    assert_eq!(FileMeta::parse(&newer.to_bytes()).unwrap(), newer);
+    assert_eq!(str::from_utf8(&newer.to_bytes()).unwrap(),
+               "mtime 7\npinned yes\naccess_count 3 times\n");
+
+    // The compact form holds just the same.
+    let compact = newer.to_compact_bytes();
+    assert_eq!(FileMeta::parse_compact(&compact).unwrap(), newer);
+    assert_eq!(FileMeta::parse_compact(&meta.to_compact_bytes()).unwrap(), meta);
+    assert_eq!(FileMeta::parse_compact(b"").unwrap(), FileMeta::default());
+    let mut times = FileMeta::default();
+    times.mtime = Some(1_617_225_600);
+    times.atime = Some(i64::MIN);
+    assert_eq!(FileMeta::parse_compact(&times.to_compact_bytes()).unwrap(), times);
+    assert!(times.to_compact_bytes().len() < times.to_bytes().len());
+    assert_eq!(&times.to_compact_bytes()[.. 6], &[1, 0x80, 0x9e, 0xa7, 0x86, 0x0c]);
+    for bad in [&[1u8][..], &[1, 0x80], &[9, 0], &[0, 5, b'a']] {
+        assert_eq!(FileMeta::parse_compact(bad).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    // Fields it does know have to make sense, though.
+    for bad in [&b"mtime\n"[..], b"size -1\n", b"atime 1.5\n"] {
+        assert_eq!(FileMeta::parse(bad).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    // Through a real map, setting one field leaves the others (even unknown ones) alone.
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-file-meta", process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("map")).unwrap();
+    let mut map = FsCacheBlockMap::new(base.join("map").into_os_string());
+    let path = OsStr::new("/dir/file");
+    assert_eq!(map.read_file_meta(path).unwrap(), FileMeta::default());
+    map.write_file_meta(path, &newer).unwrap();
+    map.set_file_atime(path, 99).unwrap();
+    let mut expected = newer.clone();
+    expected.atime = Some(99);
+    assert_eq!(map.read_file_meta(path).unwrap(), expected);
+    assert!(map.check_file_mtime(path, 7).unwrap() == CacheBlockMapFileResult::Current);
+    assert_eq!(fs::read_dir(base.join("map/dir/file")).unwrap().count(), 1);
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_upgrade_legacy_meta_files() {
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-legacy-meta", process::id()));
+    let _ = fs::remove_dir_all(&base);
+    let map_dir = base.join("map");
+    fs::create_dir_all(map_dir.join("dir/file")).unwrap();
+    fs::create_dir_all(map_dir.join("other")).unwrap();
+    for (name, value) in [("mtime", "100"), ("atime", "200"), ("size", "55"), ("sample", "9")] {
+        fs::write(map_dir.join("dir/file").join(name), value).unwrap();
+    }
+    std::os::unix::fs::symlink("/buckets/0", map_dir.join("dir/file/0")).unwrap();
+    fs::write(map_dir.join("other/mtime"), "300").unwrap();
+    fs::write(map_dir.join("other/blocks"), "0 hole 10\n").unwrap();
+
+    assert_eq!(upgrade_legacy_meta_files(&map_dir).unwrap(), 2);
+    assert_eq!(upgrade_legacy_meta_files(&map_dir).unwrap(), 0);
+
+    let map = FsCacheBlockMap::new(map_dir.clone().into_os_string());
+    let meta = map.read_file_meta(OsStr::new("/dir/file")).unwrap();
+    assert_eq!((meta.mtime, meta.atime, meta.size, meta.latency, meta.sample),
+               (Some(100), Some(200), Some(55), None, Some(9)));
+    let mut names: Vec<OsString> = fs::read_dir(map_dir.join("dir/file")).unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, vec!["0", "meta"]);
+
+    let map = FsCacheIndexBlockMap::new(map_dir.clone().into_os_string());
+    assert!(map.check_file_mtime(OsStr::new("/other"), 300).unwrap()
+            == CacheBlockMapFileResult::Current);
+    assert_eq!(map.get_hole(OsStr::new("/other"), 0).unwrap(), Some(10));
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_control_cli() {
+    // A plain file stands in for the control file of a running mount.
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-control-cli", process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(&base).unwrap();
+    let control = base.join(".backfs_control");
+    fs::write(&control, b"").unwrap();
+
+    let status = process::Command::new(env!("CARGO_BIN_EXE_backfs-rs"))
+        .arg("--control")
+        .arg(&base)
+        .args(["invalidate", "/some/path "])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(fs::read(&control).unwrap(), b"\0invalidate\0/some/path \0");
+
+    // Without a mount there's no control file to write to.
+    fs::remove_file(&control).unwrap();
+    let status = process::Command::new(env!("CARGO_BIN_EXE_backfs-rs"))
+        .arg("--control")
+        .arg(&base)
+        .arg("free_orphans")
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    fs::remove_dir_all(&base).unwrap();
+}
+
+#[test]
+fn test_inspect_cli() {
+    let base = std::env::temp_dir().join(format!("backfs-test-{}-inspect-cli", process::id()));
+    let _ = fs::remove_dir_all(&base);
+    fs::create_dir_all(base.join("map")).unwrap();
+    fs::create_dir_all(base.join("buckets")).unwrap();
+
+    {
+        let buckets_dir = base.join("buckets").into_os_string();
+        let used_list = Fsll::new(&buckets_dir, "head", "tail");
+        let free_list = Fsll::new(&buckets_dir, "free_head", "free_tail");
+        let cache = FsCache::new(
+            AnyBlockMap::new(MapFormat::Links, base.join("map").into_os_string()),
+            AnyBucketStore::Dirs(
+                FsCacheBucketStore::new(buckets_dir, used_list, free_list, 10, None)),
+            10);
+        cache.init().unwrap();
+        let mut data = Cursor::new(Vec::from("ABCDEFGHIJKLMNOPQRSTUVWXY"));
+        cache.fetch(OsStr::new("/hello.txt"), 0, 1024, &mut data, 1).unwrap();
+    }
+
+    let snapshot = || {
+        walkdir::WalkDir::new(&base).sort_by_file_name().into_iter()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let len = fs::symlink_metadata(entry.path()).unwrap().len();
+                (entry.path().to_owned(), len)
+            })
+            .collect::<Vec<_>>()
+    };
+    let before = snapshot();
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_backfs-rs"))
+        .arg("--inspect")
+        .arg(&base)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report = str::from_utf8(&output.stdout).unwrap();
+    assert!(report.contains("Block size: 10\n"), "{}", report);
+    assert!(report.contains("Used: 25 bytes"), "{}", report);
+    assert!(report.contains("in 3 buckets"), "{}", report);
+    assert!(report.contains("Files: 1 (3 blocks)"), "{}", report);
+
+    // Nothing in the cache was touched.
+    assert_eq!(before, snapshot());
+
+    let output = process::Command::new(env!("CARGO_BIN_EXE_backfs-rs"))
+        .arg("--inspect")
+        .arg(base.join("nonexistent"))
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(str::from_utf8(&output.stderr).unwrap().contains("isn't a BackFS cache directory"));
+
+    fs::remove_dir_all(&base).unwrap();
+}